@@ -0,0 +1,196 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Auxiliary source that listens to the periodic frames sent by devices on
+//! a CAN bus (using SocketCAN on Linux). The frames are decoded by
+//! [sunsniff_core::can].
+
+use futures::channel::mpsc;
+use futures::prelude::*;
+use log::{debug, error, info};
+use serde::Deserialize;
+use serde_with::serde_as;
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::time::MissedTickBehavior;
+
+use sunsniff_core::can::{Values, PROTOCOLS};
+
+use crate::receiver::{Metadata, Update, UpdateStream};
+
+/// Structure corresponding to the `[can]` section of the configuration file.
+#[serde_as]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CanConfig {
+    interface: String,
+    protocol: String,
+    #[serde_as(as = "serde_with::DurationSecondsWithFrac<f64>")]
+    interval: Duration,
+    /// Name to use in place of an inverter serial number (defaults to the
+    /// protocol name)
+    serial: Option<String>,
+}
+
+/// Raw SocketCAN socket bound to a single interface
+struct CanSocket {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl CanSocket {
+    fn open(interface: &str) -> io::Result<Self> {
+        let name = CString::new(interface)?;
+        // SAFETY: name is a valid NUL-terminated string
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: socket has no memory-safety preconditions
+        let fd = unsafe {
+            libc::socket(
+                libc::PF_CAN,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::CAN_RAW,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd is a newly-created socket that nothing else owns
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // SAFETY: sockaddr_can is plain data, for which zero is valid
+        let mut addr: libc::sockaddr_can = unsafe { std::mem::zeroed() };
+        addr.can_family = libc::AF_CAN as libc::sa_family_t;
+        addr.can_ifindex = ifindex as libc::c_int;
+        // SAFETY: addr is a valid sockaddr_can and the length matches it
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_can as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_can>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+
+    /// Receive the next data frame, returning its ID and data
+    async fn recv(&self) -> io::Result<(u32, Vec<u8>)> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let result = guard.try_io(|fd| {
+                // SAFETY: can_frame is plain data, for which zero is valid
+                let mut frame: libc::can_frame = unsafe { std::mem::zeroed() };
+                let size = std::mem::size_of::<libc::can_frame>();
+                // SAFETY: frame is a writable buffer of the given size
+                let n = unsafe {
+                    libc::read(
+                        fd.as_raw_fd(),
+                        &mut frame as *mut libc::can_frame as *mut libc::c_void,
+                        size,
+                    )
+                };
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else if n as usize != size {
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "short CAN frame",
+                    ))
+                } else {
+                    Ok(frame)
+                }
+            });
+            let frame = match result {
+                Ok(frame) => frame?,
+                Err(_would_block) => continue,
+            };
+            if frame.can_id & (libc::CAN_ERR_FLAG | libc::CAN_RTR_FLAG) != 0 {
+                continue;
+            }
+            let can_id = if frame.can_id & libc::CAN_EFF_FLAG != 0 {
+                frame.can_id & libc::CAN_EFF_MASK
+            } else {
+                frame.can_id & libc::CAN_SFF_MASK
+            };
+            let len = (frame.can_dlc as usize).min(frame.data.len());
+            return Ok((can_id, frame.data[..len].to_vec()));
+        }
+    }
+}
+
+/// Create a stream of updates from the CAN bus. The stream never ends by
+/// itself, so it is intended to be merged into the main stream with
+/// [crate::receiver::merge_auxiliary].
+pub async fn create_stream(config: &CanConfig) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+    let (&protocol_name, protocol) = PROTOCOLS
+        .get_entry(config.protocol.as_str())
+        .ok_or_else(|| format!("Unknown CAN protocol {:?}", config.protocol))?;
+    let source = format!("can:{}", config.interface);
+    let serial = config.serial.clone().unwrap_or(config.protocol.clone());
+    let socket = CanSocket::open(&config.interface)
+        .map_err(|err| format!("Failed to open CAN interface {}: {err}", config.interface))?;
+    let interval = config.interval;
+    let (mut sender, receiver) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut values = Values::new(protocol);
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            tokio::select! {
+                frame = socket.recv() => match frame {
+                    Ok((can_id, data)) => values.update(can_id, &data),
+                    Err(err) => {
+                        error!("Failed to receive CAN frame: {err}");
+                        // Avoid spinning if the interface is down
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                },
+                _ = interval.tick() => {
+                    if !values.complete() {
+                        debug!("Not all values have been received from the CAN bus yet");
+                        continue;
+                    }
+                    info!("Received a set of values from the CAN bus");
+                    let now = chrono::Utc::now();
+                    let update = Update::new(
+                        now.timestamp_nanos_opt().unwrap(),
+                        &serial,
+                        protocol.fields,
+                        values.values().to_vec(),
+                    )
+                    .with_metadata(Metadata {
+                        source: Some(source.clone()),
+                        protocol: Some(protocol_name),
+                        ..Default::default()
+                    });
+                    if sender.send(Arc::new(update)).await.is_err() {
+                        break; // The main stream has ended
+                    }
+                }
+            }
+        }
+    });
+    Ok(Box::pin(receiver))
+}
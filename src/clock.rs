@@ -0,0 +1,96 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Source of the current time, which can be replaced in tests
+//!
+//! Code that reads the time or waits should take an [Arc<dyn Clock>] rather
+//! than calling the system or runtime directly. [SystemClock] is used in
+//! normal operation. [MockClock] follows Tokio's clock instead of the
+//! system time, so that tests can use [tokio::time::pause] and
+//! [tokio::time::advance] to control both the wall-clock time and sleeps.
+
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+
+pub trait Clock: Send + Sync {
+    /// Current time, in nanoseconds since the UNIX epoch
+    fn now(&self) -> i64;
+
+    /// Current monotonic time, for measuring intervals
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// Wait until `duration` has elapsed
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::sleep(duration).boxed()
+    }
+}
+
+/// The real time
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        // Not chrono::Utc::now, which needs the clock feature
+        let now = chrono::DateTime::<chrono::Utc>::from(SystemTime::now());
+        now.timestamp_nanos_opt().unwrap()
+    }
+}
+
+/// A clock that starts at a fixed time and advances with Tokio's clock
+#[derive(Clone, Copy, Debug)]
+pub struct MockClock {
+    /// Time (in nanoseconds since the UNIX epoch) at `origin`
+    start: i64,
+    origin: Instant,
+}
+
+impl MockClock {
+    pub fn new(start: i64) -> Arc<Self> {
+        Arc::new(Self {
+            start,
+            origin: Instant::now(),
+        })
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> i64 {
+        self.start + self.origin.elapsed().as_nanos() as i64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mock_clock() {
+        let clock = MockClock::new(1_000_000_000);
+        let start = clock.instant();
+        assert_eq!(clock.now(), 1_000_000_000);
+        clock.sleep(Duration::from_secs(5)).await;
+        assert_eq!(clock.now(), 6_000_000_000);
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_eq!(clock.now(), 6_001_000_000);
+        assert_eq!(clock.instant() - start, Duration::from_millis(5001));
+    }
+}
@@ -0,0 +1,75 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Compression of the payloads sent by backends
+
+use flate2::write::GzEncoder;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::io::Write;
+
+/// Compression to apply to payloads, from the `compression` key of a
+/// backend's configuration
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+}
+
+impl Compression {
+    /// Value for the `Content-Encoding` header, if the payload is compressed
+    pub fn content_encoding(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gzip"),
+        }
+    }
+
+    /// Compress a payload
+    pub fn compress(self, data: &[u8]) -> Cow<'_, [u8]> {
+        match self {
+            Compression::None => Cow::Borrowed(data),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                // Writing to a Vec cannot fail
+                encoder.write_all(data).unwrap();
+                Cow::Owned(encoder.finish().unwrap())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_compress() {
+        let data = b"inverter,serial=1234 value=1.5 1000\n".repeat(10);
+        assert_eq!(Compression::None.compress(&data), &data[..]);
+        let compressed = Compression::Gzip.compress(&data);
+        assert!(compressed.len() < data.len());
+        let mut decompressed = vec![];
+        GzDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+}
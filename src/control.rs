@@ -0,0 +1,326 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Command bus for sending requests from receivers back to the frontend
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::prelude::*;
+use log::warn;
+
+use crate::fields::Field;
+
+pub mod guard;
+
+/// A request from a receiver to the frontend
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Poll the inverter with the given serial number immediately, rather
+    /// than waiting for the next interval
+    PollNow { serial: String },
+    /// Write a value to a holding register of the inverter with the given
+    /// serial number. The inverter is polled immediately afterwards.
+    WriteRegister {
+        serial: String,
+        register: u16,
+        value: u16,
+        /// Description of where the request came from, for auditing
+        origin: String,
+    },
+    /// Stop the frontend. This ends the stream of updates, which causes the
+    /// program to exit once the receivers have finished.
+    Shutdown,
+}
+
+impl Command {
+    /// Whether the command applies to the inverter with a given serial number
+    pub fn targets(&self, serial: &str) -> bool {
+        match self {
+            Command::PollNow { serial: target } => target == serial,
+            Command::WriteRegister { serial: target, .. } => target == serial,
+            Command::Shutdown => true,
+        }
+    }
+
+    /// Replace the serial number in the command using a lookup function.
+    /// Returns `None` if the lookup fails. Commands that do not have a
+    /// serial number are returned unchanged.
+    pub fn map_serial(self, lookup: impl FnOnce(&str) -> Option<String>) -> Option<Self> {
+        Some(match self {
+            Command::PollNow { serial } => Command::PollNow {
+                serial: lookup(&serial)?,
+            },
+            Command::WriteRegister {
+                serial,
+                register,
+                value,
+                origin,
+            } => Command::WriteRegister {
+                serial: lookup(&serial)?,
+                register,
+                value,
+                origin,
+            },
+            Command::Shutdown => Command::Shutdown,
+        })
+    }
+}
+
+/// What became of a [Command::WriteRegister]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WriteOutcome {
+    /// The register was read back with the written value
+    Verified,
+    /// The inverter rejected or clamped the value, and stored this instead
+    Changed { stored: u16 },
+    /// The write itself failed
+    WriteFailed(String),
+    /// The write succeeded but the register could not be read back
+    ReadFailed(String),
+}
+
+impl WriteOutcome {
+    /// Whether the inverter is known to hold the written value
+    pub fn is_success(&self) -> bool {
+        *self == WriteOutcome::Verified
+    }
+}
+
+/// Report of a [Command::WriteRegister] having been carried out
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteEvent {
+    pub serial: String,
+    pub register: u16,
+    pub value: u16,
+    /// Where the request came from
+    pub origin: String,
+    pub outcome: WriteOutcome,
+}
+
+/// Reason that a setting cannot be changed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SettingError {
+    /// There is no setting with the ID, or this build cannot change settings
+    Unknown,
+    /// The value cannot be stored in the register
+    OutOfRange,
+}
+
+/// Look up a setting that can be changed, returning its field and register.
+/// This always fails in builds without the modbus frontend or with the
+/// `read_only` feature.
+#[cfg(all(feature = "modbus", not(feature = "read_only")))]
+pub fn setting(id: &str) -> Option<(&'static Field<'static>, u16)> {
+    crate::modbus::setting(id)
+}
+
+#[cfg(any(not(feature = "modbus"), feature = "read_only"))]
+pub fn setting(_id: &str) -> Option<(&'static Field<'static>, u16)> {
+    None
+}
+
+/// Names of the values of a setting that is a choice between a few options,
+/// in order of value, or `None` if it is numeric or cannot be changed
+#[cfg(all(feature = "modbus", not(feature = "read_only")))]
+pub fn setting_options(id: &str) -> Option<&'static [&'static str]> {
+    crate::modbus::setting_options(id)
+}
+
+#[cfg(any(not(feature = "modbus"), feature = "read_only"))]
+pub fn setting_options(_id: &str) -> Option<&'static [&'static str]> {
+    None
+}
+
+/// Build the command to change a setting to `value` (in the units of the
+/// field returned by [setting])
+pub fn setting_command(
+    serial: String,
+    id: &str,
+    value: f64,
+    origin: &str,
+) -> Result<Command, SettingError> {
+    let (field, register) = setting(id).ok_or(SettingError::Unknown)?;
+    let value = field.to_u16(value).ok_or(SettingError::OutOfRange)?;
+    if setting_options(id).is_some_and(|options| usize::from(value) >= options.len()) {
+        return Err(SettingError::OutOfRange);
+    }
+    Ok(Command::WriteRegister {
+        serial,
+        register,
+        value,
+        origin: origin.to_owned(),
+    })
+}
+
+pub type CommandSender = UnboundedSender<Command>;
+pub type CommandReceiver = UnboundedReceiver<Command>;
+
+/// Create a channel for sending commands to the frontend. Receivers are
+/// given clones of the sender, and the frontend subscribes to the receiver.
+pub fn channel() -> (CommandSender, CommandReceiver) {
+    mpsc::unbounded()
+}
+
+/// Wait until a [Command::Shutdown] is received, ignoring other commands.
+/// This is for frontends that cannot act on any other commands. If the
+/// channel is closed, this never completes.
+pub async fn wait_shutdown(mut commands: CommandReceiver) {
+    while let Some(command) = commands.next().await {
+        if command == Command::Shutdown {
+            return;
+        }
+        warn!("Ignoring unsupported command {command:?}");
+    }
+    future::pending().await
+}
+
+/// Forward commands from `input` to `output`, translating the serial
+/// numbers with `lookup`. This is used when receivers see different serial
+/// numbers to the frontend. Commands for unknown serial numbers are dropped.
+pub async fn translate(
+    mut input: CommandReceiver,
+    output: CommandSender,
+    lookup: impl Fn(&str) -> Option<String>,
+) {
+    while let Some(command) = input.next().await {
+        match command.clone().map_serial(&lookup) {
+            Some(command) => {
+                if output.unbounded_send(command).is_err() {
+                    break;
+                }
+            }
+            None => warn!("Ignoring command for unknown inverter: {command:?}"),
+        }
+    }
+}
+
+/// Forward each command from `input` to all of `outputs`. This is used
+/// when there are several frontends, each of which only acts on the
+/// commands for its own inverters.
+pub async fn broadcast(mut input: CommandReceiver, outputs: Vec<CommandSender>) {
+    while let Some(command) = input.next().await {
+        for output in outputs.iter() {
+            // A frontend that has stopped does not need any more commands
+            let _ = output.unbounded_send(command.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_targets() {
+        let poll = Command::PollNow {
+            serial: "1234".to_owned(),
+        };
+        assert!(poll.targets("1234"));
+        assert!(!poll.targets("4321"));
+        let write = Command::WriteRegister {
+            serial: "1234".to_owned(),
+            register: 1,
+            value: 2,
+            origin: "test".to_owned(),
+        };
+        assert!(write.targets("1234"));
+        assert!(!write.targets("4321"));
+        assert!(Command::Shutdown.targets("4321"));
+    }
+
+    #[cfg(all(feature = "modbus", not(feature = "read_only")))]
+    #[test]
+    fn test_setting_command() {
+        let command = setting_command("1234".to_owned(), "inverter_program_soc_1", 50.0, "test");
+        assert_eq!(
+            command,
+            Ok(Command::WriteRegister {
+                serial: "1234".to_owned(),
+                register: 268,
+                value: 50,
+                origin: "test".to_owned(),
+            })
+        );
+        let command = setting_command("1234".to_owned(), "inverter_program_soc_1", 1e6, "test");
+        assert_eq!(command, Err(SettingError::OutOfRange));
+        let command = setting_command("1234".to_owned(), "pv_power", 50.0, "test");
+        assert_eq!(command, Err(SettingError::Unknown));
+        let command = setting_command("1234".to_owned(), "inverter_work_mode", 2.0, "test");
+        assert!(matches!(
+            command,
+            Ok(Command::WriteRegister {
+                register: 244,
+                value: 2,
+                ..
+            })
+        ));
+        let command = setting_command("1234".to_owned(), "inverter_work_mode", 3.0, "test");
+        assert_eq!(command, Err(SettingError::OutOfRange));
+    }
+
+    #[tokio::test]
+    async fn test_wait_shutdown() {
+        let (sender, receiver) = channel();
+        sender
+            .unbounded_send(Command::PollNow {
+                serial: "1234".to_owned(),
+            })
+            .unwrap();
+        sender.unbounded_send(Command::Shutdown).unwrap();
+        wait_shutdown(receiver).await;
+    }
+
+    #[tokio::test]
+    async fn test_translate() {
+        let (input_sender, input) = channel();
+        let (output, mut output_receiver) = channel();
+        for serial in ["alias", "unknown"] {
+            input_sender
+                .unbounded_send(Command::PollNow {
+                    serial: serial.to_owned(),
+                })
+                .unwrap();
+        }
+        input_sender.unbounded_send(Command::Shutdown).unwrap();
+        drop(input_sender);
+        translate(input, output, |serial| {
+            (serial == "alias").then(|| "1234".to_owned())
+        })
+        .await;
+        let commands: Vec<Command> = output_receiver.by_ref().collect().await;
+        assert_eq!(
+            commands,
+            [
+                Command::PollNow {
+                    serial: "1234".to_owned()
+                },
+                Command::Shutdown
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_broadcast() {
+        let (input_sender, input) = channel();
+        let (output1, output1_receiver) = channel();
+        let (output2, output2_receiver) = channel();
+        input_sender.unbounded_send(Command::Shutdown).unwrap();
+        drop(input_sender);
+        drop(output2_receiver);
+        broadcast(input, vec![output1, output2]).await;
+        let commands: Vec<Command> = output1_receiver.collect().await;
+        assert_eq!(commands, [Command::Shutdown]);
+    }
+}
@@ -0,0 +1,264 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Safety checks applied to every command that changes the inverter, no
+//! matter which receiver it came from.
+
+use futures::prelude::*;
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use super::{Command, CommandReceiver, CommandSender};
+use crate::clock::{Clock, SystemClock};
+
+/// Window over which [Config::rate_limit] is applied
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Structure corresponding to the `[control]` section of the configuration
+/// file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Registers that may be written (as decimal strings, since TOML keys
+    /// are strings), with the allowed range of raw values
+    #[serde(default)]
+    registers: HashMap<String, Bounds>,
+    /// Maximum number of writes (to all inverters) per minute
+    rate_limit: Option<usize>,
+    /// File to append a record of every attempted write to
+    audit_log: Option<PathBuf>,
+}
+
+/// Inclusive range of raw values allowed for a register
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+struct Bounds {
+    #[serde(default)]
+    min: u16,
+    #[serde(default = "default_max")]
+    max: u16,
+}
+
+fn default_max() -> u16 {
+    u16::MAX
+}
+
+pub struct Guard {
+    registers: HashMap<u16, Bounds>,
+    rate_limit: Option<usize>,
+    /// Times of the writes allowed within the last [RATE_WINDOW]
+    recent: VecDeque<Instant>,
+    audit_log: Option<LineWriter<File>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Guard {
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let registers = config
+            .registers
+            .iter()
+            .map(|(register, bounds)| {
+                let register: u16 = register
+                    .parse()
+                    .map_err(|_| format!("Invalid register number {register:?}"))?;
+                Ok((register, *bounds))
+            })
+            .collect::<Result<_, String>>()?;
+        let audit_log = match &config.audit_log {
+            Some(path) => Some(LineWriter::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            None => None,
+        };
+        Ok(Self {
+            registers,
+            rate_limit: config.rate_limit,
+            recent: VecDeque::new(),
+            audit_log,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Use a different clock for rate limiting and the audit log, for testing
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Decide whether a command may be passed to the frontend. Only
+    /// [Command::WriteRegister] is restricted.
+    fn check(&mut self, command: &Command) -> Result<(), String> {
+        let Command::WriteRegister {
+            register, value, ..
+        } = command
+        else {
+            return Ok(());
+        };
+        let bounds = self
+            .registers
+            .get(register)
+            .ok_or_else(|| format!("register {register} is not allowed"))?;
+        if !(bounds.min..=bounds.max).contains(value) {
+            return Err(format!(
+                "value {value} is outside [{}, {}]",
+                bounds.min, bounds.max
+            ));
+        }
+        let now = self.clock.instant();
+        while self
+            .recent
+            .front()
+            .is_some_and(|&time| now.duration_since(time) >= RATE_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+        if let Some(limit) = self.rate_limit {
+            if self.recent.len() >= limit {
+                return Err(format!("more than {limit} writes per minute"));
+            }
+        }
+        self.recent.push_back(now);
+        Ok(())
+    }
+
+    /// Describe the outcome of a write, for the audit log
+    fn audit_line(&self, command: &Command, result: &Result<(), String>) -> Option<String> {
+        let Command::WriteRegister {
+            serial,
+            register,
+            value,
+            origin,
+        } = command
+        else {
+            return None;
+        };
+        let outcome = match result {
+            Ok(()) => "allowed".to_owned(),
+            Err(reason) => format!("rejected ({reason})"),
+        };
+        let now = chrono::DateTime::from_timestamp_nanos(self.clock.now());
+        Some(format!(
+            "{} origin={origin} serial={serial} register={register} value={value} {outcome}",
+            now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        ))
+    }
+
+    /// Record the outcome of a write in the audit log
+    fn audit(&mut self, command: &Command, result: &Result<(), String>) {
+        let Some(line) = self.audit_line(command, result) else {
+            return;
+        };
+        match result {
+            Ok(()) => info!("Write {line}"),
+            Err(_) => warn!("Write {line}"),
+        }
+        if let Some(audit_log) = &mut self.audit_log {
+            if let Err(err) = writeln!(audit_log, "{line}") {
+                warn!("Failed to write to audit log: {err}");
+            }
+        }
+    }
+}
+
+/// Forward the commands from `input` to `output` that pass the checks in
+/// `guard`.
+pub async fn run(mut input: CommandReceiver, output: CommandSender, mut guard: Guard) {
+    while let Some(command) = input.next().await {
+        let result = guard.check(&command);
+        guard.audit(&command, &result);
+        if result.is_ok() && output.unbounded_send(command).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn write(register: u16, value: u16) -> Command {
+        Command::WriteRegister {
+            serial: "1234".to_owned(),
+            register,
+            value,
+            origin: "test".to_owned(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check() {
+        let config: Config = toml::from_str(
+            "rate_limit = 2\n\
+             [registers]\n\
+             268 = { min = 10, max = 100 }\n\
+             269 = {}",
+        )
+        .unwrap();
+        let mut guard = Guard::new(&config).unwrap().with_clock(MockClock::new(0));
+        assert!(guard.check(&Command::Shutdown).is_ok());
+        assert!(guard.check(&write(270, 0)).is_err());
+        assert!(guard.check(&write(268, 5)).is_err());
+        assert!(guard.check(&write(268, 101)).is_err());
+        assert!(guard.check(&write(268, 100)).is_ok());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(guard.check(&write(269, 65535)).is_ok());
+        // Rate limit reached
+        tokio::time::advance(RATE_WINDOW - Duration::from_secs(2)).await;
+        assert!(guard.check(&write(268, 50)).is_err());
+        // Other commands are not limited
+        let poll = Command::PollNow {
+            serial: "1234".to_owned(),
+        };
+        assert!(guard.check(&poll).is_ok());
+        // The first write has left the window, but not the second
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(guard.check(&write(268, 50)).is_ok());
+        assert!(guard.check(&write(268, 50)).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_audit_line() {
+        let config: Config = toml::from_str("").unwrap();
+        let guard = Guard::new(&config)
+            .unwrap()
+            .with_clock(MockClock::new(1_700_000_000_000_000_000));
+        assert_eq!(
+            guard.audit_line(&write(268, 50), &Ok(())).as_deref(),
+            Some("2023-11-14T22:13:20Z origin=test serial=1234 register=268 value=50 allowed")
+        );
+        assert_eq!(
+            guard
+                .audit_line(
+                    &write(270, 0),
+                    &Err("register 270 is not allowed".to_owned())
+                )
+                .as_deref(),
+            Some(
+                "2023-11-14T22:13:20Z origin=test serial=1234 register=270 value=0 \
+                 rejected (register 270 is not allowed)"
+            )
+        );
+        assert_eq!(guard.audit_line(&Command::Shutdown, &Ok(())), None);
+    }
+}
@@ -0,0 +1,275 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that emits updates as D-Bus signals.
+//!
+//! Each inverter is exported as an object at
+//! `/org/sunsniff/Inverter/<serial>` implementing `org.sunsniff.Inverter1`,
+//! which emits an `Update` signal with the values of the fields (keyed by
+//! field ID) whenever an update arrives. The latest values can also be read
+//! as properties, and the `Fields` method describes the fields.
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use zbus::object_server::SignalEmitter;
+use zbus::{interface, Connection};
+
+use crate::fields::Field;
+use crate::receiver::{Receiver, Update, UpdateReceiver};
+use crate::routing::Serials;
+use crate::timestamps::Timestamps;
+use crate::units::Units;
+
+/// Message bus to connect to
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Bus {
+    #[default]
+    System,
+    Session,
+}
+
+/// Structure corresponding to the `[dbus]` section of the configuration
+/// file. It is constructed from the config file by serde.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub bus: Bus,
+    /// Well-known name to request on the bus
+    #[serde(default = "default_name")]
+    pub name: String,
+    /// Units to convert values to
+    #[serde(default)]
+    pub units: Units,
+    /// Serial numbers of the inverters to send to this backend (all if not
+    /// given)
+    #[serde(default)]
+    pub serials: Serials,
+    /// Whether to write the time reported by the inverter or the time the
+    /// update was received
+    #[serde(default)]
+    pub timestamps: Timestamps,
+}
+
+fn default_name() -> String {
+    "org.sunsniff".to_owned()
+}
+
+/// Object path for an inverter. Characters that are not allowed in object
+/// paths are replaced by underscores.
+fn object_path(serial: &str) -> String {
+    let element: String = serial
+        .chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    format!("/org/sunsniff/Inverter/{element}")
+}
+
+/// Values of an update, keyed by field ID
+fn values(update: &Update<'_>) -> HashMap<String, f64> {
+    update
+        .iter()
+        .map(|(field, value)| (field.id.to_owned(), value))
+        .collect()
+}
+
+/// Description of a field returned by the `Fields` method: the ID, group,
+/// name and unit
+type FieldInfo = (String, String, String, String);
+
+fn field_info(field: &Field<'_>) -> FieldInfo {
+    (
+        field.id.to_owned(),
+        field.group.to_owned(),
+        field.name.to_owned(),
+        field.unit.to_owned(),
+    )
+}
+
+/// The object exported for each inverter
+struct Inverter {
+    serial: String,
+    timestamp: i64,
+    fields: Vec<FieldInfo>,
+    values: HashMap<String, f64>,
+}
+
+impl Inverter {
+    fn new(update: &Update<'_>) -> Self {
+        Self {
+            serial: update.serial.clone(),
+            timestamp: update.timestamp,
+            fields: update.fields.iter().map(field_info).collect(),
+            values: values(update),
+        }
+    }
+}
+
+#[interface(name = "org.sunsniff.Inverter1")]
+impl Inverter {
+    /// Serial number of the inverter
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn serial(&self) -> String {
+        self.serial.clone()
+    }
+
+    /// Time of the latest update, in nanoseconds since the UNIX epoch
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    /// Latest values, keyed by field ID
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn values(&self) -> HashMap<String, f64> {
+        self.values.clone()
+    }
+
+    /// The ID, group, name and unit of each field
+    #[zbus(out_args("fields"))]
+    fn fields(&self) -> Vec<FieldInfo> {
+        self.fields.clone()
+    }
+
+    /// Emitted for every update
+    #[zbus(signal)]
+    async fn update(
+        emitter: &SignalEmitter<'_>,
+        timestamp: i64,
+        values: HashMap<String, f64>,
+    ) -> zbus::Result<()>;
+}
+
+pub struct DbusReceiver {
+    connection: Connection,
+    /// Object paths that have been exported
+    exported: HashSet<String>,
+}
+
+impl DbusReceiver {
+    pub async fn new(config: &Config) -> zbus::Result<Self> {
+        let connection = match config.bus {
+            Bus::System => Connection::system().await?,
+            Bus::Session => Connection::session().await?,
+        };
+        // Signals can be emitted without the name, so this is not fatal
+        match connection.request_name(config.name.as_str()).await {
+            Ok(()) => info!("Acquired D-Bus name {}", config.name),
+            Err(err) => warn!("Couldn't acquire D-Bus name {}: {err}", config.name),
+        }
+        Ok(Self {
+            connection,
+            exported: HashSet::new(),
+        })
+    }
+
+    async fn send(&mut self, update: &Update<'_>) -> zbus::Result<()> {
+        let path = object_path(&update.serial);
+        let server = self.connection.object_server();
+        if !self.exported.contains(&path) {
+            server.at(path.as_str(), Inverter::new(update)).await?;
+            self.exported.insert(path.clone());
+        }
+        let iface = server.interface::<_, Inverter>(path.as_str()).await?;
+        let values = values(update);
+        {
+            let mut inverter = iface.get_mut().await;
+            inverter.timestamp = update.timestamp;
+            if inverter.fields.len() != update.fields.len() {
+                inverter.fields = update.fields.iter().map(field_info).collect();
+            }
+            inverter.values = values.clone();
+        }
+        Inverter::update(iface.signal_emitter(), update.timestamp, values).await
+    }
+}
+
+#[async_trait]
+impl Receiver for DbusReceiver {
+    async fn run<'a>(&mut self, mut receiver: UpdateReceiver<'a>) {
+        while let Some(update) = receiver.next().await {
+            if let Err(err) = self.send(&update).await {
+                warn!("Failed to send update to D-Bus: {err}");
+            }
+        }
+    }
+
+    async fn self_test(&mut self, update: Arc<Update<'static>>) -> Result<(), String> {
+        self.send(&update)
+            .await
+            .map_err(|err| format!("could not send to D-Bus: {err}"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{FieldType, Reset, WordOrder};
+
+    static FIELDS: [Field; 1] = [Field {
+        field_type: FieldType::Power,
+        group: "PV",
+        name: "Power",
+        id: "pv_power",
+        scale: 1.0,
+        bias: 0.0,
+        unit: "W",
+        sum_of: &[],
+        word_order: WordOrder::Little,
+        reset: Reset::Never,
+    }];
+
+    #[test]
+    fn test_object_path() {
+        assert_eq!(
+            object_path("2101234567"),
+            "/org/sunsniff/Inverter/2101234567"
+        );
+        assert_eq!(
+            object_path("2101234567-dongle"),
+            "/org/sunsniff/Inverter/2101234567_dongle"
+        );
+    }
+
+    #[test]
+    fn test_inverter() {
+        let update = Update::new(1000, "1234", &FIELDS, vec![1500.0]);
+        let inverter = Inverter::new(&update);
+        assert_eq!(inverter.serial, "1234");
+        assert_eq!(inverter.timestamp, 1000);
+        assert_eq!(
+            inverter.values,
+            HashMap::from([("pv_power".to_owned(), 1500.0)])
+        );
+        assert_eq!(
+            inverter.fields,
+            [(
+                "pv_power".to_owned(),
+                "PV".to_owned(),
+                "Power".to_owned(),
+                "W".to_owned()
+            )]
+        );
+    }
+}
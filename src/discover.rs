@@ -0,0 +1,104 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Discovery of Solarman WiFi dongles ("loggers") on the local network
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// UDP port on which loggers listen for discovery requests
+pub const DISCOVERY_PORT: u16 = 48899;
+/// Broadcast message to which loggers respond
+const DISCOVERY_MESSAGE: &[u8] = b"WIFIKIT-214028-READ";
+
+/// A logger that responded to discovery
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Logger {
+    pub ip: IpAddr,
+    pub mac: String,
+    /// Serial number of the logger (not of the inverter)
+    pub serial: String,
+}
+
+/// Parse a response, which has the form `ip,mac,serial`
+fn parse_response(data: &[u8]) -> Option<Logger> {
+    let text = std::str::from_utf8(data).ok()?;
+    let mut parts = text.trim().split(',');
+    let ip = parts.next()?.parse().ok()?;
+    let mac = parts.next()?.to_owned();
+    let serial = parts.next()?.to_owned();
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(Logger { ip, mac, serial })
+}
+
+/// Broadcast a discovery request and collect the responses that arrive
+/// within `timeout`.
+pub fn discover(timeout: Duration) -> io::Result<Vec<Logger>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    socket.send_to(DISCOVERY_MESSAGE, (Ipv4Addr::BROADCAST, DISCOVERY_PORT))?;
+    let deadline = Instant::now() + timeout;
+    let mut loggers = vec![];
+    let mut buf = [0u8; 1024];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        match socket.recv_from(&mut buf) {
+            Ok((n, _)) => {
+                if let Some(logger) = parse_response(&buf[..n]) {
+                    if !loggers.contains(&logger) {
+                        loggers.push(logger);
+                    }
+                }
+            }
+            Err(err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut =>
+            {
+                break
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(loggers)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        assert_eq!(
+            parse_response(b"192.168.0.21,98D863A1B2C3,2712345678"),
+            Some(Logger {
+                ip: "192.168.0.21".parse().unwrap(),
+                mac: "98D863A1B2C3".to_owned(),
+                serial: "2712345678".to_owned(),
+            })
+        );
+        assert_eq!(parse_response(b"+ok"), None);
+        assert_eq!(parse_response(b"192.168.0.21,98D863A1B2C3"), None);
+        assert_eq!(parse_response(b"1.2.3.4,a,b,c"), None);
+        assert_eq!(parse_response(&[0xff, 0xfe]), None);
+    }
+}
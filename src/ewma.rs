@@ -0,0 +1,122 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Exponentially-weighted moving average, used for the running estimates of
+//! the interval between updates and of processing times.
+
+/// Weight given to each new sample
+const WEIGHT: f64 = 0.25;
+
+/// Default for [Ewma::with_outliers]: samples more than this many times the
+/// current estimate (such as the interval spanning an outage) are outliers
+pub const OUTLIER_FACTOR: f64 = 4.0;
+
+/// Number of outliers in a row after which the estimate is restarted from
+/// the latest sample, since the quantity has changed rather than spiked
+const OUTLIER_LIMIT: u32 = 3;
+
+/// Running estimate of a quantity, optionally ignoring samples that are
+/// much larger than the estimate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ewma {
+    estimate: Option<f64>,
+    /// Samples more than this multiple of the estimate are outliers
+    outlier_factor: f64,
+    /// Number of outliers since the last sample that was used
+    outliers: u32,
+}
+
+impl Default for Ewma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ewma {
+    /// Create an estimate that uses every sample
+    pub const fn new() -> Self {
+        Self::with_outliers(f64::INFINITY)
+    }
+
+    /// Create an estimate that ignores samples more than `factor` times the
+    /// current estimate, unless [OUTLIER_LIMIT] of them arrive in a row
+    pub const fn with_outliers(factor: f64) -> Self {
+        Self {
+            estimate: None,
+            outlier_factor: factor,
+            outliers: 0,
+        }
+    }
+
+    /// The current estimate, or `None` if there have been no samples
+    pub fn estimate(&self) -> Option<f64> {
+        self.estimate
+    }
+
+    /// Add a sample, returning whether it is an outlier
+    pub fn add(&mut self, sample: f64) -> bool {
+        let Some(estimate) = self.estimate else {
+            self.estimate = Some(sample);
+            return false;
+        };
+        if sample > self.outlier_factor * estimate {
+            self.outliers += 1;
+            if self.outliers >= OUTLIER_LIMIT {
+                self.estimate = Some(sample);
+                self.outliers = 0;
+            }
+            return true;
+        }
+        self.outliers = 0;
+        self.estimate = Some(estimate + WEIGHT * (sample - estimate));
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_average() {
+        let mut ewma = Ewma::new();
+        assert_eq!(ewma.estimate(), None);
+        assert!(!ewma.add(10.0));
+        assert_eq!(ewma.estimate(), Some(10.0));
+        assert!(!ewma.add(14.0));
+        assert_eq!(ewma.estimate(), Some(11.0));
+        // Without outlier handling, every sample is used
+        assert!(!ewma.add(1011.0));
+        assert_eq!(ewma.estimate(), Some(261.0));
+    }
+
+    #[test]
+    fn test_outliers() {
+        let mut ewma = Ewma::with_outliers(OUTLIER_FACTOR);
+        ewma.add(10.0);
+        // A single long sample (such as an outage) is ignored
+        assert!(ewma.add(1000.0));
+        assert_eq!(ewma.estimate(), Some(10.0));
+        assert!(!ewma.add(14.0));
+        assert_eq!(ewma.estimate(), Some(11.0));
+        // But enough of them in a row replace the estimate
+        assert!(ewma.add(100.0));
+        assert!(ewma.add(100.0));
+        assert_eq!(ewma.estimate(), Some(11.0));
+        assert!(ewma.add(100.0));
+        assert_eq!(ewma.estimate(), Some(100.0));
+    }
+}
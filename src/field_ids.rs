@@ -0,0 +1,240 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Checking of the field IDs named in the configuration, so that typos are
+//! reported at startup instead of the option being silently ignored
+
+use std::fmt;
+
+use crate::fields::{self, Field};
+
+/// Field IDs that configuration options may refer to: those in the
+/// built-in tables, plus any added for the configured sources
+#[derive(Default)]
+pub struct KnownFields {
+    /// Field tables other than the built-in ones
+    tables: Vec<&'static [Field<'static>]>,
+    /// Prefixes of IDs of fields that are only created at runtime
+    prefixes: Vec<&'static str>,
+}
+
+impl KnownFields {
+    /// Also accept the IDs of the fields in `fields`
+    pub fn with_fields(mut self, fields: &'static [Field<'static>]) -> Self {
+        self.tables.push(fields);
+        self
+    }
+
+    /// Also accept any ID starting with `prefix`, for fields that are only
+    /// created at runtime (such as Pylontech packs)
+    pub fn with_prefix(mut self, prefix: &'static str) -> Self {
+        self.prefixes.push(prefix);
+        self
+    }
+
+    /// Whether `id` is a known field ID
+    pub fn contains(&self, id: &str) -> bool {
+        fields::is_known(id)
+            || self
+                .tables
+                .iter()
+                .flat_map(|t| t.iter())
+                .any(|f| f.id == id)
+            || self.prefixes.iter().any(|prefix| id.starts_with(prefix))
+    }
+
+    /// Find the known ID that is most similar to `id`, if any are similar
+    /// enough to be a plausible typo
+    fn suggest(&self, id: &str) -> Option<&'static str> {
+        let max_distance = (id.chars().count() / 3).max(1);
+        fields::known_ids()
+            .chain(self.tables.iter().flat_map(|t| t.iter()).map(|f| f.id))
+            .map(|known| (edit_distance(id, known), known))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .min()
+            .map(|(_, known)| known)
+    }
+
+    /// Check the field IDs named by a configuration option (such as
+    /// `[field_overrides]`), returning an error listing the unknown ones.
+    pub fn check<'a>(
+        &self,
+        option: &str,
+        ids: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), UnknownFields> {
+        let mut unknown: Vec<_> = ids
+            .into_iter()
+            .filter(|id| !self.contains(id))
+            .map(|id| (id.to_owned(), self.suggest(id)))
+            .collect();
+        if unknown.is_empty() {
+            return Ok(());
+        }
+        unknown.sort();
+        Err(UnknownFields {
+            option: option.to_owned(),
+            ids: unknown,
+        })
+    }
+}
+
+/// Error for field IDs in the configuration that do not name any field
+#[derive(PartialEq)]
+pub struct UnknownFields {
+    /// Configuration option that named the fields
+    option: String,
+    /// The unknown IDs, each with the closest known ID
+    ids: Vec<(String, Option<&'static str>)>,
+}
+
+impl fmt::Display for UnknownFields {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown field(s) in {}:", self.option)?;
+        for (i, (id, suggestion)) in self.ids.iter().enumerate() {
+            let sep = if i == 0 { " " } else { ", " };
+            write!(f, "{sep}{id:?}")?;
+            if let Some(suggestion) = suggestion {
+                write!(f, " (did you mean {suggestion:?}?)")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Errors returned from `main` are shown with [fmt::Debug], so show the
+/// message rather than the structure
+impl fmt::Debug for UnknownFields {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for UnknownFields {}
+
+/// Levenshtein distance between two strings, in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // Distances from the prefix of `a` seen so far to each prefix of `b`
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{FieldType, Reset, WordOrder};
+
+    const fn extra(
+        field_type: FieldType,
+        name: &'static str,
+        id: &'static str,
+        unit: &'static str,
+    ) -> Field<'static> {
+        Field {
+            field_type,
+            group: "Battery",
+            name,
+            id,
+            scale: 1.0,
+            bias: 0.0,
+            unit,
+            sum_of: &[],
+            word_order: WordOrder::Little,
+            reset: Reset::Never,
+        }
+    }
+
+    // The tests use their own table, since the built-in tables depend on
+    // which frontends are enabled
+    static EXTRA: [Field; 2] = [
+        extra(
+            FieldType::Voltage,
+            "Cell voltage",
+            "extra_cell_voltage",
+            "V",
+        ),
+        extra(
+            FieldType::Energy,
+            "Charge total",
+            "extra_charge_total",
+            "kWh",
+        ),
+    ];
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("grid_power", "grid_power"), 0);
+        assert_eq!(edit_distance("grid_powr", "grid_power"), 1);
+        assert_eq!(edit_distance("gird_power", "grid_power"), 2);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn test_contains() {
+        let known = KnownFields::default()
+            .with_fields(&EXTRA)
+            .with_prefix("bms_pack_");
+        assert!(known.contains("extra_charge_total"));
+        assert!(known.contains("extra_cell_voltage"));
+        assert!(known.contains("bms_pack_2_voltage"));
+        assert!(!known.contains("bms_pak_2_voltage"));
+        assert!(!KnownFields::default().contains("extra_cell_voltage"));
+    }
+
+    #[test]
+    fn test_check() {
+        let known = KnownFields::default().with_fields(&EXTRA);
+        assert_eq!(
+            known.check("[field_overrides]", ["extra_charge_total"]),
+            Ok(())
+        );
+        let err = known
+            .check(
+                "[field_overrides]",
+                ["extra_cell_voltag", "extra_charge_totl", "nonsense"],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown field(s) in [field_overrides]: \
+             \"extra_cell_voltag\" (did you mean \"extra_cell_voltage\"?), \
+             \"extra_charge_totl\" (did you mean \"extra_charge_total\"?), \
+             \"nonsense\""
+        );
+    }
+
+    #[cfg(any(feature = "afpacket", feature = "modbus", feature = "pcap"))]
+    #[test]
+    fn test_builtin() {
+        let known = KnownFields::default();
+        assert!(known.contains("battery_charge_total"));
+        assert_eq!(
+            known.suggest("battery_charge_totl"),
+            Some("battery_charge_total")
+        );
+    }
+}
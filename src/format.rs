@@ -0,0 +1,118 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Encodings of the updates sent by backends over the network or a socket.
+//! All of them encode the same structure (see [crate::json]); the binary
+//! encodings are considerably smaller, which helps on constrained links.
+
+use serde::{Deserialize, Serialize};
+
+/// Encoding to use, from the `format` key of a backend's configuration
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    #[default]
+    Json,
+    #[cfg(feature = "cbor")]
+    Cbor,
+    #[cfg(feature = "msgpack")]
+    Msgpack,
+}
+
+impl Format {
+    /// Value for the `Content-Type` header
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            #[cfg(feature = "cbor")]
+            Format::Cbor => "application/cbor",
+            #[cfg(feature = "msgpack")]
+            Format::Msgpack => "application/msgpack",
+        }
+    }
+
+    /// Whether the encoding is text (UTF-8) rather than binary
+    pub fn is_text(self) -> bool {
+        self == Format::Json
+    }
+
+    /// Encode a value
+    pub fn encode<T: Serialize + ?Sized>(self, value: &T) -> Vec<u8> {
+        // Encoding to a Vec can only fail for types that serde_json cannot
+        // represent, such as maps with non-string keys, which are not used.
+        match self {
+            Format::Json => serde_json::to_vec(value).unwrap(),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => {
+                let mut data = vec![];
+                ciborium::into_writer(value, &mut data).unwrap();
+                data
+            }
+            #[cfg(feature = "msgpack")]
+            Format::Msgpack => rmp_serde::to_vec_named(value).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::json::{FieldValue, Update};
+
+    fn update() -> Vec<FieldValue> {
+        (0..100)
+            .map(|i| FieldValue {
+                id: format!("field_{i}"),
+                group: "Group".to_owned(),
+                name: format!("Field {i}"),
+                unit: "W".to_owned(),
+                value: Some(i as f64 * 10.0),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_json() {
+        let fields = update();
+        let update = Update::new("1234", 1000, &fields);
+        let data = Format::Json.encode(&update);
+        assert_eq!(data, update.encode().into_bytes());
+        assert!(Format::Json.is_text());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor() {
+        let fields = update();
+        let update = Update::new("1234", 1000, &fields);
+        let data = Format::Cbor.encode(&update);
+        assert!(data.len() < Format::Json.encode(&update).len());
+        let decoded: Update<'static> = ciborium::from_reader(&data[..]).unwrap();
+        assert_eq!(decoded, update);
+        assert!(!Format::Cbor.is_text());
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_msgpack() {
+        let fields = update();
+        let update = Update::new("1234", 1000, &fields);
+        let data = Format::Msgpack.encode(&update);
+        assert!(data.len() < Format::Json.encode(&update).len());
+        let decoded: Update<'static> = rmp_serde::from_slice(&data).unwrap();
+        assert_eq!(decoded, update);
+    }
+}
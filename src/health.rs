@@ -0,0 +1,251 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Reporting of receivers going down and recovering. While a receiver is
+//! failing it may log an error every few seconds (which are throttled by
+//! [crate::logging]), but an operator mostly wants to know when it stopped
+//! working and when it started again. A [Health] logs exactly one message for
+//! each of these transitions, and passes them on to any notification hooks
+//! registered with [subscribe].
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use log::{info, warn};
+use serde::Serialize;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::clock::{Clock, SystemClock};
+
+/// Whether a receiver is working
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Up,
+    Down,
+}
+
+/// A transition between [State]s, as sent to notification hooks
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Event {
+    /// Name of the receiver, such as `influxdb2 'main'`
+    pub receiver: String,
+    /// The new state
+    pub state: State,
+    /// The message that was logged
+    pub message: String,
+    /// Why the receiver went down
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// How long the receiver was down for, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    /// Number of updates that were held back during the outage and written
+    /// on recovery
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flushed: Option<usize>,
+}
+
+/// Channels to the notification hooks
+static SUBSCRIBERS: Mutex<Vec<UnboundedSender<Event>>> = Mutex::new(Vec::new());
+
+/// Receive all future [Event]s from all receivers
+pub fn subscribe() -> UnboundedReceiver<Event> {
+    let (sender, receiver) = mpsc::unbounded();
+    SUBSCRIBERS.lock().unwrap().push(sender);
+    receiver
+}
+
+/// Send an event to the hooks, forgetting those that have gone away
+fn notify(event: Event) {
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .retain(|sender| sender.unbounded_send(event.clone()).is_ok());
+}
+
+/// Format a duration coarsely (such as `13m` or `2h5m`) for log messages
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h{}m", secs / 3600, secs / 60 % 60),
+        _ => format!("{}d{}h", secs / 86400, secs / 3600 % 24),
+    }
+}
+
+/// Tracks whether a single receiver is working
+pub struct Health {
+    name: String,
+    /// When the receiver went down, if it is currently down
+    down_since: Option<Instant>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Health {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            down_since: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Use a different clock to measure outages, for testing
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn is_down(&self) -> bool {
+        self.down_since.is_some()
+    }
+
+    /// Record a failure. This is only reported if the receiver was up.
+    pub fn down(&mut self, reason: impl Display) {
+        if self.is_down() {
+            return;
+        }
+        self.down_since = Some(self.clock.instant());
+        let reason = reason.to_string();
+        let message = format!("{} is DOWN: {reason}", self.name);
+        warn!("{message}");
+        notify(Event {
+            receiver: self.name.clone(),
+            state: State::Down,
+            message,
+            reason: Some(reason),
+            duration: None,
+            flushed: None,
+        });
+    }
+
+    /// Record a success, after which `flushed` updates that were held back
+    /// have been written. This is only reported if the receiver was down.
+    pub fn up(&mut self, flushed: usize) {
+        let Some(since) = self.down_since.take() else {
+            return;
+        };
+        let duration = self.clock.instant() - since;
+        let mut message = format!(
+            "{} recovered after {}",
+            self.name,
+            format_duration(duration)
+        );
+        if flushed > 0 {
+            message += &format!(", {flushed} updates flushed");
+        }
+        info!("{message}");
+        notify(Event {
+            receiver: self.name.clone(),
+            state: State::Up,
+            message,
+            reason: None,
+            duration: Some(duration.as_secs_f64()),
+            flushed: Some(flushed),
+        });
+    }
+}
+
+/// Structure corresponding to the `[webhook]` section of the configuration
+/// file. It is constructed from the config file by serde.
+#[cfg(feature = "webhook")]
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// URL to POST each [Event] to, as JSON
+    pub url: String,
+}
+
+/// Start a task that posts each [Event] to a webhook
+#[cfg(feature = "webhook")]
+pub fn spawn_webhook(config: &WebhookConfig) -> reqwest::Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    let url = config.url.clone();
+    let mut events = subscribe();
+    tokio::spawn(async move {
+        use futures::StreamExt;
+
+        while let Some(event) = events.next().await {
+            let result = client
+                .post(&url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&event).unwrap())
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+            if let Err(err) = result {
+                warn!("Could not send notification to webhook: {err}");
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::MockClock;
+    use futures::prelude::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(45)), "45s");
+        assert_eq!(format_duration(Duration::from_secs(13 * 60 + 20)), "13m");
+        assert_eq!(format_duration(Duration::from_secs(7500)), "2h5m");
+        assert_eq!(
+            format_duration(Duration::from_secs(3 * 86400 + 7200)),
+            "3d2h"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_health() {
+        // Other tests may report events concurrently
+        let name = "influxdb2 'test_health'";
+        let mut events = subscribe().filter(|event| future::ready(event.receiver == name));
+        let mut health = Health::new(name).with_clock(MockClock::new(0));
+        health.up(0);
+        health.down("connection refused");
+        health.down("connection reset");
+        assert!(health.is_down());
+        tokio::time::advance(Duration::from_secs(13 * 60)).await;
+        health.up(156);
+        health.up(0);
+        assert!(!health.is_down());
+
+        let event = events.next().await.unwrap();
+        assert_eq!(event.state, State::Down);
+        assert_eq!(
+            event.message,
+            "influxdb2 'test_health' is DOWN: connection refused"
+        );
+        assert_eq!(event.reason.as_deref(), Some("connection refused"));
+        let event = events.next().await.unwrap();
+        assert_eq!(event.state, State::Up);
+        assert_eq!(
+            event.message,
+            "influxdb2 'test_health' recovered after 13m, 156 updates flushed"
+        );
+        assert_eq!(event.duration, Some(780.0));
+        assert_eq!(event.flushed, Some(156));
+        assert!(events.next().now_or_never().is_none());
+    }
+}
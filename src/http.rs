@@ -0,0 +1,797 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! HTTP API providing the latest values and allowing settings to be changed
+
+use async_trait::async_trait;
+use axum::body::Body;
+use axum::extract::ws::rejection::WebSocketUpgradeRejection;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, Path, Query, Request, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::prelude::*;
+use log::{error, info, warn};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use subtle::ConstantTimeEq;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::compression::Compression;
+use super::control::{self, Command, CommandSender, SettingError};
+use super::format::Format;
+use super::json::{self, FieldValue, SCHEMA_VERSION};
+use super::receiver::{Receiver, Update, UpdateReceiver};
+use super::routing::Serials;
+use super::timestamps::Timestamps;
+use super::units::Units;
+
+/// Structure corresponding to the `[http]` section of the configuration
+/// file. It is constructed from the config file by serde.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Address and port on which to listen
+    pub bind: SocketAddr,
+    /// If set, all requests must provide this as a bearer token. Settings
+    /// can only be changed if this is set.
+    pub token: Option<String>,
+    /// Time (in seconds) for which to keep values in memory, to be returned
+    /// by the history endpoint. If not set, no history is kept.
+    pub history: Option<f64>,
+    /// Advertise the server on the local network with mDNS
+    #[serde(default = "default_mdns")]
+    pub mdns: bool,
+    /// Compression of responses, for clients that accept it
+    #[serde(default)]
+    pub compression: Compression,
+    /// Encoding of responses and WebSocket messages
+    #[serde(default)]
+    pub format: Format,
+    /// Units to convert values to
+    #[serde(default)]
+    pub units: Units,
+    /// Serial numbers of the inverters to send to this backend (all if not
+    /// given)
+    #[serde(default)]
+    pub serials: Serials,
+    /// Whether to write the time reported by the inverter or the time the
+    /// update was received
+    #[serde(default)]
+    pub timestamps: Timestamps,
+}
+
+fn default_mdns() -> bool {
+    true
+}
+
+/// Latest update received for an inverter
+#[derive(Serialize, Clone, Debug, PartialEq)]
+struct Inverter {
+    schema_version: u32,
+    serial: String,
+    /// Nanoseconds since UNIX epoch
+    timestamp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sequence: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gap_seconds: Option<f64>,
+    #[serde(skip)]
+    fields: Vec<FieldValue>,
+}
+
+impl From<&Update<'_>> for Inverter {
+    fn from(update: &Update<'_>) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            serial: update.serial.clone(),
+            timestamp: update.timestamp,
+            sequence: update.metadata.sequence,
+            gap_seconds: update.metadata.gap.map(|gap| gap.as_secs_f64()),
+            fields: json::field_values(update),
+        }
+    }
+}
+
+impl Inverter {
+    /// Encode as a message for WebSocket clients
+    fn live_message(&self, format: Format) -> Message {
+        let update = json::Update {
+            sequence: self.sequence,
+            gap_seconds: self.gap_seconds,
+            ..json::Update::new(&self.serial, self.timestamp, &self.fields)
+        };
+        if format.is_text() {
+            Message::Text(update.encode().into())
+        } else {
+            Message::Binary(format.encode(&update).into())
+        }
+    }
+}
+
+/// Response body, encoded in the configured format
+struct Encoded<T>(Format, T);
+
+impl<T: Serialize> IntoResponse for Encoded<T> {
+    fn into_response(self) -> Response {
+        let Encoded(format, value) = self;
+        (
+            [(header::CONTENT_TYPE, format.content_type())],
+            format.encode(&value),
+        )
+            .into_response()
+    }
+}
+
+/// Recent values for an inverter, oldest first
+#[derive(Default)]
+struct History {
+    /// Field IDs, in the same order as the values
+    ids: Vec<String>,
+    /// Timestamp and values of each update
+    updates: VecDeque<(i64, Vec<f64>)>,
+}
+
+impl History {
+    /// Add an update, discarding updates that are more than `max_age`
+    /// nanoseconds older than it
+    fn push(&mut self, update: &Update<'_>, max_age: i64) {
+        let ids = update.fields.iter().map(|field| field.id);
+        if !ids.clone().eq(self.ids.iter().map(String::as_str)) {
+            // The set of fields has changed, so the old values are not usable
+            self.ids = ids.map(str::to_owned).collect();
+            self.updates.clear();
+        }
+        self.updates
+            .push_back((update.timestamp, update.values.clone()));
+        let cutoff = update.timestamp.saturating_sub(max_age);
+        while self.updates.front().is_some_and(|(ts, _)| *ts < cutoff) {
+            self.updates.pop_front();
+        }
+    }
+
+    /// Get the values of one field, starting at a given timestamp
+    fn query(&self, id: &str, since: i64) -> Option<Vec<(i64, f64)>> {
+        let idx = self.ids.iter().position(|x| x == id)?;
+        Some(
+            self.updates
+                .iter()
+                .filter(|(ts, _)| *ts >= since)
+                .map(|(ts, values)| (*ts, values[idx]))
+                .collect(),
+        )
+    }
+}
+
+/// Query parameters for the history endpoint
+#[derive(Deserialize)]
+struct HistoryQuery {
+    field: String,
+    /// Only return values from this timestamp (nanoseconds since UNIX epoch)
+    #[serde(default)]
+    since: i64,
+}
+
+/// Query parameters for the WebSocket endpoint
+#[derive(Deserialize)]
+struct LiveQuery {
+    /// Alternative to the Authorization header, since browsers cannot set
+    /// headers on WebSocket requests
+    token: Option<String>,
+    /// Overrides the configured format, so that the dashboard can always
+    /// use JSON
+    format: Option<Format>,
+}
+
+/// Body of a request to change a setting
+#[derive(Deserialize)]
+struct SettingRequest {
+    value: f64,
+}
+
+/// State shared between the receiver and the request handlers
+#[derive(Clone)]
+struct AppState {
+    inverters: Arc<Mutex<BTreeMap<String, Inverter>>>,
+    /// History for each inverter, if enabled
+    history: Option<Arc<Mutex<HashMap<String, History>>>>,
+    /// Updates for WebSocket clients
+    live: broadcast::Sender<Arc<Inverter>>,
+    token: Option<Arc<str>>,
+    commands: CommandSender,
+    compression: Compression,
+    format: Format,
+}
+
+type ApiError = (StatusCode, &'static str);
+
+/// Number of updates to buffer for each WebSocket client
+const LIVE_CAPACITY: usize = 16;
+
+impl AppState {
+    /// Check a token given by the client, if one is configured. The
+    /// comparison takes the same time wherever the tokens differ, so that
+    /// the token cannot be guessed a character at a time.
+    fn authorize_token(&self, given: Option<&str>) -> Result<(), ApiError> {
+        let Some(token) = &self.token else {
+            return Ok(());
+        };
+        let given = given.unwrap_or_default();
+        if bool::from(given.as_bytes().ct_eq(token.as_bytes())) {
+            Ok(())
+        } else {
+            Err((StatusCode::UNAUTHORIZED, "missing or incorrect token"))
+        }
+    }
+
+    /// Check the bearer token, if one is configured
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), ApiError> {
+        let given = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        self.authorize_token(given)
+    }
+}
+
+async fn get_inverters(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Encoded<Vec<Inverter>>, ApiError> {
+    state.authorize(&headers)?;
+    let inverters = state.inverters.lock().unwrap();
+    Ok(Encoded(state.format, inverters.values().cloned().collect()))
+}
+
+async fn get_fields(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(serial): Path<String>,
+) -> Result<Encoded<Vec<FieldValue>>, ApiError> {
+    state.authorize(&headers)?;
+    let inverters = state.inverters.lock().unwrap();
+    match inverters.get(&serial) {
+        Some(inverter) => Ok(Encoded(state.format, inverter.fields.clone())),
+        None => Err((StatusCode::NOT_FOUND, "unknown inverter")),
+    }
+}
+
+async fn get_history(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(serial): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Encoded<Vec<(i64, f64)>>, ApiError> {
+    state.authorize(&headers)?;
+    let history = state
+        .history
+        .as_ref()
+        .ok_or((StatusCode::NOT_FOUND, "history is not enabled"))?;
+    let history = history.lock().unwrap();
+    let history = history
+        .get(&serial)
+        .ok_or((StatusCode::NOT_FOUND, "unknown inverter"))?;
+    match history.query(&query.field, query.since) {
+        Some(values) => Ok(Encoded(state.format, values)),
+        None => Err((StatusCode::NOT_FOUND, "unknown field")),
+    }
+}
+
+async fn get_live(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<LiveQuery>,
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Response {
+    // Check the token before validating the upgrade request
+    let auth = match &query.token {
+        Some(token) => state.authorize_token(Some(token)),
+        None => state.authorize(&headers),
+    };
+    if let Err(err) = auth {
+        return err.into_response();
+    }
+    match ws {
+        Ok(ws) => {
+            let format = query.format.unwrap_or(state.format);
+            ws.on_upgrade(move |socket| send_live(socket, state, format))
+        }
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+/// Send the latest update for each inverter, followed by every new update
+async fn send_live(mut socket: WebSocket, state: AppState, format: Format) {
+    // Subscribe before taking the snapshot, so that no updates are missed
+    let mut live = state.live.subscribe();
+    let snapshot: Vec<Message> = {
+        let inverters = state.inverters.lock().unwrap();
+        inverters
+            .values()
+            .map(|inverter| inverter.live_message(format))
+            .collect()
+    };
+    for msg in snapshot {
+        if socket.send(msg).await.is_err() {
+            return;
+        }
+    }
+    loop {
+        match live.recv().await {
+            Ok(inverter) => {
+                if socket.send(inverter.live_message(format)).await.is_err() {
+                    return;
+                }
+            }
+            Err(RecvError::Lagged(n)) => warn!("WebSocket client missed {n} updates"),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+async fn get_dashboard() -> Html<&'static str> {
+    Html(include_str!("http/dashboard.html"))
+}
+
+fn setting_command(
+    serial: String,
+    id: &str,
+    value: f64,
+    peer: SocketAddr,
+) -> Result<Command, ApiError> {
+    // The peer is recorded in the audit log
+    let origin = format!("http:{peer}");
+    control::setting_command(serial, id, value, &origin).map_err(|err| match err {
+        SettingError::Unknown => (StatusCode::NOT_FOUND, "unknown setting"),
+        SettingError::OutOfRange => (StatusCode::BAD_REQUEST, "value out of range"),
+    })
+}
+
+async fn post_setting(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path((serial, id)): Path<(String, String)>,
+    Json(request): Json<SettingRequest>,
+) -> Result<StatusCode, ApiError> {
+    if state.token.is_none() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "settings can only be changed if a token is configured",
+        ));
+    }
+    state.authorize(&headers)?;
+    if !state.inverters.lock().unwrap().contains_key(&serial) {
+        return Err((StatusCode::NOT_FOUND, "unknown inverter"));
+    }
+    let command = setting_command(serial, &id, request.value, peer)?;
+    info!("Request from {peer} to set {id} to {}", request.value);
+    state.commands.unbounded_send(command).map_err(|_| {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the frontend does not support changing settings",
+        )
+    })?;
+    // The write happens asynchronously in the frontend
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Compress responses for clients that accept the configured encoding.
+/// WebSocket upgrades and responses that are already encoded are passed
+/// through unchanged.
+async fn compress(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(encoding) = state.compression.content_encoding() else {
+        return next.run(request).await;
+    };
+    let accepted = request
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .any(|item| item.split(';').next().unwrap().trim() == encoding)
+        });
+    let response = next.run(request).await;
+    if !accepted
+        || response.status() == StatusCode::SWITCHING_PROTOCOLS
+        || response.headers().contains_key(header::CONTENT_ENCODING)
+    {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, usize::MAX).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    let body = state.compression.compress(&body).into_owned();
+    Response::from_parts(parts, Body::from(body))
+}
+
+fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/", get(get_dashboard))
+        .route("/live", get(get_live))
+        .route("/inverters", get(get_inverters))
+        .route("/inverters/{serial}/fields", get(get_fields))
+        .route("/inverters/{serial}/history", get(get_history))
+        .route("/inverters/{serial}/settings/{id}", post(post_setting))
+        .layer(middleware::from_fn_with_state(state.clone(), compress))
+        .with_state(state)
+}
+
+/// Service type advertised over mDNS
+const MDNS_SERVICE_TYPE: &str = "_sunsniff._tcp.local.";
+
+/// Advertise the server with mDNS. The returned daemon must be kept alive.
+fn advertise(addr: SocketAddr, auth: bool) -> Result<ServiceDaemon, mdns_sd::Error> {
+    let hostname = gethostname::gethostname().to_string_lossy().into_owned();
+    let properties = [
+        ("path", "/"),
+        ("auth", if auth { "token" } else { "none" }),
+        ("version", env!("CARGO_PKG_VERSION")),
+    ];
+    // If listening on all interfaces, let mdns-sd find the addresses
+    let ips: Vec<IpAddr> = if addr.ip().is_unspecified() {
+        vec![]
+    } else {
+        vec![addr.ip()]
+    };
+    let mut info = ServiceInfo::new(
+        MDNS_SERVICE_TYPE,
+        &hostname,
+        &format!("{hostname}.local."),
+        &ips[..],
+        addr.port(),
+        &properties[..],
+    )?;
+    if ips.is_empty() {
+        info = info.enable_addr_auto();
+    }
+    let daemon = ServiceDaemon::new()?;
+    daemon.register(info)?;
+    Ok(daemon)
+}
+
+pub struct HttpReceiver {
+    state: AppState,
+    /// Maximum age of history, in nanoseconds
+    history_age: i64,
+    listener: Option<TcpListener>,
+    /// Keeps the mDNS advertisement alive
+    _mdns: Option<ServiceDaemon>,
+}
+
+impl HttpReceiver {
+    pub async fn new(config: &Config, commands: CommandSender) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(config.bind).await?;
+        let addr = listener.local_addr()?;
+        info!("HTTP API listening on {}", addr);
+        let mdns = if config.mdns {
+            advertise(addr, config.token.is_some())
+                .inspect_err(|err| warn!("Could not advertise with mDNS: {err}"))
+                .ok()
+        } else {
+            None
+        };
+        let state = AppState {
+            inverters: Arc::new(Mutex::new(BTreeMap::new())),
+            history: config.history.map(|_| Arc::new(Mutex::new(HashMap::new()))),
+            live: broadcast::channel(LIVE_CAPACITY).0,
+            token: config.token.as_deref().map(Arc::from),
+            commands,
+            compression: config.compression,
+            format: config.format,
+        };
+        Ok(Self {
+            state,
+            history_age: (config.history.unwrap_or(0.0) * 1e9) as i64,
+            listener: Some(listener),
+            _mdns: mdns,
+        })
+    }
+}
+
+#[async_trait]
+impl Receiver for HttpReceiver {
+    async fn run<'a>(&mut self, mut receiver: UpdateReceiver<'a>) {
+        if let Some(listener) = self.listener.take() {
+            let app =
+                router(self.state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+            tokio::spawn(async move {
+                if let Err(err) = axum::serve(listener, app).await {
+                    error!("HTTP server failed: {err}");
+                }
+            });
+        }
+        while let Some(update) = receiver.next().await {
+            if let Some(history) = &self.state.history {
+                let mut history = history.lock().unwrap();
+                let entry = history.entry(update.serial.clone()).or_default();
+                entry.push(&update, self.history_age);
+            }
+            let inverter = Inverter::from(update.as_ref());
+            // This only fails if there are no WebSocket clients
+            let _ = self.state.live.send(Arc::new(inverter.clone()));
+            let mut inverters = self.state.inverters.lock().unwrap();
+            inverters.insert(inverter.serial.clone(), inverter);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::control::CommandReceiver;
+    use crate::fields::{Field, FieldType, Reset, WordOrder};
+    use axum::body::Body;
+    use axum::extract::connect_info::MockConnectInfo;
+    use axum::http::Request;
+    use std::io::Read;
+    use tower::ServiceExt;
+
+    static FIELDS: [Field; 1] = [Field {
+        field_type: FieldType::Power,
+        group: "PV",
+        name: "Power",
+        id: "pv_power",
+        scale: 1.0,
+        bias: 0.0,
+        unit: "W",
+        sum_of: &[],
+        word_order: WordOrder::Little,
+        reset: Reset::Never,
+    }];
+
+    fn state(token: Option<&str>) -> (AppState, CommandReceiver) {
+        let (commands, receiver) = control::channel();
+        let state = AppState {
+            inverters: Arc::new(Mutex::new(BTreeMap::new())),
+            history: None,
+            live: broadcast::channel(LIVE_CAPACITY).0,
+            token: token.map(Arc::from),
+            commands,
+            compression: Compression::None,
+            format: Format::Json,
+        };
+        let update = Update::new(1234, "1234567890", &FIELDS, vec![100.0]);
+        let inverter = Inverter::from(&update);
+        state
+            .inverters
+            .lock()
+            .unwrap()
+            .insert(inverter.serial.clone(), inverter);
+        (state, receiver)
+    }
+
+    async fn request(state: &AppState, request: Request<Body>) -> (StatusCode, String) {
+        let response = router(state.clone())
+            .layer(MockConnectInfo(SocketAddr::from(([192, 0, 2, 1], 50000))))
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_get() {
+        let (state, _receiver) = state(None);
+        assert_eq!(
+            request(&state, get("/inverters")).await,
+            (
+                StatusCode::OK,
+                r#"[{"schema_version":1,"serial":"1234567890","timestamp":1234}]"#.to_owned()
+            )
+        );
+        assert_eq!(
+            request(&state, get("/inverters/1234567890/fields")).await,
+            (
+                StatusCode::OK,
+                r#"[{"id":"pv_power","group":"PV","name":"Power","unit":"W","value":100.0}]"#
+                    .to_owned()
+            )
+        );
+        let (status, _) = request(&state, get("/inverters/0000000000/fields")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_live_message() {
+        let update = Update::new(1234, "1234567890", &FIELDS, vec![100.0]);
+        let inverter = Inverter::from(&update);
+        assert_eq!(
+            inverter.live_message(Format::Json),
+            Message::Text(r#"{"schema_version":1,"serial":"1234567890","timestamp":1234,"fields":[{"id":"pv_power","group":"PV","name":"Power","unit":"W","value":100.0}]}"#.into())
+        );
+    }
+
+    #[cfg(feature = "cbor")]
+    #[tokio::test]
+    async fn test_format() {
+        let (mut state, _receiver) = state(None);
+        state.format = Format::Cbor;
+        let response = router(state)
+            .oneshot(get("/inverters/1234567890/fields"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/cbor");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let fields: Vec<FieldValue> = ciborium::from_reader(&body[..]).unwrap();
+        assert_eq!(fields[0].value, Some(100.0));
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let (mut state, _receiver) = state(None);
+        state.compression = Compression::Gzip;
+        let (_, expected) = request(&state, get("/inverters")).await;
+        let request = Request::get("/inverters")
+            .header(header::ACCEPT_ENCODING, "deflate, gzip;q=0.5")
+            .body(Body::empty())
+            .unwrap();
+        let response = router(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, expected);
+    }
+
+    #[tokio::test]
+    async fn test_dashboard() {
+        let (state, _receiver) = state(Some("secret"));
+        let (status, body) = request(&state, get("/")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("new WebSocket"));
+        // The WebSocket endpoint requires the token
+        let (status, _) = request(&state, get("/live")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_history_push() {
+        let mut history = History::default();
+        for ts in [10, 20, 30, 40] {
+            let update = Update::new(ts, "1234567890", &FIELDS, vec![ts as f64]);
+            history.push(&update, 15);
+        }
+        assert_eq!(
+            history.query("pv_power", 0),
+            Some(vec![(30, 30.0), (40, 40.0)])
+        );
+        assert_eq!(history.query("pv_power", 35), Some(vec![(40, 40.0)]));
+        assert_eq!(history.query("battery_soc", 0), None);
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let (mut state, _receiver) = state(None);
+        let (status, _) =
+            request(&state, get("/inverters/1234567890/history?field=pv_power")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let mut history = History::default();
+        history.push(&Update::new(1234, "1234567890", &FIELDS, vec![100.0]), 0);
+        let history = HashMap::from([("1234567890".to_owned(), history)]);
+        state.history = Some(Arc::new(Mutex::new(history)));
+        assert_eq!(
+            request(&state, get("/inverters/1234567890/history?field=pv_power")).await,
+            (StatusCode::OK, "[[1234,100.0]]".to_owned())
+        );
+        let uri = "/inverters/1234567890/history?field=pv_power&since=2000";
+        assert_eq!(
+            request(&state, get(uri)).await,
+            (StatusCode::OK, "[]".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token() {
+        let (state, _receiver) = state(Some("secret"));
+        let (status, _) = request(&state, get("/inverters")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let req = Request::get("/inverters")
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = request(&state, req).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_authorize_token() {
+        let (secured, _receiver) = state(Some("secret"));
+        assert!(secured.authorize_token(Some("secret")).is_ok());
+        for given in [
+            None,
+            Some(""),
+            Some("secre"),
+            Some("secret2"),
+            Some("Secret"),
+        ] {
+            assert!(secured.authorize_token(given).is_err(), "{given:?}");
+        }
+        let (open, _receiver) = state(None);
+        assert!(open.authorize_token(None).is_ok());
+    }
+
+    fn post(uri: &str, token: &str, body: &str) -> Request<Body> {
+        Request::post(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_owned()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_setting_no_token() {
+        let (state, _receiver) = state(None);
+        let uri = "/inverters/1234567890/settings/inverter_program_soc_1";
+        let (status, _) = request(&state, post(uri, "", r#"{"value": 50}"#)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[cfg(all(feature = "modbus", not(feature = "read_only")))]
+    #[tokio::test]
+    async fn test_setting() {
+        let (state, mut receiver) = state(Some("secret"));
+        let uri = "/inverters/1234567890/settings/inverter_program_soc_1";
+        let (status, _) = request(&state, post(uri, "secret", r#"{"value": 50}"#)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            Command::WriteRegister {
+                serial: "1234567890".to_owned(),
+                register: 268,
+                value: 50,
+                origin: "http:192.0.2.1:50000".to_owned()
+            }
+        );
+
+        let uri = "/inverters/1234567890/settings/pv_power";
+        let (status, _) = request(&state, post(uri, "secret", r#"{"value": 50}"#)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -14,28 +14,351 @@
  * with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
-use async_std::task;
 use async_trait::async_trait;
-use futures::channel::mpsc::UnboundedReceiver;
-use futures::stream::{self, StreamExt};
+use futures::prelude::*;
+use futures::stream;
+use influxdb2::models::data_point::DataPointBuilder;
 use influxdb2::models::health::Status;
-use influxdb2::models::DataPoint;
+use influxdb2::models::{DataPoint, Query, WriteDataPoint};
 use influxdb2::Client;
-use log::{info, warn};
+use influxdb2_structmap::value::Value;
+use log::{debug, info, warn};
+use reqwest::header;
 use serde::Deserialize;
-use std::iter::zip;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::num::NonZeroUsize;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::time::Instant;
 
-use super::receiver::{Receiver, Update};
+use super::clock::{Clock, SystemClock};
+use super::compression::Compression;
+use super::fields::TABLE_HASH;
+use super::health::Health;
+use super::receiver::{Metadata, Receiver, Update, UpdateReceiver};
+use super::rollup::{Aggregator, Period, Rollup, Stats};
+use super::routing::Serials;
+use super::timestamps::Timestamps;
+use super::units::Units;
+use offline::Spool;
+
+pub mod offline;
+
+/// Format raw register values for debugging, as space-separated hex
+fn format_raw(parts: &[u16]) -> String {
+    let parts: Vec<String> = parts.iter().map(|part| format!("{part:#06x}")).collect();
+    parts.join(" ")
+}
+
+/// Add the update metadata to a point, as tags (for the source and
+/// protocol) and fields (for the frame length, decode time and sequence
+/// number)
+fn add_metadata(mut build: DataPointBuilder, metadata: &Metadata) -> DataPointBuilder {
+    if let Some(source) = &metadata.source {
+        build = build.tag("source", source.as_str());
+    }
+    if let Some(protocol) = metadata.protocol {
+        build = build.tag("protocol", protocol);
+    }
+    if let Some(frame_length) = metadata.frame_length {
+        build = build.field("frame_length", frame_length as i64);
+    }
+    if let Some(duration) = metadata.decode_duration {
+        build = build.field("decode_seconds", duration.as_secs_f64());
+    }
+    if let Some(sequence) = metadata.sequence {
+        build = build.field("sequence", sequence as i64);
+    }
+    build.field("fields_hash", TABLE_HASH)
+}
+
+/// Quote a string for use as a Flux string literal
+fn flux_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' | '\\' | '$' => {
+                out.push('\\');
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Flux query for the timestamp of the latest point for an inverter
+fn latest_query(bucket: &str, serial: &str) -> String {
+    format!(
+        "from(bucket: {}) \
+         |> range(start: 0) \
+         |> filter(fn: (r) => r._measurement == \"inverter\" and r.serial == {}) \
+         |> keep(columns: [\"_time\"]) \
+         |> max(column: \"_time\")",
+        flux_string(bucket),
+        flux_string(serial)
+    )
+}
+
+/// Delay before retrying a failed write (which doubles after each failure,
+/// up to a maximum, with the offline-first profile)
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Minimum time between progress messages while writing a backlog
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Delay before retrying a failed write, starting at [RETRY_DELAY] and
+/// doubling after each failure up to a maximum
+struct Backoff {
+    delay: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    fn new(max: Duration) -> Self {
+        Self {
+            delay: RETRY_DELAY,
+            max,
+        }
+    }
+
+    /// Start again from the initial delay, after a success
+    fn reset(&mut self) {
+        self.delay = RETRY_DELAY;
+    }
+
+    /// Wait for the current delay, and increase it for the next failure
+    async fn wait(&mut self, clock: &dyn Clock) {
+        clock.sleep(self.delay).await;
+        self.delay = (self.delay * 2).min(self.max);
+    }
+}
+
+/// Error from writing points to Influxdb
+#[derive(Debug)]
+enum WriteError {
+    /// Influxdb rejected the points (for example, because a field has a
+    /// different type to existing points), so retrying them will not help
+    Rejected { status: u16, message: String },
+    /// Any other error, which may be transient
+    Other(Box<dyn Error + Send + Sync>),
+}
+
+impl WriteError {
+    /// Classify an HTTP error response. Client errors other than those
+    /// caused by configuration, throttling or timeouts indicate a problem
+    /// with the points themselves.
+    fn http(status: u16, message: String) -> Self {
+        match status {
+            401 | 403 | 404 | 408 | 429 => {
+                Self::Other(format!("HTTP status {status}: {message}").into())
+            }
+            400..=499 => Self::Rejected { status, message },
+            _ => Self::Other(format!("HTTP status {status}: {message}").into()),
+        }
+    }
+}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Rejected { status, message } => {
+                write!(f, "rejected with HTTP status {status}: {}", message.trim())
+            }
+            Self::Other(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<influxdb2::RequestError> for WriteError {
+    fn from(err: influxdb2::RequestError) -> Self {
+        match err {
+            influxdb2::RequestError::Http { status, text } => Self::http(status.as_u16(), text),
+            err => Self::Other(err.into()),
+        }
+    }
+}
+
+impl From<reqwest::Error> for WriteError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Other(err.into())
+    }
+}
+
+impl From<std::io::Error> for WriteError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Other(err.into())
+    }
+}
+
+/// Updates waiting to be written to Influxdb. They are kept in timestamp
+/// order (so that each inverter's updates are written in order, even when
+/// they arrive while a write is being retried), and written from the front.
+/// When more than `capacity` updates are pending, the oldest are discarded.
+/// Each point is stored with its size in bytes, so that writes can be split
+/// into chunks that Influxdb will accept.
+///
+/// When Influxdb rejects a write because of the points in it, the points are
+/// split into smaller writes until the offending points are isolated and
+/// can be dropped.
+struct Pending<T> {
+    updates: VecDeque<(i64, Vec<(T, usize)>)>,
+    capacity: usize,
+    /// Number of points at the front that were part of a rejected write
+    suspect: usize,
+    /// Maximum number of points per write while there are suspect points
+    split: usize,
+}
+
+impl<T: Clone> Pending<T> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            updates: VecDeque::new(),
+            capacity: capacity.max(1),
+            suspect: 0,
+            split: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+
+    /// Number of updates (including partially-written ones) pending
+    fn len(&self) -> usize {
+        self.updates.len()
+    }
+
+    /// Add the points for an update with the given timestamp. Returns true
+    /// if the oldest update had to be discarded to make room.
+    fn push(&mut self, timestamp: i64, points: Vec<(T, usize)>) -> bool {
+        // Updates with equal timestamps stay in the order they arrived
+        let pos = self.updates.partition_point(|(t, _)| *t <= timestamp);
+        self.updates.insert(pos, (timestamp, points));
+        if self.updates.len() > self.capacity {
+            self.updates.pop_front();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Get the oldest pending points, up to `max_points` points and
+    /// `max_bytes` bytes. At least one point is returned (if any are
+    /// pending), even if it is larger than `max_bytes`.
+    fn chunk(&self, mut max_points: usize, max_bytes: usize) -> Vec<T> {
+        if self.suspect > 0 {
+            max_points = max_points.min(self.split).min(self.suspect);
+        }
+        let mut points = vec![];
+        let mut bytes = 0;
+        for (point, size) in self.updates.iter().flat_map(|(_, points)| points.iter()) {
+            if !points.is_empty() && (points.len() >= max_points || bytes + size > max_bytes) {
+                break;
+            }
+            bytes += size;
+            points.push(point.clone());
+        }
+        points
+    }
+
+    /// Remove the oldest `n` points, once they have been written
+    fn commit(&mut self, mut n: usize) {
+        self.suspect = self.suspect.saturating_sub(n);
+        while let Some((_, points)) = self.updates.front_mut() {
+            let k = n.min(points.len());
+            points.drain(..k);
+            n -= k;
+            if !points.is_empty() {
+                break;
+            }
+            self.updates.pop_front();
+        }
+    }
+
+    /// Handle the rejection of a write of the oldest `n` points. If it was
+    /// a single point, it is removed and returned so that it can be
+    /// reported; otherwise subsequent writes are split in half until the
+    /// rejected points are found.
+    fn reject(&mut self, n: usize) -> Option<T> {
+        if n > 1 {
+            self.suspect = n;
+            self.split = n / 2;
+            return None;
+        }
+        let point = self
+            .updates
+            .front()
+            .and_then(|(_, points)| points.first())
+            .map(|(point, _)| point.clone());
+        self.commit(1);
+        point
+    }
+}
+
+/// Format a point in the line protocol, for logging
+fn point_line(point: &DataPoint) -> String {
+    let mut buffer = vec![];
+    point
+        .write_data_point_to(&mut buffer)
+        .expect("writing to a Vec cannot fail");
+    String::from_utf8_lossy(&buffer).trim_end().to_owned()
+}
+
+/// Size of a point in the line protocol, in bytes
+fn point_size(point: &DataPoint) -> usize {
+    let mut buffer = vec![];
+    point
+        .write_data_point_to(&mut buffer)
+        .expect("writing to a Vec cannot fail");
+    buffer.len()
+}
 
 pub struct Influxdb2Receiver {
     client: Client,
     bucket: String,
+    /// Compression of write requests
+    compression: Compression,
+    /// Client for compressed writes, which the Influxdb client can't do
+    http: reqwest::Client,
+    /// URL and credentials for compressed writes
+    write_url: String,
+    org: String,
+    token: String,
+    skip_existing: bool,
+    /// Whether to write the update metadata with each point
+    metadata: bool,
+    /// Whether to write every update (rather than just the rollups)
+    raw: bool,
+    /// Rollups to write, if any are configured
+    rollups: Option<Aggregator>,
+    /// Maximum number of updates to hold while Influxdb is unavailable
+    max_pending: usize,
+    /// Maximum number of points in each write request
+    max_points: usize,
+    /// Maximum size of each write request, in bytes
+    max_bytes: usize,
+    /// Timestamp (in ns) of the latest point already in the bucket for each
+    /// inverter, if [Config::skip_existing] is set
+    latest: HashMap<String, Option<i64>>,
+    /// Spool for updates while Influxdb is unreachable, if the offline-first
+    /// profile is enabled
+    spool: Option<Spool>,
+    /// Whether the last write succeeded
+    online: bool,
+    /// Reports when Influxdb becomes unreachable and when the backlog has
+    /// been written after it comes back
+    health: Health,
+    /// Maximum delay between retries
+    max_retry_delay: Duration,
+    clock: Arc<dyn Clock>,
 }
 
 impl Influxdb2Receiver {
-    pub async fn new(config: &Config) -> Self {
+    pub async fn new(config: &Config) -> std::io::Result<Self> {
         let client = Client::new(&config.host, &config.org, &config.token);
         match client.health().await {
             Ok(health_check) => {
@@ -59,54 +382,429 @@ impl Influxdb2Receiver {
                 warn!("Could not connect to Influxdb server: {}", err);
             }
         }
-        Self {
+        let spool = match &config.offline_first {
+            Some(offline_config) => Some(Spool::new(offline_config)?),
+            None => None,
+        };
+        Ok(Self {
             client,
             bucket: config.bucket.to_owned(),
+            compression: config.compression(),
+            http: reqwest::Client::new(),
+            write_url: format!("{}/api/v2/write", config.host.trim_end_matches('/')),
+            org: config.org.to_owned(),
+            token: config.token.to_owned(),
+            skip_existing: config.skip_existing,
+            metadata: config.metadata,
+            raw: config.raw,
+            rollups: (!config.rollups.is_empty()).then(|| Aggregator::new(&config.rollups)),
+            max_pending: config.max_pending,
+            max_points: config.max_points,
+            max_bytes: config.max_bytes,
+            latest: HashMap::new(),
+            spool,
+            online: true,
+            health: Health::new(format!("influxdb2 '{}'", config.name())),
+            max_retry_delay: config
+                .offline_first
+                .as_ref()
+                .map_or(RETRY_DELAY, offline::Config::max_retry_delay),
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Use a different clock for retries and backfilling, for testing
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.health = self.health.with_clock(clock.clone());
+        self.clock = clock;
+        self
+    }
+
+    /// Find the timestamp of the latest point in the bucket for an inverter
+    async fn query_latest(&self, serial: &str) -> Result<Option<i64>, influxdb2::RequestError> {
+        let query = Query::new(latest_query(&self.bucket, serial));
+        let records = self.client.query_raw(Some(query)).await?;
+        Ok(records
+            .iter()
+            .filter_map(|record| match record.values.get("_time") {
+                Some(Value::TimeRFC(time)) => time.timestamp_nanos_opt(),
+                _ => None,
+            })
+            .max())
+    }
+
+    /// Whether an update is already in the bucket. The first time an
+    /// inverter is seen, the bucket is queried for its latest point. If that
+    /// is not known because Influxdb is unreachable, the update is treated as
+    /// new, so that it is spooled or held rather than holding up the updates
+    /// behind it, and the query is tried again once Influxdb is back. Writing
+    /// a point that is already in the bucket just overwrites it.
+    async fn exists(&mut self, update: &Update<'_>) -> bool {
+        if !self.skip_existing {
+            return false;
+        }
+        let latest = match self.latest.get(&update.serial) {
+            Some(latest) => *latest,
+            None if !self.online => return false,
+            None => {
+                let latest = match self.query_latest(&update.serial).await {
+                    Ok(latest) => latest,
+                    Err(err) => {
+                        info!(
+                            "Error querying Influxdb for the latest point of {}; \
+                             writing updates until it can be checked ({:?})",
+                            update.serial, err
+                        );
+                        return false;
+                    }
+                };
+                if let Some(timestamp) = latest {
+                    info!(
+                        "Skipping updates for {} up to {}",
+                        update.serial,
+                        chrono::DateTime::from_timestamp_nanos(timestamp)
+                    );
+                }
+                self.latest.insert(update.serial.clone(), latest);
+                latest
+            }
+        };
+        latest.is_some_and(|latest| update.timestamp <= latest)
+    }
+
+    /// Write points to the bucket, compressing the request if configured
+    async fn write(&self, points: Vec<DataPoint>) -> Result<(), WriteError> {
+        let Some(encoding) = self.compression.content_encoding() else {
+            let body = stream::iter(points);
+            return Ok(self.client.write(self.bucket.as_str(), body).await?);
+        };
+        let mut body = vec![];
+        for point in points.iter() {
+            point.write_data_point_to(&mut body)?;
+        }
+        let query = [
+            ("org", self.org.as_str()),
+            ("bucket", self.bucket.as_str()),
+            ("precision", "ns"),
+        ];
+        let response = self
+            .http
+            .post(&self.write_url)
+            .query(&query)
+            .header(header::AUTHORIZATION, format!("Token {}", self.token))
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .header(header::CONTENT_ENCODING, encoding)
+            .body(self.compression.compress(&body).into_owned())
+            .send()
+            .await?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            let message = response.text().await.unwrap_or_default();
+            Err(WriteError::http(status.as_u16(), message))
+        }
+    }
+
+    /// Convert an update to points, with their sizes
+    fn points(&self, update: &Update<'_>) -> Vec<(DataPoint, usize)> {
+        let mut points = vec![];
+        if let Some(gap) = update.metadata.gap {
+            // Annotate the gap, so that it can be told apart from zeros
+            let build = DataPoint::builder("gap")
+                .timestamp(update.timestamp)
+                .tag("serial", update.serial.as_str())
+                .field("seconds", gap.as_secs_f64())
+                .field("start", update.timestamp - gap.as_nanos() as i64);
+            match build.build() {
+                Ok(point) => {
+                    let size = point_size(&point);
+                    points.push((point, size));
+                }
+                Err(err) => {
+                    warn!("Error building point: {:?}", err);
+                }
+            }
+        }
+        for (i, (field, value)) in update.iter().enumerate() {
+            // Influxdb rejects the whole write if any value is not finite
+            if !value.is_finite() {
+                continue;
+            }
+            let build = DataPoint::builder("inverter")
+                .timestamp(update.timestamp)
+                .tag("serial", update.serial.as_str())
+                .tag("group", field.group)
+                .tag("name", field.name);
+            let build = if field.unit.is_empty() {
+                build
+            } else {
+                build.tag("unit", field.unit)
+            };
+            let build = build.field("value", value);
+            let build = match update.raw.as_ref().map(|raw| &raw[i]) {
+                Some(parts) if !parts.is_empty() => build.field("raw", format_raw(parts)),
+                _ => build,
+            };
+            let build = if self.metadata {
+                add_metadata(build, &update.metadata)
+            } else {
+                build
+            };
+            let build = build.build();
+            match build {
+                Ok(point) => {
+                    let size = point_size(&point);
+                    points.push((point, size));
+                }
+                Err(err) => {
+                    warn!("Error building point: {:?}", err);
+                }
+            }
+        }
+        points
+    }
+
+    /// Convert rollups to points, with their sizes
+    fn rollup_points(rollups: &[Rollup]) -> Vec<(DataPoint, usize)> {
+        let mut points = vec![];
+        for rollup in rollups.iter() {
+            let build = DataPoint::builder(format!("inverter_{}", rollup.period.label()))
+                .timestamp(rollup.start)
+                .tag("serial", rollup.serial.as_str())
+                .tag("group", rollup.group.as_str())
+                .tag("name", rollup.name.as_str());
+            let build = if rollup.unit.is_empty() {
+                build
+            } else {
+                build.tag("unit", rollup.unit.as_str())
+            };
+            let build = match rollup.stats {
+                Stats::Measurement {
+                    min,
+                    max,
+                    mean,
+                    count,
+                } => build
+                    .field("min", min)
+                    .field("max", max)
+                    .field("mean", mean)
+                    .field("count", count as i64),
+                Stats::Counter { last, delta } => build.field("last", last).field("delta", delta),
+            };
+            match build.build() {
+                Ok(point) => {
+                    let size = point_size(&point);
+                    points.push((point, size));
+                }
+                Err(err) => {
+                    warn!("Error building point: {:?}", err);
+                }
+            }
+        }
+        points
+    }
+
+    /// Add rollup points to the pending set
+    fn add_rollups(&self, pending: &mut Pending<DataPoint>, rollups: &[Rollup]) {
+        let points = Self::rollup_points(rollups);
+        let timestamp = rollups.iter().map(|rollup| rollup.start).max();
+        if let Some(timestamp) = timestamp.filter(|_| !points.is_empty()) {
+            if pending.push(timestamp, points) {
+                warn!(
+                    "Too many updates waiting to be written to Influxdb; \
+                     discarding the oldest (max_pending = {})",
+                    self.max_pending
+                );
+            }
+        }
+    }
+
+    /// Add the points for an update to the pending set, unless it is
+    /// already in the bucket. If Influxdb is unreachable and there is a
+    /// spool, the update is added to the spool instead. Rollups are always
+    /// held in memory, since they are not updates that can be spooled.
+    async fn add(&mut self, pending: &mut Pending<DataPoint>, update: &Update<'_>) {
+        if let Some(aggregator) = self.rollups.as_mut() {
+            let rollups = aggregator.add(update);
+            self.add_rollups(pending, &rollups);
+        }
+        if !self.raw {
+            return;
+        }
+        if self.exists(update).await {
+            debug!("Skipping update that is already in Influxdb");
+            return;
+        }
+        if let Some(spool) = self.spool.as_mut().filter(|_| !self.online) {
+            match spool.push(update) {
+                Ok(()) => return,
+                Err(err) => warn!("Could not spool update; keeping it in memory ({err})"),
+            }
+        }
+        let points = self.points(update);
+        if !points.is_empty() && pending.push(update.timestamp, points) {
+            warn!(
+                "Too many updates waiting to be written to Influxdb; \
+                 discarding the oldest (max_pending = {})",
+                self.max_pending
+            );
+        }
+    }
+
+    /// Whether there are spooled updates that can be backfilled now
+    fn can_backfill(&self) -> bool {
+        self.online && self.spool.as_ref().is_some_and(Spool::has_updates)
+    }
+
+    /// Move up to a chunk of spooled updates into the pending set. This is
+    /// only done when nothing else is pending, so that newer updates are
+    /// not held up for more than a chunk.
+    fn backfill(&mut self, pending: &mut Pending<DataPoint>) {
+        let Some(spool) = self.spool.as_mut() else {
+            return;
+        };
+        let now = self.clock.now();
+        let mut updates = vec![];
+        let mut n = 0;
+        while n < self.max_points {
+            match spool.pop(now) {
+                Ok(Some(update)) => {
+                    n += update.fields.len();
+                    updates.push(update);
+                }
+                Ok(None) => {
+                    info!("Finished backfilling spooled updates to Influxdb");
+                    break;
+                }
+                Err(err) => {
+                    warn!("Could not read spooled updates: {err}");
+                    break;
+                }
+            }
+        }
+        for update in updates.iter() {
+            pending.push(update.timestamp, self.points(update));
         }
     }
 }
 
 #[async_trait]
 impl Receiver for Influxdb2Receiver {
-    async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
-        while let Some(update) = receiver.next().await {
-            let mut points = vec![];
-            for (field, value) in zip(update.fields.iter(), update.values.iter()) {
-                let build = DataPoint::builder("inverter")
-                    .timestamp(update.timestamp)
-                    .tag("serial", update.serial.as_str())
-                    .tag("group", field.group)
-                    .tag("name", field.name);
-                let build = if field.unit.is_empty() {
-                    build
+    async fn self_test(&mut self, update: Arc<Update<'static>>) -> Result<(), String> {
+        let points = if self.raw {
+            self.points(&update)
+        } else {
+            // Write the (partial) rollups of the update instead, which the
+            // real rollups for the period will overwrite
+            let mut aggregator = Aggregator::new(&[Period::Hour]);
+            aggregator.add(&update);
+            Self::rollup_points(&aggregator.finish())
+        };
+        let points = points.into_iter().map(|(point, _)| point);
+        self.write(points.collect())
+            .await
+            .map_err(|err| format!("could not write to Influxdb: {err}"))
+    }
+
+    async fn run<'a>(&mut self, mut receiver: UpdateReceiver<'a>) {
+        let mut pending = Pending::new(self.max_pending);
+        let mut open = true;
+        let mut last_progress: Option<Instant> = None;
+        let mut backoff = Backoff::new(self.max_retry_delay);
+        // Updates written since the pending set was last empty
+        let mut flushed = 0;
+        loop {
+            // Wait for an update if there is nothing to write; otherwise
+            // just collect the updates that have already arrived, so that
+            // they are buffered in `pending` while retrying.
+            while open {
+                let update = if pending.is_empty() && !self.can_backfill() {
+                    receiver.next().await
                 } else {
-                    build.tag("unit", field.unit)
-                };
-                let build = build.field("value", *value).build();
-                match build {
-                    Ok(value) => {
-                        points.push(value);
+                    match receiver.next().now_or_never() {
+                        Some(update) => update,
+                        None => break,
                     }
-                    Err(err) => {
-                        warn!("Error building point: {:?}", err);
+                };
+                match update {
+                    Some(update) => self.add(&mut pending, &update).await,
+                    None => {
+                        open = false;
+                        // Write the rollups for the periods in progress
+                        if let Some(aggregator) = self.rollups.as_mut() {
+                            let rollups = aggregator.finish();
+                            self.add_rollups(&mut pending, &rollups);
+                        }
                     }
                 }
             }
-            if !points.is_empty() {
-                loop {
-                    match self
-                        .client
-                        .write(self.bucket.as_str(), stream::iter(points.clone()))
-                        .await
-                    {
-                        Ok(_) => {
-                            break;
-                        }
-                        Err(err) => {
-                            info!("Error writing to Influxdb; trying again in 5s ({:?})", err);
-                            task::sleep(Duration::from_secs(5)).await;
+            if pending.is_empty() && self.can_backfill() {
+                self.backfill(&mut pending);
+            }
+            if pending.is_empty() {
+                if open {
+                    continue;
+                }
+                break;
+            }
+            let points = pending.chunk(self.max_points, self.max_bytes);
+            let n = points.len();
+            match self.write(points).await {
+                Ok(_) => {
+                    let before = pending.len();
+                    pending.commit(n);
+                    flushed += before - pending.len();
+                    self.online = true;
+                    backoff.reset();
+                    // Report progress on long backfills, but not on every
+                    // chunk
+                    if pending.is_empty() {
+                        if last_progress.take().is_some() {
+                            info!("Finished writing backlog to Influxdb");
                         }
+                        self.health.up(flushed);
+                        flushed = 0;
+                    } else if last_progress
+                        .is_none_or(|t| self.clock.instant() - t >= PROGRESS_INTERVAL)
+                    {
+                        info!(
+                            "Writing backlog to Influxdb: {} updates remaining",
+                            pending.len()
+                        );
+                        last_progress = Some(self.clock.instant());
+                    }
+                }
+                Err(WriteError::Rejected { status, message }) => {
+                    // Influxdb is reachable, so there is no need to back
+                    // off; just narrow down which points were rejected.
+                    self.online = true;
+                    backoff.reset();
+                    if let Some(point) = pending.reject(n) {
+                        warn!(
+                            "Influxdb rejected a point (HTTP status {status}); dropping it: {} ({})",
+                            point_line(&point),
+                            message.trim()
+                        );
+                    } else {
+                        info!(
+                            "Influxdb rejected a write of {n} points (HTTP status {status}); \
+                             splitting it to find the bad points"
+                        );
+                    }
+                }
+                Err(WriteError::Other(err)) => {
+                    if self.online && self.spool.is_some() {
+                        info!("Influxdb is unreachable; spooling new updates to disk");
                     }
+                    self.online = false;
+                    self.health.down(&err);
+                    warn!(
+                        "Error writing to Influxdb; trying again in {}s ({:?})",
+                        backoff.delay.as_secs_f64(),
+                        err
+                    );
+                    backoff.wait(self.clock.as_ref()).await;
                 }
             }
         }
@@ -116,13 +814,306 @@ impl Receiver for Influxdb2Receiver {
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
+    /// Name to identify this backend on the command line (defaults to the
+    /// bucket name)
+    pub name: Option<String>,
     #[serde(default = "default_host")]
     pub host: String,
     pub org: String,
     pub token: String,
     pub bucket: String,
+    /// Units to convert values to
+    #[serde(default)]
+    pub units: Units,
+    /// Serial numbers of the inverters to send to this backend (all if not
+    /// given)
+    #[serde(default)]
+    pub serials: Serials,
+    /// Whether to write the time reported by the inverter or the time the
+    /// update was received
+    #[serde(default)]
+    pub timestamps: Timestamps,
+    /// Skip updates that are no newer than the latest point already in the
+    /// bucket for the same inverter
+    #[serde(default)]
+    pub skip_existing: bool,
+    /// Write the source, protocol, frame length and decode time of each
+    /// update as extra tags and fields
+    #[serde(default)]
+    pub metadata: bool,
+    /// Write every update to the `inverter` measurement. This can be
+    /// disabled to only write rollups.
+    #[serde(default = "default_raw")]
+    pub raw: bool,
+    /// Periods for which to write rollups (see [crate::rollup])
+    #[serde(default)]
+    pub rollups: Vec<Period>,
+    /// Maximum number of updates to hold in memory while Influxdb is
+    /// unavailable. Beyond this, the oldest updates are discarded.
+    #[serde(default = "default_max_pending")]
+    pub max_pending: usize,
+    /// Maximum number of points to write in one request
+    #[serde(default = "default_max_points")]
+    pub max_points: usize,
+    /// Maximum size (in bytes, in the line protocol) of each write request
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: usize,
+    /// Compression of write requests (defaults to gzip with the
+    /// offline-first profile, and none otherwise)
+    pub compression: Option<Compression>,
+    /// Enable the offline-first profile
+    pub offline_first: Option<offline::Config>,
+    /// Number of instances to run, each on its own thread and handling a
+    /// subset of the inverters (see [crate::workers])
+    #[serde(default = "default_workers")]
+    pub workers: NonZeroUsize,
+}
+
+impl Config {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.bucket)
+    }
+
+    /// Check the parts of the configuration that serde cannot
+    pub fn check(&self) -> Result<(), String> {
+        match &self.offline_first {
+            Some(offline) => offline.check(),
+            None => Ok(()),
+        }
+    }
+
+    fn compression(&self) -> Compression {
+        self.compression.unwrap_or(match self.offline_first {
+            Some(_) => Compression::Gzip,
+            None => Compression::None,
+        })
+    }
 }
 
 fn default_host() -> String {
     "http://localhost:8086".to_string()
 }
+
+fn default_raw() -> bool {
+    true
+}
+
+fn default_max_pending() -> usize {
+    10000
+}
+
+fn default_max_points() -> usize {
+    5000
+}
+
+fn default_max_bytes() -> usize {
+    1_000_000
+}
+
+fn default_workers() -> NonZeroUsize {
+    NonZeroUsize::MIN
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn test_format_raw() {
+        assert_eq!(format_raw(&[0x915]), "0x0915");
+        assert_eq!(format_raw(&[1, 0xffff]), "0x0001 0xffff");
+    }
+
+    #[test]
+    fn test_flux_string() {
+        assert_eq!(flux_string("bucket"), r#""bucket""#);
+        assert_eq!(flux_string(r#"a"b\c${d}"#), r#""a\"b\\c\${d}""#);
+    }
+
+    /// Attach sizes to points for [Pending::push]
+    fn sized<T>(points: Vec<T>) -> Vec<(T, usize)> {
+        points.into_iter().map(|point| (point, 10)).collect()
+    }
+
+    #[test]
+    fn test_rollup_points() {
+        let rollup = Rollup {
+            period: Period::Hour,
+            start: 1_000_000_000,
+            serial: "1234".to_owned(),
+            group: "Grid".to_owned(),
+            name: "Power".to_owned(),
+            id: "grid_power".to_owned(),
+            unit: "W".to_owned(),
+            stats: Stats::Measurement {
+                min: 1.0,
+                max: 3.0,
+                mean: 2.0,
+                count: 2,
+            },
+        };
+        let counter = Rollup {
+            period: Period::Day,
+            unit: String::new(),
+            stats: Stats::Counter {
+                last: 5.0,
+                delta: 1.5,
+            },
+            ..rollup.clone()
+        };
+        let points = Influxdb2Receiver::rollup_points(&[rollup, counter]);
+        let lines: Vec<String> = points.iter().map(|(point, _)| point_line(point)).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "inverter_hourly,group=Grid,name=Power,serial=1234,unit=W \
+                 count=2i,max=3,mean=2,min=1 1000000000",
+                "inverter_daily,group=Grid,name=Power,serial=1234 delta=1.5,last=5 1000000000",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pending_recovery() {
+        let mut pending = Pending::new(10);
+        assert!(!pending.push(10, sized(vec!["a10"])));
+        // The write fails, and more updates arrive while retrying, one of
+        // them late
+        assert_eq!(pending.chunk(100, 1000), vec!["a10"]);
+        assert!(!pending.push(20, sized(vec!["a20", "b20"])));
+        assert!(!pending.push(15, sized(vec!["b15"])));
+        assert!(!pending.push(20, sized(vec!["a20-retransmit"])));
+        // Once Influxdb is back, everything is written in timestamp order
+        let chunk = pending.chunk(2, 1000);
+        assert_eq!(chunk, vec!["a10", "b15"]);
+        pending.commit(chunk.len());
+        let chunk = pending.chunk(100, 1000);
+        assert_eq!(chunk, vec!["a20", "b20", "a20-retransmit"]);
+        pending.commit(chunk.len());
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_pending_overflow() {
+        let mut pending = Pending::new(2);
+        assert!(!pending.push(1, sized(vec![1])));
+        assert!(!pending.push(2, sized(vec![2])));
+        // A long outage: the oldest updates are discarded
+        assert!(pending.push(3, sized(vec![3])));
+        assert!(pending.push(4, sized(vec![4])));
+        assert_eq!(pending.chunk(100, 1000), vec![3, 4]);
+    }
+
+    #[test]
+    fn test_pending_chunks() {
+        let mut pending = Pending::new(10);
+        pending.push(1, sized(vec![1, 2, 3]));
+        pending.push(2, vec![(4, 10), (5, 100)]);
+        // Limited by the number of points, splitting an update
+        assert_eq!(pending.chunk(2, 1000), vec![1, 2]);
+        pending.commit(2);
+        assert_eq!(pending.len(), 2);
+        // Limited by the size
+        assert_eq!(pending.chunk(100, 25), vec![3, 4]);
+        pending.commit(2);
+        assert_eq!(pending.len(), 1);
+        // A point that is too big by itself is still written
+        assert_eq!(pending.chunk(100, 25), vec![5]);
+        pending.commit(1);
+        assert!(pending.is_empty());
+    }
+
+    /// Write everything pending, with Influxdb rejecting any write that
+    /// contains a point in `bad`. Returns the writes that succeeded and the
+    /// points that were dropped.
+    fn write_all(pending: &mut Pending<i32>, bad: &[i32]) -> (Vec<Vec<i32>>, Vec<i32>) {
+        let mut written = vec![];
+        let mut dropped = vec![];
+        while !pending.is_empty() {
+            let chunk = pending.chunk(8, 1000);
+            if chunk.iter().any(|point| bad.contains(point)) {
+                dropped.extend(pending.reject(chunk.len()));
+            } else {
+                pending.commit(chunk.len());
+                written.push(chunk);
+            }
+        }
+        (written, dropped)
+    }
+
+    #[test]
+    fn test_pending_reject() {
+        let mut pending = Pending::new(10);
+        pending.push(1, sized((0..10).collect()));
+        pending.push(2, sized((10..20).collect()));
+        let (written, dropped) = write_all(&mut pending, &[5, 6]);
+        assert_eq!(dropped, vec![5, 6]);
+        assert_eq!(
+            written,
+            vec![
+                vec![0, 1, 2, 3],
+                vec![4],
+                vec![7],
+                vec![8, 9, 10, 11, 12, 13, 14, 15],
+                vec![16, 17, 18, 19],
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_backoff() {
+        let clock = MockClock::new(0);
+        let mut backoff = Backoff::new(Duration::from_secs(30));
+        let mut waits = vec![];
+        for _ in 0..4 {
+            let start = clock.now();
+            backoff.wait(clock.as_ref()).await;
+            waits.push((clock.now() - start) / 1_000_000_000);
+        }
+        assert_eq!(waits, [5, 10, 20, 30]);
+        backoff.reset();
+        assert_eq!(backoff.delay, RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    async fn test_exists_unreachable() {
+        let config: Config = toml::from_str(
+            r#"
+            host = "http://127.0.0.1:9"
+            org = "org"
+            token = "token"
+            bucket = "bucket"
+            skip_existing = true
+            "#,
+        )
+        .unwrap();
+        let mut receiver = Influxdb2Receiver::new(&config).await.unwrap();
+        let update = Update::new(1000, "1234", &[], vec![]);
+        let timeout = Duration::from_secs(10);
+        // The latest point is unknown, so the update is treated as new
+        let exists = tokio::time::timeout(timeout, receiver.exists(&update)).await;
+        assert_eq!(exists, Ok(false));
+        assert!(receiver.latest.is_empty());
+        // While Influxdb is known to be down, it is not queried at all
+        receiver.online = false;
+        let exists = tokio::time::timeout(timeout, receiver.exists(&update)).await;
+        assert_eq!(exists, Ok(false));
+    }
+
+    #[test]
+    fn test_write_error() {
+        let rejected = |status| {
+            matches!(
+                WriteError::http(status, String::new()),
+                WriteError::Rejected { .. }
+            )
+        };
+        assert!(rejected(400));
+        assert!(rejected(422));
+        assert!(!rejected(401));
+        assert!(!rejected(429));
+        assert!(!rejected(500));
+        assert!(!rejected(503));
+    }
+}
@@ -0,0 +1,218 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Offline-first profile for the Influxdb backend, for sites with an
+//! unreliable or metered uplink. While Influxdb is unreachable, updates are
+//! appended to a spool file on disk rather than held in memory. When the
+//! uplink returns, new updates are written first (so that dashboards show
+//! live values again) and the spooled updates are backfilled in between.
+
+use log::warn;
+use serde::Deserialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, LineWriter};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::journal::{JournalReader, JournalWriter};
+use crate::receiver::Update;
+
+/// Structure corresponding to the `[influxdb2.offline_first]` section of the
+/// configuration file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// File in which to keep updates while Influxdb is unreachable
+    pub spool: PathBuf,
+    /// Age (in days) beyond which spooled updates are discarded
+    #[serde(default = "default_days")]
+    pub days: f64,
+    /// Maximum time (in seconds) between attempts to reach Influxdb. The
+    /// delay starts at 5 seconds and doubles after each failure.
+    #[serde(default = "default_max_retry_delay")]
+    pub max_retry_delay: f64,
+}
+
+fn default_days() -> f64 {
+    7.0
+}
+
+fn default_max_retry_delay() -> f64 {
+    300.0
+}
+
+impl Config {
+    /// Check the parts of the configuration that serde cannot
+    pub fn check(&self) -> Result<(), String> {
+        if !(self.days.is_finite() && self.days > 0.0) {
+            return Err(format!(
+                "offline_first days must be positive, not {}",
+                self.days
+            ));
+        }
+        if !(self.max_retry_delay.is_finite() && self.max_retry_delay > 0.0) {
+            return Err(format!(
+                "offline_first max_retry_delay must be positive, not {}",
+                self.max_retry_delay
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn max_retry_delay(&self) -> Duration {
+        Duration::from_secs_f64(self.max_retry_delay)
+    }
+}
+
+/// Updates waiting on disk, in the journal format. The file is appended to
+/// while Influxdb is unreachable, read back from the start to backfill, and
+/// truncated once it has all been read. It is not truncated on startup, so
+/// updates spooled before a restart are still backfilled.
+pub struct Spool {
+    path: PathBuf,
+    /// Maximum age of updates to backfill, in nanoseconds
+    max_age: i64,
+    writer: Option<JournalWriter<LineWriter<File>>>,
+    /// Reader for backfilling. Each time the file is read, its field tables
+    /// are leaked, which is acceptable since it only happens once per
+    /// outage.
+    reader: Option<JournalReader<BufReader<File>>>,
+    /// Whether the file may contain updates that have not been read
+    has_updates: bool,
+}
+
+impl Spool {
+    pub fn new(config: &Config) -> std::io::Result<Self> {
+        let has_updates = match std::fs::metadata(&config.spool) {
+            Ok(metadata) => metadata.len() > 0,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => false,
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            path: config.spool.clone(),
+            max_age: (config.days * 86400e9) as i64,
+            writer: None,
+            reader: None,
+            has_updates,
+        })
+    }
+
+    /// Whether there may be updates to backfill
+    pub fn has_updates(&self) -> bool {
+        self.has_updates
+    }
+
+    /// Append an update to the file
+    pub fn push(&mut self, update: &Update<'_>) -> std::io::Result<()> {
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?;
+                self.writer
+                    .insert(JournalWriter::new(LineWriter::new(file)))
+            }
+        };
+        writer.write(update)?;
+        self.has_updates = true;
+        Ok(())
+    }
+
+    /// Read the next update to backfill. Updates that are more than the
+    /// maximum age older than `now` (in nanoseconds since the UNIX epoch)
+    /// are skipped. Once the end of the file is reached, it is truncated.
+    pub fn pop(&mut self, now: i64) -> std::io::Result<Option<Update<'static>>> {
+        if !self.has_updates {
+            return Ok(None);
+        }
+        let reader = match &mut self.reader {
+            Some(reader) => reader,
+            None => {
+                // Give up on the file (until more updates are added) if it
+                // can't be read, rather than retrying continuously
+                let file = File::open(&self.path).inspect_err(|_| self.has_updates = false)?;
+                self.reader.insert(JournalReader::new(BufReader::new(file)))
+            }
+        };
+        for update in reader {
+            match update {
+                Ok(update) if now - update.timestamp <= self.max_age => return Ok(Some(update)),
+                Ok(_) => {}
+                Err(err) => warn!("Skipping bad entry in {}: {err}", self.path.display()),
+            }
+        }
+        // Start afresh, so that the field tables are written again
+        self.reader = None;
+        self.writer = None;
+        self.has_updates = false;
+        File::create(&self.path)?;
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let config = |text: &str| -> Config {
+            toml::from_str(&format!("spool = \"spool.jsonl\"\n{text}")).unwrap()
+        };
+        assert!(config("").check().is_ok());
+        assert!(config("days = 0.5\nmax_retry_delay = 60").check().is_ok());
+        assert!(config("days = 0").check().is_err());
+        assert!(config("days = nan").check().is_err());
+        assert!(config("max_retry_delay = -1").check().is_err());
+        assert!(config("max_retry_delay = nan").check().is_err());
+        assert!(config("max_retry_delay = inf").check().is_err());
+    }
+
+    #[test]
+    fn test_spool() {
+        let dir = std::env::temp_dir().join(format!("sunsniff-spool-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            spool: dir.join("spool.jsonl"),
+            days: 1.0,
+            max_retry_delay: default_max_retry_delay(),
+        };
+        let day = 86400 * 1_000_000_000i64;
+        let mut spool = Spool::new(&config).unwrap();
+        assert!(!spool.has_updates());
+        for timestamp in [day, 2 * day, 3 * day] {
+            spool
+                .push(&Update::new(timestamp, "1234", &[], vec![]))
+                .unwrap();
+        }
+        // Updates survive a restart
+        let mut spool = Spool::new(&config).unwrap();
+        assert!(spool.has_updates());
+        let now = 3 * day + 1;
+        assert_eq!(spool.pop(now).unwrap().unwrap().timestamp, 3 * day);
+        // Updates that arrive during the backfill are also read
+        spool
+            .push(&Update::new(4 * day, "1234", &[], vec![]))
+            .unwrap();
+        assert_eq!(spool.pop(now).unwrap().unwrap().timestamp, 4 * day);
+        assert!(spool.pop(now).unwrap().is_none());
+        assert!(!spool.has_updates());
+        assert_eq!(std::fs::metadata(&config.spool).unwrap().len(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,651 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Append-only journal of every published update, which can be replayed
+//! into a backend that was added later.
+//!
+//! The journal is a JSON Lines file. To avoid repeating the field
+//! descriptions in every update, each distinct table of fields is written
+//! once (the first time it is used by each run of sunsniff) and later
+//! updates refer to it by number.
+//!
+//! If retention is configured, updates older than a number of days are
+//! periodically moved out of the journal into hourly rollups (see
+//! [crate::rollup]), so that it does not grow without limit.
+
+use async_trait::async_trait;
+use chrono::{Local, TimeZone};
+use futures::prelude::*;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::fields::{Field, FieldType, Reset, WordOrder};
+use crate::json::SCHEMA_VERSION;
+use crate::receiver::{Receiver, Update, UpdateReceiver};
+use crate::rollup::{Aggregator, CsvWriter, Period, Rollup};
+
+/// Time between moving old updates out of the journal
+const COMPACT_INTERVAL: Duration = Duration::from_secs(86400);
+
+/// Structure corresponding to the `[journal]` section of the configuration
+/// file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// File to append the updates to
+    file: PathBuf,
+    /// Limit on how long updates are kept
+    retention: Option<Retention>,
+}
+
+/// Structure corresponding to the `[journal.retention]` section of the
+/// configuration file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Retention {
+    /// Number of days of updates to keep in the journal
+    days: u32,
+    /// CSV file to append the hourly rollups of older updates to
+    rollups: PathBuf,
+}
+
+/// Owned version of [Field], for serialisation
+#[derive(Serialize, Deserialize, Debug)]
+struct FieldRecord {
+    field_type: FieldType,
+    group: String,
+    name: String,
+    id: String,
+    scale: f64,
+    bias: f64,
+    unit: String,
+    sum_of: Vec<(usize, f64)>,
+    word_order: WordOrder,
+    /// Missing from journals written before it was added
+    #[serde(default)]
+    reset: Reset,
+}
+
+impl From<&Field<'_>> for FieldRecord {
+    fn from(field: &Field<'_>) -> Self {
+        Self {
+            field_type: field.field_type,
+            group: field.group.to_owned(),
+            name: field.name.to_owned(),
+            id: field.id.to_owned(),
+            scale: field.scale,
+            bias: field.bias,
+            unit: field.unit.to_owned(),
+            sum_of: field.sum_of.to_vec(),
+            word_order: field.word_order,
+            reset: field.reset,
+        }
+    }
+}
+
+impl FieldRecord {
+    /// Borrow as a [Field]
+    fn as_field(&self) -> Field<'_> {
+        Field {
+            field_type: self.field_type,
+            group: &self.group,
+            name: &self.name,
+            id: &self.id,
+            scale: self.scale,
+            bias: self.bias,
+            unit: &self.unit,
+            sum_of: &self.sum_of,
+            word_order: self.word_order,
+            reset: self.reset,
+        }
+    }
+
+    /// Convert to a [Field]. The strings are leaked, which is acceptable
+    /// because there are only a few tables in a journal.
+    fn leak(self) -> Field<'static> {
+        Field {
+            field_type: self.field_type,
+            group: self.group.leak(),
+            name: self.name.leak(),
+            id: self.id.leak(),
+            scale: self.scale,
+            bias: self.bias,
+            unit: self.unit.leak(),
+            sum_of: self.sum_of.leak(),
+            word_order: self.word_order,
+            reset: self.reset,
+        }
+    }
+}
+
+/// A line in the journal
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record {
+    /// Definition of a table of fields (replacing any earlier table with the
+    /// same number)
+    Fields {
+        /// Version of the encoding (see [crate::json]). Journals written
+        /// before it was recorded are version 1.
+        #[serde(default = "first_version")]
+        schema_version: u32,
+        table: usize,
+        fields: Vec<FieldRecord>,
+    },
+    Update {
+        table: usize,
+        timestamp: i64,
+        serial: String,
+        /// Values, with `None` for values that are not finite (which JSON
+        /// cannot represent)
+        values: Vec<Option<f64>>,
+    },
+}
+
+fn first_version() -> u32 {
+    1
+}
+
+/// Writes updates to a journal
+pub struct JournalWriter<W: Write> {
+    writer: W,
+    /// Table number for each table of fields that has been written,
+    /// indexed by its address
+    tables: HashMap<usize, usize>,
+}
+
+impl<W: Write> JournalWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            tables: HashMap::new(),
+        }
+    }
+
+    fn write_record(&mut self, record: &Record) -> std::io::Result<()> {
+        serde_json::to_writer(&mut self.writer, record)?;
+        self.writer.write_all(b"\n")
+    }
+
+    pub fn write(&mut self, update: &Update<'_>) -> std::io::Result<()> {
+        let next = self.tables.len();
+        let table = *self
+            .tables
+            .entry(update.fields.as_ptr() as usize)
+            .or_insert(next);
+        if table == next {
+            self.write_record(&Record::Fields {
+                schema_version: SCHEMA_VERSION,
+                table,
+                fields: update.fields.iter().map(FieldRecord::from).collect(),
+            })?;
+        }
+        self.write_record(&Record::Update {
+            table,
+            timestamp: update.timestamp,
+            serial: update.serial.clone(),
+            values: update
+                .values
+                .iter()
+                .map(|&value| value.is_finite().then_some(value))
+                .collect(),
+        })
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads the updates back from a journal
+pub struct JournalReader<R: BufRead> {
+    lines: std::io::Lines<R>,
+    tables: HashMap<usize, &'static [Field<'static>]>,
+}
+
+impl<R: BufRead> JournalReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: reader.lines(),
+            tables: HashMap::new(),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for JournalReader<R> {
+    type Item = Result<Update<'static>, Box<dyn std::error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for line in self.lines.by_ref() {
+            let record = match line {
+                Ok(line) if line.is_empty() => continue,
+                Ok(line) => serde_json::from_str(&line),
+                Err(err) => return Some(Err(err.into())),
+            };
+            match record {
+                Ok(Record::Fields {
+                    schema_version,
+                    table,
+                    fields,
+                }) => {
+                    if schema_version != SCHEMA_VERSION {
+                        return Some(Err(format!(
+                            "Unsupported schema version {schema_version} (expected {SCHEMA_VERSION})"
+                        )
+                        .into()));
+                    }
+                    let fields: Vec<Field<'static>> =
+                        fields.into_iter().map(FieldRecord::leak).collect();
+                    self.tables.insert(table, fields.leak());
+                }
+                Ok(Record::Update {
+                    table,
+                    timestamp,
+                    serial,
+                    values,
+                }) => {
+                    let Some(fields) = self.tables.get(&table) else {
+                        return Some(Err(format!("Undefined field table {table}").into()));
+                    };
+                    if fields.len() != values.len() {
+                        return Some(Err("Wrong number of values in update".into()));
+                    }
+                    let values = values
+                        .into_iter()
+                        .map(|value| value.unwrap_or(f64::NAN))
+                        .collect();
+                    return Some(Ok(Update::new(timestamp, serial, fields, values)));
+                }
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+        None
+    }
+}
+
+/// A table of fields in a journal that is being compacted
+struct CompactTable {
+    /// The record defining the table, as it appears in the journal
+    line: String,
+    fields: Vec<FieldRecord>,
+    /// Whether the record has been copied to the output
+    written: bool,
+}
+
+/// Copy a journal from `input` to `output`, except for the updates older
+/// than `cutoff` (in nanoseconds since the UNIX epoch), which are passed to
+/// `aggregator` instead. The records that are kept are copied as they are,
+/// apart from the definitions of tables that are no longer used. Returns
+/// the number of updates removed and the rollups of them.
+fn compact<R: BufRead, W: Write, Tz: TimeZone>(
+    input: R,
+    mut output: W,
+    cutoff: i64,
+    aggregator: &mut Aggregator<Tz>,
+) -> Result<(usize, Vec<Rollup>), Box<dyn Error>> {
+    let mut tables: HashMap<usize, CompactTable> = HashMap::new();
+    let mut removed = 0;
+    let mut rollups = vec![];
+    for line in input.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line)? {
+            Record::Fields {
+                schema_version,
+                table,
+                fields,
+            } => {
+                if schema_version != SCHEMA_VERSION {
+                    return Err(format!(
+                        "Unsupported schema version {schema_version} (expected {SCHEMA_VERSION})"
+                    )
+                    .into());
+                }
+                let table_info = CompactTable {
+                    line,
+                    fields,
+                    written: false,
+                };
+                tables.insert(table, table_info);
+            }
+            Record::Update {
+                table,
+                timestamp,
+                serial,
+                values,
+            } => {
+                let Some(table) = tables.get_mut(&table) else {
+                    return Err(format!("Undefined field table {table}").into());
+                };
+                if timestamp >= cutoff {
+                    if !table.written {
+                        writeln!(output, "{}", table.line)?;
+                        table.written = true;
+                    }
+                    writeln!(output, "{line}")?;
+                    continue;
+                }
+                if table.fields.len() != values.len() {
+                    return Err("Wrong number of values in update".into());
+                }
+                let fields: Vec<Field<'_>> =
+                    table.fields.iter().map(FieldRecord::as_field).collect();
+                let values = values
+                    .into_iter()
+                    .map(|value| value.unwrap_or(f64::NAN))
+                    .collect();
+                rollups.extend(aggregator.add(&Update::new(timestamp, serial, &fields, values)));
+                removed += 1;
+            }
+        }
+    }
+    output.flush()?;
+    rollups.extend(aggregator.finish());
+    Ok((removed, rollups))
+}
+
+/// Move the updates older than `cutoff` (in nanoseconds since the UNIX
+/// epoch) out of the journal at `path`, appending their hourly rollups to
+/// `rollups`. The journal is rewritten to a temporary file that then
+/// replaces it, so that it is left as it was if anything fails. Returns the
+/// number of updates removed.
+fn compact_file(
+    path: &Path,
+    cutoff: i64,
+    rollups: &mut CsvWriter,
+) -> Result<usize, Box<dyn Error>> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let input = BufReader::new(File::open(path)?);
+    let output = BufWriter::new(File::create(&temp_path)?);
+    let mut aggregator = Aggregator::new(&[Period::Hour]);
+    let result = compact(input, output, cutoff, &mut aggregator).and_then(|(removed, hourly)| {
+        if removed > 0 {
+            rollups.write(&hourly)?;
+            rollups.flush()?;
+            std::fs::rename(&temp_path, path)?;
+        }
+        Ok(removed)
+    });
+    if !matches!(result, Ok(removed) if removed > 0) {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+fn open_journal(path: &Path) -> std::io::Result<JournalWriter<LineWriter<File>>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(JournalWriter::new(LineWriter::new(file)))
+}
+
+pub struct JournalReceiver {
+    file: PathBuf,
+    writer: JournalWriter<LineWriter<File>>,
+    /// Number of days of updates to keep, and where to write the rollups
+    /// of older ones, if retention is configured
+    retention: Option<(u32, CsvWriter)>,
+    /// When old updates were last moved out of the journal
+    compacted: Option<Instant>,
+}
+
+impl JournalReceiver {
+    pub fn new(config: &Config) -> std::io::Result<Self> {
+        let retention = match &config.retention {
+            Some(retention) => Some((retention.days, CsvWriter::open(&retention.rollups)?)),
+            None => None,
+        };
+        Ok(Self {
+            file: config.file.clone(),
+            writer: open_journal(&config.file)?,
+            retention,
+            compacted: None,
+        })
+    }
+
+    /// Move old updates out of the journal, if retention is configured and
+    /// this has not been done in the last [COMPACT_INTERVAL]. The journal
+    /// is then reopened, and the field tables are written again as needed.
+    fn maintain(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some((days, rollups)) = self.retention.as_mut() else {
+            return Ok(());
+        };
+        if self
+            .compacted
+            .is_some_and(|compacted| compacted.elapsed() < COMPACT_INTERVAL)
+        {
+            return Ok(());
+        }
+        self.compacted = Some(Instant::now());
+        // Only move whole hours, so that each hour is rolled up once
+        let keep = i64::from(*days) * 86400 * 1_000_000_000;
+        let cutoff = Period::Hour.start(SystemClock.now() - keep, &Local);
+        self.writer.flush()?;
+        let removed = compact_file(&self.file, cutoff, rollups)?;
+        if removed > 0 {
+            info!("Moved {removed} updates older than {days} days from the journal into rollups");
+            self.writer = open_journal(&self.file)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Receiver for JournalReceiver {
+    async fn run<'a>(&mut self, mut receiver: UpdateReceiver<'a>) {
+        while let Some(update) = receiver.next().await {
+            if let Err(err) = self.maintain() {
+                warn!("Failed to remove old updates from the journal: {err}");
+            }
+            if let Err(err) = self.writer.write(&update) {
+                warn!("Failed to write to journal: {err}");
+            }
+        }
+        if let Err(err) = self.writer.flush() {
+            warn!("Failed to write to journal: {err}");
+        }
+    }
+
+    async fn self_test(&mut self, update: Arc<Update<'static>>) -> Result<(), String> {
+        self.writer
+            .write(&update)
+            .and_then(|()| self.writer.flush())
+            .map_err(|err| format!("could not write to the journal: {err}"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FIELDS: &[Field<'static>] = &[
+        Field {
+            field_type: FieldType::Power,
+            group: "Grid",
+            name: "Power",
+            id: "grid_power",
+            scale: 1.0,
+            bias: 0.0,
+            unit: "W",
+            sum_of: &[],
+            word_order: WordOrder::Little,
+            reset: Reset::Never,
+        },
+        Field {
+            field_type: FieldType::Power,
+            group: "Load",
+            name: "Power",
+            id: "load_power",
+            scale: 1.0,
+            bias: 0.0,
+            unit: "W",
+            sum_of: &[(0, 1.0)],
+            word_order: WordOrder::Little,
+            reset: Reset::Never,
+        },
+    ];
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = JournalWriter::new(vec![]);
+        writer
+            .write(&Update::new(1000, "1234", FIELDS, vec![1.5, f64::NAN]))
+            .unwrap();
+        writer
+            .write(&Update::new(2000, "1234", FIELDS, vec![2.5, 3.0]))
+            .unwrap();
+        let text = String::from_utf8(writer.writer).unwrap();
+        // The field table is only written once
+        assert_eq!(text.lines().count(), 3);
+        // Field types keep the names that earlier versions read
+        assert!(text.contains(r#""field_type":"Power""#));
+
+        let updates: Vec<Update<'static>> = JournalReader::new(text.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].timestamp, 1000);
+        assert_eq!(updates[0].serial, "1234");
+        assert_eq!(updates[0].values[0], 1.5);
+        assert!(updates[0].values[1].is_nan());
+        assert_eq!(updates[1].values, [2.5, 3.0]);
+        let fields = updates[1].fields;
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[1].id, "load_power");
+        assert_eq!(fields[1].sum_of, [(0, 1.0)]);
+    }
+
+    #[test]
+    fn test_schema_version() {
+        // Journals from before the version was recorded
+        let text = r#"{"type":"fields","table":0,"fields":[]}
+{"type":"update","table":0,"timestamp":0,"serial":"1","values":[]}"#;
+        let mut reader = JournalReader::new(text.as_bytes());
+        assert!(reader.next().unwrap().is_ok());
+
+        let text = r#"{"type":"fields","schema_version":2,"table":0,"fields":[]}"#;
+        let mut reader = JournalReader::new(text.as_bytes());
+        assert!(reader.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_undefined_table() {
+        let text = r#"{"type":"update","table":0,"timestamp":0,"serial":"1","values":[]}"#;
+        let mut reader = JournalReader::new(text.as_bytes());
+        assert!(reader.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_compact() {
+        const HOUR: i64 = 3600 * 1_000_000_000;
+        let mut writer = JournalWriter::new(vec![]);
+        for (timestamp, value) in [(0, 1.0), (HOUR / 2, 3.0), (HOUR, 5.0), (2 * HOUR, 7.0)] {
+            writer
+                .write(&Update::new(timestamp, "1234", FIELDS, vec![value, 0.0]))
+                .unwrap();
+        }
+        let text = String::from_utf8(writer.writer).unwrap();
+        let mut aggregator = Aggregator::with_timezone(&[Period::Hour], chrono::Utc);
+        let mut output = vec![];
+        let (removed, rollups) =
+            compact(text.as_bytes(), &mut output, HOUR, &mut aggregator).unwrap();
+        assert_eq!(removed, 2);
+        assert_eq!(rollups.len(), 2);
+        assert_eq!(rollups[0].id, "grid_power");
+        assert_eq!(
+            rollups[0].stats,
+            crate::rollup::Stats::Measurement {
+                min: 1.0,
+                max: 3.0,
+                mean: 2.0,
+                count: 2
+            }
+        );
+
+        // The kept updates are unchanged, with the table defined first
+        let kept: Vec<Update<'static>> = JournalReader::new(output.as_slice())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(kept.len(), 2);
+        assert_eq!(kept[0].timestamp, HOUR);
+        assert_eq!(kept[1].values, [7.0, 0.0]);
+        assert_eq!(kept[1].fields[1].sum_of, [(0, 1.0)]);
+
+        // Nothing to remove
+        let mut again = vec![];
+        let (removed, rollups) =
+            compact(output.as_slice(), &mut again, HOUR, &mut aggregator).unwrap();
+        assert_eq!(removed, 0);
+        assert!(rollups.is_empty());
+        assert_eq!(again, output);
+    }
+
+    #[test]
+    fn test_compact_file() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("sunsniff-compact-{}", std::process::id()));
+        let rollups_path = dir.join(format!("sunsniff-compact-{}.csv", std::process::id()));
+        let mut writer = open_journal(&path).unwrap();
+        for timestamp in [1000, 2000] {
+            writer
+                .write(&Update::new(timestamp, "1234", FIELDS, vec![1.0, 2.0]))
+                .unwrap();
+        }
+        writer.flush().unwrap();
+        let mut rollups = CsvWriter::open(&rollups_path).unwrap();
+        assert_eq!(compact_file(&path, 2000, &mut rollups).unwrap(), 1);
+        let journal = std::fs::read_to_string(&path).unwrap();
+        let csv = std::fs::read_to_string(&rollups_path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&rollups_path).unwrap();
+        assert_eq!(journal.lines().count(), 2);
+        // Header and one line per field
+        assert_eq!(csv.lines().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_self_test() {
+        let path = std::env::temp_dir().join(format!("sunsniff-journal-{}", std::process::id()));
+        let config: Config = toml::from_str(&format!("file = {:?}", path)).unwrap();
+        let mut receiver = JournalReceiver::new(&config).unwrap();
+        let update = Update::new(1000, "1234", FIELDS, vec![1.5, 2.5]);
+        receiver.self_test(Arc::new(update)).await.unwrap();
+        // The update must be on disk as soon as the self-test passes
+        let file = std::fs::File::open(&path).unwrap();
+        let updates: Vec<Update<'static>> = JournalReader::new(std::io::BufReader::new(file))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].values, [1.5, 2.5]);
+
+        // A journal that cannot be written fails the self-test
+        let config: Config = toml::from_str("file = \"/dev/full\"").unwrap();
+        let mut receiver = JournalReceiver::new(&config).unwrap();
+        let update = Update::new(1000, "1234", FIELDS, vec![1.5, 2.5]);
+        assert!(receiver.self_test(Arc::new(update)).await.is_err());
+    }
+}
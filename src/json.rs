@@ -0,0 +1,258 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! JSON encoding of updates, shared by the backends that pass them to other
+//! programs (the HTTP API and its WebSocket, the Unix socket and the
+//! journal).
+//!
+//! Every encoded update carries [SCHEMA_VERSION]. Adding properties (to an
+//! update or a field) is a compatible change and does not change the
+//! version, so consumers must ignore properties they do not know about.
+//! Removing or renaming a property, or changing its type or meaning,
+//! increments the version. The schema is written to
+//! `schema/update.schema.json` by `sunsniff schema`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::borrow::Cow;
+
+use crate::receiver;
+
+/// Version of the JSON encoding
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Value of a field, with its description
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FieldValue {
+    pub id: String,
+    pub group: String,
+    pub name: String,
+    pub unit: String,
+    /// `None` (`null` in JSON) for values that are not finite
+    pub value: Option<f64>,
+}
+
+/// Get the values of all the fields in an update
+pub fn field_values(update: &receiver::Update<'_>) -> Vec<FieldValue> {
+    update
+        .iter()
+        .map(|(field, value)| FieldValue {
+            id: field.id.to_owned(),
+            group: field.group.to_owned(),
+            name: field.name.to_owned(),
+            unit: field.unit.to_owned(),
+            value: value.is_finite().then_some(value),
+        })
+        .collect()
+}
+
+/// An update, as encoded in JSON
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Update<'a> {
+    pub schema_version: u32,
+    pub serial: Cow<'a, str>,
+    /// Nanoseconds since UNIX epoch
+    pub timestamp: i64,
+    /// Position of the update among those from the same source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    /// Seconds since the previous update, if there was a gap in the data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gap_seconds: Option<f64>,
+    pub fields: Cow<'a, [FieldValue]>,
+}
+
+impl<'a> Update<'a> {
+    pub fn new(serial: &'a str, timestamp: i64, fields: &'a [FieldValue]) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            serial: Cow::Borrowed(serial),
+            timestamp,
+            sequence: None,
+            gap_seconds: None,
+            fields: Cow::Borrowed(fields),
+        }
+    }
+
+    /// Decode an update, checking that it has a version this code
+    /// understands
+    pub fn decode(text: &str) -> Result<Update<'static>, Box<dyn std::error::Error>> {
+        let update: Update<'static> = serde_json::from_str(text)?;
+        if update.schema_version != SCHEMA_VERSION {
+            return Err(format!(
+                "Unsupported schema version {} (expected {SCHEMA_VERSION})",
+                update.schema_version
+            )
+            .into());
+        }
+        Ok(update)
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+impl From<&receiver::Update<'_>> for Update<'static> {
+    fn from(update: &receiver::Update<'_>) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            serial: Cow::Owned(update.serial.clone()),
+            timestamp: update.timestamp,
+            sequence: update.metadata.sequence,
+            gap_seconds: update.metadata.gap.map(|gap| gap.as_secs_f64()),
+            fields: Cow::Owned(field_values(update)),
+        }
+    }
+}
+
+/// JSON Schema describing an encoded update
+pub fn schema() -> serde_json::Value {
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "sunsniff update",
+        "description": "Values of the fields of an inverter at one time. Consumers must ignore properties that are not described here.",
+        "type": "object",
+        "required": ["schema_version", "serial", "timestamp", "fields"],
+        "properties": {
+            "schema_version": {
+                "description": "Incremented when a property is removed, renamed or changes type or meaning",
+                "const": SCHEMA_VERSION
+            },
+            "serial": {
+                "description": "Serial number of the inverter",
+                "type": "string"
+            },
+            "timestamp": {
+                "description": "Nanoseconds since the UNIX epoch",
+                "type": "integer"
+            },
+            "sequence": {
+                "description": "Position of the update among those from the same source, counting from 1 when sunsniff starts. Gaps indicate updates that were dropped.",
+                "type": "integer",
+                "minimum": 1
+            },
+            "gap_seconds": {
+                "description": "Seconds since the previous update for the inverter, present only if updates stopped for long enough to count as a gap in the data (see the `gap_intervals` pipeline option)",
+                "type": "number"
+            },
+            "fields": {
+                "type": "array",
+                "items": {"$ref": "#/$defs/field_value"}
+            }
+        },
+        "$defs": {
+            "field_value": {
+                "type": "object",
+                "required": ["id", "group", "name", "unit", "value"],
+                "properties": {
+                    "id": {
+                        "description": "Identifier of the field, as listed by `sunsniff fields`",
+                        "type": "string"
+                    },
+                    "group": {"type": "string"},
+                    "name": {"type": "string"},
+                    "unit": {"type": "string"},
+                    "value": {
+                        "description": "Null if the value is not available or not finite",
+                        "type": ["number", "null"]
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Text of the schema file
+pub fn schema_text() -> String {
+    serde_json::to_string_pretty(&schema()).unwrap() + "\n"
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType, Reset, WordOrder};
+
+    static FIELDS: [Field; 1] = [Field {
+        field_type: FieldType::Power,
+        group: "PV",
+        name: "Power",
+        id: "pv_power",
+        scale: 1.0,
+        bias: 0.0,
+        unit: "W",
+        sum_of: &[],
+        word_order: WordOrder::Little,
+        reset: Reset::Never,
+    }];
+
+    #[test]
+    fn test_round_trip() {
+        let update = receiver::Update::new(1000, "1234", &FIELDS, vec![f64::NAN]);
+        let text = Update::from(&update).encode();
+        assert_eq!(
+            text,
+            r#"{"schema_version":1,"serial":"1234","timestamp":1000,"fields":[{"id":"pv_power","group":"PV","name":"Power","unit":"W","value":null}]}"#
+        );
+        let decoded = Update::decode(&text).unwrap();
+        assert_eq!(decoded.serial, "1234");
+        assert_eq!(decoded.fields[0].value, None);
+    }
+
+    #[test]
+    fn test_decode_version() {
+        let text = r#"{"schema_version":2,"serial":"1234","timestamp":1000,"fields":[]}"#;
+        assert!(Update::decode(text).is_err());
+        // Unknown properties are ignored
+        let text = r#"{"schema_version":1,"serial":"1234","timestamp":1000,"fields":[],"extra":1}"#;
+        assert!(Update::decode(text).is_ok());
+    }
+
+    #[test]
+    fn test_schema_matches_encoding() {
+        let update = receiver::Update::new(1000, "1234", &FIELDS, vec![1.0]);
+        let encoded = serde_json::to_value(Update::from(&update)).unwrap();
+        let schema = schema();
+        let required = |schema: &serde_json::Value| {
+            let mut names: Vec<String> = schema["required"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|name| name.as_str().unwrap().to_owned())
+                .collect();
+            names.sort();
+            names
+        };
+        let keys = |value: &serde_json::Value| {
+            let mut keys: Vec<String> = value.as_object().unwrap().keys().cloned().collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(keys(&encoded), required(&schema));
+        assert_eq!(
+            keys(&encoded["fields"][0]),
+            required(&schema["$defs"]["field_value"])
+        );
+    }
+
+    #[test]
+    fn test_schema_file() {
+        assert!(
+            include_str!("../schema/update.schema.json") == schema_text(),
+            "schema/update.schema.json is out of date: regenerate it with `sunsniff schema`"
+        );
+    }
+}
@@ -16,16 +16,66 @@
 
 #![doc = include_str!("../README.md")]
 
-#[cfg(all(not(feature = "pcap"), not(feature = "modbus")))]
+#[cfg(all(
+    not(feature = "afpacket"),
+    not(feature = "pcap"),
+    not(feature = "modbus"),
+    not(feature = "voltronic")
+))]
 compile_error!("At least one frontend feature must be enabled");
 
-pub mod fields;
+pub use sunsniff_core::{fields, program, receiver};
+
+#[cfg(feature = "can")]
+pub mod can;
+pub mod clock;
+#[cfg(any(feature = "http", feature = "influxdb2"))]
+pub mod compression;
+pub mod control;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod discover;
+pub mod ewma;
+pub mod field_ids;
+#[cfg(any(feature = "http", feature = "socket"))]
+pub mod format;
+pub mod health;
+#[cfg(feature = "http")]
+pub mod http;
 #[cfg(feature = "influxdb2")]
 pub mod influxdb2;
+#[cfg(feature = "journal")]
+pub mod journal;
+#[cfg(any(feature = "http", feature = "journal", feature = "socket"))]
+pub mod json;
+pub mod logging;
 #[cfg(feature = "modbus")]
 pub mod modbus;
+pub mod monitor;
 #[cfg(feature = "mqtt")]
 pub mod mqtt;
-#[cfg(feature = "pcap")]
+#[cfg(any(feature = "afpacket", feature = "pcap"))]
 pub mod pcap;
-pub mod receiver;
+pub mod pipeline;
+#[cfg(feature = "pylontech")]
+pub mod pylontech;
+pub mod queue;
+#[cfg(feature = "rollup")]
+pub mod rollup;
+pub mod routing;
+pub mod secrets;
+#[cfg(feature = "socket")]
+pub mod socket;
+pub mod stats;
+#[cfg(feature = "socket")]
+pub mod tail;
+pub mod timestamps;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod units;
+#[cfg(any(feature = "pcap", feature = "socket"))]
+pub mod unix;
+#[cfg(feature = "voltronic")]
+pub mod voltronic;
+pub mod wizard;
+pub mod workers;
@@ -0,0 +1,202 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Installation of the logger, with throttling of repeated warnings and
+//! errors. When a backend is unreachable it may log the same failure every
+//! few seconds; rather than filling the log, a message that is repeated
+//! within a minute of first being logged is only counted, and at the end of
+//! the minute a summary of the count is logged.
+
+use log::{Level, Log, Metadata, Record};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long repeats of a message are suppressed for
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Identifies repeats of a message
+#[derive(PartialEq, Eq, Hash)]
+struct Key {
+    level: Level,
+    target: String,
+    message: String,
+}
+
+/// Wraps a logger to throttle repeated warnings and errors
+pub struct Throttle<L> {
+    inner: L,
+    /// When each recent message was first logged, and how many times it
+    /// has been repeated since
+    recent: Mutex<HashMap<Key, (Instant, u64)>>,
+}
+
+impl<L: Log> Throttle<L> {
+    pub fn new(inner: L) -> Self {
+        Self {
+            inner,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Log the summaries of the messages whose windows have ended
+    fn expire(&self, recent: &mut HashMap<Key, (Instant, u64)>, now: Instant) {
+        recent.retain(|key, (start, repeats)| {
+            if now.duration_since(*start) < WINDOW {
+                return true;
+            }
+            if *repeats > 0 {
+                let times = if *repeats == 1 { "time" } else { "times" };
+                self.inner.log(
+                    &Record::builder()
+                        .level(key.level)
+                        .target(&key.target)
+                        .args(format_args!(
+                            "{} (repeated {repeats} {times} in the last minute)",
+                            key.message
+                        ))
+                        .build(),
+                );
+            }
+            false
+        });
+    }
+
+    fn log_at(&self, record: &Record<'_>, now: Instant) {
+        if !self.inner.enabled(record.metadata()) {
+            return;
+        }
+        // Other messages give a chance to log the summaries promptly
+        let mut recent = self.recent.lock().unwrap();
+        self.expire(&mut recent, now);
+        // Only warnings and errors are repeated often enough to matter
+        if record.level() > Level::Warn {
+            self.inner.log(record);
+            return;
+        }
+        let key = Key {
+            level: record.level(),
+            target: record.target().to_owned(),
+            message: record.args().to_string(),
+        };
+        match recent.get_mut(&key) {
+            Some((_, repeats)) => *repeats += 1,
+            None => {
+                recent.insert(key, (now, 0));
+                self.inner.log(record);
+            }
+        }
+    }
+}
+
+impl<L: Log> Log for Throttle<L> {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        self.log_at(record, Instant::now());
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Install the logger built by `builder`, with throttling
+pub fn init(builder: &mut env_logger::Builder) {
+    let logger = builder.build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(Throttle::new(logger))).expect("logger already installed");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Logger that records the messages
+    #[derive(Default, Clone)]
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    impl Log for Capture {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.level() <= Level::Info
+        }
+
+        fn log(&self, record: &Record<'_>) {
+            let line = format!("{} {}", record.level(), record.args());
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_throttle() {
+        let capture = Capture::default();
+        let throttle = Throttle::new(capture.clone());
+        let start = Instant::now();
+        let log = |level, message: &str, secs| {
+            throttle.log_at(
+                &Record::builder()
+                    .level(level)
+                    .args(format_args!("{message}"))
+                    .build(),
+                start + Duration::from_secs(secs),
+            );
+        };
+        for i in 0..12 {
+            log(Level::Warn, "Influxdb is unreachable", i * 5);
+            // Other messages are independent
+            log(Level::Error, "Influxdb is unreachable", i * 5);
+            // Informational messages are not throttled
+            log(Level::Info, "Received a set of values", i * 5);
+            // Nor are disabled messages counted
+            log(Level::Debug, "Polling", i * 5);
+        }
+        log(Level::Warn, "Influxdb is unreachable", 60);
+        log(Level::Warn, "Influxdb is unreachable", 65);
+        // An isolated message expires without a summary
+        log(Level::Warn, "Something else", 70);
+        log(Level::Warn, "Influxdb is unreachable", 130);
+
+        let lines = capture.0.lock().unwrap();
+        let count = |line: &str| lines.iter().filter(|l| *l == line).count();
+        assert_eq!(count("INFO Received a set of values"), 12);
+        assert!(!lines.iter().any(|line| line.starts_with("DEBUG")));
+        let warnings: Vec<&str> = lines
+            .iter()
+            .filter(|line| line.starts_with("WARN"))
+            .map(|line| line.as_str())
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                "WARN Influxdb is unreachable",
+                "WARN Influxdb is unreachable (repeated 11 times in the last minute)",
+                "WARN Influxdb is unreachable",
+                "WARN Something else",
+                "WARN Influxdb is unreachable (repeated 1 time in the last minute)",
+                "WARN Influxdb is unreachable",
+            ]
+        );
+        assert_eq!(
+            count("ERROR Influxdb is unreachable (repeated 11 times in the last minute)"),
+            1
+        );
+    }
+}
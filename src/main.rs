@@ -14,39 +14,163 @@
  * with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
-use clap::Parser;
-use futures::channel::mpsc::UnboundedSender;
+use clap::{Parser, Subcommand};
 use futures::prelude::*;
 use futures::stream::FuturesUnordered;
 use futures::try_join;
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+#[cfg(all(feature = "journal", feature = "influxdb2"))]
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
+use sunsniff::control::{CommandReceiver, CommandSender};
+#[cfg(feature = "dbus")]
+use sunsniff::dbus::DbusReceiver;
+use sunsniff::field_ids::{KnownFields, UnknownFields};
+#[cfg(feature = "http")]
+use sunsniff::http::HttpReceiver;
 #[cfg(feature = "influxdb2")]
 use sunsniff::influxdb2::Influxdb2Receiver;
+#[cfg(all(feature = "journal", feature = "influxdb2"))]
+use sunsniff::journal::JournalReader;
+#[cfg(feature = "journal")]
+use sunsniff::journal::JournalReceiver;
 #[cfg(feature = "modbus")]
 use sunsniff::modbus::ModbusConfig;
+use sunsniff::monitor::Monitor;
 #[cfg(feature = "mqtt")]
 use sunsniff::mqtt::MqttReceiver;
-#[cfg(feature = "pcap")]
+#[cfg(any(feature = "afpacket", feature = "pcap"))]
 use sunsniff::pcap::PcapConfig;
-use sunsniff::receiver::{Receiver, Update, UpdateItem};
+use sunsniff::pipeline::{FieldOverride, Pipeline};
+#[cfg(feature = "pylontech")]
+use sunsniff::pylontech::PylontechConfig;
+use sunsniff::receiver::{Receiver, Update, UpdateItem, UpdateStream};
+#[cfg(feature = "rollup")]
+use sunsniff::rollup::RollupReceiver;
+use sunsniff::routing::Serials;
+#[cfg(feature = "socket")]
+use sunsniff::socket::SocketReceiver;
+use sunsniff::timestamps::{Restamper, Timestamps};
+#[cfg(feature = "tui")]
+use sunsniff::tui::{LogBuffer, TuiReceiver};
+use sunsniff::units::Converter;
+#[cfg(feature = "voltronic")]
+use sunsniff::voltronic::VoltronicConfig;
+use sunsniff::wizard::Prompter;
+use sunsniff::workers::{self, Worker};
 
 #[derive(Debug, Parser)]
-#[clap(author, version)]
+#[clap(
+    author,
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    #[clap()]
-    config_file: PathBuf,
+    #[clap(required = true)]
+    config_file: Option<PathBuf>,
+    /// Before starting, wait up to this many seconds for an update from each
+    /// source and check that each backend accepts one, then report the result
+    #[clap(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    self_test: Option<Duration>,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Deserialize)]
-#[serde(rename_all = "snake_case")]
-enum InputConfig {
+/// Parse a command-line duration given in seconds
+fn parse_seconds(text: &str) -> Result<Duration, String> {
+    let seconds: f64 = text.parse().map_err(|err| format!("{err}"))?;
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| format!("must be a finite, non-negative number of seconds, not {seconds}"))
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Print a Markdown table describing all the supported fields
+    Fields,
+    /// Print the JSON Schema of the updates sent by the HTTP, socket and
+    /// journal backends
+    #[cfg(any(feature = "http", feature = "journal", feature = "socket"))]
+    Schema,
+    /// Interactively create a configuration file
+    Init {
+        /// File to write
+        #[clap(default_value = "config.toml")]
+        path: PathBuf,
+    },
+    /// Find WiFi dongles on the local network
+    Discover {
+        /// Time (in seconds) to wait for responses
+        #[clap(long, default_value = "3", value_parser = parse_seconds)]
+        timeout: Duration,
+    },
+    /// Send the updates in a journal to one of the configured backends
+    #[cfg(all(feature = "journal", feature = "influxdb2"))]
+    ReplayJournal {
+        /// Journal file to read
+        journal: PathBuf,
+        /// Configuration file containing the backend
+        #[clap(long)]
+        config: PathBuf,
+        /// Backend to send to, in the form influxdb2:<name>
+        #[clap(long)]
+        to: String,
+    },
+    /// Capture packets and forward them to a sunsniff process with `socket`
+    /// set in its `[pcap]` section
     #[cfg(feature = "pcap")]
-    Pcap(PcapConfig),
-    #[cfg(feature = "modbus")]
-    Modbus(ModbusConfig),
+    Capture {
+        /// Network device to capture
+        device: String,
+        /// Additional pcap filter expression
+        #[clap(long)]
+        filter: Option<String>,
+        /// Unix socket on which the main process is listening
+        #[clap(long)]
+        socket: PathBuf,
+        /// Switch to this user (and their primary group) once the device is
+        /// open
+        #[clap(long)]
+        user: Option<String>,
+        /// Switch to this group once the device is open
+        #[clap(long)]
+        group: Option<String>,
+    },
+    /// Replace the serial number in a frame (in hex, as recorded by the
+    /// `frames` option) so that it can be added to the test corpus
+    #[cfg(any(feature = "afpacket", feature = "pcap"))]
+    AnonymiseFrame {
+        /// File containing the frame (default: stdin)
+        file: Option<PathBuf>,
+    },
+    /// Encrypt a value (read from stdin) for use in the configuration file
+    #[cfg(feature = "secrets")]
+    Encrypt {
+        /// Public key (from age-keygen) to encrypt with
+        #[clap(long)]
+        recipient: String,
+    },
+    /// Print changes in values, received from a running sunsniff's `[socket]`
+    /// backend
+    #[cfg(feature = "socket")]
+    Tail {
+        /// Path of the socket
+        #[clap(long)]
+        socket: PathBuf,
+        /// Comma-separated IDs of the fields to show (default: all)
+        #[clap(long, value_delimiter = ',')]
+        fields: Vec<String>,
+    },
+    /// Show live values and the state of the backends in the terminal
+    #[cfg(feature = "tui")]
+    Tui {
+        /// Configuration file
+        config: PathBuf,
+    },
 }
 
 /// Structure corresponding to the configuration file. It is constructured
@@ -54,71 +178,773 @@ enum InputConfig {
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 struct Config {
-    #[serde(flatten)]
-    input: InputConfig,
+    #[cfg(any(feature = "afpacket", feature = "pcap"))]
+    pcap: Option<Box<PcapConfig>>,
+    #[cfg(feature = "modbus")]
+    modbus: Option<ModbusConfig>,
+    #[cfg(feature = "voltronic")]
+    voltronic: Option<VoltronicConfig>,
+    #[serde(default)]
+    pipeline: sunsniff::pipeline::Config,
+    #[serde(default)]
+    field_overrides: HashMap<String, FieldOverride>,
+    control: Option<sunsniff::control::guard::Config>,
+    #[serde(default)]
+    monitor: sunsniff::monitor::Config,
+    #[serde(default)]
+    queue: sunsniff::queue::Config,
+    #[serde(default)]
+    read_only: bool,
+    #[cfg(feature = "http")]
+    http: Option<sunsniff::http::Config>,
     #[cfg(feature = "influxdb2")]
     #[serde(default)]
     influxdb2: Vec<sunsniff::influxdb2::Config>,
     #[cfg(feature = "mqtt")]
     #[serde(default)]
     mqtt: Vec<sunsniff::mqtt::Config>,
+    #[cfg(feature = "pylontech")]
+    pylontech: Option<PylontechConfig>,
+    #[cfg(feature = "can")]
+    can: Option<sunsniff::can::CanConfig>,
+    #[cfg(feature = "journal")]
+    journal: Option<sunsniff::journal::Config>,
+    #[cfg(feature = "rollup")]
+    rollup: Option<sunsniff::rollup::Config>,
+    #[cfg(feature = "socket")]
+    socket: Option<sunsniff::socket::Config>,
+    #[cfg(feature = "dbus")]
+    dbus: Option<sunsniff::dbus::Config>,
+    #[cfg(feature = "webhook")]
+    webhook: Option<sunsniff::health::WebhookConfig>,
+}
+
+impl Config {
+    /// Whether commands (such as changing settings) are disabled, either by
+    /// the `read_only` feature or the configuration
+    fn read_only(&self) -> bool {
+        cfg!(feature = "read_only") || self.read_only
+    }
+
+    /// Whether any receiver is configured to accept changes to settings
+    fn accepts_writes(&self) -> bool {
+        #[allow(unused_mut)]
+        let mut writes = false;
+        #[cfg(feature = "http")]
+        {
+            writes |= self.http.as_ref().is_some_and(|http| http.token.is_some());
+        }
+        #[cfg(feature = "mqtt")]
+        {
+            writes |= self.mqtt.iter().any(|mqtt| mqtt.settings);
+        }
+        writes
+    }
+
+    /// Number of frontends (inverter sources) that are configured
+    fn frontends(&self) -> usize {
+        #[allow(unused_mut)]
+        let mut frontends = 0;
+        #[cfg(any(feature = "afpacket", feature = "pcap"))]
+        {
+            frontends += usize::from(self.pcap.is_some());
+        }
+        #[cfg(feature = "modbus")]
+        {
+            frontends += usize::from(self.modbus.is_some());
+        }
+        #[cfg(feature = "voltronic")]
+        {
+            frontends += usize::from(self.voltronic.is_some());
+        }
+        frontends
+    }
+
+    /// Check that the configuration does not ask for commands if it is
+    /// read-only
+    fn check_read_only(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.read_only() {
+            return Ok(());
+        }
+        #[cfg(feature = "mqtt")]
+        if self.mqtt.iter().any(|mqtt| mqtt.command_prefix.is_some()) {
+            return Err("MQTT command_prefix cannot be used in read-only mode".into());
+        }
+        Ok(())
+    }
+
+    /// Check that the field IDs named in the configuration exist
+    fn check_field_ids(&self) -> Result<(), UnknownFields> {
+        let known = KnownFields::default();
+        #[cfg(feature = "voltronic")]
+        let known = known.with_fields(sunsniff::voltronic::FIELDS);
+        // Pylontech fields depend on the number of packs that are found
+        #[cfg(feature = "pylontech")]
+        let known = match self.pylontech {
+            Some(_) => known.with_prefix("bms_pack_"),
+            None => known,
+        };
+        known.check(
+            "[field_overrides]",
+            self.field_overrides.keys().map(String::as_str),
+        )?;
+        known.check(
+            "[pipeline.binary_fields]",
+            self.pipeline
+                .binary_fields
+                .values()
+                .map(|binary| binary.field.as_str()),
+        )
+    }
+}
+
+fn load_config(path: &Path) -> Result<Config, Box<dyn std::error::Error>> {
+    let config = std::fs::read_to_string(path)?;
+    let mut config: toml::Value = toml::from_str(&config)?;
+    sunsniff::secrets::decrypt(&mut config)?;
+    let config = Config::deserialize(config)?;
+    config.check_read_only()?;
+    config.pipeline.check()?;
+    config.monitor.check()?;
+    config.queue.check()?;
+    #[cfg(feature = "modbus")]
+    if let Some(modbus) = &config.modbus {
+        modbus.check()?;
+    }
+    #[cfg(feature = "influxdb2")]
+    for influxdb2 in config.influxdb2.iter() {
+        influxdb2.check()?;
+    }
+    #[cfg(feature = "mqtt")]
+    for mqtt in config.mqtt.iter() {
+        mqtt.check()?;
+    }
+    config.check_field_ids()?;
+    Ok(config)
+}
+
+/// Implementation of the `discover` subcommand
+fn discover(timeout: Duration) -> std::io::Result<()> {
+    let loggers = sunsniff::discover::discover(timeout)?;
+    if loggers.is_empty() {
+        println!("No dongles found");
+    }
+    for logger in loggers.iter() {
+        println!(
+            "Found dongle at {} (serial {}, MAC {})",
+            logger.ip, logger.serial, logger.mac
+        );
+        println!("  To capture it with the pcap frontend, use:");
+        println!("    filter = \"src host {}\"", logger.ip);
+    }
+    Ok(())
+}
+
+/// Implementation of the `init` subcommand
+async fn init(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if path.exists() {
+        return Err(format!("{} already exists", path.display()).into());
+    }
+    let mut prompter = Prompter::new(std::io::stdin().lock(), std::io::stdout());
+    let text = sunsniff::wizard::run(&mut prompter)?;
+    prompter.say("Testing the configuration...")?;
+    let result = async {
+        let config: Config = toml::from_str(&text)?;
+        let (command_sender, command_receiver) = sunsniff::control::channel();
+        create_receivers(&config, command_sender).await?;
+        // Only check that the frontend can be created, without polling it
+        drop(create_stream(&config, command_receiver).await?);
+        Ok::<_, Box<dyn std::error::Error>>(())
+    }
+    .await;
+    if let Err(err) = result {
+        prompter.say(&format!("The configuration did not work: {err}"))?;
+        if !prompter.ask_bool("Write it anyway?", false)? {
+            return Ok(());
+        }
+    }
+    std::fs::write(path, text)?;
+    prompter.say(&format!("Wrote {}", path.display()))?;
+    Ok(())
+}
+
+/// Number of updates read ahead of the backend when replaying a journal
+#[cfg(all(feature = "journal", feature = "influxdb2"))]
+const REPLAY_QUEUE: usize = 1000;
+
+/// Implementation of the `replay-journal` subcommand
+#[cfg(all(feature = "journal", feature = "influxdb2"))]
+async fn replay_journal(
+    journal: &Path,
+    config: &Path,
+    to: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(config)?;
+    // Influxdb2 is currently the only backend that keeps history
+    let name = to
+        .strip_prefix("influxdb2:")
+        .ok_or("--to must have the form influxdb2:<name>")?;
+    let backend = config
+        .influxdb2
+        .iter()
+        .find(|backend| backend.name() == name)
+        .ok_or_else(|| format!("No influxdb2 backend called {name:?}"))?;
+    if backend.timestamps == Timestamps::Ingestion {
+        // The journal only has the inverter's timestamps
+        log::warn!("Replaying with the timestamps from the journal, not the ingestion time");
+    }
+    let mut receiver = Influxdb2Receiver::new(backend).await?;
+    let mut converter = Converter::new(&backend.units)?;
+    let reader = JournalReader::new(BufReader::new(std::fs::File::open(journal)?));
+    // Bounded, so that the journal is read no faster than it is written
+    let (mut sink, stream) = futures::channel::mpsc::channel(REPLAY_QUEUE);
+    let send = async move {
+        for update in reader {
+            sink.send(converter.convert(&Arc::new(update?))).await?;
+        }
+        sink.close().await?;
+        Ok::<_, Box<dyn std::error::Error>>(())
+    };
+    try_join!(send, receiver.run(Box::pin(stream)).map(Ok))?;
+    Ok(())
+}
+
+/// A receiver, with a name for log messages, the converter to its preferred
+/// units, the serial numbers to route to it and the timestamps it writes.
+/// If the receiver is one of several workers, it runs on its own thread and
+/// only receives the updates from its share of the inverters.
+struct Backend {
+    name: String,
+    receiver: Box<dyn Receiver>,
+    converter: Converter,
+    serials: Serials,
+    timestamps: Timestamps,
+    worker: Option<Worker>,
+}
+
+impl Backend {
+    /// Whether updates from an inverter are routed to this receiver
+    fn matches(&self, serial: &str) -> bool {
+        self.serials.matches(serial) && self.worker.is_none_or(|worker| worker.matches(serial))
+    }
+}
+
+/// Create the receivers (backends) described by the configuration. Each
+/// receiver that can send commands is given a clone of `command_sender`.
+async fn create_receivers(
+    config: &Config,
+    command_sender: CommandSender,
+) -> Result<Vec<Backend>, Box<dyn std::error::Error>> {
+    let mut receivers: Vec<Backend> = vec![];
+    #[cfg(feature = "influxdb2")]
+    {
+        for backend in config.influxdb2.iter() {
+            if backend.workers.get() > 1 && backend.offline_first.is_some() {
+                return Err(
+                    "Influxdb2 offline_first cannot be used with more than one worker".into(),
+                );
+            }
+            if !backend.raw && backend.rollups.is_empty() {
+                return Err(format!(
+                    "Influxdb2 backend {} has raw = false but no rollups",
+                    backend.name()
+                )
+                .into());
+            }
+            let name = format!("influxdb2:{}", backend.name());
+            for worker in Worker::all(backend.workers) {
+                receivers.push(Backend {
+                    name: worker.map_or_else(|| name.clone(), |worker| worker.name(&name)),
+                    receiver: Box::new(Influxdb2Receiver::new(backend).await?),
+                    converter: Converter::new(&backend.units)?,
+                    serials: backend.serials.clone(),
+                    timestamps: backend.timestamps,
+                    worker,
+                });
+            }
+        }
+    }
+    #[cfg(feature = "mqtt")]
+    {
+        for backend in config.mqtt.iter().flat_map(|mqtt| mqtt.split_sites()) {
+            receivers.push(Backend {
+                name: format!("mqtt:{}", backend.name()),
+                receiver: Box::new(MqttReceiver::new(&backend, command_sender.clone())?),
+                converter: Converter::new(&backend.units)?,
+                serials: backend.serials.clone(),
+                timestamps: backend.timestamps,
+                worker: None,
+            });
+        }
+    }
+    #[cfg(feature = "journal")]
+    {
+        // The journal records the values as they are, so that they can be
+        // replayed into backends with any preferred units.
+        if let Some(journal_config) = &config.journal {
+            receivers.push(Backend {
+                name: "journal".to_owned(),
+                receiver: Box::new(JournalReceiver::new(journal_config)?),
+                converter: Converter::default(),
+                serials: Serials::default(),
+                timestamps: Timestamps::default(),
+                worker: None,
+            });
+        }
+    }
+    #[cfg(feature = "rollup")]
+    {
+        if let Some(rollup_config) = &config.rollup {
+            receivers.push(Backend {
+                name: "rollup".to_owned(),
+                receiver: Box::new(RollupReceiver::new(rollup_config)?),
+                converter: Converter::new(&rollup_config.units)?,
+                serials: rollup_config.serials.clone(),
+                timestamps: rollup_config.timestamps,
+                worker: None,
+            });
+        }
+    }
+    #[cfg(feature = "http")]
+    {
+        if let Some(http_config) = &config.http {
+            receivers.push(Backend {
+                name: "http".to_owned(),
+                receiver: Box::new(HttpReceiver::new(http_config, command_sender.clone()).await?),
+                converter: Converter::new(&http_config.units)?,
+                serials: http_config.serials.clone(),
+                timestamps: http_config.timestamps,
+                worker: None,
+            });
+        }
+    }
+    #[cfg(feature = "socket")]
+    {
+        if let Some(socket_config) = &config.socket {
+            receivers.push(Backend {
+                name: "socket".to_owned(),
+                receiver: Box::new(SocketReceiver::new(socket_config)?),
+                converter: Converter::new(&socket_config.units)?,
+                serials: socket_config.serials.clone(),
+                timestamps: socket_config.timestamps,
+                worker: None,
+            });
+        }
+    }
+    #[cfg(feature = "dbus")]
+    {
+        if let Some(dbus_config) = &config.dbus {
+            receivers.push(Backend {
+                name: "dbus".to_owned(),
+                receiver: Box::new(DbusReceiver::new(dbus_config).await?),
+                converter: Converter::new(&dbus_config.units)?,
+                serials: dbus_config.serials.clone(),
+                timestamps: dbus_config.timestamps,
+                worker: None,
+            });
+        }
+    }
+    // Only the receivers hold senders, so that the frontend sees the channel
+    // close when they have all finished.
+    drop(command_sender);
+    Ok(receivers)
+}
+
+/// Create the stream of updates from the frontends. If there is more than
+/// one, their updates are merged as they arrive, each frontend is sent all
+/// the commands (and acts on the ones for its own inverters), and the
+/// stream ends once they have all ended.
+async fn create_stream(
+    config: &Config,
+    command_receiver: CommandReceiver,
+) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+    #[allow(unused_mut, unused_variables)]
+    let mut command_receivers = match config.frontends() {
+        0 => return Err("No frontend is configured ([pcap], [modbus] or [voltronic])".into()),
+        1 => vec![command_receiver],
+        frontends => {
+            let (senders, receivers) = (0..frontends).map(|_| sunsniff::control::channel()).unzip();
+            tokio::spawn(sunsniff::control::broadcast(command_receiver, senders));
+            receivers
+        }
+    };
+    #[allow(unused_mut)]
+    let mut streams: Vec<UpdateStream> = vec![];
+    #[cfg(any(feature = "afpacket", feature = "pcap"))]
+    if let Some(pcap_config) = &config.pcap {
+        let commands = command_receivers.pop().unwrap();
+        let clock = Arc::new(sunsniff::clock::SystemClock);
+        streams.push(sunsniff::pcap::create_stream(pcap_config, commands, clock)?);
+    }
+    #[cfg(feature = "modbus")]
+    if let Some(modbus_config) = &config.modbus {
+        let commands = command_receivers.pop().unwrap();
+        let clock = Arc::new(sunsniff::clock::SystemClock);
+        streams.push(sunsniff::modbus::create_stream(modbus_config, commands, clock).await?);
+    }
+    #[cfg(feature = "voltronic")]
+    if let Some(voltronic_config) = &config.voltronic {
+        let commands = command_receivers.pop().unwrap();
+        streams.push(sunsniff::voltronic::create_stream(voltronic_config, commands).await?);
+    }
+    let stream = if streams.len() == 1 {
+        streams.pop().unwrap()
+    } else {
+        Box::pin(stream::select_all(streams))
+    };
+    #[cfg(feature = "pylontech")]
+    let stream = match &config.pylontech {
+        Some(pylontech_config) => sunsniff::receiver::merge_auxiliary(
+            stream,
+            sunsniff::pylontech::create_stream(pylontech_config).await?,
+        ),
+        None => stream,
+    };
+    #[cfg(feature = "can")]
+    let stream = match &config.can {
+        Some(can_config) => sunsniff::receiver::merge_auxiliary(
+            stream,
+            sunsniff::can::create_stream(can_config).await?,
+        ),
+        None => stream,
+    };
+    Ok(stream)
+}
+
+/// Queue of updates for a receiver
+struct Sink {
+    sender: sunsniff::queue::Sender<Arc<Update<'static>>>,
+    /// Converts values to the receiver's preferred units
+    converter: Converter,
+    /// Watches how quickly the receiver processes the updates
+    monitor: Arc<Monitor>,
+    /// Serial numbers to route to the receiver
+    serials: Serials,
+    /// Share of the inverters routed to the receiver, if it is a worker
+    worker: Option<Worker>,
+    /// Replaces the timestamps with the ingestion time, if configured
+    restamper: Restamper,
+}
+
+/// Number of sources that updates are merged from
+fn count_sources(config: &Config) -> usize {
+    #[allow(unused_mut)]
+    let mut sources = config.frontends();
+    // Each inverter polled over modbus is a separate source
+    #[cfg(feature = "modbus")]
+    if let Some(modbus) = &config.modbus {
+        sources += modbus.inverters() - 1;
+    }
+    #[cfg(feature = "pylontech")]
+    {
+        sources += usize::from(config.pylontech.is_some());
+    }
+    #[cfg(feature = "can")]
+    {
+        sources += usize::from(config.can.is_some());
+    }
+    sources
+}
+
+/// Implementation of `--self-test`. Wait for an update from each of
+/// `sources` sources, then hand one to each backend and print a summary.
+/// Each step is allowed `timeout`.
+async fn self_test(
+    stream: &mut (dyn Stream<Item = UpdateItem> + Unpin),
+    backends: &mut [(Backend, Arc<Monitor>)],
+    sources: usize,
+    timeout: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut updates: Vec<UpdateItem> = vec![];
+    let mut seen = HashSet::new();
+    let deadline = tokio::time::Instant::now() + timeout;
+    while seen.len() < sources {
+        match tokio::time::timeout_at(deadline, stream.next()).await {
+            Ok(Some(update)) => {
+                seen.insert(update.metadata.source.clone());
+                updates.push(update);
+            }
+            Ok(None) | Err(_) => break,
+        }
+    }
+    let mut failed = 0;
+    for source in seen.iter() {
+        println!("PASS {}", source.as_deref().unwrap_or("input"));
+    }
+    if seen.len() < sources {
+        println!(
+            "FAIL input: only {} of {sources} sources sent an update",
+            seen.len()
+        );
+        failed += 1;
+    }
+    for (backend, _) in backends.iter_mut() {
+        let Some(update) = updates
+            .iter()
+            .find(|update| backend.matches(&update.serial))
+        else {
+            println!("SKIP {}: no update is routed to it", backend.name);
+            continue;
+        };
+        let update = backend.converter.convert(update);
+        let update = Restamper::new(backend.timestamps).restamp(&update);
+        let result = tokio::time::timeout(timeout, backend.receiver.self_test(update)).await;
+        match result {
+            Ok(Ok(())) => println!("PASS {}", backend.name),
+            Ok(Err(err)) => {
+                println!("FAIL {}: {err}", backend.name);
+                failed += 1;
+            }
+            Err(_) => {
+                println!("FAIL {}: timed out", backend.name);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("Self-test failed ({failed} failures)").into());
+    }
+    Ok(())
 }
 
 /// Top-level execution. Receive updates from a stream and distribute them to
-/// multiple receivers.
+/// the receivers they are routed to, converting them to each receiver's
+/// preferred units.
 async fn run(
     stream: &mut (dyn Stream<Item = UpdateItem> + Unpin),
-    sinks: &mut [UnboundedSender<Arc<Update<'static>>>],
+    sinks: &mut [Sink],
 ) -> Result<(), Box<dyn std::error::Error>> {
     while let Some(update) = stream.next().await {
         for sink in sinks.iter_mut() {
-            sink.unbounded_send(Arc::clone(&update))?;
+            if sink.serials.matches(&update.serial)
+                && sink
+                    .worker
+                    .is_none_or(|worker| worker.matches(&update.serial))
+            {
+                sink.monitor.sent(&update);
+                let converted = sink.converter.convert(&update);
+                let restamped = sink.restamper.restamp(&converted);
+                if sink.sender.send(restamped).await?.is_some() {
+                    sink.monitor.dropped();
+                }
+            }
         }
     }
-    for sink in sinks.iter_mut() {
-        sink.close().await?; // TODO: do these in parallel?
+    for sink in sinks.iter() {
+        sink.sender.close();
     }
     Ok(())
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
+    sunsniff::stats::start();
     let args = Args::parse();
-    let config = std::fs::read_to_string(args.config_file)?;
-    let config: Config = toml::from_str(&config)?;
-
-    let mut receivers: Vec<Box<dyn Receiver>> = vec![];
-    #[cfg(feature = "influxdb2")]
-    {
-        for backend in config.influxdb2.iter() {
-            receivers.push(Box::new(Influxdb2Receiver::new(backend).await));
+    // The terminal interface shows log messages itself, rather than having
+    // them written over it
+    #[cfg(feature = "tui")]
+    let log = match args.command {
+        Some(Command::Tui { .. }) => Some(LogBuffer::init()),
+        _ => {
+            sunsniff::logging::init(&mut env_logger::Builder::from_default_env());
+            None
+        }
+    };
+    #[cfg(not(feature = "tui"))]
+    sunsniff::logging::init(&mut env_logger::Builder::from_default_env());
+    let config_file = match args.command {
+        Some(Command::Fields) => {
+            print!("{}", sunsniff::fields::DOCUMENTATION);
+            return Ok(());
         }
+        #[cfg(any(feature = "http", feature = "journal", feature = "socket"))]
+        Some(Command::Schema) => {
+            print!("{}", sunsniff::json::schema_text());
+            return Ok(());
+        }
+        Some(Command::Init { path }) => {
+            init(&path).await?;
+            return Ok(());
+        }
+        Some(Command::Discover { timeout }) => {
+            discover(timeout)?;
+            return Ok(());
+        }
+        #[cfg(all(feature = "journal", feature = "influxdb2"))]
+        Some(Command::ReplayJournal {
+            journal,
+            config,
+            to,
+        }) => {
+            replay_journal(&journal, &config, &to).await?;
+            return Ok(());
+        }
+        #[cfg(feature = "pcap")]
+        Some(Command::Capture {
+            device,
+            filter,
+            socket,
+            user,
+            group,
+        }) => {
+            sunsniff::pcap::capture::run(&sunsniff::pcap::capture::CaptureArgs {
+                device: &device,
+                filter: filter.as_deref(),
+                socket: &socket,
+                user: user.as_deref(),
+                group: group.as_deref(),
+            })?;
+            return Ok(());
+        }
+        #[cfg(any(feature = "afpacket", feature = "pcap"))]
+        Some(Command::AnonymiseFrame { file }) => {
+            let text = match file {
+                Some(path) => std::fs::read_to_string(path)?,
+                None => std::io::read_to_string(std::io::stdin())?,
+            };
+            print!("{}", sunsniff::pcap::corpus::anonymise_frame(&text)?);
+            return Ok(());
+        }
+        #[cfg(feature = "secrets")]
+        Some(Command::Encrypt { recipient }) => {
+            let plaintext = std::io::read_to_string(std::io::stdin())?;
+            let plaintext = plaintext.strip_suffix('\n').unwrap_or(&plaintext);
+            print!("{}", sunsniff::secrets::encrypt(&recipient, plaintext)?);
+            return Ok(());
+        }
+        #[cfg(feature = "socket")]
+        Some(Command::Tail { socket, fields }) => {
+            sunsniff::tail::run(&socket, &fields, std::io::stdout().lock()).await?;
+            return Ok(());
+        }
+        #[cfg(feature = "tui")]
+        Some(Command::Tui { config }) => config,
+        // clap ensures that the config file is given if there is no subcommand
+        None => args.config_file.unwrap(),
+    };
+    let config = load_config(&config_file)?;
+    // Identifies the build in bug reports
+    log::info!(
+        "sunsniff {} with field tables {}",
+        env!("CARGO_PKG_VERSION"),
+        sunsniff::fields::TABLE_HASH
+    );
+    #[cfg(any(feature = "http", feature = "journal", feature = "socket"))]
+    log::info!("JSON schema version {}", sunsniff::json::SCHEMA_VERSION);
+
+    #[cfg(feature = "webhook")]
+    if let Some(webhook_config) = &config.webhook {
+        sunsniff::health::spawn_webhook(webhook_config)?;
     }
-    #[cfg(feature = "mqtt")]
-    {
-        for backend in config.mqtt.iter() {
-            receivers.push(Box::new(MqttReceiver::new(backend)?));
+
+    let mut pipeline = Pipeline::new(&config.pipeline, &config.field_overrides)?;
+    let (command_sender, mut command_receiver) = sunsniff::control::channel();
+    if config.read_only() {
+        // Any attempt by a receiver to send a command will fail
+        log::info!("Running in read-only mode");
+        command_receiver.close();
+    } else if config.control.is_none() && config.accepts_writes() {
+        log::warn!(
+            "Settings can be changed but there is no [control] section, so ANY \
+             register can be written with ANY value. Add a [control] section to \
+             list the registers that may be changed, or set read_only = true."
+        );
+    }
+    let command_receiver = match &config.control {
+        Some(control_config) => {
+            let guard = sunsniff::control::guard::Guard::new(control_config)?;
+            let (sender, receiver) = sunsniff::control::channel();
+            tokio::spawn(sunsniff::control::guard::run(
+                command_receiver,
+                sender,
+                guard,
+            ));
+            receiver
         }
+        None => command_receiver,
+    };
+    // Receivers see the serial numbers output by the pipeline, so commands
+    // need to be translated back to the real serial numbers.
+    let command_receiver = match pipeline.serial_map() {
+        Some(map) => {
+            let (sender, receiver) = sunsniff::control::channel();
+            tokio::spawn(sunsniff::control::translate(
+                command_receiver,
+                sender,
+                move |serial| map.lock().unwrap().get(serial).cloned(),
+            ));
+            receiver
+        }
+        None => command_receiver,
+    };
+    let receivers = create_receivers(&config, command_sender).await?;
+    let mut backends: Vec<(Backend, Arc<Monitor>)> = receivers
+        .into_iter()
+        .map(|backend| {
+            let monitor = Monitor::new(backend.name.clone(), &config.monitor);
+            (backend, monitor)
+        })
+        .collect();
+    // Completes when the user asks to stop
+    let quit = future::pending::<()>().boxed();
+    #[cfg(feature = "tui")]
+    let quit = match log {
+        Some(log) => {
+            let monitors = backends.iter().map(|(_, monitor)| monitor.clone());
+            let (receiver, tui_quit) = TuiReceiver::new(monitors.collect(), log);
+            let backend = Backend {
+                name: "tui".to_owned(),
+                receiver: Box::new(receiver),
+                converter: Converter::default(),
+                serials: Serials::default(),
+                timestamps: Timestamps::default(),
+                worker: None,
+            };
+            backends.push((backend, Monitor::new("tui", &config.monitor)));
+            tui_quit.map(|_| ()).boxed()
+        }
+        None => quit,
+    };
+
+    // TODO: better handling of errors from receivers
+    let stream = create_stream(&config, command_receiver).await?;
+    let mut stream = stream.filter_map(move |update| {
+        sunsniff::stats::updated();
+        future::ready(pipeline.process(update))
+    });
+    if let Some(timeout) = args.self_test {
+        self_test(&mut stream, &mut backends, count_sources(&config), timeout).await?;
     }
 
+    let names: Vec<&str> = backends
+        .iter()
+        .map(|(backend, _)| backend.name.as_str())
+        .collect();
+    let unknown = config.queue.unknown(&names);
+    if !unknown.is_empty() {
+        return Err(format!("Unknown receivers in [queue.receivers]: {unknown:?}").into());
+    }
     let mut sinks = vec![];
     let futures = FuturesUnordered::new();
-    for receiver in receivers.iter_mut() {
-        let (sink, stream) = futures::channel::mpsc::unbounded();
-        futures.push(receiver.run(stream));
-        sinks.push(sink);
+    for (mut backend, monitor) in backends.into_iter() {
+        let (capacity, policy) = config.queue.settings(&backend.name);
+        let (sender, stream) = sunsniff::queue::channel(capacity, policy);
+        let stream = monitor.wrap(stream);
+        match backend.worker {
+            Some(_) => futures.push(workers::spawn(backend.name, backend.receiver, stream).boxed()),
+            None => futures.push(async move { backend.receiver.run(stream).await }.boxed()),
+        }
+        sinks.push(Sink {
+            sender,
+            converter: backend.converter,
+            monitor,
+            serials: backend.serials,
+            worker: backend.worker,
+            restamper: Restamper::new(backend.timestamps),
+        });
     }
 
-    // TODO: better handling of errors from receivers
-    let mut stream = match &config.input {
-        #[cfg(feature = "pcap")]
-        InputConfig::Pcap(pcap_config) => sunsniff::pcap::create_stream(pcap_config)?,
-        #[cfg(feature = "modbus")]
-        InputConfig::Modbus(modbus_config) => {
-            sunsniff::modbus::create_stream(modbus_config).await?
-        }
-    };
+    let mut stream = stream.take_until(quit);
     try_join!(
         run(&mut stream, &mut sinks),
         futures.collect::<Vec<_>>().map(Ok)
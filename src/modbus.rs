@@ -20,16 +20,25 @@ use log::{error, info};
 use serde::Deserialize;
 use serde_with::serde_as;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::time::MissedTickBehavior;
 use tokio_modbus::client::Context;
 use tokio_modbus::prelude::Reader;
-use tokio_modbus::slave::Slave;
+use tokio_modbus::slave::{Slave, SlaveContext};
+use tokio_modbus::ExceptionCode;
 
-use crate::receiver::{Update, UpdateStream};
+use sunsniff_core::fields::{Field, FieldType};
+use sunsniff_core::modbus::{
+    clock_seconds, field_registers, read_length, BUS_FIELDS, BUS_SUFFIX, FIELDS, READ_RANGES,
+    REG_CLOCK,
+};
 
-const REG_CLOCK: u16 = 22;
-const NUM_PROGRAMS: usize = 6;
+use crate::clock::Clock;
+use crate::control::{self, Command, CommandReceiver};
+use crate::program::ProgramFields;
+use crate::receiver::{Metadata, Update, UpdateItem, UpdateStream};
+
+pub use sunsniff_core::modbus::{setting, setting_options};
 
 /// Structure corresponding to the `[modbus]` section of the configuration file.
 #[serde_as]
@@ -41,67 +50,486 @@ pub struct ModbusConfig {
     interval: Duration,
     #[serde(default = "default_baud")]
     baud: u32,
-    #[serde(default = "default_modbus_id")]
-    modbus_id: u8,
+    modbus_id: Option<u8>,
+    /// Modbus IDs of several inverters behind one gateway, instead of
+    /// `modbus_id`
+    #[serde(default)]
+    modbus_ids: Vec<u8>,
+    #[serde(default)]
+    raw_values: bool,
+    /// Time to wait after writing a register before reading it back
+    #[cfg_attr(feature = "read_only", allow(dead_code))]
+    #[serde_as(as = "serde_with::DurationSecondsWithFrac<f64>")]
+    #[serde(default = "default_verify_delay")]
+    verify_delay: Duration,
+    /// Poll on multiples of `interval` in wall-clock time, rather than
+    /// counting from startup
+    #[serde(default)]
+    align: bool,
+    /// Poll faster while the values are changing
+    adaptive: Option<AdaptiveConfig>,
+    /// Publish the health of the connection as [BUS_FIELDS]
+    #[serde(default)]
+    bus_health: bool,
+}
+
+/// Structure corresponding to the `[modbus.adaptive]` section of the
+/// configuration file
+#[serde_as]
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AdaptiveConfig {
+    /// Interval between polls while there is activity
+    #[serde_as(as = "serde_with::DurationSecondsWithFrac<f64>")]
+    fast_interval: Duration,
+    /// Change in a power value (in W) between polls that counts as activity
+    #[serde(default = "default_power_threshold")]
+    power_threshold: f64,
+    /// Fields for which any change counts as activity
+    #[serde(default = "default_state_fields")]
+    state_fields: Vec<String>,
+    /// Time without activity after which polling slows down again
+    #[serde_as(as = "serde_with::DurationSecondsWithFrac<f64>")]
+    #[serde(default = "default_hold")]
+    hold: Duration,
 }
 
 fn default_baud() -> u32 {
     9600
 }
 
+fn default_verify_delay() -> Duration {
+    Duration::from_secs(1)
+}
+
 fn default_modbus_id() -> u8 {
     1
 }
 
-async fn read_values(
-    ctx: &mut Context,
-) -> Result<Vec<f64>, Box<dyn std::error::Error + Send + Sync>> {
-    let mut values = Vec::with_capacity(FIELDS.len());
-    let mut parts = [0u16; 2];
-    for (field, regs) in FIELDS.iter().zip(REGISTERS.iter()) {
-        let value = if !regs.is_empty() {
-            for (i, reg) in regs.iter().enumerate() {
-                // TODO: better error handling
-                parts[i] = ctx.read_holding_registers(*reg, 1).await??[0];
+impl ModbusConfig {
+    /// Check the parts of the configuration that serde cannot
+    pub fn check(&self) -> Result<(), String> {
+        if self.interval.is_zero() {
+            return Err("modbus interval must be positive".to_owned());
+        }
+        if let Some(adaptive) = &self.adaptive {
+            if adaptive.fast_interval.is_zero() {
+                return Err("modbus fast_interval must be positive".to_owned());
+            }
+        }
+        self.ids().map(|_| ())
+    }
+
+    /// Modbus IDs of the inverters to poll
+    fn ids(&self) -> Result<Vec<u8>, String> {
+        match (self.modbus_id, self.modbus_ids.as_slice()) {
+            (Some(_), [_, ..]) => Err("modbus_id and modbus_ids cannot both be given".to_owned()),
+            (id, []) => Ok(vec![id.unwrap_or_else(default_modbus_id)]),
+            (None, ids) => Ok(ids.to_vec()),
+        }
+    }
+
+    /// Number of inverters that are polled, each of which is a separate
+    /// source of updates
+    pub fn inverters(&self) -> usize {
+        self.ids().map_or(1, |ids| ids.len())
+    }
+}
+
+fn default_power_threshold() -> f64 {
+    100.0
+}
+
+fn default_state_fields() -> Vec<String> {
+    vec!["grid_connected".to_owned()]
+}
+
+fn default_hold() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Decides whether to poll fast, according to an [AdaptiveConfig]
+struct Activity {
+    power_threshold: f64,
+    /// Time without activity (in nanoseconds) before slowing down
+    hold: i64,
+    /// Indices of the power fields
+    power: Vec<usize>,
+    /// Indices of the fields in [AdaptiveConfig::state_fields]
+    states: Vec<usize>,
+    /// Values from the previous poll
+    previous: Option<Vec<f64>>,
+    /// Timestamp of the last poll that showed activity
+    active: Option<i64>,
+}
+
+impl Activity {
+    fn new(config: &AdaptiveConfig, fields: &[Field]) -> Result<Self, String> {
+        let power = fields
+            .iter()
+            .enumerate()
+            .filter(|(_, field)| field.field_type == FieldType::Power)
+            .map(|(i, _)| i)
+            .collect();
+        let states = config
+            .state_fields
+            .iter()
+            .map(|id| {
+                fields
+                    .iter()
+                    .position(|field| field.id == id)
+                    .ok_or_else(|| format!("Unknown field {id:?} in [modbus.adaptive]"))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            power_threshold: config.power_threshold,
+            hold: config.hold.as_nanos() as i64,
+            power,
+            states,
+            previous: None,
+            active: None,
+        })
+    }
+
+    /// Record the values from a poll, and return whether to poll fast
+    fn update(&mut self, timestamp: i64, values: &[f64]) -> bool {
+        if let Some(previous) = &self.previous {
+            // Comparisons with NaN are false, so missing values don't count
+            let changed = |i: &usize, threshold: f64| (values[*i] - previous[*i]).abs() > threshold;
+            if self.power.iter().any(|i| changed(i, self.power_threshold))
+                || self.states.iter().any(|i| changed(i, 0.0))
+            {
+                self.active = Some(timestamp);
+            }
+        }
+        self.previous = Some(values.to_vec());
+        self.active
+            .is_some_and(|active| timestamp - active < self.hold)
+    }
+}
+
+/// Schedule of polls
+enum Ticker {
+    /// Every interval, counting from startup
+    Interval(tokio::time::Interval),
+    /// On multiples of the interval (in nanoseconds) since the UNIX epoch
+    Aligned {
+        interval: i64,
+        clock: Arc<dyn Clock>,
+        /// Time of the last poll
+        last: Option<i64>,
+    },
+}
+
+impl Ticker {
+    fn new(interval: Duration, align: bool, clock: Arc<dyn Clock>) -> Self {
+        if align {
+            Self::Aligned {
+                interval: interval.as_nanos() as i64,
+                clock,
+                last: None,
             }
-            field.from_u16s(parts[..regs.len()].iter().cloned())
         } else {
-            field.from_sum(&values)
-        };
-        values.push(value);
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            Self::Interval(interval)
+        }
+    }
+
+    /// Wait until the next poll is due. When aligned, returns the time it
+    /// was due, which is used as the timestamp of the update. Polls that
+    /// have been missed are skipped.
+    async fn tick(&mut self) -> Option<i64> {
+        match self {
+            Self::Interval(interval) => {
+                interval.tick().await;
+                None
+            }
+            Self::Aligned {
+                interval,
+                clock,
+                last,
+            } => {
+                let now = clock.now();
+                let mut next = (now.div_euclid(*interval) + 1) * *interval;
+                // The sleep may end slightly before the wall-clock time
+                // reaches the boundary, which must not cause a second poll
+                if let Some(last) = *last {
+                    next = next.max(last + *interval);
+                }
+                if next > now {
+                    clock.sleep(Duration::from_nanos((next - now) as u64)).await;
+                }
+                *last = Some(next);
+                Some(next)
+            }
+        }
+    }
+
+    /// Restart the interval after a poll out of turn. Aligned polls are not
+    /// affected.
+    fn reset(&mut self) {
+        if let Self::Interval(interval) = self {
+            interval.reset();
+        }
+    }
+
+    /// Change the time between polls, starting after the next one
+    fn set_interval(&mut self, period: Duration) {
+        match self {
+            Self::Interval(interval) => {
+                let start = tokio::time::Instant::now() + period;
+                *interval = tokio::time::interval_at(start, period);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            }
+            Self::Aligned { interval, .. } => {
+                *interval = period.as_nanos() as i64;
+            }
+        }
+    }
+}
+
+/// Reason that reading registers from the inverter failed. Exceptions mean
+/// that the inverter is reachable but rejected the request (for example,
+/// because the register map does not match it), while the other errors point
+/// to the wiring or the bus.
+#[derive(Debug)]
+enum ReadError {
+    /// The inverter returned an exception response
+    Exception {
+        start: u16,
+        count: u16,
+        code: ExceptionCode,
+    },
+    /// The inverter did not respond in time
+    Timeout(std::io::Error),
+    /// The response was corrupt (such as a CRC mismatch) or did not match
+    /// the request
+    Corrupt(String),
+    /// Any other failure to communicate
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exception { start, count, code } => write!(
+                f,
+                "inverter rejected reading registers {start}-{}: {code}",
+                start + (count - 1)
+            ),
+            Self::Timeout(err) => write!(f, "timed out: {err}"),
+            Self::Corrupt(err) => write!(f, "corrupt response: {err}"),
+            Self::Io(err) => write!(f, "I/O error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+impl From<tokio_modbus::Error> for ReadError {
+    fn from(err: tokio_modbus::Error) -> Self {
+        match err {
+            tokio_modbus::Error::Protocol(err) => Self::Corrupt(err.to_string()),
+            tokio_modbus::Error::Transport(err) => match err.kind() {
+                std::io::ErrorKind::TimedOut => Self::Timeout(err),
+                // The RTU codec reports CRC mismatches as invalid data
+                std::io::ErrorKind::InvalidData => Self::Corrupt(err.to_string()),
+                _ => Self::Io(err),
+            },
+        }
+    }
+}
+
+/// Read `count` registers starting at `start`
+async fn read_registers(ctx: &mut Context, start: u16, count: u16) -> Result<Vec<u16>, ReadError> {
+    match ctx.read_holding_registers(start, count).await? {
+        Ok(words) if words.len() == usize::from(count) => Ok(words),
+        Ok(words) => Err(ReadError::Corrupt(format!(
+            "expected {count} registers from {start}, but received {}",
+            words.len()
+        ))),
+        Err(code) => Err(ReadError::Exception { start, count, code }),
+    }
+}
+
+async fn read_values(
+    ctx: &mut Context,
+    programs: &ProgramFields,
+) -> Result<(Vec<f64>, Vec<Vec<u16>>), ReadError> {
+    // Read runs of consecutive registers in one request each, rather than
+    // one register at a time
+    let mut words = Vec::with_capacity(read_length());
+    for &(start, count) in READ_RANGES {
+        words.extend(read_registers(ctx, start, count).await?);
     }
+    let raw = field_registers(&words);
+    let mut values: Vec<f64> = FIELDS
+        .iter()
+        .zip(raw.iter())
+        .map(|(field, parts)| {
+            if !parts.is_empty() {
+                field.from_u16s(parts.iter().cloned())
+            } else {
+                f64::NAN // Derived fields are filled in later
+            }
+        })
+        .collect();
     // Get the inverter time, since that'll determine which program is current
-    let time_regs = ctx.read_holding_registers(REG_CLOCK, 3).await??;
-    let hour = time_regs[1] & 0xff;
-    let minute = time_regs[2] >> 8;
-    let second = time_regs[2] & 0xff;
-    let now = (hour as f64) * 3600.0 + (minute as f64) * 60.0 + (second as f64);
-    let mut prog = NUM_PROGRAMS - 1;
-    for i in 0..(NUM_PROGRAMS - 1) {
-        let start = values[field_idx::INVERTER_PROGRAM_TIME_1 + i];
-        let stop = values[field_idx::INVERTER_PROGRAM_TIME_2 + i];
-        if now >= start && now < stop {
-            prog = i;
-            break;
+    let time_regs = read_registers(ctx, REG_CLOCK, 3).await?;
+    programs.apply(&mut values, clock_seconds(&time_regs));
+
+    Ok((values, raw))
+}
+
+/// Outcomes of the polls of an inverter, published as [BUS_FIELDS]
+#[derive(Default)]
+struct BusHealth {
+    connected: bool,
+    polls: u64,
+    exceptions: u64,
+    timeouts: u64,
+    corrupt: u64,
+    io_errors: u64,
+}
+
+impl BusHealth {
+    fn record<T>(&mut self, result: &Result<T, ReadError>) {
+        self.polls += 1;
+        // Responses of any kind show that the inverter is reachable
+        self.connected = matches!(result, Ok(_) | Err(ReadError::Exception { .. }));
+        match result {
+            Ok(_) => {}
+            Err(ReadError::Exception { .. }) => self.exceptions += 1,
+            Err(ReadError::Timeout(_)) => self.timeouts += 1,
+            Err(ReadError::Corrupt(_)) => self.corrupt += 1,
+            Err(ReadError::Io(_)) => self.io_errors += 1,
         }
     }
-    values[field_idx::INVERTER_PROGRAM_POWER] = values[field_idx::INVERTER_PROGRAM_POWER_1 + prog];
-    values[field_idx::INVERTER_PROGRAM_SOC] = values[field_idx::INVERTER_PROGRAM_SOC_1 + prog];
 
-    Ok(values)
+    fn update(&self, serial: &str, timestamp: i64) -> Update<'static> {
+        let values = vec![
+            if self.connected { 1.0 } else { 0.0 },
+            self.polls as f64,
+            self.exceptions as f64,
+            self.timeouts as f64,
+            self.corrupt as f64,
+            self.io_errors as f64,
+        ];
+        Update::new(
+            timestamp,
+            format!("{serial}{BUS_SUFFIX}"),
+            BUS_FIELDS,
+            values,
+        )
+    }
 }
 
-pub async fn create_stream(
-    config: &ModbusConfig,
-) -> Result<UpdateStream, Box<dyn std::error::Error>> {
-    let modbus_id = config.modbus_id;
-    let interval = config.interval;
-    let (mut sender, receiver) = mpsc::channel(1);
-    let slave = Slave(modbus_id);
-    let mut ctx = match config.device.parse() {
-        Ok(socket_addr) => modbus_robust::new_tcp_slave(socket_addr, slave),
-        Err(_) => modbus_robust::new_rtu_slave(&config.device, config.baud, slave),
-    };
+/// Changing settings, which is left out of read-only builds
+#[cfg(not(feature = "read_only"))]
+mod write {
+    use log::{error, info, warn};
+    use std::time::Duration;
+    use tokio_modbus::client::Context;
+
+    use crate::clock::Clock;
+    use crate::control::{WriteEvent, WriteOutcome};
+    use crate::receiver::Update;
+    use sunsniff_core::modbus::{WRITE_FIELDS, WRITE_SUFFIX};
+    use tokio_modbus::prelude::{Reader, Writer};
+
+    pub async fn write_register(
+        ctx: &mut Context,
+        register: u16,
+        value: u16,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        ctx.write_single_register(register, value).await??;
+        Ok(())
+    }
+
+    /// Classify the result of reading a register back after writing `value`
+    pub fn check_readback(value: u16, result: Result<&[u16], String>) -> WriteOutcome {
+        match result {
+            Ok(&[stored]) if stored == value => WriteOutcome::Verified,
+            Ok(&[stored]) => WriteOutcome::Changed { stored },
+            Ok(stored) => {
+                WriteOutcome::ReadFailed(format!("expected 1 value, got {}", stored.len()))
+            }
+            Err(err) => WriteOutcome::ReadFailed(err),
+        }
+    }
+
+    /// Read a register back after writing it, to find out whether the
+    /// inverter kept the value. Inverters may silently reject or clamp values
+    /// that are out of range.
+    pub async fn verify_register(
+        ctx: &mut Context,
+        register: u16,
+        value: u16,
+        delay: Duration,
+        clock: &dyn Clock,
+    ) -> WriteOutcome {
+        clock.sleep(delay).await;
+        match ctx.read_holding_registers(register, 1).await {
+            Ok(Ok(stored)) => check_readback(value, Ok(&stored)),
+            Ok(Err(err)) => check_readback(value, Err(format!("{err:?}"))),
+            Err(err) => check_readback(value, Err(format!("{err:?}"))),
+        }
+    }
+
+    /// Log the outcome of a write, and turn it into an update with
+    /// [WRITE_FIELDS] at `timestamp`, so that the backends can report it
+    pub fn report(event: WriteEvent, timestamp: i64) -> Update<'static> {
+        let WriteEvent {
+            register, value, ..
+        } = event;
+        match &event.outcome {
+            WriteOutcome::Verified => {
+                info!("Verified write of {value} to register {register}");
+            }
+            WriteOutcome::Changed { stored } => {
+                warn!("Inverter stored {stored} in register {register} instead of {value}");
+            }
+            WriteOutcome::WriteFailed(err) => {
+                error!("Failed to write register {register}: {err}");
+            }
+            WriteOutcome::ReadFailed(err) => {
+                error!("Failed to read back register {register}: {err}");
+            }
+        }
+        let stored = match event.outcome {
+            WriteOutcome::Verified => Some(value),
+            WriteOutcome::Changed { stored } => Some(stored),
+            WriteOutcome::WriteFailed(_) | WriteOutcome::ReadFailed(_) => None,
+        };
+        let values = vec![
+            register as f64,
+            value as f64,
+            stored.map_or(f64::NAN, f64::from),
+            if event.outcome.is_success() { 1.0 } else { 0.0 },
+        ];
+        Update::new(
+            timestamp,
+            format!("{}{WRITE_SUFFIX}", event.serial),
+            WRITE_FIELDS,
+            values,
+        )
+    }
+}
+
+/// Connection to the inverters, shared by those polled through it. On RTU,
+/// the inverters share the serial port and take turns to use it; over TCP,
+/// each has its own connection, so they are polled concurrently.
+type Bus = Arc<futures::lock::Mutex<Context>>;
+
+/// Lock the bus and address an inverter on it
+async fn select(bus: &Bus, slave: Slave) -> futures::lock::MutexGuard<'_, Context> {
+    let mut ctx = bus.lock().await;
+    ctx.set_slave(slave);
+    ctx
+}
+
+async fn read_serial(ctx: &mut Context) -> Result<String, Box<dyn std::error::Error>> {
     let serial_words = ctx.read_holding_registers(3, 5).await??;
     let mut serial_bytes = [0u8; 10];
     for i in 0..5 {
@@ -109,28 +537,371 @@ pub async fn create_stream(
         serial_bytes[2 * i] = bytes[0];
         serial_bytes[2 * i + 1] = bytes[1];
     }
-    let serial = std::str::from_utf8(&serial_bytes)?.to_owned();
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(interval);
-        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    Ok(std::str::from_utf8(&serial_bytes)?.to_owned())
+}
+
+/// Polling state of a single inverter
+struct Poller {
+    bus: Bus,
+    slave: Slave,
+    serial: String,
+    source: String,
+    ticker: Ticker,
+    slow_interval: Duration,
+    /// Fast interval and activity tracking, for [AdaptiveConfig]
+    adaptive: Option<(Duration, Activity)>,
+    fast: bool,
+    raw_values: bool,
+    /// Counts of the poll outcomes, if they are published
+    health: Option<BusHealth>,
+    #[cfg(not(feature = "read_only"))]
+    verify_delay: Duration,
+    programs: ProgramFields,
+    clock: Arc<dyn Clock>,
+}
+
+impl Poller {
+    async fn run(mut self, mut commands: CommandReceiver, mut sender: mpsc::Sender<UpdateItem>) {
+        let serial = self.serial.clone();
         loop {
-            interval.tick().await;
-            match read_values(&mut ctx).await {
+            // Time at which the poll was due, if it is aligned
+            let due = tokio::select! {
+                due = self.ticker.tick() => due,
+                Some(command) = commands.next() => {
+                    if !command.targets(&serial) {
+                        continue;
+                    }
+                    match command {
+                        Command::PollNow { .. } => {
+                            info!("Polling immediately on request");
+                        }
+                        #[cfg(feature = "read_only")]
+                        Command::WriteRegister { .. } => {
+                            error!("Settings cannot be changed in a read-only build");
+                            continue;
+                        }
+                        #[cfg(not(feature = "read_only"))]
+                        Command::WriteRegister { register, value, origin, .. } => {
+                            info!("Writing {value} to register {register}");
+                            let mut ctx = select(&self.bus, self.slave).await;
+                            let outcome = match write::write_register(&mut ctx, register, value).await {
+                                Ok(()) => {
+                                    write::verify_register(
+                                        &mut ctx,
+                                        register,
+                                        value,
+                                        self.verify_delay,
+                                        self.clock.as_ref(),
+                                    )
+                                    .await
+                                }
+                                Err(err) => control::WriteOutcome::WriteFailed(format!("{err:?}")),
+                            };
+                            drop(ctx);
+                            let failed = matches!(outcome, control::WriteOutcome::WriteFailed(_));
+                            let event = control::WriteEvent {
+                                serial: serial.clone(),
+                                register,
+                                value,
+                                origin,
+                                outcome,
+                            };
+                            let update = write::report(event, self.clock.now());
+                            if sender.send(Arc::new(update)).await.is_err() {
+                                break; // The main stream has ended
+                            }
+                            if failed {
+                                continue;
+                            }
+                        }
+                        Command::Shutdown => {
+                            info!("Stopping modbus frontend");
+                            break;
+                        }
+                    }
+                    // Restart the interval from this poll
+                    self.ticker.reset();
+                    None
+                }
+            };
+            let mut ctx = select(&self.bus, self.slave).await;
+            let start = Instant::now();
+            let result = read_values(&mut ctx, &self.programs).await;
+            drop(ctx);
+            if let Some(health) = &mut self.health {
+                health.record(&result);
+                let update = health.update(&serial, self.clock.now());
+                if sender.send(Arc::new(update)).await.is_err() {
+                    break; // The main stream has ended
+                }
+            }
+            match result {
                 Err(err) => {
-                    error!("Failed to read values from modbus: {err:?}");
+                    error!("Failed to read values from modbus for {serial}: {err}");
                 }
-                Ok(values) => {
+                Ok((values, raw)) => {
                     info!("Received a set of values from modbus");
-                    let now = chrono::Utc::now();
-                    let update =
-                        Update::new(now.timestamp_nanos_opt().unwrap(), &serial, FIELDS, values);
-                    // TODO: Handle error from send
-                    sender.send(Arc::new(update)).await.unwrap();
+                    let timestamp = due.unwrap_or_else(|| self.clock.now());
+                    if let Some((fast_interval, activity)) = &mut self.adaptive {
+                        if activity.update(timestamp, &values) != self.fast {
+                            self.fast = !self.fast;
+                            let interval = match self.fast {
+                                true => *fast_interval,
+                                false => self.slow_interval,
+                            };
+                            info!("Polling {serial} every {}s", interval.as_secs_f64());
+                            self.ticker.set_interval(interval);
+                        }
+                    }
+                    let mut update =
+                        Update::new(timestamp, &serial, FIELDS, values).with_metadata(Metadata {
+                            source: Some(self.source.clone()),
+                            protocol: Some("modbus"),
+                            decode_duration: Some(start.elapsed()),
+                            ..Default::default()
+                        });
+                    if self.raw_values {
+                        update = update.with_raw(raw);
+                    }
+                    if sender.send(Arc::new(update)).await.is_err() {
+                        break; // The main stream has ended
+                    }
                 }
             }
         }
-    });
+    }
+}
+
+/// Poll the inverters at the configured interval. Updates are timestamped
+/// with `clock`.
+pub async fn create_stream(
+    config: &ModbusConfig,
+    commands: CommandReceiver,
+    clock: Arc<dyn Clock>,
+) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+    let ids = config.ids()?;
+    let buses: Vec<Bus> = match config.device.parse() {
+        Ok(socket_addr) => ids
+            .iter()
+            .map(|&id| {
+                let ctx = modbus_robust::new_tcp_slave(socket_addr, Slave(id));
+                Arc::new(futures::lock::Mutex::new(ctx))
+            })
+            .collect(),
+        Err(_) => {
+            let ctx = modbus_robust::new_rtu_slave(&config.device, config.baud, Slave(ids[0]));
+            let bus = Arc::new(futures::lock::Mutex::new(ctx));
+            ids.iter().map(|_| Arc::clone(&bus)).collect()
+        }
+    };
+    // Each inverter is sent all the commands, and acts on its own
+    let mut command_receivers = match ids.len() {
+        1 => vec![commands],
+        n => {
+            let (senders, receivers) = (0..n).map(|_| control::channel()).unzip();
+            tokio::spawn(control::broadcast(commands, senders));
+            receivers
+        }
+    };
+    let (sender, receiver) = mpsc::channel(1);
+    for (&id, bus) in ids.iter().zip(buses) {
+        let slave = Slave(id);
+        let serial = read_serial(&mut *select(&bus, slave).await).await?;
+        let source = match ids.len() {
+            1 => format!("modbus:{}", config.device),
+            _ => format!("modbus:{}#{id}", config.device),
+        };
+        let adaptive = match &config.adaptive {
+            Some(adaptive) => Some((adaptive.fast_interval, Activity::new(adaptive, FIELDS)?)),
+            None => None,
+        };
+        let poller = Poller {
+            bus,
+            slave,
+            serial,
+            source,
+            ticker: Ticker::new(config.interval, config.align, Arc::clone(&clock)),
+            slow_interval: config.interval,
+            adaptive,
+            fast: false,
+            raw_values: config.raw_values,
+            health: config.bus_health.then(BusHealth::default),
+            #[cfg(not(feature = "read_only"))]
+            verify_delay: config.verify_delay,
+            programs: ProgramFields::new(FIELDS).expect("program fields are missing"),
+            clock: Arc::clone(&clock),
+        };
+        tokio::spawn(poller.run(command_receivers.remove(0), sender.clone()));
+    }
     Ok(Box::pin(receiver))
 }
 
-include!(concat!(env!("OUT_DIR"), "/modbus_fields.rs"));
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn field(id: &str) -> usize {
+        FIELDS.iter().position(|field| field.id == id).unwrap()
+    }
+
+    #[test]
+    fn test_read_error() {
+        let timeout = std::io::Error::from(std::io::ErrorKind::TimedOut);
+        let err = ReadError::from(tokio_modbus::Error::Transport(timeout));
+        assert!(matches!(err, ReadError::Timeout(_)));
+        let crc = std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid CRC");
+        let err = ReadError::from(tokio_modbus::Error::Transport(crc));
+        assert!(matches!(err, ReadError::Corrupt(_)));
+        let broken = std::io::Error::from(std::io::ErrorKind::BrokenPipe);
+        let err = ReadError::from(tokio_modbus::Error::Transport(broken));
+        assert!(matches!(err, ReadError::Io(_)));
+        let err = ReadError::Exception {
+            start: 60,
+            count: 10,
+            code: ExceptionCode::IllegalDataAddress,
+        };
+        assert!(err
+            .to_string()
+            .starts_with("inverter rejected reading registers 60-69: "));
+    }
+
+    #[test]
+    fn test_bus_health() {
+        let mut health = BusHealth::default();
+        health.record(&Ok(()));
+        let timeout = std::io::Error::from(std::io::ErrorKind::TimedOut);
+        health.record::<()>(&Err(ReadError::Timeout(timeout)));
+        let update = health.update("1234", 0);
+        assert_eq!(update.serial, "1234-modbus");
+        assert_eq!(update.values, [0.0, 2.0, 0.0, 1.0, 0.0, 0.0]);
+        health.record::<()>(&Err(ReadError::Exception {
+            start: 60,
+            count: 10,
+            code: ExceptionCode::ServerDeviceBusy,
+        }));
+        let update = health.update("1234", 0);
+        assert_eq!(update.values, [1.0, 3.0, 1.0, 1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_ids() {
+        let config = |extra: &str| -> ModbusConfig {
+            toml::from_str(&format!(
+                "device = \"/dev/ttyUSB0\"\ninterval = 10\n{extra}"
+            ))
+            .unwrap()
+        };
+        assert_eq!(config("").ids(), Ok(vec![1]));
+        assert_eq!(config("modbus_id = 3").ids(), Ok(vec![3]));
+        assert_eq!(config("modbus_ids = [1, 2]").ids(), Ok(vec![1, 2]));
+        assert_eq!(config("modbus_ids = [1, 2]").inverters(), 2);
+        assert!(config("modbus_id = 1\nmodbus_ids = [1, 2]").ids().is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let config = |text: &str| -> ModbusConfig {
+            toml::from_str(&format!("device = \"/dev/ttyUSB0\"\n{text}")).unwrap()
+        };
+        assert!(config("interval = 10").check().is_ok());
+        assert!(config("interval = 0").check().is_err());
+        assert!(config("interval = 10\nadaptive = { fast_interval = 2 }")
+            .check()
+            .is_ok());
+        assert!(config("interval = 10\nadaptive = { fast_interval = 0 }")
+            .check()
+            .is_err());
+        assert!(config("interval = 10\nmodbus_id = 1\nmodbus_ids = [2]")
+            .check()
+            .is_err());
+        // serde rejects negative intervals
+        assert!(toml::from_str::<ModbusConfig>("device = \"x\"\ninterval = -1").is_err());
+    }
+
+    #[test]
+    fn test_activity() {
+        let config = AdaptiveConfig {
+            fast_interval: Duration::from_secs(2),
+            power_threshold: 100.0,
+            state_fields: default_state_fields(),
+            hold: Duration::from_nanos(10),
+        };
+        let mut activity = Activity::new(&config, FIELDS).unwrap();
+        let mut values = vec![f64::NAN; FIELDS.len()];
+        values[field("grid_power")] = 500.0;
+        values[field("grid_connected")] = 1.0;
+        assert!(!activity.update(0, &values));
+        values[field("grid_power")] = 550.0;
+        assert!(!activity.update(1, &values));
+        values[field("grid_power")] = 700.0;
+        assert!(activity.update(2, &values));
+        assert!(activity.update(11, &values));
+        assert!(!activity.update(12, &values));
+        values[field("grid_connected")] = 0.0;
+        assert!(activity.update(13, &values));
+    }
+
+    #[test]
+    fn test_activity_unknown_field() {
+        let config = AdaptiveConfig {
+            fast_interval: Duration::from_secs(2),
+            power_threshold: 100.0,
+            state_fields: vec!["nonexistent".to_owned()],
+            hold: default_hold(),
+        };
+        assert!(Activity::new(&config, FIELDS).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_aligned_ticker() {
+        let second = 1_000_000_000;
+        let clock = MockClock::new(1000 * second + 12_000_000);
+        let mut ticker = Ticker::new(Duration::from_secs(30), true, clock.clone());
+        assert_eq!(ticker.tick().await, Some(1020 * second));
+        assert_eq!(clock.now(), 1020 * second);
+        // A slow poll misses the next boundary
+        tokio::time::advance(Duration::from_secs(45)).await;
+        assert_eq!(ticker.tick().await, Some(1080 * second));
+        // A poll out of turn does not move the boundaries
+        tokio::time::advance(Duration::from_secs(10)).await;
+        ticker.reset();
+        assert_eq!(ticker.tick().await, Some(1110 * second));
+        assert_eq!(clock.now(), 1110 * second);
+        ticker.set_interval(Duration::from_secs(2));
+        assert_eq!(ticker.tick().await, Some(1112 * second));
+    }
+
+    #[cfg(not(feature = "read_only"))]
+    #[test]
+    fn test_report_write() {
+        use crate::control::{WriteEvent, WriteOutcome};
+
+        let event = |value, outcome| WriteEvent {
+            serial: "1234".to_owned(),
+            register: 268,
+            value,
+            origin: "test".to_owned(),
+            outcome,
+        };
+
+        let outcome = write::check_readback(50, Ok(&[50]));
+        assert!(outcome.is_success());
+        let update = write::report(event(50, outcome), 5);
+        assert_eq!(update.serial, "1234-write");
+        assert_eq!(update.timestamp, 5);
+        assert_eq!(update.values, [268.0, 50.0, 50.0, 1.0]);
+
+        let outcome = write::check_readback(150, Ok(&[100]));
+        assert_eq!(outcome, WriteOutcome::Changed { stored: 100 });
+        assert!(!outcome.is_success());
+        let update = write::report(event(150, outcome), 5);
+        assert_eq!(update.values, [268.0, 150.0, 100.0, 0.0]);
+
+        let outcome = write::check_readback(50, Err("timeout".to_owned()));
+        assert_eq!(outcome, WriteOutcome::ReadFailed("timeout".to_owned()));
+        let update = write::report(event(50, outcome), 5);
+        assert!(update.values[2].is_nan());
+        assert_eq!(update.values[3], 0.0);
+    }
+}
@@ -0,0 +1,338 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Detection of receivers that cannot keep up with the frontend
+//!
+//! Each receiver is given its own queue, so a receiver that takes longer to
+//! process an update than the interval between updates will fill its queue
+//! (see [crate::queue]). A [Monitor] measures how long the receiver takes
+//! to process each update (from when it takes an update from the queue to
+//! when it asks for the next one) and warns when it is consistently slower
+//! than the updates arrive.
+
+use futures::prelude::*;
+use log::{info, warn};
+use serde::Deserialize;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use super::ewma::{self, Ewma};
+use super::receiver::{Update, UpdateReceiver};
+
+/// Structure corresponding to the `[monitor]` section of the configuration
+/// file. It is constructed from the config file by serde.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Warn whenever a receiver takes longer than this (in seconds) to
+    /// process a single update
+    pub deadline: Option<f64>,
+}
+
+impl Config {
+    /// Check the parts of the configuration that serde cannot
+    pub fn check(&self) -> Result<(), String> {
+        if let Some(deadline) = self.deadline {
+            if !(deadline.is_finite() && deadline > 0.0) {
+                return Err(format!("monitor deadline must be positive, not {deadline}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// All the monitors that have been created, so that their state can be
+/// reported (see [statuses])
+static MONITORS: Mutex<Vec<Weak<Monitor>>> = Mutex::new(Vec::new());
+
+/// Snapshot of the state of every receiver that is still running
+pub fn statuses() -> Vec<Status> {
+    let mut monitors = MONITORS.lock().unwrap();
+    monitors.retain(|monitor| monitor.strong_count() > 0);
+    monitors
+        .iter()
+        .filter_map(Weak::upgrade)
+        .map(|monitor| monitor.status())
+        .collect()
+}
+
+#[derive(Default)]
+struct State {
+    /// Latest update timestamp seen, in nanoseconds since the UNIX epoch
+    last_timestamp: Option<i64>,
+    /// Average interval between updates, in seconds, ignoring outages
+    cadence: Ewma,
+    /// Average time taken by the receiver to process an update, in seconds
+    processing: Ewma,
+    /// Number of updates sent to the receiver that it has not taken yet
+    queued: usize,
+    /// Total number of updates sent to the receiver
+    sent: u64,
+    /// Number of updates dropped because the queue was full
+    dropped: u64,
+    /// When the receiver took the update that it is currently processing
+    busy_since: Option<Instant>,
+    /// Whether the receiver is currently reported as too slow
+    slow: bool,
+}
+
+/// Snapshot of the state of a receiver, for display
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    pub name: String,
+    /// Total number of updates sent to the receiver
+    pub sent: u64,
+    /// Number of updates waiting to be processed
+    pub queued: usize,
+    /// Average time taken to process an update, in seconds
+    pub processing: Option<f64>,
+    /// Whether the receiver is falling behind
+    pub slow: bool,
+}
+
+/// Tracks how quickly a single receiver processes its updates
+pub struct Monitor {
+    name: String,
+    deadline: Option<Duration>,
+    state: Mutex<State>,
+}
+
+impl Monitor {
+    pub fn new(name: impl Into<String>, config: &Config) -> Arc<Self> {
+        let monitor = Arc::new(Self {
+            name: name.into(),
+            deadline: config.deadline.map(Duration::from_secs_f64),
+            state: Mutex::new(State {
+                cadence: Ewma::with_outliers(ewma::OUTLIER_FACTOR),
+                ..Default::default()
+            }),
+        });
+        MONITORS.lock().unwrap().push(Arc::downgrade(&monitor));
+        monitor
+    }
+
+    /// Record that an update has been added to the receiver's queue. The
+    /// cadence is measured from the update timestamps rather than the wall
+    /// clock, so that replaying a capture file does not count as a burst.
+    pub fn sent(&self, update: &Update<'_>) {
+        let mut state = self.state.lock().unwrap();
+        state.queued += 1;
+        state.sent += 1;
+        if let Some(last) = state.last_timestamp {
+            if update.timestamp > last {
+                let interval = (update.timestamp - last) as f64 / 1e9;
+                state.cadence.add(interval);
+            }
+        }
+        state.last_timestamp = state.last_timestamp.max(Some(update.timestamp));
+        self.check(&mut state);
+    }
+
+    /// Record that an update sent to the receiver was dropped because its
+    /// queue was full, warning about the first and then each power of ten
+    pub fn dropped(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.queued = state.queued.saturating_sub(1);
+        state.dropped += 1;
+        let mut n = state.dropped;
+        while n.is_multiple_of(10) {
+            n /= 10;
+        }
+        if n == 1 {
+            warn!(
+                "Receiver {} has had {} update(s) dropped because its queue is full",
+                self.name, state.dropped
+            );
+        }
+    }
+
+    /// Record that the receiver has taken an update from its queue
+    fn taken(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.queued = state.queued.saturating_sub(1);
+        state.busy_since = Some(Instant::now());
+    }
+
+    /// Record that the receiver is ready for the next update, if it was
+    /// processing one
+    fn ready(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(start) = state.busy_since.take() {
+            self.finished(&mut state, start.elapsed());
+        }
+    }
+
+    /// Record the time taken to process an update
+    fn finished(&self, state: &mut State, elapsed: Duration) {
+        if let Some(deadline) = self.deadline {
+            if elapsed > deadline {
+                warn!(
+                    "Receiver {} took {:.3}s to process an update (deadline {:.3}s)",
+                    self.name,
+                    elapsed.as_secs_f64(),
+                    deadline.as_secs_f64()
+                );
+            }
+        }
+        state.processing.add(elapsed.as_secs_f64());
+        self.check(state);
+    }
+
+    /// Report transitions between keeping up and falling behind. A receiver
+    /// is only considered slow once updates are waiting in its queue, so
+    /// that a single slow update does not trigger a warning.
+    fn check(&self, state: &mut State) {
+        let (Some(processing), Some(cadence)) =
+            (state.processing.estimate(), state.cadence.estimate())
+        else {
+            return;
+        };
+        if !state.slow && processing > cadence && state.queued > 1 {
+            warn!(
+                "Receiver {} is not keeping up: it takes {:.3}s per update but updates \
+                 arrive every {:.3}s ({} queued)",
+                self.name, processing, cadence, state.queued
+            );
+            state.slow = true;
+        } else if state.slow && state.queued == 0 {
+            info!("Receiver {} has caught up", self.name);
+            state.slow = false;
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        let state = self.state.lock().unwrap();
+        Status {
+            name: self.name.clone(),
+            sent: state.sent,
+            queued: state.queued,
+            processing: state.processing.estimate(),
+            slow: state.slow,
+        }
+    }
+
+    /// Wrap the stream of updates for the receiver, to observe when it takes
+    /// updates from its queue
+    pub fn wrap<'a, S>(self: &Arc<Self>, mut stream: S) -> UpdateReceiver<'a>
+    where
+        S: Stream<Item = Arc<Update<'a>>> + Unpin + Send + 'a,
+    {
+        let monitor = Arc::clone(self);
+        Box::pin(stream::poll_fn(move |cx| {
+            monitor.ready();
+            let item = stream.poll_next_unpin(cx);
+            if let Poll::Ready(Some(_)) = &item {
+                monitor.taken();
+            }
+            item
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SECOND: i64 = 1_000_000_000;
+
+    fn update(timestamp: i64) -> Update<'static> {
+        Update::new(timestamp, "1234", &[], vec![])
+    }
+
+    #[test]
+    fn test_check() {
+        let config = |deadline| Config { deadline };
+        assert!(config(None).check().is_ok());
+        assert!(config(Some(0.5)).check().is_ok());
+        assert!(config(Some(-1.0)).check().is_err());
+        assert!(config(Some(0.0)).check().is_err());
+        assert!(config(Some(f64::NAN)).check().is_err());
+    }
+
+    #[test]
+    fn test_slow_receiver() {
+        let monitor = Monitor::new("test", &Config::default());
+        let process = |secs| {
+            let mut state = monitor.state.lock().unwrap();
+            state.queued -= 1;
+            monitor.finished(&mut state, Duration::from_secs(secs));
+        };
+        for i in 0..3 {
+            monitor.sent(&update(i * 10 * SECOND));
+        }
+        // Keeping up with the 10s cadence
+        process(5);
+        assert!(!monitor.state.lock().unwrap().slow);
+        // Too slow for the cadence, and updates are queuing up
+        process(30);
+        process(30);
+        monitor.sent(&update(30 * SECOND));
+        monitor.sent(&update(40 * SECOND));
+        process(30);
+        assert!(monitor.state.lock().unwrap().slow);
+        // Catches up once the queue is drained
+        process(1);
+        assert!(!monitor.state.lock().unwrap().slow);
+        let status = monitor.status();
+        assert_eq!(status.name, "test");
+        assert_eq!(status.sent, 5);
+        assert_eq!(status.queued, 0);
+        assert!(!status.slow);
+    }
+
+    #[test]
+    fn test_dropped() {
+        let monitor = Monitor::new("test", &Config::default());
+        monitor.sent(&update(0));
+        monitor.sent(&update(SECOND));
+        monitor.dropped();
+        let state = monitor.state.lock().unwrap();
+        assert_eq!(state.queued, 1);
+        assert_eq!(state.sent, 2);
+        assert_eq!(state.dropped, 1);
+    }
+
+    #[test]
+    fn test_statuses() {
+        let monitor = Monitor::new("test_statuses", &Config::default());
+        monitor.sent(&update(0));
+        let find = || {
+            statuses()
+                .into_iter()
+                .find(|status| status.name == "test_statuses")
+        };
+        assert_eq!(find().map(|status| status.queued), Some(1));
+        drop(monitor);
+        assert_eq!(find(), None);
+    }
+
+    #[tokio::test]
+    async fn test_wrap() {
+        let monitor = Monitor::new("test", &Config::default());
+        let updates = vec![Arc::new(update(0)), Arc::new(update(SECOND))];
+        for update in updates.iter() {
+            monitor.sent(update);
+        }
+        let received: Vec<_> = monitor.wrap(stream::iter(updates)).collect().await;
+        assert_eq!(received.len(), 2);
+        let state = monitor.state.lock().unwrap();
+        assert_eq!(state.queued, 0);
+        assert!(state.busy_since.is_none());
+        assert!(state.processing.estimate().is_some());
+        assert_eq!(state.cadence.estimate(), Some(1.0));
+    }
+}
@@ -14,37 +14,62 @@
  * with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
+use async_std::task;
 use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset, Local, TimeZone};
 use futures::channel::mpsc::UnboundedReceiver;
 use futures::stream::StreamExt;
-use log::warn;
-use mqtt_async_client::client::{Client, Publish, QoS};
+use log::{info, warn};
+use mqtt_async_client::client::{Client, Publish, QoS, ReadResult, Subscribe, SubscribeTopic};
 use serde::{self, Deserialize, Serialize};
 use serde_json;
-use std::collections::HashSet;
-use std::iter::zip;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
+use std::time::Duration;
 
-use super::fields::{Field, FieldType};
-use super::receiver::{Receiver, Update};
+use super::control::{self, Command, CommandSender, SettingError};
+use super::ewma::{self, Ewma};
+use super::fields::{Field, FieldType, Reset, TABLE_HASH};
+use super::health::{self, Event, Health};
+use super::receiver::{Metadata, Receiver, Update, UpdateReceiver};
+use super::routing::Serials;
+use super::stats::{self, Snapshot};
+use super::timestamps::Timestamps;
+use super::units::Units;
 
 struct ClassInfo<'a> {
     device_class: Option<&'a str>,
-    state_class: &'a str,
+    state_class: Option<&'a str>,
 }
 
 impl<'a> ClassInfo<'a> {
     const fn new(device_class: &'a str, state_class: &'a str) -> Self {
         ClassInfo {
             device_class: Some(device_class),
-            state_class,
+            state_class: Some(state_class),
         }
     }
 
     const fn new_no_device(state_class: &'a str) -> Self {
         ClassInfo {
             device_class: None,
-            state_class,
+            state_class: Some(state_class),
+        }
+    }
+
+    /// Class info for sensors with a non-numeric state
+    const fn new_text() -> Self {
+        ClassInfo {
+            device_class: None,
+            state_class: None,
+        }
+    }
+
+    /// Class info for binary sensors, which have no state class
+    const fn new_binary(device_class: &'a str) -> Self {
+        ClassInfo {
+            device_class: Some(device_class),
+            state_class: None,
         }
     }
 }
@@ -54,13 +79,22 @@ impl From<FieldType> for ClassInfo<'static> {
         match ft {
             FieldType::Charge | FieldType::Unitless => ClassInfo::new_no_device("measurement"),
             FieldType::Current => ClassInfo::new("current", "measurement"),
+            FieldType::Duration => ClassInfo::new("duration", "measurement"),
             FieldType::Energy => ClassInfo::new("energy", "total_increasing"),
             FieldType::Frequency => ClassInfo::new_no_device("measurement"),
             FieldType::Power => ClassInfo::new("power", "measurement"),
             FieldType::StateOfCharge => ClassInfo::new("battery", "measurement"),
             FieldType::Temperature => ClassInfo::new("temperature", "measurement"),
-            FieldType::Time => ClassInfo::new_no_device("measurement"),
+            // Published as HH:MM text (see Field::format_value)
+            FieldType::Time => ClassInfo::new_text(),
             FieldType::Voltage => ClassInfo::new("voltage", "measurement"),
+            FieldType::Connectivity => ClassInfo::new_binary("connectivity"),
+            FieldType::PowerDetected => ClassInfo::new_binary("power"),
+            // Published as RFC 3339 text (see Field::format_value)
+            FieldType::Timestamp => ClassInfo {
+                device_class: Some("timestamp"),
+                state_class: None,
+            },
         }
     }
 }
@@ -68,6 +102,8 @@ impl From<FieldType> for ClassInfo<'static> {
 #[derive(Serialize)]
 struct Device<'a> {
     identifiers: (&'a str,),
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
 }
 
 #[derive(Serialize)]
@@ -75,15 +111,159 @@ struct Sensor<'a> {
     device: Device<'a>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     device_class: Option<&'a str>,
-    expire_after: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    entity_category: Option<&'a str>,
+    expire_after: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    json_attributes_topic: Option<&'a str>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_reset_value_template: Option<&'a str>,
+    name: &'a str,
+    object_id: &'a str,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload_off: Option<&'a str>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload_on: Option<&'a str>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    state_class: Option<&'a str>,
+    state_topic: &'a str,
+    unique_id: &'a str,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<&'a str>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value_template: Option<&'a str>,
+}
+
+/// Discovery information for a setting that can be changed from Home
+/// Assistant
+#[derive(Serialize)]
+struct Number<'a> {
+    command_topic: &'a str,
+    device: Device<'a>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'a str>,
+    entity_category: &'a str,
+    max: f64,
+    min: f64,
+    mode: &'a str,
     name: &'a str,
     object_id: &'a str,
-    state_class: &'a str,
     state_topic: &'a str,
+    step: f64,
     unique_id: &'a str,
     unit_of_measurement: &'a str,
 }
 
+/// Discovery information for a setting that is a choice between a few
+/// options (such as the work mode). The state topic holds the number of the
+/// option, which the value template turns into its name. Home Assistant
+/// publishes the name of the chosen option to the command topic.
+#[derive(Serialize)]
+struct Select<'a> {
+    command_topic: &'a str,
+    device: Device<'a>,
+    entity_category: &'a str,
+    name: &'a str,
+    object_id: &'a str,
+    options: &'a [&'a str],
+    state_topic: &'a str,
+    unique_id: &'a str,
+    value_template: &'a str,
+}
+
+/// Template turning the number of an option into its name
+fn select_template(options: &[&str]) -> String {
+    format!(
+        "{{{{ {}[value | int] | default(value) }}}}",
+        serde_json::to_string(options).unwrap()
+    )
+}
+
+/// Parse a value published to a setting topic: the name of an option for
+/// settings that have them, or a number in the units of the sensor
+fn parse_setting(id: &str, payload: &[u8]) -> Option<f64> {
+    let text = std::str::from_utf8(payload).ok()?.trim();
+    if let Some(index) = control::setting_options(id)
+        .and_then(|options| options.iter().position(|&option| option == text))
+    {
+        return Some(index as f64);
+    }
+    text.parse().ok()
+}
+
+/// Range and step of the values that can be written to a setting, or
+/// `None` if it cannot be changed through Home Assistant. Program times are
+/// left out, since they are published as text.
+fn setting_limits(field: &Field) -> Option<(f64, f64, f64)> {
+    control::setting(field.id)?;
+    if control::setting_options(field.id).is_some() {
+        return None; // Published with register_select
+    }
+    match field.field_type {
+        FieldType::Power => Some((0.0, i16::MAX as f64 * field.scale, field.scale)),
+        FieldType::StateOfCharge => Some((0.0, 100.0, field.scale)),
+        _ => None,
+    }
+}
+
+/// Extract the serial number and setting ID from a
+/// `<prefix>/<serial>/set/<id>` topic
+fn setting_topic<'a>(prefix: &str, topic: &'a str) -> Option<(&'a str, &'a str)> {
+    let (serial, id) = topic
+        .strip_prefix(prefix)?
+        .strip_prefix('/')?
+        .split_once("/set/")?;
+    (!serial.is_empty() && !serial.contains('/') && !id.is_empty() && !id.contains('/'))
+        .then_some((serial, id))
+}
+
+/// Extra attributes published alongside a sensor value
+#[derive(Serialize, Default)]
+struct Attributes<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw: Option<&'a [u16]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    protocol: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frame_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    decode_seconds: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sequence: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields_hash: Option<&'a str>,
+}
+
+impl<'a> Attributes<'a> {
+    fn new(raw: Option<&'a [u16]>, metadata: Option<&'a Metadata>) -> Self {
+        let mut attributes = Attributes {
+            raw,
+            ..Default::default()
+        };
+        if let Some(metadata) = metadata {
+            attributes.source = metadata.source.as_deref();
+            attributes.protocol = metadata.protocol;
+            attributes.frame_length = metadata.frame_length;
+            attributes.decode_seconds = metadata.decode_duration.map(|d| d.as_secs_f64());
+            attributes.sequence = metadata.sequence;
+            attributes.fields_hash = Some(TABLE_HASH);
+        }
+        attributes
+    }
+
+    fn is_empty(&self) -> bool {
+        self.raw.is_none()
+            && self.source.is_none()
+            && self.protocol.is_none()
+            && self.frame_length.is_none()
+            && self.decode_seconds.is_none()
+            && self.sequence.is_none()
+            && self.fields_hash.is_none()
+    }
+}
+
 /// Field associated with a specific device
 struct DeviceField<'a> {
     field: &'a Field<'a>,
@@ -91,102 +271,1249 @@ struct DeviceField<'a> {
     unique_id: String,
     state_topic: String,
     config_topic: String,
+    attributes_topic: String,
+}
+
+/// Replace characters that are not safe in a topic level
+fn topic_level(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Unique ID, base topic (for the state and attributes) and discovery topic
+/// for the sensor `id` in `group` of the Home Assistant device `device`.
+/// The `component` is `sensor` or `binary_sensor`.
+fn sensor_topics(
+    component: &str,
+    device: &str,
+    group: &str,
+    id: &str,
+    topic_prefix: &str,
+    layout: TopicLayout,
+) -> (String, String, String) {
+    let unique_id = format!("{device}_{id}");
+    let (base, config_topic) = match layout {
+        TopicLayout::Flat => (
+            format!("{topic_prefix}/{component}/{unique_id}"),
+            format!("{topic_prefix}/{component}/{unique_id}/config"),
+        ),
+        TopicLayout::Grouped => {
+            // Home Assistant only accepts discovery topics of the form
+            // <prefix>/<component>/[<node_id>/]<object_id>/config, so
+            // the group only appears in the state topics.
+            let node = format!("{topic_prefix}/{component}/{device}");
+            (
+                format!("{node}/{}/{id}", topic_level(group)),
+                format!("{node}/{id}/config"),
+            )
+        }
+    };
+    (unique_id, base, config_topic)
 }
 
 impl<'a> DeviceField<'a> {
-    fn new(field: &'a Field<'a>, serial: &'a str) -> Self {
-        let unique_id = format!("sunsniff_{}_{}", serial, field.id);
-        let state_topic = format!("homeassistant/sensor/{unique_id}/state");
-        let config_topic = format!("homeassistant/sensor/{unique_id}/config");
+    fn new(field: &'a Field<'a>, serial: &'a str, topic_prefix: &str, layout: TopicLayout) -> Self {
+        let component = match field.field_type.is_boolean() {
+            true => "binary_sensor",
+            false => "sensor",
+        };
+        let (unique_id, base, config_topic) = sensor_topics(
+            component,
+            &format!("sunsniff_{serial}"),
+            field.group,
+            field.id,
+            topic_prefix,
+            layout,
+        );
+        let state_topic = format!("{base}/state");
+        let attributes_topic = format!("{base}/attributes");
         Self {
             field,
             serial,
             unique_id,
             state_topic,
             config_topic,
+            attributes_topic,
+        }
+    }
+}
+
+/// Running estimate of the interval between updates from one inverter,
+/// used to derive `expire_after`
+struct Cadence {
+    /// Timestamp of the previous update (in nanoseconds)
+    last: Option<i64>,
+    /// Smoothed interval between updates (in seconds), ignoring outages
+    interval: Ewma,
+    /// The `expire_after` value in the published discovery information
+    expire_after: Option<u32>,
+}
+
+impl Default for Cadence {
+    fn default() -> Self {
+        Self {
+            last: None,
+            interval: Ewma::with_outliers(ewma::OUTLIER_FACTOR),
+            expire_after: None,
+        }
+    }
+}
+
+impl Cadence {
+    /// Update the estimate with the timestamp of a new update. If the
+    /// derived `expire_after` has changed significantly since it was last
+    /// published, returns the new value.
+    fn observe(&mut self, timestamp: i64, factor: f64) -> Option<u32> {
+        let last = self.last.replace(timestamp);
+        let gap = (timestamp - last?) as f64 * 1e-9;
+        if gap <= 0.0 {
+            return None; // Out of order or duplicate
+        }
+        self.interval.add(gap);
+        let interval = self.interval.estimate()?;
+        let target = (interval * factor).ceil().max(1.0) as u32;
+        // Avoid re-publishing discovery information for small fluctuations
+        match self.expire_after {
+            Some(current) if target.abs_diff(current) <= current / 4 => None,
+            _ => {
+                self.expire_after = Some(target);
+                Some(target)
+            }
+        }
+    }
+}
+
+/// Time after midnight by which a daily value is assumed to have been reset,
+/// even if it has not dropped (in nanoseconds)
+const RESET_GRACE: i64 = 3600 * 1_000_000_000;
+
+/// Start of the current cycle of a field that is reset daily, which Home
+/// Assistant needs (as `last_reset`) to compute statistics. It must not
+/// change during the day, or Home Assistant counts the day's total again.
+#[derive(Default)]
+struct DailyReset {
+    /// Previous value
+    value: Option<f64>,
+    /// Start of the current cycle
+    last_reset: Option<DateTime<FixedOffset>>,
+}
+
+impl DailyReset {
+    /// Update with a new value, returning the start of its cycle. The start
+    /// only moves to a new midnight once the value drops, or well after
+    /// midnight, since the inverter's clock may differ from ours.
+    fn observe<Tz: TimeZone>(
+        &mut self,
+        timestamp: i64,
+        value: f64,
+        tz: &Tz,
+    ) -> DateTime<FixedOffset> {
+        let time = tz.timestamp_nanos(timestamp);
+        let midnight = time
+            .date_naive()
+            .and_hms_opt(0, 0, 0)
+            .and_then(|midnight| tz.from_local_datetime(&midnight).earliest())
+            .unwrap_or_else(|| time.clone())
+            .fixed_offset();
+        let dropped = self.value.is_some_and(|previous| value < previous);
+        let late = timestamp - midnight.timestamp_nanos_opt().unwrap_or(timestamp) >= RESET_GRACE;
+        self.value = Some(value);
+        match self.last_reset {
+            Some(last_reset) if last_reset >= midnight || !(dropped || late) => last_reset,
+            _ => *self.last_reset.insert(midnight),
         }
     }
 }
 
+/// State payload for a field that is reset daily
+#[derive(Serialize)]
+struct DailyState {
+    value: f64,
+    last_reset: String,
+}
+
+/// Groups of fields that describe the monitoring setup rather than the
+/// inverter, which Home Assistant shows separately
+const DIAGNOSTIC_GROUPS: &[&str] = &["Dongle", "Modbus"];
+
+/// Interval between publishing the state of sunsniff itself
+const DIAGNOSTICS_INTERVAL: Duration = Duration::from_secs(60);
+/// `expire_after` for the sensors of sunsniff itself, so that they become
+/// unavailable soon after sunsniff stops
+const DIAGNOSTICS_EXPIRE_AFTER: u32 = 180;
+
+/// A sensor of the Home Assistant device that describes sunsniff itself
+#[derive(Debug, Default, PartialEq)]
+struct DiagnosticSensor {
+    id: String,
+    name: String,
+    device_class: Option<&'static str>,
+    state_class: Option<&'static str>,
+    unit: Option<&'static str>,
+    /// Value to publish, if there is one yet
+    state: Option<String>,
+}
+
+/// Sensors describing sunsniff itself: the uptime, time of the last update,
+/// number of decode errors and length of each receiver's queue
+fn diagnostic_sensors(snapshot: &Snapshot) -> Vec<DiagnosticSensor> {
+    let mut sensors = vec![
+        DiagnosticSensor {
+            id: "uptime".to_owned(),
+            name: "Uptime".to_owned(),
+            device_class: Some("duration"),
+            state_class: Some("measurement"),
+            unit: Some("s"),
+            state: Some(snapshot.uptime.as_secs().to_string()),
+        },
+        DiagnosticSensor {
+            id: "last_update".to_owned(),
+            name: "Last update".to_owned(),
+            device_class: Some("timestamp"),
+            state: snapshot
+                .last_update
+                .map(|ns| DateTime::from_timestamp_nanos(ns).to_rfc3339()),
+            ..Default::default()
+        },
+        DiagnosticSensor {
+            id: "decode_errors".to_owned(),
+            name: "Decode errors".to_owned(),
+            state_class: Some("total_increasing"),
+            state: Some(snapshot.decode_errors.to_string()),
+            ..Default::default()
+        },
+    ];
+    for status in snapshot.receivers.iter() {
+        sensors.push(DiagnosticSensor {
+            id: format!("queue_{}", topic_level(&status.name)),
+            name: format!("Queue {}", status.name),
+            state_class: Some("measurement"),
+            state: Some(status.queued.to_string()),
+            ..Default::default()
+        });
+    }
+    sensors
+}
+
 pub struct MqttReceiver {
     client: Client,
-    registered: HashSet<String>,
+    /// Home Assistant discovery prefix, under which all sensor topics are
+    /// published
+    topic_prefix: String,
+    topic_layout: TopicLayout,
+    republish_discovery: bool,
+    command_prefix: Option<String>,
+    commands: CommandSender,
+    /// Whether to publish the settings that can be changed
+    settings: bool,
+    /// Factor to convert setting values from the units published to the
+    /// units of the setting, indexed by field ID
+    setting_factors: HashMap<String, f64>,
+    /// Discovery messages that have been published, indexed by unique ID,
+    /// with the `expire_after` they were published with
+    registered: HashMap<String, (u32, Publish)>,
+    /// Default value for `expire_after`
+    expire_after: u32,
+    /// Multiple of the observed cadence to use for `expire_after`
+    expire_factor: Option<f64>,
+    /// Cadence of the updates from each inverter
+    cadence: HashMap<String, Cadence>,
+    /// Whether to publish the update metadata as attributes
+    metadata: bool,
+    /// Serial numbers routed to this backend, which may be refreshed
+    serials: Serials,
+    /// Cycles of the fields that are reset daily, indexed by unique ID
+    resets: HashMap<String, DailyReset>,
+    /// Whether the client has connected (during the self-test), so that
+    /// [Receiver::run] does not connect again
+    connected: bool,
+    /// Reports when publishing starts and stops failing
+    health: Health,
+    /// Topic on which to publish the receiver [Event]s, and the events
+    notifications: Option<(String, UnboundedReceiver<Event>)>,
+    /// Host name to use for the device describing sunsniff itself, if it
+    /// is published
+    diagnostics: Option<String>,
 }
 
 impl MqttReceiver {
-    pub fn new(config: &Config) -> mqtt_async_client::Result<Self> {
-        let client = Client::builder()
-            .set_url_string(&config.url)?
-            .set_username(config.username.clone())
-            .set_password(config.password.as_ref().map(|s| s.as_bytes().to_vec()))
-            .build()?;
+    pub fn new(config: &Config, commands: CommandSender) -> mqtt_async_client::Result<Self> {
         Ok(MqttReceiver {
-            client,
-            registered: HashSet::new(),
+            client: config.client()?,
+            topic_prefix: config.topic_prefix.clone(),
+            topic_layout: config.topic_layout,
+            republish_discovery: config.republish_discovery,
+            command_prefix: config.command_prefix.clone(),
+            commands,
+            settings: config.settings,
+            setting_factors: HashMap::new(),
+            registered: HashMap::new(),
+            expire_after: config.expire_after,
+            expire_factor: config.expire_factor,
+            cadence: HashMap::new(),
+            metadata: config.metadata,
+            serials: config.serials.clone(),
+            resets: HashMap::new(),
+            connected: false,
+            health: Health::new(format!("mqtt '{}'", config.name())),
+            notifications: config
+                .notify_topic
+                .as_ref()
+                .map(|topic| (topic.clone(), health::subscribe())),
+            diagnostics: config
+                .diagnostics
+                .then(|| gethostname::gethostname().to_string_lossy().into_owned()),
         })
     }
 
+    /// Topic on which Home Assistant announces that it has (re)started
+    fn status_topic(&self) -> String {
+        format!("{}/status", self.topic_prefix)
+    }
+
+    /// Current `expire_after` for an inverter
+    fn expire_after(&self, serial: &str) -> u32 {
+        self.cadence
+            .get(serial)
+            .and_then(|cadence| cadence.expire_after)
+            .unwrap_or(self.expire_after)
+    }
+
+    /// Publish the discovery information for a field, if it has not been
+    /// published or `expire_after` has changed. If `attributes` is true, the
+    /// sensor will be given a JSON attributes topic.
     async fn register_field<'a>(
         &mut self,
         field: &DeviceField<'a>,
+        attributes: bool,
     ) -> mqtt_async_client::Result<()> {
-        if !self.registered.contains(&field.unique_id) {
+        let expire_after = self.expire_after(field.serial);
+        if self.registered.get(&field.unique_id).map(|(e, _)| *e) != Some(expire_after) {
             let full_name = format!("{} {}", field.field.group, field.field.name);
-            let class_info: ClassInfo = field.field.field_type.into();
+            let mut class_info: ClassInfo = field.field.field_type.into();
+            // Text sensors cannot have a unit in Home Assistant
+            let unit = class_info.state_class.map(|_| field.field.unit);
+            // Values that are reset daily are published with the start of
+            // the cycle (see DailyReset)
+            let daily = field.field.reset == Reset::Daily;
+            if daily {
+                class_info.state_class = Some("total");
+            }
+            // The state of a binary sensor is published as 1 or 0 (see
+            // Field::format_value)
+            let binary = field.field.field_type.is_boolean();
             let sensor = Sensor {
                 device: Device {
                     identifiers: (field.serial,),
+                    name: None,
                 },
                 device_class: class_info.device_class,
-                expire_after: 600,
+                entity_category: DIAGNOSTIC_GROUPS
+                    .contains(&field.field.group)
+                    .then_some("diagnostic"),
+                expire_after,
+                json_attributes_topic: attributes.then_some(field.attributes_topic.as_str()),
+                last_reset_value_template: daily.then_some("{{ value_json.last_reset }}"),
                 name: &full_name,
                 object_id: &field.unique_id,
+                payload_off: binary.then_some("0"),
+                payload_on: binary.then_some("1"),
                 state_class: class_info.state_class,
                 state_topic: &field.state_topic,
                 unique_id: &field.unique_id,
-                unit_of_measurement: field.field.unit,
+                unit_of_measurement: unit,
+                value_template: daily.then_some("{{ value_json.value }}"),
             };
             // TODO: more graceful error handling on to_vec
             let mut msg = Publish::new(
                 field.config_topic.to_owned(),
                 serde_json::to_vec(&sensor).unwrap(),
             );
-            let msg = msg.set_retain(true).set_qos(QoS::AtLeastOnce);
-            self.client.publish(msg).await?;
-            self.registered.insert(field.unique_id.to_owned());
+            msg.set_retain(true).set_qos(QoS::AtLeastOnce);
+            self.client.publish(&msg).await?;
+            self.registered
+                .insert(field.unique_id.to_owned(), (expire_after, msg));
+        }
+        Ok(())
+    }
+
+    /// Publish the discovery information for a setting, so that it can be
+    /// changed from Home Assistant, if it has not been published. The state
+    /// is taken from the sensor for the same field.
+    async fn register_setting<'a>(
+        &mut self,
+        field: &DeviceField<'a>,
+        prefix: &str,
+        (min, max, step): (f64, f64, f64),
+    ) -> mqtt_async_client::Result<()> {
+        let (unique_id, _, config_topic) = sensor_topics(
+            "number",
+            &format!("sunsniff_{}", field.serial),
+            field.field.group,
+            &format!("{}_setting", field.field.id),
+            &self.topic_prefix,
+            self.topic_layout,
+        );
+        if self.registered.contains_key(&unique_id) {
+            return Ok(());
+        }
+        let full_name = format!("{} {}", field.field.group, field.field.name);
+        let command_topic = format!("{prefix}/{}/set/{}", field.serial, field.field.id);
+        let class_info: ClassInfo = field.field.field_type.into();
+        let number = Number {
+            command_topic: &command_topic,
+            device: Device {
+                identifiers: (field.serial,),
+                name: None,
+            },
+            device_class: class_info.device_class,
+            entity_category: "config",
+            max,
+            min,
+            mode: "box",
+            name: &full_name,
+            object_id: &unique_id,
+            state_topic: &field.state_topic,
+            step,
+            unique_id: &unique_id,
+            unit_of_measurement: field.field.unit,
+        };
+        let mut msg = Publish::new(config_topic, serde_json::to_vec(&number).unwrap());
+        msg.set_retain(true).set_qos(QoS::AtLeastOnce);
+        self.client.publish(&msg).await?;
+        // Settings do not expire, so the expire_after is not used
+        self.registered.insert(unique_id, (0, msg));
+        Ok(())
+    }
+
+    /// Publish the discovery information for a setting that is a choice
+    /// between `options`, if it has not been published. The state is taken
+    /// from the sensor for the same field.
+    async fn register_select<'a>(
+        &mut self,
+        field: &DeviceField<'a>,
+        prefix: &str,
+        options: &[&str],
+    ) -> mqtt_async_client::Result<()> {
+        let (unique_id, _, config_topic) = sensor_topics(
+            "select",
+            &format!("sunsniff_{}", field.serial),
+            field.field.group,
+            &format!("{}_setting", field.field.id),
+            &self.topic_prefix,
+            self.topic_layout,
+        );
+        if self.registered.contains_key(&unique_id) {
+            return Ok(());
         }
+        let full_name = format!("{} {}", field.field.group, field.field.name);
+        let command_topic = format!("{prefix}/{}/set/{}", field.serial, field.field.id);
+        let select = Select {
+            command_topic: &command_topic,
+            device: Device {
+                identifiers: (field.serial,),
+                name: None,
+            },
+            entity_category: "config",
+            name: &full_name,
+            object_id: &unique_id,
+            options,
+            state_topic: &field.state_topic,
+            unique_id: &unique_id,
+            value_template: &select_template(options),
+        };
+        let mut msg = Publish::new(config_topic, serde_json::to_vec(&select).unwrap());
+        msg.set_retain(true).set_qos(QoS::AtLeastOnce);
+        self.client.publish(&msg).await?;
+        // Settings do not expire, so the expire_after is not used
+        self.registered.insert(unique_id, (0, msg));
         Ok(())
     }
+
+    /// Publish all the discovery information again. This is needed if Home
+    /// Assistant restarts and the broker did not retain the messages.
+    async fn republish(&self) {
+        info!("Re-publishing discovery information");
+        for (_, msg) in self.registered.values() {
+            self.client
+                .publish(msg)
+                .await
+                .unwrap_or_else(|e| warn!("Re-publishing {} failed: {}", msg.topic(), e));
+        }
+    }
+
+    /// Whether there are any topics to subscribe to
+    fn subscribes(&self) -> bool {
+        self.republish_discovery || self.command_prefix.is_some()
+    }
+
+    /// Extract the serial number from a `<prefix>/<serial>/refresh` topic.
+    /// Only serial numbers routed to this backend are accepted, so that a
+    /// broker cannot poll other inverters.
+    fn refresh_serial<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        let prefix = self.command_prefix.as_deref()?;
+        let serial = topic
+            .strip_prefix(prefix)?
+            .strip_prefix('/')?
+            .strip_suffix("/refresh")?;
+        (!serial.is_empty() && !serial.contains('/') && self.serials.matches(serial))
+            .then_some(serial)
+    }
+
+    async fn handle_message(&self, msg: &ReadResult) {
+        if msg.topic() == self.status_topic() {
+            if self.republish_discovery && msg.payload() == b"online" {
+                self.republish().await;
+            }
+        } else if let Some(serial) = self.refresh_serial(msg.topic()) {
+            info!("Refresh requested for {}", serial);
+            let command = Command::PollNow {
+                serial: serial.to_owned(),
+            };
+            if self.commands.unbounded_send(command).is_err() {
+                warn!("Refresh requested, but the frontend does not support it");
+            }
+        } else if let Some((serial, id)) = self.setting_topic(msg.topic()) {
+            let Some(value) = parse_setting(id, msg.payload()) else {
+                warn!("Ignoring invalid value for {id} on {serial}");
+                return;
+            };
+            let factor = self.setting_factors.get(id).copied().unwrap_or(1.0);
+            match control::setting_command(serial.to_owned(), id, value / factor, "mqtt") {
+                Ok(command) => {
+                    info!("Request to set {id} to {value} on {serial}");
+                    if self.commands.unbounded_send(command).is_err() {
+                        warn!("Setting change requested, but the frontend does not support it");
+                    }
+                }
+                Err(SettingError::Unknown) => warn!("Ignoring request to set unknown setting {id}"),
+                Err(SettingError::OutOfRange) => warn!("Value {value} for {id} is out of range"),
+            }
+        }
+    }
+
+    /// Extract the serial number and setting ID from a setting topic, if
+    /// settings are enabled. Only serial numbers routed to this backend are
+    /// accepted.
+    fn setting_topic<'a>(&self, topic: &'a str) -> Option<(&'a str, &'a str)> {
+        if !self.settings {
+            return None;
+        }
+        let (serial, id) = setting_topic(self.command_prefix.as_deref()?, topic)?;
+        self.serials.matches(serial).then_some((serial, id))
+    }
+
+    async fn subscribe(&mut self) -> mqtt_async_client::Result<()> {
+        let mut topics = vec![];
+        if self.republish_discovery {
+            topics.push(self.status_topic());
+        }
+        if let Some(prefix) = &self.command_prefix {
+            topics.push(format!("{prefix}/+/refresh"));
+            if self.settings {
+                topics.push(format!("{prefix}/+/set/+"));
+            }
+        }
+        let topics = topics
+            .into_iter()
+            .map(|topic_path| SubscribeTopic {
+                qos: QoS::AtLeastOnce,
+                topic_path,
+            })
+            .collect();
+        let result = self.client.subscribe(Subscribe::new(topics)).await?;
+        if result.any_failures() {
+            warn!("Some MQTT subscriptions were rejected by the broker");
+        }
+        Ok(())
+    }
+
+    /// Publish the values in an update (and their discovery information, if
+    /// needed). Returns the number of messages that could not be published.
+    async fn publish_update(&mut self, update: &Update<'_>) -> usize {
+        let mut failed = 0;
+        if let Some(factor) = self.expire_factor {
+            let cadence = self.cadence.entry(update.serial.clone()).or_default();
+            if let Some(expire_after) = cadence.observe(update.timestamp, factor) {
+                info!(
+                    "Setting expire_after for {} to {expire_after}s",
+                    update.serial
+                );
+            }
+        }
+        for (i, (field, value)) in update.iter().enumerate() {
+            let device_field =
+                DeviceField::new(field, &update.serial, &self.topic_prefix, self.topic_layout);
+            let raw = update.raw.as_ref().map(|raw| raw[i].as_slice());
+            let metadata = self.metadata.then_some(&update.metadata);
+            let attributes = Attributes::new(raw, metadata);
+            if let Err(e) = self
+                .register_field(&device_field, !attributes.is_empty())
+                .await
+            {
+                warn!("Registering {} failed: {}", field.id, e);
+                failed += 1;
+            }
+            if let (true, Some(prefix)) = (self.settings, self.command_prefix.clone()) {
+                if let Some(options) = control::setting_options(field.id) {
+                    if let Err(e) = self.register_select(&device_field, &prefix, options).await {
+                        warn!("Registering setting {} failed: {}", field.id, e);
+                        failed += 1;
+                    }
+                } else if let Some(limits) = setting_limits(field) {
+                    let (base, _) = control::setting(field.id).unwrap();
+                    self.setting_factors
+                        .insert(field.id.to_owned(), field.scale / base.scale);
+                    if let Err(e) = self.register_setting(&device_field, &prefix, limits).await {
+                        warn!("Registering setting {} failed: {}", field.id, e);
+                        failed += 1;
+                    }
+                }
+            }
+            if !attributes.is_empty() {
+                let attributes = serde_json::to_vec(&attributes).unwrap();
+                let msg = Publish::new(device_field.attributes_topic.clone(), attributes);
+                if let Err(e) = self.client.publish(&msg).await {
+                    warn!("Sending attributes for {} failed: {}", field.id, e);
+                    failed += 1;
+                }
+            }
+            // Home Assistant does not accept NaN for numeric sensors, so
+            // leave the previous state in place
+            if !value.is_finite() {
+                continue;
+            }
+            let payload = match field.reset {
+                Reset::Daily => {
+                    let reset = self
+                        .resets
+                        .entry(device_field.unique_id.clone())
+                        .or_default();
+                    let state = DailyState {
+                        value,
+                        last_reset: reset.observe(update.timestamp, value, &Local).to_rfc3339(),
+                    };
+                    serde_json::to_vec(&state).unwrap()
+                }
+                Reset::Never => self.format_value(field, value).into_bytes(),
+            };
+            let msg = Publish::new(device_field.state_topic, payload);
+            if let Err(e) = self.client.publish(&msg).await {
+                warn!("Sending update for {} failed: {}", field.id, e);
+                failed += 1;
+            }
+        }
+        failed
+    }
+
+    /// Publish the state of sunsniff itself (and the discovery information
+    /// for its sensors, if they have not been registered)
+    async fn publish_diagnostics(&mut self, hostname: &str) {
+        let device = format!("sunsniff_{}", topic_level(hostname));
+        let device_name = format!("sunsniff @ {hostname}");
+        for sensor in diagnostic_sensors(&stats::snapshot()) {
+            let (unique_id, base, config_topic) = sensor_topics(
+                "sensor",
+                &device,
+                "Diagnostics",
+                &sensor.id,
+                &self.topic_prefix,
+                self.topic_layout,
+            );
+            let state_topic = format!("{base}/state");
+            if !self.registered.contains_key(&unique_id) {
+                let config = Sensor {
+                    device: Device {
+                        identifiers: (&device,),
+                        name: Some(&device_name),
+                    },
+                    device_class: sensor.device_class,
+                    entity_category: Some("diagnostic"),
+                    expire_after: DIAGNOSTICS_EXPIRE_AFTER,
+                    json_attributes_topic: None,
+                    last_reset_value_template: None,
+                    name: &sensor.name,
+                    object_id: &unique_id,
+                    payload_off: None,
+                    payload_on: None,
+                    state_class: sensor.state_class,
+                    state_topic: &state_topic,
+                    unique_id: &unique_id,
+                    unit_of_measurement: sensor.unit,
+                    value_template: None,
+                };
+                let mut msg = Publish::new(config_topic, serde_json::to_vec(&config).unwrap());
+                msg.set_retain(true).set_qos(QoS::AtLeastOnce);
+                if let Err(e) = self.client.publish(&msg).await {
+                    warn!("Registering {} failed: {}", sensor.id, e);
+                    continue;
+                }
+                self.registered
+                    .insert(unique_id, (DIAGNOSTICS_EXPIRE_AFTER, msg));
+            }
+            if let Some(state) = sensor.state {
+                let msg = Publish::new(state_topic, state.into_bytes());
+                if let Err(e) = self.client.publish(&msg).await {
+                    warn!("Sending update for {} failed: {}", sensor.id, e);
+                }
+            }
+        }
+    }
+
+    /// Publish a receiver going down or recovering
+    async fn publish_event(&self, topic: &str, event: &Event) {
+        let msg = Publish::new(topic.to_owned(), serde_json::to_vec(event).unwrap());
+        if let Err(e) = self.client.publish(&msg).await {
+            warn!("Sending notification to {} failed: {}", topic, e);
+        }
+    }
 }
 
 #[async_trait]
 impl Receiver for MqttReceiver {
-    async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+    async fn run<'a>(&mut self, mut receiver: UpdateReceiver<'a>) {
+        if !self.connected {
+            self.client.connect().await.unwrap_or_else(|e| {
+                warn!("Couldn't connect to MQTT broker (will keep trying): {}", e);
+                self.health.down(format_args!("could not connect: {e}"));
+            });
+        }
+        if self.subscribes() {
+            self.subscribe()
+                .await
+                .unwrap_or_else(|e| warn!("Couldn't subscribe to MQTT topics: {}", e));
+        }
+        let mut notifications = self.notifications.take();
+        let mut diagnostics = self
+            .diagnostics
+            .clone()
+            .map(|hostname| (hostname, tokio::time::interval(DIAGNOSTICS_INTERVAL)));
+        loop {
+            tokio::select! {
+                update = receiver.next() => {
+                    let Some(update) = update else { break };
+                    match self.publish_update(&update).await {
+                        0 => self.health.up(0),
+                        failed => self
+                            .health
+                            .down(format_args!("{failed} messages could not be published")),
+                    }
+                }
+                Some(event) = async { notifications.as_mut()?.1.next().await } => {
+                    if let Some((topic, _)) = &notifications {
+                        self.publish_event(topic, &event).await;
+                    }
+                }
+                Some(_) = async { Some(diagnostics.as_mut()?.1.tick().await) } => {
+                    if let Some((hostname, _)) = &diagnostics {
+                        self.publish_diagnostics(hostname).await;
+                    }
+                }
+                msg = self.client.read_subscriptions(), if self.subscribes() => {
+                    match msg {
+                        Ok(msg) => self.handle_message(&msg).await,
+                        Err(e) => {
+                            warn!("Reading from MQTT broker failed: {}", e);
+                            task::sleep(Duration::from_secs(5)).await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn self_test(&mut self, update: Arc<Update<'static>>) -> Result<(), String> {
         self.client
             .connect()
             .await
-            .unwrap_or_else(|e| warn!("Couldn't connect to MQTT broker (will keep trying): {}", e));
-        while let Some(update) = receiver.next().await {
-            for (field, value) in zip(update.fields.iter(), update.values.iter()) {
-                let device_field = DeviceField::new(field, &update.serial);
-                self.register_field(&device_field)
-                    .await
-                    .unwrap_or_else(|e| warn!("Registering {} failed: {}", field.id, e));
-                let payload = value.to_string().as_bytes().to_vec();
-                let msg = Publish::new(device_field.state_topic, payload);
-                self.client
-                    .publish(&msg)
-                    .await
-                    .unwrap_or_else(|e| warn!("Sending update for {} failed: {}", field.id, e));
-            }
+            .map_err(|e| format!("could not connect to the MQTT broker: {e}"))?;
+        self.connected = true;
+        match self.publish_update(&update).await {
+            0 => Ok(()),
+            failed => Err(format!("{failed} messages could not be published")),
         }
     }
 }
 
-#[derive(Deserialize)]
+/// Arrangement of the sensor topics under the prefix
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TopicLayout {
+    /// `sensor/<unique_id>/state`, with one level per sensor
+    #[default]
+    Flat,
+    /// `sensor/sunsniff_<serial>/<group>/<id>/state`, for browsing
+    Grouped,
+}
+
+/// Broker and topic settings for the inverters of one site, overriding
+/// those of the enclosing `[[mqtt]]` section
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Site {
+    pub url: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub topic_prefix: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     pub url: String,
     pub username: Option<String>,
     pub password: Option<String>,
+    /// Home Assistant discovery prefix
+    #[serde(default = "default_topic_prefix")]
+    pub topic_prefix: String,
+    /// Arrangement of the sensor topics
+    #[serde(default)]
+    pub topic_layout: TopicLayout,
+    /// Subscribe to the Home Assistant status topic, and re-publish the
+    /// discovery information when Home Assistant comes online
+    #[serde(default)]
+    pub republish_discovery: bool,
+    /// If set, subscribe to `<command_prefix>/<serial>/refresh` and poll the
+    /// inverter immediately when a message is received
+    pub command_prefix: Option<String>,
+    /// Publish the settings that can be changed as Home Assistant number
+    /// entities, and subscribe to `<command_prefix>/<serial>/set/<id>` to
+    /// change them
+    #[serde(default)]
+    pub settings: bool,
+    /// Time (in seconds) after which Home Assistant marks sensors as
+    /// unavailable if no update arrives
+    #[serde(default = "default_expire_after")]
+    pub expire_after: u32,
+    /// If set, derive `expire_after` from the observed interval between
+    /// updates, multiplied by this factor
+    pub expire_factor: Option<f64>,
+    /// Publish the source, protocol, frame length and decode time of each
+    /// update as sensor attributes
+    #[serde(default)]
+    pub metadata: bool,
+    /// Units to convert values to
+    #[serde(default)]
+    pub units: Units,
+    /// Serial numbers of the inverters to send to this backend (all if not
+    /// given)
+    #[serde(default)]
+    pub serials: Serials,
+    /// Whether to write the time reported by the inverter or the time the
+    /// update was received
+    #[serde(default)]
+    pub timestamps: Timestamps,
+    /// If set, publish a JSON message to this topic whenever any receiver
+    /// goes down or recovers
+    pub notify_topic: Option<String>,
+    /// Publish a Home Assistant device describing sunsniff itself, with its
+    /// uptime, time of the last update, decode errors and receiver queues
+    #[serde(default)]
+    pub diagnostics: bool,
+    /// Overrides for the inverters whose serial numbers match the keys
+    #[serde(default)]
+    pub sites: BTreeMap<String, Site>,
+    /// Key in `sites` from which this configuration was derived
+    #[serde(skip)]
+    site: Option<String>,
+}
+
+fn default_topic_prefix() -> String {
+    "homeassistant".to_owned()
+}
+
+fn default_expire_after() -> u32 {
+    600
+}
+
+impl Config {
+    /// Name used to refer to the backend in log messages
+    pub fn name(&self) -> String {
+        match &self.site {
+            Some(site) => format!("{} ({site})", self.url),
+            None => self.url.clone(),
+        }
+    }
+
+    /// Split the configuration into one per site, each with the overrides
+    /// applied and only receiving updates for the site. The last one uses
+    /// the default settings for the remaining inverters.
+    pub fn split_sites(&self) -> Vec<Config> {
+        let mut configs = vec![];
+        for (pattern, site) in self.sites.iter() {
+            configs.push(Config {
+                url: site.url.clone().unwrap_or_else(|| self.url.clone()),
+                username: site.username.clone().or_else(|| self.username.clone()),
+                password: site.password.clone().or_else(|| self.password.clone()),
+                topic_prefix: site
+                    .topic_prefix
+                    .clone()
+                    .unwrap_or_else(|| self.topic_prefix.clone()),
+                serials: Serials::new(vec![pattern.clone()]),
+                // Only the default configuration publishes notifications
+                // and diagnostics, so that each is only published once
+                notify_topic: None,
+                diagnostics: false,
+                sites: BTreeMap::new(),
+                site: Some(pattern.clone()),
+                ..self.clone()
+            });
+        }
+        configs.push(Config {
+            serials: self.serials.clone().exclude(self.sites.keys().cloned()),
+            sites: BTreeMap::new(),
+            ..self.clone()
+        });
+        configs
+    }
+
+    /// Check the parts of the configuration that serde cannot
+    pub fn check(&self) -> Result<(), String> {
+        if self.settings && self.command_prefix.is_none() {
+            return Err("MQTT settings requires command_prefix".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Create a client for the broker
+    pub fn client(&self) -> mqtt_async_client::Result<Client> {
+        Client::builder()
+            .set_url_string(&self.url)?
+            .set_username(self.username.clone())
+            .set_password(self.password.as_ref().map(|s| s.as_bytes().to_vec()))
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::monitor::Status;
+
+    const SECOND: i64 = 1_000_000_000;
+
+    #[test]
+    fn test_cadence() {
+        let mut cadence = Cadence::default();
+        assert_eq!(cadence.observe(0, 3.0), None);
+        assert_eq!(cadence.observe(10 * SECOND, 3.0), Some(30));
+        // Small changes are ignored
+        assert_eq!(cadence.observe(21 * SECOND, 3.0), None);
+        // Duplicates are ignored
+        assert_eq!(cadence.observe(21 * SECOND, 3.0), None);
+        // Moderate changes are followed, but smoothed
+        assert_eq!(cadence.observe(51 * SECOND, 3.0), Some(46));
+    }
+
+    #[test]
+    fn test_cadence_outage() {
+        let mut cadence = Cadence::default();
+        assert_eq!(cadence.observe(0, 3.0), None);
+        assert_eq!(cadence.observe(10 * SECOND, 3.0), Some(30));
+        assert_eq!(cadence.observe(20 * SECOND, 3.0), None);
+        // An outage does not change expire_after
+        assert_eq!(cadence.observe(3620 * SECOND, 3.0), None);
+        assert_eq!(cadence.observe(3630 * SECOND, 3.0), None);
+        // A lasting change in the interval is followed
+        assert_eq!(cadence.observe(3730 * SECOND, 3.0), None);
+        assert_eq!(cadence.observe(3830 * SECOND, 3.0), None);
+        assert_eq!(cadence.observe(3930 * SECOND, 3.0), Some(300));
+    }
+
+    #[test]
+    fn test_daily_reset() {
+        const HOUR: i64 = 3600 * SECOND;
+        // 2024-03-01 22:00 in UTC+2
+        let start = 1709323200 * SECOND;
+        let tz = FixedOffset::east_opt(7200).unwrap();
+        let midnight = |day| format!("2024-03-{day:02}T00:00:00+02:00");
+        let mut reset = DailyReset::default();
+        assert_eq!(reset.observe(start, 10.0, &tz).to_rfc3339(), midnight(1));
+        assert_eq!(
+            reset.observe(start + HOUR, 12.0, &tz).to_rfc3339(),
+            midnight(1)
+        );
+        // Just after midnight, but the inverter hasn't reset yet
+        assert_eq!(
+            reset
+                .observe(start + 2 * HOUR + 60 * SECOND, 12.5, &tz)
+                .to_rfc3339(),
+            midnight(1)
+        );
+        // The value drops
+        assert_eq!(
+            reset
+                .observe(start + 2 * HOUR + 600 * SECOND, 0.1, &tz)
+                .to_rfc3339(),
+            midnight(2)
+        );
+        assert_eq!(
+            reset.observe(start + 5 * HOUR, 3.0, &tz).to_rfc3339(),
+            midnight(2)
+        );
+
+        // A value that stays at zero moves on after the grace period
+        let mut reset = DailyReset::default();
+        reset.observe(start, 0.0, &tz);
+        assert_eq!(
+            reset
+                .observe(start + 2 * HOUR + 60 * SECOND, 0.0, &tz)
+                .to_rfc3339(),
+            midnight(1)
+        );
+        assert_eq!(
+            reset.observe(start + 3 * HOUR, 0.0, &tz).to_rfc3339(),
+            midnight(2)
+        );
+    }
+
+    #[test]
+    fn test_daily_state() {
+        let state = DailyState {
+            value: 1.5,
+            last_reset: "2024-03-02T00:00:00+02:00".to_owned(),
+        };
+        assert_eq!(
+            serde_json::to_string(&state).unwrap(),
+            r#"{"value":1.5,"last_reset":"2024-03-02T00:00:00+02:00"}"#
+        );
+    }
+
+    #[test]
+    fn test_setting_topic() {
+        assert_eq!(
+            setting_topic("sunsniff", "sunsniff/1234/set/inverter_program_soc_1"),
+            Some(("1234", "inverter_program_soc_1"))
+        );
+        assert_eq!(setting_topic("sunsniff", "sunsniff/1234/refresh"), None);
+        assert_eq!(setting_topic("sunsniff", "sunsniff//set/x"), None);
+        assert_eq!(setting_topic("sunsniff", "sunsniff/1234/set/"), None);
+        assert_eq!(setting_topic("sunsniff", "sunsniff/12/34/set/x"), None);
+        assert_eq!(setting_topic("sunsniff", "other/1234/set/x"), None);
+    }
+
+    #[cfg(all(feature = "modbus", not(feature = "read_only")))]
+    #[test]
+    fn test_setting_limits() {
+        let (soc, _) = control::setting("inverter_program_soc_1").unwrap();
+        assert_eq!(setting_limits(soc), Some((0.0, 100.0, 1.0)));
+        let (power, _) = control::setting("inverter_program_power_1").unwrap();
+        let kw = Field {
+            unit: "kW",
+            scale: power.scale * 0.001,
+            ..*power
+        };
+        assert_eq!(setting_limits(&kw), Some((0.0, 32.767, 0.001)));
+        let (time, _) = control::setting("inverter_program_time_1").unwrap();
+        assert_eq!(setting_limits(time), None);
+        let (mode, _) = control::setting("inverter_work_mode").unwrap();
+        assert_eq!(setting_limits(mode), None);
+    }
+
+    #[cfg(all(feature = "modbus", not(feature = "read_only")))]
+    #[test]
+    fn test_parse_setting() {
+        assert_eq!(
+            parse_setting("inverter_program_soc_1", b" 50\n"),
+            Some(50.0)
+        );
+        assert_eq!(
+            parse_setting("inverter_program_soc_1", b"Selling first"),
+            None
+        );
+        assert_eq!(
+            parse_setting("inverter_work_mode", b"Limited to home"),
+            Some(2.0)
+        );
+        assert_eq!(parse_setting("inverter_work_mode", b"1"), Some(1.0));
+        assert_eq!(parse_setting("inverter_work_mode", b"Other"), None);
+    }
+
+    #[test]
+    fn test_select_template() {
+        assert_eq!(
+            select_template(&["A", "B"]),
+            r#"{{ ["A","B"][value | int] | default(value) }}"#
+        );
+    }
+
+    #[test]
+    fn test_topic_layout() {
+        let field = Field {
+            field_type: FieldType::StateOfCharge,
+            group: "Battery",
+            name: "SOC",
+            id: "battery_soc",
+            scale: 1.0,
+            bias: 0.0,
+            unit: "%",
+            sum_of: &[],
+            word_order: crate::fields::WordOrder::Little,
+            reset: crate::fields::Reset::Never,
+        };
+        let flat = DeviceField::new(&field, "1234", "homeassistant", TopicLayout::Flat);
+        assert_eq!(
+            flat.state_topic,
+            "homeassistant/sensor/sunsniff_1234_battery_soc/state"
+        );
+        assert_eq!(
+            flat.config_topic,
+            "homeassistant/sensor/sunsniff_1234_battery_soc/config"
+        );
+        let grouped = DeviceField::new(&field, "1234", "homeassistant", TopicLayout::Grouped);
+        assert_eq!(grouped.unique_id, flat.unique_id);
+        assert_eq!(
+            grouped.state_topic,
+            "homeassistant/sensor/sunsniff_1234/Battery/battery_soc/state"
+        );
+        assert_eq!(
+            grouped.attributes_topic,
+            "homeassistant/sensor/sunsniff_1234/Battery/battery_soc/attributes"
+        );
+        assert_eq!(
+            grouped.config_topic,
+            "homeassistant/sensor/sunsniff_1234/battery_soc/config"
+        );
+        assert_eq!(topic_level("Battery 1/2+#"), "Battery_1_2__");
+
+        let field = Field {
+            field_type: FieldType::Connectivity,
+            group: "Grid",
+            name: "Present",
+            id: "grid_present",
+            unit: "",
+            ..field
+        };
+        let flat = DeviceField::new(&field, "1234", "homeassistant", TopicLayout::Flat);
+        assert_eq!(
+            flat.config_topic,
+            "homeassistant/binary_sensor/sunsniff_1234_grid_present/config"
+        );
+    }
+
+    #[test]
+    fn test_split_sites() {
+        let config: Config = toml::from_str(
+            r#"
+            url = "mqtt://office.example.com"
+            username = "office"
+            password = "secret"
+            serials = ["2*"]
+
+            notify_topic = "sunsniff/notify"
+            diagnostics = true
+
+            [sites."2101*"]
+            url = "mqtt://site-a.example.com"
+            username = "site_a"
+            password = "site_a_secret"
+
+            [sites."2201*"]
+            topic_prefix = "site_b"
+            "#,
+        )
+        .unwrap();
+        let configs = config.split_sites();
+        assert_eq!(configs.len(), 3);
+
+        let site_a = &configs[0];
+        assert_eq!(site_a.name(), "mqtt://site-a.example.com (2101*)");
+        assert_eq!(site_a.username.as_deref(), Some("site_a"));
+        assert_eq!(site_a.password.as_deref(), Some("site_a_secret"));
+        assert_eq!(site_a.topic_prefix, "homeassistant");
+        assert!(site_a.serials.matches("2101234567"));
+        assert!(!site_a.serials.matches("2201234567"));
+        assert_eq!(site_a.notify_topic, None);
+        assert!(!site_a.diagnostics);
+
+        let site_b = &configs[1];
+        assert_eq!(site_b.url, "mqtt://office.example.com");
+        assert_eq!(site_b.username.as_deref(), Some("office"));
+        assert_eq!(site_b.topic_prefix, "site_b");
+        assert!(site_b.serials.matches("2201234567"));
+
+        let rest = &configs[2];
+        assert_eq!(rest.name(), "mqtt://office.example.com");
+        assert!(rest.sites.is_empty());
+        assert_eq!(rest.notify_topic.as_deref(), Some("sunsniff/notify"));
+        assert!(rest.diagnostics);
+        assert!(!rest.serials.matches("2101234567"));
+        assert!(!rest.serials.matches("2201234567"));
+        assert!(rest.serials.matches("2301234567"));
+        assert!(!rest.serials.matches("1234567890"));
+    }
+
+    #[test]
+    fn test_diagnostic_sensors() {
+        let snapshot = Snapshot {
+            uptime: Duration::from_secs_f64(3600.7),
+            last_update: Some(1709323200 * SECOND),
+            decode_errors: 3,
+            receivers: vec![Status {
+                name: "mqtt:mqtt://localhost".to_owned(),
+                sent: 10,
+                queued: 2,
+                processing: None,
+                slow: false,
+            }],
+        };
+        let sensors = diagnostic_sensors(&snapshot);
+        let states: Vec<_> = sensors
+            .iter()
+            .map(|sensor| (sensor.id.as_str(), sensor.state.as_deref()))
+            .collect();
+        assert_eq!(
+            states,
+            [
+                ("uptime", Some("3600")),
+                ("last_update", Some("2024-03-01T20:00:00+00:00")),
+                ("decode_errors", Some("3")),
+                ("queue_mqtt_mqtt___localhost", Some("2")),
+            ]
+        );
+        assert_eq!(sensors[3].name, "Queue mqtt:mqtt://localhost");
+
+        let snapshot = Snapshot {
+            last_update: None,
+            receivers: vec![],
+            ..snapshot
+        };
+        let sensors = diagnostic_sensors(&snapshot);
+        assert_eq!(sensors.len(), 3);
+        assert_eq!(sensors[1].state, None);
+    }
+
+    #[test]
+    fn test_attributes() {
+        let to_json = |attributes: &Attributes<'_>| serde_json::to_string(attributes).unwrap();
+        assert!(Attributes::new(None, None).is_empty());
+        let raw = [1u16, 2];
+        assert_eq!(
+            to_json(&Attributes::new(Some(&raw), None)),
+            r#"{"raw":[1,2]}"#
+        );
+        let metadata = Metadata {
+            source: Some("pcap:eth0".to_owned()),
+            protocol: Some("sunsynk"),
+            frame_length: Some(292),
+            decode_duration: Some(Duration::from_millis(2)),
+            sequence: Some(7),
+            ..Default::default()
+        };
+        let attributes = Attributes::new(None, Some(&metadata));
+        assert!(!attributes.is_empty());
+        assert_eq!(
+            to_json(&attributes),
+            format!(
+                r#"{{"source":"pcap:eth0","protocol":"sunsynk","frame_length":292,"decode_seconds":0.002,"sequence":7,"fields_hash":"{TABLE_HASH}"}}"#
+            )
+        );
+    }
 }
@@ -0,0 +1,289 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Capture with a Linux `AF_PACKET` socket, as an alternative to libpcap
+//! that has no C dependencies (for static builds). Since there is no pcap
+//! filter compiler, the socket is given a classic BPF program that only
+//! accepts TCP over IPv4, optionally to or from a single host.
+
+use futures::prelude::*;
+use std::ffi::CString;
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::unix::AsyncFd;
+
+use super::Codec;
+use crate::receiver::Update;
+
+/// Largest packet that is captured in full
+const SNAPLEN: u32 = 65535;
+
+/// Classic BPF instruction without a branch
+fn stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+/// Classic BPF conditional jump, with the number of instructions to skip
+/// if the condition is true or false
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// Filter program accepting TCP over IPv4 in Ethernet frames, to or from
+/// `host` if given (like the pcap expression `tcp and host <host>`)
+fn filter_program(host: Option<Ipv4Addr>) -> Vec<libc::sock_filter> {
+    use libc::{BPF_ABS, BPF_B, BPF_H, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+    const ETHERTYPE_OFFSET: u32 = 12;
+    const ETHERTYPE_IPV4: u32 = 0x0800;
+    const PROTOCOL_OFFSET: u32 = 14 + 9;
+    const SOURCE_OFFSET: u32 = 14 + 12;
+    const DESTINATION_OFFSET: u32 = 14 + 16;
+
+    let mut program = vec![
+        stmt(BPF_LD | BPF_H | BPF_ABS, ETHERTYPE_OFFSET),
+        // Jump to reject (the offset is filled in below)
+        jump(BPF_JMP | BPF_JEQ | BPF_K, ETHERTYPE_IPV4, 0, 0),
+        stmt(BPF_LD | BPF_B | BPF_ABS, PROTOCOL_OFFSET),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, libc::IPPROTO_TCP as u32, 0, 0),
+    ];
+    if let Some(host) = host {
+        let host = u32::from(host);
+        program.extend([
+            stmt(BPF_LD | BPF_W | BPF_ABS, SOURCE_OFFSET),
+            // Jump to accept
+            jump(BPF_JMP | BPF_JEQ | BPF_K, host, 2, 0),
+            stmt(BPF_LD | BPF_W | BPF_ABS, DESTINATION_OFFSET),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, host, 0, 0),
+        ]);
+    }
+    program.extend([stmt(BPF_RET | BPF_K, SNAPLEN), stmt(BPF_RET | BPF_K, 0)]);
+    // Point the failed checks at the final (reject) instruction
+    let reject = program.len() - 1;
+    for (i, insn) in program.iter_mut().enumerate() {
+        if insn.code == (BPF_JMP | BPF_JEQ | BPF_K) as u16 && insn.jt == 0 {
+            insn.jf = (reject - i - 1) as u8;
+        }
+    }
+    program
+}
+
+/// Raw `AF_PACKET` socket bound to a single interface, with a filter
+struct PacketSocket {
+    fd: AsyncFd<OwnedFd>,
+    buffer: Vec<u8>,
+}
+
+impl PacketSocket {
+    fn open(interface: &str, host: Option<Ipv4Addr>) -> io::Result<Self> {
+        let name = CString::new(interface)?;
+        // SAFETY: name is a valid NUL-terminated string
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The socket does not receive any packets until it is bound to a
+        // protocol, which is only done once the filter is attached.
+        // SAFETY: socket has no memory-safety preconditions
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd is a newly-created socket that nothing else owns
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut program = filter_program(host);
+        let fprog = libc::sock_fprog {
+            len: program.len() as libc::c_ushort,
+            filter: program.as_mut_ptr(),
+        };
+        // SAFETY: fprog points to a valid program, and the length matches it
+        let ret = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_ATTACH_FILTER,
+                &fprog as *const libc::sock_fprog as *const libc::c_void,
+                std::mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: sockaddr_ll is plain data, for which zero is valid
+        let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as libc::c_ushort;
+        addr.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+        addr.sll_ifindex = ifindex as libc::c_int;
+        // SAFETY: addr is a valid sockaddr_ll and the length matches it
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+            buffer: vec![0; SNAPLEN as usize],
+        })
+    }
+
+    /// Receive the next packet, returning its contents
+    async fn recv(&mut self) -> io::Result<&[u8]> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let buffer = &mut self.buffer;
+            let result = guard.try_io(|fd| {
+                // SAFETY: buffer is writable for its length
+                let n = unsafe {
+                    libc::recv(
+                        fd.as_raw_fd(),
+                        buffer.as_mut_ptr() as *mut libc::c_void,
+                        buffer.len(),
+                        0,
+                    )
+                };
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            });
+            match result {
+                Ok(n) => return Ok(&self.buffer[..n?]),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// Create a stream of updates from the packets captured on `interface`
+pub(super) fn capture(
+    interface: &str,
+    host: Option<Ipv4Addr>,
+    codec: Codec,
+) -> io::Result<impl Stream<Item = Arc<Update<'static>>>> {
+    let socket = PacketSocket::open(interface, host)?;
+    Ok(stream::unfold(
+        (socket, codec),
+        |(mut socket, mut codec)| async move {
+            loop {
+                let data = match socket.recv().await {
+                    Ok(data) => data,
+                    Err(err) => {
+                        log::error!("Error receiving packet: {err}");
+                        return None;
+                    }
+                };
+                // The kernel timestamps the packets, but only reports the
+                // time on request, and the delay to here is negligible
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let sec = now.as_secs() as i64;
+                let usec = i64::from(now.subsec_micros());
+                if let Some(update) = codec.decode_packet(data, sec, usec) {
+                    return Some((update, (socket, codec)));
+                }
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Run a classic BPF program (using only the instructions generated by
+    /// [filter_program]) on a packet, returning the number of bytes accepted
+    fn run(program: &[libc::sock_filter], packet: &[u8]) -> u32 {
+        let mut acc = 0u32;
+        let mut pc = 0;
+        loop {
+            let insn = &program[pc];
+            let code = insn.code as u32;
+            let k = insn.k as usize;
+            match code {
+                0x28 => acc = u16::from_be_bytes([packet[k], packet[k + 1]]).into(),
+                0x30 => acc = packet[k].into(),
+                0x20 => acc = u32::from_be_bytes(packet[k..k + 4].try_into().unwrap()),
+                0x15 => {
+                    let skip = if acc == insn.k { insn.jt } else { insn.jf };
+                    pc += skip as usize;
+                }
+                0x06 => return insn.k,
+                _ => panic!("unexpected instruction {code:#x}"),
+            }
+            pc += 1;
+        }
+    }
+
+    /// Minimal Ethernet frame with an IPv4 header
+    fn frame(ethertype: u16, protocol: u8, src: [u8; 4], dst: [u8; 4]) -> Vec<u8> {
+        let mut frame = vec![0; 14 + 20];
+        frame[12..14].copy_from_slice(&ethertype.to_be_bytes());
+        frame[14 + 9] = protocol;
+        frame[14 + 12..14 + 16].copy_from_slice(&src);
+        frame[14 + 16..14 + 20].copy_from_slice(&dst);
+        frame
+    }
+
+    #[test]
+    fn test_filter_program() {
+        let dongle = [192, 168, 0, 21];
+        let other = [192, 168, 0, 1];
+        let any_tcp = filter_program(None);
+        let host_tcp = filter_program(Some(Ipv4Addr::from(dongle)));
+        let tcp = frame(0x0800, 6, dongle, other);
+        assert_eq!(run(&any_tcp, &tcp), SNAPLEN);
+        assert_eq!(run(&host_tcp, &tcp), SNAPLEN);
+        let reply = frame(0x0800, 6, other, dongle);
+        assert_eq!(run(&host_tcp, &reply), SNAPLEN);
+        let unrelated = frame(0x0800, 6, other, other);
+        assert_eq!(run(&any_tcp, &unrelated), SNAPLEN);
+        assert_eq!(run(&host_tcp, &unrelated), 0);
+        let udp = frame(0x0800, 17, dongle, other);
+        assert_eq!(run(&any_tcp, &udp), 0);
+        assert_eq!(run(&host_tcp, &udp), 0);
+        let ipv6 = frame(0x86dd, 6, dongle, other);
+        assert_eq!(run(&any_tcp, &ipv6), 0);
+        assert_eq!(run(&host_tcp, &ipv6), 0);
+    }
+}
@@ -0,0 +1,488 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Privilege separation for the pcap frontend.
+//!
+//! `sunsniff capture` runs a small process that only captures packets, so
+//! that it is the only process that needs the privileges to do so. It
+//! forwards the packets over a Unix socket to the main process (configured
+//! with `socket` in the `[pcap]` section), which decodes them and runs the
+//! backends without those privileges.
+//!
+//! The capture process first sends a hello message, followed by one message
+//! per packet. All integers are little-endian.
+//!
+//! - Hello: the magic bytes `SNSF`, a version byte (currently 1), and the
+//!   name of the capture device as a u16 length followed by UTF-8 bytes.
+//! - Packet: the capture time as an i64 number of seconds and a u32 number
+//!   of microseconds, followed by the packet data as a u32 length and the
+//!   bytes. Packets are always Ethernet frames; those captured on Linux's
+//!   `any` device are converted.
+//!
+//! The capture process can switch to an unprivileged user once the device is
+//! open, so that only opening it needs the privileges.
+
+use futures::prelude::*;
+use log::{info, warn};
+use pcap::{Active, Capture, Device, Linktype};
+use std::error::Error;
+use std::ffi::CString;
+use std::io::{self, BufWriter, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+use tokio::net::UnixListener;
+
+use super::Codec;
+use crate::receiver::Update;
+
+const MAGIC: &[u8; 4] = b"SNSF";
+const VERSION: u8 = 1;
+/// Largest packet that will be accepted, to detect a corrupt stream
+const MAX_PACKET: u32 = 262144;
+/// Minimum time between attempts to connect to the main process
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Length of the header of Linux "cooked" captures, which replaces the
+/// Ethernet header on the `any` device
+const SLL_HEADER: usize = 16;
+/// Length of an Ethernet header (without a VLAN tag)
+const ETHERNET_HEADER: usize = 14;
+
+/// A packet received from the capture process
+#[derive(Debug, PartialEq, Eq)]
+struct CapturedPacket {
+    sec: i64,
+    usec: u32,
+    data: Vec<u8>,
+}
+
+fn write_hello(writer: &mut impl Write, device: &str) -> io::Result<()> {
+    let length = u16::try_from(device.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "device name is too long"))?;
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    writer.write_all(&length.to_le_bytes())?;
+    writer.write_all(device.as_bytes())
+}
+
+fn write_packet(writer: &mut impl Write, sec: i64, usec: u32, data: &[u8]) -> io::Result<()> {
+    // Packets are limited by the snapshot length, so this cannot overflow
+    let length = data.len() as u32;
+    writer.write_all(&sec.to_le_bytes())?;
+    writer.write_all(&usec.to_le_bytes())?;
+    writer.write_all(&length.to_le_bytes())?;
+    writer.write_all(data)
+}
+
+/// Convert a Linux "cooked" capture to an Ethernet frame, with zero MAC
+/// addresses. Returns `None` if the packet is truncated.
+fn sll_to_ethernet(data: &[u8]) -> Option<Vec<u8>> {
+    let payload = data.get(SLL_HEADER..)?;
+    let mut frame = Vec::with_capacity(ETHERNET_HEADER + payload.len());
+    frame.extend_from_slice(&[0u8; 12]);
+    // The protocol type is at the same place at the end of both headers
+    frame.extend_from_slice(&data[SLL_HEADER - 2..SLL_HEADER]);
+    frame.extend_from_slice(payload);
+    Some(frame)
+}
+
+/// Convert the return value of a libc function that returns 0 on success
+fn check_libc(ret: libc::c_int) -> io::Result<()> {
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Size of the buffer for the strings in the results of `getpwnam_r` and
+/// `getgrnam_r`
+const LOOKUP_BUFFER: usize = 16384;
+
+/// Look up a user by name, returning their user ID and primary group ID
+fn lookup_user(name: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = CString::new(name)?;
+    // SAFETY: passwd is plain old data
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER];
+    let mut result = std::ptr::null_mut();
+    // SAFETY: the pointers are valid for the duration of the call
+    let err = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if err != 0 {
+        return Err(io::Error::from_raw_os_error(err));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown user {name:?}"),
+        ));
+    }
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+/// Look up a group by name, returning its ID
+pub(super) fn lookup_group(name: &str) -> io::Result<libc::gid_t> {
+    let c_name = CString::new(name)?;
+    // SAFETY: group is plain old data
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER];
+    let mut result = std::ptr::null_mut();
+    // SAFETY: the pointers are valid for the duration of the call
+    let err = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut group,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if err != 0 {
+        return Err(io::Error::from_raw_os_error(err));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown group {name:?}"),
+        ));
+    }
+    Ok(group.gr_gid)
+}
+
+/// Switch to `user` (and their primary group, unless `group` is given) and
+/// drop the supplementary groups. With only `group`, just the group is
+/// changed.
+fn drop_privileges(user: Option<&str>, group: Option<&str>) -> io::Result<()> {
+    let (uid, gid) = match (user, group) {
+        (None, None) => return Ok(()),
+        (Some(user), group) => {
+            let (uid, gid) = lookup_user(user)?;
+            (Some(uid), group.map_or(Ok(gid), lookup_group)?)
+        }
+        (None, Some(group)) => (None, lookup_group(group)?),
+    };
+    // The groups must be changed first, while the process still may
+    // SAFETY: these only take integers and a pointer to a single gid
+    unsafe {
+        check_libc(libc::setgroups(1, &gid))?;
+        check_libc(libc::setgid(gid))?;
+        if let Some(uid) = uid {
+            check_libc(libc::setuid(uid))?;
+        }
+    }
+    info!(
+        "Dropped privileges (uid {}, gid {gid})",
+        // SAFETY: getuid cannot fail
+        unsafe { libc::getuid() }
+    );
+    Ok(())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read the hello message, returning the device name
+async fn read_hello(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<String> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).await?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a sunsniff capture stream"));
+    }
+    let version = reader.read_u8().await?;
+    if version != VERSION {
+        return Err(invalid_data(&format!(
+            "unsupported capture protocol version {version}"
+        )));
+    }
+    let mut device = vec![0u8; reader.read_u16_le().await? as usize];
+    reader.read_exact(&mut device).await?;
+    String::from_utf8(device).map_err(|_| invalid_data("device name is not valid UTF-8"))
+}
+
+/// Read a packet message, returning `None` if the capture process has
+/// disconnected
+async fn read_packet(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<CapturedPacket>> {
+    let sec = match reader.read_i64_le().await {
+        Ok(sec) => sec,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let usec = reader.read_u32_le().await?;
+    let length = reader.read_u32_le().await?;
+    if length > MAX_PACKET {
+        return Err(invalid_data(&format!(
+            "packet of {length} bytes is too large"
+        )));
+    }
+    let mut data = vec![0u8; length as usize];
+    reader.read_exact(&mut data).await?;
+    Ok(Some(CapturedPacket { sec, usec, data }))
+}
+
+/// Sends packets to the main process, connecting (and reconnecting) as
+/// needed. Packets are dropped while it is not connected.
+struct Forwarder<'a> {
+    socket: &'a Path,
+    device: &'a str,
+    stream: Option<BufWriter<UnixStream>>,
+    last_attempt: Option<Instant>,
+}
+
+impl<'a> Forwarder<'a> {
+    fn new(socket: &'a Path, device: &'a str) -> Self {
+        Self {
+            socket,
+            device,
+            stream: None,
+            last_attempt: None,
+        }
+    }
+
+    fn connect(&mut self) -> io::Result<BufWriter<UnixStream>> {
+        let mut stream = BufWriter::new(UnixStream::connect(self.socket)?);
+        write_hello(&mut stream, self.device)?;
+        stream.flush()?;
+        info!("Connected to {}", self.socket.display());
+        Ok(stream)
+    }
+
+    /// Send a packet, given its capture time (the type of the seconds varies
+    /// by platform)
+    fn send(&mut self, sec: impl Into<i64>, usec: u32, data: &[u8]) {
+        if self.stream.is_none() {
+            if self
+                .last_attempt
+                .is_some_and(|last| last.elapsed() < RETRY_DELAY)
+            {
+                return;
+            }
+            self.last_attempt = Some(Instant::now());
+            match self.connect() {
+                Ok(stream) => self.stream = Some(stream),
+                Err(err) => {
+                    warn!("Couldn't connect to {}: {err}", self.socket.display());
+                    return;
+                }
+            }
+        }
+        if let Some(stream) = &mut self.stream {
+            if let Err(err) =
+                write_packet(stream, sec.into(), usec, data).and_then(|_| stream.flush())
+            {
+                warn!("Lost connection to {}: {err}", self.socket.display());
+                self.stream = None;
+            }
+        }
+    }
+}
+
+/// Options of the `capture` subcommand
+pub struct CaptureArgs<'a> {
+    /// Network device to capture
+    pub device: &'a str,
+    /// Additional pcap filter expression
+    pub filter: Option<&'a str>,
+    /// Unix socket on which the main process is listening
+    pub socket: &'a Path,
+    /// User to switch to once the device is open
+    pub user: Option<&'a str>,
+    /// Group to switch to once the device is open
+    pub group: Option<&'a str>,
+}
+
+/// Implementation of the `capture` subcommand: capture packets from the
+/// device and forward them to the main process. This only returns if there
+/// is an error capturing packets.
+pub fn run(args: &CaptureArgs) -> Result<(), Box<dyn Error>> {
+    let mut cap: Capture<Active> = Capture::from_device(Device::from(args.device))?
+        .immediate_mode(true)
+        .open()?;
+    cap.filter(&super::full_filter(args.filter, None), true)?;
+    // The `any` device only supports Linux "cooked" captures
+    let cooked = args.device == "any";
+    if !cooked {
+        cap.set_datalink(Linktype::ETHERNET)?;
+    }
+    drop_privileges(args.user, args.group)?;
+    let mut forwarder = Forwarder::new(args.socket, args.device);
+    loop {
+        let packet = match cap.next_packet() {
+            Ok(packet) => packet,
+            Err(pcap::Error::TimeoutExpired) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let ts = &packet.header.ts;
+        if cooked {
+            if let Some(frame) = sll_to_ethernet(packet.data) {
+                forwarder.send(ts.tv_sec, ts.tv_usec as u32, &frame);
+            }
+        } else {
+            forwarder.send(ts.tv_sec, ts.tv_usec as u32, packet.data);
+        }
+    }
+}
+
+/// Listen on `path` for the capture process, returning a stream of the
+/// decoded updates. Only one capture process is served at a time. Only those
+/// allowed by `mode` and `group` may connect and inject packets.
+pub(super) fn listen(
+    path: &Path,
+    mode: u32,
+    group: Option<&str>,
+    codec: Codec,
+) -> io::Result<impl Stream<Item = Arc<Update<'static>>>> {
+    let group = group.map(lookup_group).transpose()?;
+    let listener = crate::unix::bind(path, mode, group)?;
+    let path = path.to_owned();
+    Ok(stream::unfold(
+        (listener, None, codec),
+        move |(listener, mut conn, mut codec)| {
+            let path = path.clone();
+            async move {
+                loop {
+                    let Some(reader) = &mut conn else {
+                        conn = accept(&listener, &path, &mut codec).await;
+                        continue;
+                    };
+                    match read_packet(reader).await {
+                        Ok(Some(packet)) => {
+                            if let Some(update) =
+                                codec.decode_packet(&packet.data, packet.sec, packet.usec)
+                            {
+                                return Some((update, (listener, conn, codec)));
+                            }
+                        }
+                        Ok(None) => {
+                            info!("Capture process disconnected");
+                            conn = None;
+                        }
+                        Err(err) => {
+                            warn!("Error reading from capture process: {err}");
+                            conn = None;
+                        }
+                    }
+                }
+            }
+        },
+    ))
+}
+
+/// Wait for the capture process to connect, and read its hello message
+async fn accept(
+    listener: &UnixListener,
+    path: &Path,
+    codec: &mut Codec,
+) -> Option<BufReader<tokio::net::UnixStream>> {
+    let stream = match listener.accept().await {
+        Ok((stream, _)) => stream,
+        Err(err) => {
+            warn!("Error accepting connection on {}: {err}", path.display());
+            tokio::time::sleep(RETRY_DELAY).await;
+            return None;
+        }
+    };
+    let mut reader = BufReader::new(stream);
+    match read_hello(&mut reader).await {
+        Ok(device) => {
+            info!("Capture process connected (device {device})");
+            codec.source = format!("pcap:{device}");
+            Some(reader)
+        }
+        Err(err) => {
+            warn!("Invalid hello from capture process: {err}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_protocol() {
+        let mut buffer = vec![];
+        write_hello(&mut buffer, "eth0").unwrap();
+        write_packet(&mut buffer, 1667629966, 123456, &[1, 2, 3]).unwrap();
+        write_packet(&mut buffer, 1667629967, 0, &[]).unwrap();
+        let mut reader = buffer.as_slice();
+        assert_eq!(read_hello(&mut reader).await.unwrap(), "eth0");
+        assert_eq!(
+            read_packet(&mut reader).await.unwrap(),
+            Some(CapturedPacket {
+                sec: 1667629966,
+                usec: 123456,
+                data: vec![1, 2, 3]
+            })
+        );
+        assert_eq!(
+            read_packet(&mut reader).await.unwrap(),
+            Some(CapturedPacket {
+                sec: 1667629967,
+                usec: 0,
+                data: vec![]
+            })
+        );
+        assert_eq!(read_packet(&mut reader).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_sll_to_ethernet() {
+        let mut packet = vec![0, 0, 0, 1, 0, 6, 1, 2, 3, 4, 5, 6, 0, 0, 0x08, 0x00];
+        packet.extend_from_slice(&[0x45, 0x00]);
+        assert_eq!(
+            sll_to_ethernet(&packet),
+            Some(vec![
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x08, 0x00, 0x45, 0x00
+            ])
+        );
+        assert_eq!(sll_to_ethernet(&packet[..15]), None);
+    }
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert!(lookup_user("no-such-user-for-sunsniff").is_err());
+        assert!(lookup_group("no-such-group-for-sunsniff").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bad_stream() {
+        let mut reader: &[u8] = b"GET / HTTP/1.1\r\n";
+        assert!(read_hello(&mut reader).await.is_err());
+
+        let mut buffer = vec![];
+        write_packet(&mut buffer, 0, 0, &[]).unwrap();
+        buffer[12..16].copy_from_slice(&(MAX_PACKET + 1).to_le_bytes());
+        assert!(read_packet(&mut buffer.as_slice()).await.is_err());
+
+        // Truncated in the middle of a packet
+        let mut buffer = vec![];
+        write_packet(&mut buffer, 0, 0, &[1, 2, 3]).unwrap();
+        buffer.pop();
+        assert!(read_packet(&mut buffer.as_slice()).await.is_err());
+    }
+}
@@ -0,0 +1,192 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Frames for the corpus in `tests/corpus`, which holds anonymised frames
+//! captured from real dongles (and some malformed ones) together with what
+//! they are expected to decode to. Frames are written as hex bytes, 16 per
+//! line. [anonymise_frame] implements `sunsniff anonymise-frame`, which
+//! turns a frame from a user's own dongle into this form.
+
+use std::collections::HashMap;
+use std::hash::RandomState;
+use sunsniff_core::logger::{sunsynk, Protocol};
+
+/// Parse a frame written as hex, either with whitespace between the bytes
+/// (as in the corpus) or as a line of the file written by the `frames`
+/// option (where the hex follows the capture time).
+pub fn parse_frame(text: &str) -> Result<Vec<u8>, String> {
+    let mut words = text.split_whitespace().peekable();
+    // Skip the capture time of a line from a `frames` file
+    words.next_if(|word| word.contains('.'));
+    let hex: String = words.collect();
+    if hex.is_empty() {
+        return Err("no frame given".to_owned());
+    }
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err("frame contains characters that are not hex digits".to_owned());
+    }
+    if !hex.len().is_multiple_of(2) {
+        return Err("frame has an odd number of hex digits".to_owned());
+    }
+    Ok((0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect())
+}
+
+/// Format a frame as hex, 16 bytes per line
+pub fn format_frame(frame: &[u8]) -> String {
+    frame
+        .chunks(16)
+        .map(|line| {
+            let bytes: Vec<String> = line.iter().map(|b| format!("{b:02x}")).collect();
+            bytes.join(" ") + "\n"
+        })
+        .collect()
+}
+
+/// Replace the serial number in a frame (given as hex) with a random
+/// pseudonym, and format it for the corpus
+pub fn anonymise_frame(text: &str) -> Result<String, String> {
+    let mut frame = parse_frame(text)?;
+    let protocol = sunsynk::Sunsynk::new(chrono_tz::UTC, HashMap::new());
+    if !protocol.anonymise(&mut frame, &RandomState::new()) {
+        return Err("not a Sunsynk logger frame, so it could not be anonymised".to_owned());
+    }
+    Ok(format_frame(&frame))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pcap::{Codec, PcapConfig};
+    use crate::pipeline::Pipeline;
+    use assert_approx_eq::assert_approx_eq;
+    use serde::Deserialize;
+    use std::path::{Path, PathBuf};
+
+    /// Structure corresponding to `tests/corpus/corpus.toml`
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Corpus {
+        frame: Vec<Case>,
+    }
+
+    /// A frame in the corpus, and what it should decode to
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Case {
+        /// File containing the frame, relative to the corpus directory
+        file: String,
+        description: String,
+        /// Serial number of the update, or `None` if the frame should not
+        /// produce one
+        serial: Option<String>,
+        /// Timestamp of the update, in seconds since the UNIX epoch
+        timestamp: Option<i64>,
+        /// Values of some of the fields in the update
+        #[serde(default)]
+        values: HashMap<String, f64>,
+    }
+
+    fn corpus_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus")
+    }
+
+    fn load_corpus() -> Corpus {
+        let text = std::fs::read_to_string(corpus_dir().join("corpus.toml")).unwrap();
+        toml::from_str(&text).unwrap()
+    }
+
+    #[test]
+    fn test_parse_frame() {
+        assert_eq!(parse_frame("a5 01\n0a\n"), Ok(vec![0xa5, 0x01, 0x0a]));
+        assert_eq!(
+            parse_frame("1667629966.123456 a5010a\n"),
+            Ok(vec![0xa5, 0x01, 0x0a])
+        );
+        assert!(parse_frame("").is_err());
+        assert!(parse_frame("a5 0").is_err());
+        assert!(parse_frame("a5 xy").is_err());
+        assert!(parse_frame("a5 é").is_err());
+    }
+
+    #[test]
+    fn test_format_frame() {
+        let frame: Vec<u8> = (0..18).collect();
+        assert_eq!(
+            format_frame(&frame),
+            "00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f\n10 11\n"
+        );
+        assert_eq!(parse_frame(&format_frame(&frame)), Ok(frame));
+    }
+
+    #[test]
+    fn test_anonymise_frame() {
+        let text = std::fs::read_to_string(corpus_dir().join("sunsynk_292.hex")).unwrap();
+        let original = parse_frame(&text).unwrap();
+        let anonymised = parse_frame(&anonymise_frame(&text).unwrap()).unwrap();
+        assert_eq!(anonymised.len(), original.len());
+        assert_ne!(anonymised[11..21], original[11..21]);
+        assert_eq!(anonymised[21..], original[21..]);
+        assert!(anonymise_frame("00 01 02").is_err());
+    }
+
+    /// Decode every frame in the corpus, through the same path as captured
+    /// packets, and check the result
+    #[test]
+    fn test_corpus() {
+        let config: PcapConfig = toml::from_str("device = \"corpus\"\ntimezone = \"UTC\"").unwrap();
+        for case in load_corpus().frame.iter() {
+            let text = std::fs::read_to_string(corpus_dir().join(&case.file)).unwrap();
+            let frame = parse_frame(&text).unwrap();
+            let mut codec = Codec::new(&config).unwrap();
+            let mut pipeline = Pipeline::new(&Default::default(), &HashMap::new()).unwrap();
+            let update = codec
+                .decode_payload(&frame, 0)
+                .and_then(|update| pipeline.process(update));
+            let Some(serial) = &case.serial else {
+                assert!(update.is_none(), "{}: {}", case.file, case.description);
+                continue;
+            };
+            let update = update.unwrap_or_else(|| panic!("{} was not decoded", case.file));
+            assert_eq!(&update.serial, serial, "{}", case.file);
+            if let Some(timestamp) = case.timestamp {
+                assert_eq!(update.timestamp, timestamp * 1_000_000_000, "{}", case.file);
+            }
+            for (id, expected) in case.values.iter() {
+                let value = update.get(id);
+                let value = value.unwrap_or_else(|| panic!("{}: {id} was not decoded", case.file));
+                assert_approx_eq!(value, *expected, 1e-6);
+            }
+        }
+    }
+
+    /// Check that no frames in the corpus are left out of corpus.toml
+    #[test]
+    fn test_corpus_complete() {
+        let corpus = load_corpus();
+        for entry in std::fs::read_dir(corpus_dir()).unwrap() {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            if name.ends_with(".hex") {
+                assert!(
+                    corpus.frame.iter().any(|case| case.file == name),
+                    "{name} is not listed in corpus.toml"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Capture of undecoded frames, so that they can be shared to help map out
+//! unknown offsets and firmware versions.
+
+use log::warn;
+use serde::Deserialize;
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::hash::RandomState;
+use std::io::{LineWriter, Write};
+use std::path::PathBuf;
+
+#[cfg(feature = "mqtt")]
+use futures::{channel::mpsc, StreamExt};
+
+/// Structure corresponding to the `[pcap.frames]` section of the
+/// configuration file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// File to append the frames to
+    file: Option<PathBuf>,
+    /// MQTT broker to publish the frames to
+    #[cfg(feature = "mqtt")]
+    mqtt: Option<crate::mqtt::Config>,
+    /// MQTT topic for the frames
+    #[cfg(feature = "mqtt")]
+    #[serde(default = "default_topic")]
+    topic: String,
+}
+
+#[cfg(feature = "mqtt")]
+fn default_topic() -> String {
+    String::from("sunsniff/frames")
+}
+
+/// Writes (already anonymised) frames to the configured destinations
+pub struct FrameSink {
+    file: Option<LineWriter<File>>,
+    #[cfg(feature = "mqtt")]
+    mqtt: Option<mpsc::UnboundedSender<String>>,
+    /// Hasher for anonymisation. It is randomly seeded, so pseudonyms are
+    /// only consistent for the lifetime of the process.
+    pub hasher: RandomState,
+}
+
+impl FrameSink {
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = match &config.file {
+            Some(path) => Some(LineWriter::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            None => None,
+        };
+        Ok(Self {
+            file,
+            #[cfg(feature = "mqtt")]
+            mqtt: match &config.mqtt {
+                Some(mqtt_config) => Some(spawn_publisher(mqtt_config, config.topic.clone())?),
+                None => None,
+            },
+            hasher: RandomState::new(),
+        })
+    }
+
+    /// Record a frame, given its capture time as seconds and microseconds
+    /// (whose types vary by platform)
+    pub fn write(&mut self, sec: impl Display, usec: impl Display, frame: &[u8]) {
+        let hex: String = frame.iter().map(|b| format!("{b:02x}")).collect();
+        if let Some(file) = &mut self.file {
+            if let Err(err) = writeln!(file, "{sec}.{usec:06} {hex}") {
+                warn!("Failed to write frame: {err}");
+            }
+        }
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            // This only fails if the publisher has stopped, which it does not do
+            let _ = mqtt.unbounded_send(hex);
+        }
+    }
+}
+
+/// Start a task that publishes frames (as hex strings) to MQTT
+#[cfg(feature = "mqtt")]
+fn spawn_publisher(
+    config: &crate::mqtt::Config,
+    topic: String,
+) -> mqtt_async_client::Result<mpsc::UnboundedSender<String>> {
+    use mqtt_async_client::client::Publish;
+
+    let mut client = config.client()?;
+    let (sender, mut receiver) = mpsc::unbounded::<String>();
+    tokio::spawn(async move {
+        client
+            .connect()
+            .await
+            .unwrap_or_else(|e| warn!("Couldn't connect to MQTT broker (will keep trying): {}", e));
+        while let Some(hex) = receiver.next().await {
+            let msg = Publish::new(topic.clone(), hex.into_bytes());
+            client
+                .publish(&msg)
+                .await
+                .unwrap_or_else(|e| warn!("Sending frame failed: {}", e));
+        }
+    });
+    Ok(sender)
+}
@@ -0,0 +1,308 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Reassembly of frames that the logger splits across several TCP
+//! segments.
+//!
+//! Segments are tracked per TCP connection (flow). When a segment holds only
+//! the start of a frame (see [Protocol::framing]), it is held until the
+//! segments that follow it on the same flow complete the frame. Since the
+//! loggers do not give the length of the frame, a held frame is also
+//! released as it is (to be reported as unrecognised) if the next segment
+//! would make it too long, if the next segment starts a new frame, or if
+//! nothing more arrives for [TIMEOUT].
+
+use etherparse::{NetSlice, SlicedPacket, TransportSlice};
+use log::debug;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use sunsniff_core::logger::{Framing, Protocol};
+
+/// Time after which a held frame is released (in nanoseconds)
+const TIMEOUT: i64 = 30 * 1_000_000_000;
+
+/// Endpoints of a TCP connection, in the direction of the data
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+struct Flow {
+    source: SocketAddr,
+    destination: SocketAddr,
+}
+
+/// The parts of a TCP segment needed for reassembly
+pub(super) struct Segment<'a> {
+    flow: Flow,
+    /// Sequence number of the first byte of the payload
+    sequence: u32,
+    /// Whether the connection is being opened, closed or reset
+    boundary: bool,
+    payload: &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    /// Extract the TCP segment from a packet
+    pub(super) fn parse(packet_data: &'a [u8]) -> Option<Self> {
+        let packet = SlicedPacket::from_ethernet(packet_data).ok()?;
+        let (source, destination): (IpAddr, IpAddr) = match packet.net {
+            Some(NetSlice::Ipv4(ip)) => (
+                ip.header().source_addr().into(),
+                ip.header().destination_addr().into(),
+            ),
+            Some(NetSlice::Ipv6(ip)) => (
+                ip.header().source_addr().into(),
+                ip.header().destination_addr().into(),
+            ),
+            _ => return None,
+        };
+        match packet.transport? {
+            TransportSlice::Tcp(tcp) => Some(Self {
+                flow: Flow {
+                    source: SocketAddr::new(source, tcp.source_port()),
+                    destination: SocketAddr::new(destination, tcp.destination_port()),
+                },
+                sequence: tcp.sequence_number(),
+                boundary: tcp.syn() || tcp.fin() || tcp.rst(),
+                payload: tcp.payload(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The start of a frame, waiting for the rest
+struct Held {
+    data: Vec<u8>,
+    /// Sequence number expected for the next segment
+    next: u32,
+    /// Capture time of the latest segment (in nanoseconds)
+    timestamp: i64,
+}
+
+/// Reassembles frames from TCP segments
+#[derive(Default)]
+pub(super) struct Reassembler {
+    held: HashMap<Flow, Held>,
+}
+
+impl Reassembler {
+    /// Add a segment captured at `timestamp` (in nanoseconds), returning
+    /// the frames (or other payloads) that are ready to decode.
+    pub(super) fn push<'a>(
+        &mut self,
+        segment: &Segment<'a>,
+        protocol: &dyn Protocol,
+        timestamp: i64,
+    ) -> Vec<Cow<'a, [u8]>> {
+        let mut frames = self.expire(timestamp);
+        let held = self.held.remove(&segment.flow);
+        if segment.payload.is_empty() {
+            // Keep waiting, unless the connection is closing
+            if let Some(held) = held {
+                if segment.boundary {
+                    frames.push(Cow::Owned(held.data));
+                } else {
+                    self.held.insert(segment.flow, held);
+                }
+            }
+            return frames;
+        }
+        let payload = match held {
+            Some(held) if held.next == segment.sequence => {
+                let mut data = held.data;
+                data.extend_from_slice(segment.payload);
+                match (protocol.framing(&data), protocol.framing(segment.payload)) {
+                    (Framing::Complete, _) | (Framing::Partial, Framing::Other) => Cow::Owned(data),
+                    _ => {
+                        // The held frame is not continued by this segment
+                        data.truncate(data.len() - segment.payload.len());
+                        frames.push(Cow::Owned(data));
+                        Cow::Borrowed(segment.payload)
+                    }
+                }
+            }
+            Some(_) => {
+                debug!(
+                    "Discarding the start of a frame from {} after a gap in the TCP stream",
+                    segment.flow.source
+                );
+                Cow::Borrowed(segment.payload)
+            }
+            None => Cow::Borrowed(segment.payload),
+        };
+        if protocol.framing(&payload) == Framing::Partial && !segment.boundary {
+            let next = segment.sequence.wrapping_add(segment.payload.len() as u32);
+            let data = payload.into_owned();
+            self.held.insert(
+                segment.flow,
+                Held {
+                    data,
+                    next,
+                    timestamp,
+                },
+            );
+        } else {
+            frames.push(payload);
+        }
+        frames
+    }
+
+    /// Release frames that have been held for more than [TIMEOUT]
+    fn expire<'a>(&mut self, timestamp: i64) -> Vec<Cow<'a, [u8]>> {
+        let mut frames = vec![];
+        self.held.retain(|_, held| {
+            if timestamp - held.timestamp > TIMEOUT {
+                frames.push(Cow::Owned(std::mem::take(&mut held.data)));
+                false
+            } else {
+                true
+            }
+        });
+        frames
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::corpus::parse_frame;
+    use super::*;
+    use std::hash::RandomState;
+    use std::path::Path;
+    use sunsniff_core::logger::sunsynk::Sunsynk;
+    use sunsniff_core::receiver::Update;
+
+    /// Protocol whose frames start with `F` and are 8 bytes long
+    struct Fixed;
+
+    impl Protocol for Fixed {
+        fn decode(&self, _payload: &[u8]) -> Option<(Update<'static>, Vec<Vec<u16>>)> {
+            None
+        }
+
+        fn anonymise(&self, _payload: &mut [u8], _hasher: &RandomState) -> bool {
+            false
+        }
+
+        fn framing(&self, payload: &[u8]) -> Framing {
+            match payload.len() {
+                _ if payload.first() != Some(&b'F') => Framing::Other,
+                8 => Framing::Complete,
+                len if len < 8 => Framing::Partial,
+                _ => Framing::Other,
+            }
+        }
+    }
+
+    fn segment(port: u16, sequence: u32, payload: &[u8]) -> Segment<'_> {
+        Segment {
+            flow: Flow {
+                source: SocketAddr::from(([192, 168, 0, 2], port)),
+                destination: SocketAddr::from(([192, 168, 0, 1], 10000)),
+            },
+            sequence,
+            boundary: false,
+            payload,
+        }
+    }
+
+    fn push(reassembler: &mut Reassembler, segment: &Segment<'_>, timestamp: i64) -> Vec<Vec<u8>> {
+        reassembler
+            .push(segment, &Fixed, timestamp)
+            .into_iter()
+            .map(Cow::into_owned)
+            .collect()
+    }
+
+    /// The frame split across two segments in the corpus is put back together
+    #[test]
+    fn test_corpus_fragments() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+        let read = |name: &str| parse_frame(&std::fs::read_to_string(dir.join(name)).unwrap());
+        let first = read("sunsynk_292_fragment_1.hex").unwrap();
+        let second = read("sunsynk_292_fragment_2.hex").unwrap();
+        let protocol = Sunsynk::new(chrono_tz::UTC, HashMap::new());
+        let mut r = Reassembler::default();
+        assert!(r.push(&segment(1, 0, &first), &protocol, 0).is_empty());
+        let frames = r.push(&segment(1, first.len() as u32, &second), &protocol, 0);
+        assert_eq!(frames, [read("sunsynk_292.hex").unwrap()]);
+    }
+
+    #[test]
+    fn test_whole() {
+        let mut r = Reassembler::default();
+        assert_eq!(push(&mut r, &segment(1, 0, b"F1234567"), 0), [b"F1234567"]);
+        assert_eq!(push(&mut r, &segment(1, 8, b"xyz"), 0), [b"xyz"]);
+    }
+
+    #[test]
+    fn test_split() {
+        let mut r = Reassembler::default();
+        assert!(push(&mut r, &segment(1, 100, b"F12"), 0).is_empty());
+        // Another connection is tracked separately
+        assert_eq!(
+            push(&mut r, &segment(2, 100, b"Fabcdefg"), 0),
+            [b"Fabcdefg"]
+        );
+        assert!(push(&mut r, &segment(1, 103, b"34"), 0).is_empty());
+        assert_eq!(push(&mut r, &segment(1, 105, b"567"), 0), [b"F1234567"]);
+    }
+
+    #[test]
+    fn test_sequence_wrap() {
+        let mut r = Reassembler::default();
+        assert!(push(&mut r, &segment(1, u32::MAX - 1, b"F12"), 0).is_empty());
+        assert_eq!(push(&mut r, &segment(1, 1, b"34567"), 0), [b"F1234567"]);
+    }
+
+    #[test]
+    fn test_release() {
+        let mut r = Reassembler::default();
+        // A new frame releases the held one
+        assert!(push(&mut r, &segment(1, 0, b"F12"), 0).is_empty());
+        assert_eq!(
+            push(&mut r, &segment(1, 3, b"F1234567"), 0),
+            [&b"F12"[..], &b"F1234567"[..]]
+        );
+        // So does a segment that would make it too long
+        assert!(push(&mut r, &segment(1, 11, b"F12"), 0).is_empty());
+        assert_eq!(
+            push(&mut r, &segment(1, 14, b"xxxxxxxx"), 0),
+            [&b"F12"[..], &b"xxxxxxxx"[..]]
+        );
+        // And the timeout
+        assert!(push(&mut r, &segment(1, 22, b"F12"), 0).is_empty());
+        assert_eq!(
+            push(&mut r, &segment(2, 0, b"x"), TIMEOUT + 1),
+            [&b"F12"[..], &b"x"[..]]
+        );
+        // And closing the connection
+        assert!(push(&mut r, &segment(1, 25, b"F12"), 0).is_empty());
+        let mut fin = segment(1, 28, b"");
+        fin.boundary = true;
+        assert_eq!(push(&mut r, &fin, 0), [b"F12"]);
+    }
+
+    #[test]
+    fn test_gap() {
+        let mut r = Reassembler::default();
+        assert!(push(&mut r, &segment(1, 0, b"F12"), 0).is_empty());
+        // A retransmission replaces the held segment
+        assert!(push(&mut r, &segment(1, 0, b"F12"), 0).is_empty());
+        assert_eq!(push(&mut r, &segment(1, 3, b"34567"), 0), [b"F1234567"]);
+        // A missing segment discards the start of the frame
+        assert!(push(&mut r, &segment(1, 8, b"F12"), 0).is_empty());
+        assert_eq!(push(&mut r, &segment(1, 20, b"567"), 0), [b"567"]);
+    }
+}
@@ -0,0 +1,240 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Ingestion of capture files that are dropped into a directory (for
+//! example, by a cron job running tcpdump on a router). Each file is read
+//! once, and the names of the files that have been read are recorded so that
+//! they are not read again after a restart.
+
+use futures::channel::mpsc;
+use futures::prelude::*;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_with::serde_as;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::Codec;
+use crate::receiver::Update;
+
+/// Structure corresponding to the `[pcap.watch]` section of the
+/// configuration file.
+#[serde_as]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Directory to look for capture files in
+    pub directory: PathBuf,
+    /// File listing the capture files that have been read. Defaults to
+    /// `.sunsniff-processed` in `directory`.
+    processed: Option<PathBuf>,
+    /// How often to look for new files. Files that have been modified more
+    /// recently than this are assumed to still be being written.
+    #[serde_as(as = "serde_with::DurationSecondsWithFrac<f64>")]
+    #[serde(default = "default_interval")]
+    interval: Duration,
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Whether a file name looks like a (possibly compressed) capture file
+fn is_capture(name: &str) -> bool {
+    let name = name
+        .strip_suffix(".gz")
+        .or_else(|| name.strip_suffix(".zst"))
+        .unwrap_or(name);
+    name.ends_with(".pcap") || name.ends_with(".pcapng")
+}
+
+/// Keeps track of which files in the directory have been read
+struct Watcher {
+    directory: PathBuf,
+    interval: Duration,
+    processed: HashSet<String>,
+    /// Log of the processed files, which is appended to
+    log: LineWriter<File>,
+}
+
+impl Watcher {
+    fn new(config: &Config) -> io::Result<Self> {
+        let path = match &config.processed {
+            Some(path) => path.clone(),
+            None => config.directory.join(".sunsniff-processed"),
+        };
+        let processed = match File::open(&path) {
+            Ok(file) => BufReader::new(file).lines().collect::<io::Result<_>>()?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(err) => return Err(err),
+        };
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            directory: config.directory.clone(),
+            interval: config.interval,
+            processed,
+            log: LineWriter::new(log),
+        })
+    }
+
+    /// Find the capture files that have not been read yet and are no longer
+    /// being written, in order of name (which, for rotated captures, is the
+    /// order in which they were written)
+    fn ready(&self) -> io::Result<Vec<String>> {
+        let mut names = vec![];
+        for entry in std::fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if !is_capture(&name) || self.processed.contains(&name) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            // A modification time in the future counts as recent
+            let settled = metadata
+                .modified()?
+                .elapsed()
+                .is_ok_and(|age| age >= self.interval);
+            if metadata.is_file() && settled {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Record that a file has been read
+    fn done(&mut self, name: String) -> io::Result<()> {
+        writeln!(self.log, "{name}")?;
+        self.processed.insert(name);
+        Ok(())
+    }
+}
+
+/// Decode the packets in a capture file and send the updates. Returns false
+/// if the receiver has gone away.
+fn ingest(
+    path: &Path,
+    filter: &str,
+    codec: &mut Codec,
+    sender: &mut mpsc::Sender<Arc<Update<'static>>>,
+) -> bool {
+    let mut cap = match super::open_file(path).and_then(|mut cap| {
+        cap.filter(filter, true)?;
+        cap.set_datalink(pcap::Linktype::ETHERNET)?;
+        Ok(cap)
+    }) {
+        Ok(cap) => cap,
+        Err(err) => {
+            warn!("Skipping {}: {err}", path.display());
+            return true;
+        }
+    };
+    loop {
+        match cap.next_packet() {
+            Ok(packet) => {
+                let ts = &packet.header.ts;
+                if let Some(update) = codec.decode_packet(packet.data, ts.tv_sec, ts.tv_usec) {
+                    if futures::executor::block_on(sender.send(update)).is_err() {
+                        return false;
+                    }
+                }
+            }
+            Err(pcap::Error::NoMorePackets) => return true,
+            Err(err) => {
+                warn!("Error reading {}: {err}", path.display());
+                return true;
+            }
+        }
+    }
+}
+
+/// Create a stream of the updates in the capture files in the directory.
+/// The files are read on a separate thread, which waits for new files when
+/// it has read all the existing ones, so the stream never ends by itself.
+pub(super) fn watch(
+    config: &Config,
+    filter: String,
+    mut codec: Codec,
+) -> io::Result<impl Stream<Item = Arc<Update<'static>>>> {
+    let mut watcher = Watcher::new(config)?;
+    let (mut sender, receiver) = mpsc::channel(1);
+    std::thread::spawn(move || loop {
+        let names = watcher.ready().unwrap_or_else(|err| {
+            warn!("Failed to list {}: {err}", watcher.directory.display());
+            vec![]
+        });
+        for name in names {
+            let path = watcher.directory.join(&name);
+            info!("Reading {}", path.display());
+            if !ingest(&path, &filter, &mut codec, &mut sender) {
+                return; // The main stream has ended
+            }
+            // Even if the file could not be read, so that it is not retried
+            // forever. Removing it from the list causes it to be retried.
+            if let Err(err) = watcher.done(name) {
+                warn!("Failed to record that {} was read: {err}", path.display());
+            }
+        }
+        std::thread::sleep(watcher.interval);
+    });
+    Ok(receiver)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_capture() {
+        assert!(is_capture("capture-2024-01.pcap"));
+        assert!(is_capture("capture-2024-01.pcap.gz"));
+        assert!(is_capture("capture-2024-01.pcapng.zst"));
+        assert!(!is_capture(".sunsniff-processed"));
+        assert!(!is_capture("capture-2024-01.pcap.tmp"));
+        assert!(!is_capture("notes.gz"));
+    }
+
+    #[test]
+    fn test_watcher() {
+        let dir = std::env::temp_dir().join(format!("sunsniff-watch-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+        for name in ["b.pcap", "a.pcap.gz", "notes.txt"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        std::fs::create_dir(dir.join("c.pcap")).unwrap();
+        let config: Config = toml::from_str(&format!("directory = {dir:?}\ninterval = 0")).unwrap();
+
+        let mut watcher = Watcher::new(&config).unwrap();
+        assert_eq!(watcher.ready().unwrap(), ["a.pcap.gz", "b.pcap"]);
+        watcher.done("a.pcap.gz".to_owned()).unwrap();
+        assert_eq!(watcher.ready().unwrap(), ["b.pcap"]);
+        drop(watcher);
+
+        // The processed files are remembered after a restart
+        let watcher = Watcher::new(&config).unwrap();
+        assert_eq!(watcher.ready().unwrap(), ["b.pcap"]);
+        // Recently modified files are left until they have settled
+        let config: Config = toml::from_str(&format!("directory = {dir:?}")).unwrap();
+        assert!(Watcher::new(&config).unwrap().ready().unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
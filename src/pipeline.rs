@@ -0,0 +1,1254 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Post-processing applied to updates between the frontend and the receivers
+
+use log::{debug, info, warn};
+use serde::Deserialize;
+use siphasher::sip::SipHasher13;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hasher;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use super::clock::{Clock, SystemClock};
+use super::ewma::Ewma;
+use super::fields::{self, Field, FieldType, Reset, WordOrder};
+use super::receiver::{Update, UpdateItem};
+
+/// A single transformation step in the pipeline
+pub trait Stage {
+    /// Transform an update, or return `None` to drop it
+    fn process(&mut self, update: Update<'static>) -> Option<Update<'static>>;
+}
+
+/// Linear correction applied to a field, from the `[field_overrides]`
+/// section of the configuration file
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FieldOverride {
+    /// Amount by which to multiply the value
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    /// Amount to add to the value, after scaling
+    #[serde(default)]
+    pub bias: f64,
+    /// Calibration curve applied after `scale` and `bias`, as pairs of
+    /// (reported, actual) values. Values are linearly interpolated between
+    /// the points, and extrapolated from the first or last pair of points.
+    #[serde(default)]
+    pub calibration: Vec<(f64, f64)>,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+impl FieldOverride {
+    fn apply(&self, value: f64) -> f64 {
+        let value = value * self.scale + self.bias;
+        let points = &self.calibration;
+        match points.len() {
+            0 => value,
+            1 => value + points[0].1 - points[0].0,
+            n => {
+                // Find the segment to interpolate (or extrapolate) along
+                let i = points[1..n - 1]
+                    .iter()
+                    .position(|p| value < p.0)
+                    .unwrap_or(n - 2);
+                let (x0, y0) = points[i];
+                let (x1, y1) = points[i + 1];
+                y0 + (value - x0) * (y1 - y0) / (x1 - x0)
+            }
+        }
+    }
+}
+
+/// Computes fields that are defined as sums of other fields (frontends
+/// leave these as NaN) and applies overrides. This is done in field order,
+/// so that sums are computed from the overridden values.
+struct FieldValues {
+    overrides: HashMap<String, FieldOverride>,
+}
+
+impl Stage for FieldValues {
+    fn process(&mut self, mut update: Update<'static>) -> Option<Update<'static>> {
+        for (i, field) in update.fields.iter().enumerate() {
+            if !field.sum_of.is_empty() {
+                update.values[i] = field.from_sum(&update.values);
+            }
+            if let Some(o) = self.overrides.get(field.id) {
+                update.values[i] = o.apply(update.values[i]);
+            }
+        }
+        Some(update)
+    }
+}
+
+/// Structure corresponding to the `[pipeline]` section of the configuration
+/// file. It is constructed from the config file by serde.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Drop updates with the same serial number and timestamp as the
+    /// previous one (e.g. from TCP retransmissions)
+    #[serde(default)]
+    pub dedup: bool,
+    /// Minimum time (in seconds) between updates for each inverter
+    pub min_interval: Option<f64>,
+    /// Report a gap in the data when the time between updates for an
+    /// inverter is more than this many times the usual interval
+    pub gap_intervals: Option<f64>,
+    /// Round values to thousandths of a unit, and store them as integers
+    /// in [`Update::fixed`]
+    #[serde(default)]
+    pub fixed_point: bool,
+    /// How inverter serial numbers are presented to the receivers
+    #[serde(default)]
+    pub serial_mode: SerialMode,
+    /// Secret mixed into hashed serial numbers, to prevent them from being
+    /// recovered by trying all possible serial numbers
+    #[serde(default)]
+    pub serial_salt: String,
+    /// Names to use in place of serial numbers, for [SerialMode::Alias]
+    #[serde(default)]
+    pub serial_aliases: HashMap<String, String>,
+    /// What to do with values that are infinite
+    #[serde(default)]
+    pub non_finite: NonFinite,
+    /// Replacement for values that are infinite, for [NonFinite::Replace]
+    #[serde(default)]
+    pub non_finite_value: f64,
+    /// What to do with negative values, by field type
+    #[serde(default)]
+    pub negative: HashMap<FieldType, Negative>,
+    /// On/off fields derived from other fields, indexed by the ID of the
+    /// new field
+    #[serde(default)]
+    pub binary_fields: BTreeMap<String, BinaryField>,
+    /// Frontends in order of preference, for inverters seen by more than
+    /// one. Each is either a full source (e.g. `pcap:eth0`) or the part
+    /// before the colon (e.g. `pcap`).
+    #[serde(default)]
+    pub source_priority: Vec<String>,
+    /// Time (in seconds) without updates after which a preferred source is
+    /// considered stale, for [Config::source_priority]
+    pub source_timeout: Option<f64>,
+}
+
+impl Config {
+    /// Check the parts of the configuration that serde cannot
+    pub fn check(&self) -> Result<(), String> {
+        for (id, binary) in self.binary_fields.iter() {
+            if !binary.field_type.is_boolean() {
+                return Err(format!(
+                    "binary field {id:?} has type {:?}, which is not an on/off type",
+                    binary.field_type
+                ));
+            }
+            if fields::is_known(id) {
+                return Err(format!(
+                    "binary field {id:?} has the ID of an existing field"
+                ));
+            }
+        }
+        if let Some(timeout) = self.source_timeout {
+            if !timeout.is_finite() || timeout <= 0.0 {
+                return Err(format!("source_timeout must be positive, not {timeout}"));
+            }
+        }
+        if let Some(factor) = self.gap_intervals {
+            if !factor.is_finite() || factor <= 0.0 {
+                return Err(format!("gap_intervals must be positive, not {factor}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An on/off field derived from another field, from the
+/// `[pipeline.binary_fields]` section of the configuration file. It is on
+/// (1) when the other field is above a threshold and off (0) otherwise.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BinaryField {
+    /// ID of the field that the state is derived from
+    pub field: String,
+    /// Value of `field` above which the state is on
+    pub above: f64,
+    /// Type of the new field (one for which [FieldType::is_boolean] is true)
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+    /// Name of the new field
+    pub name: String,
+    /// Group of the new field (defaults to the group of `field`)
+    pub group: Option<String>,
+}
+
+/// What to do with negative values of a field type. Registers are decoded
+/// as signed, but some (such as lifetime energy counters) are really
+/// unsigned and appear to go negative once they pass half their range.
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Negative {
+    /// Leave them as they are
+    #[default]
+    Allow,
+    /// Replace them with zero
+    Clamp,
+    /// Reinterpret the registers as unsigned
+    Wrap,
+}
+
+/// What to do with values that are infinite (for example, from a derived
+/// field that divides by zero). NaN marks a missing value, so it is left
+/// alone.
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NonFinite {
+    /// Make them NaN, like missing values, which the backends that cannot
+    /// store it (Influxdb2 and MQTT) leave out
+    #[default]
+    Skip,
+    /// Replace them with a fixed value
+    Replace,
+    /// Drop the whole update
+    Drop,
+}
+
+/// How inverter serial numbers are presented to the receivers
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SerialMode {
+    /// Use the real serial number
+    #[default]
+    Plain,
+    /// Replace the serial number with a hash of it
+    Hash,
+    /// Replace the serial number with an alias from the configuration
+    /// (falling back to the hash if there is no alias)
+    Alias,
+}
+
+/// Mapping from the serial numbers seen by the receivers to the real ones
+pub type SerialMap = Arc<Mutex<HashMap<String, String>>>;
+
+/// Hash a serial number. This is stable across runs, so that it can be used
+/// in place of the serial number in stored data.
+fn hash_serial(serial: &str, salt: &str) -> String {
+    let mut hasher = SipHasher13::new();
+    hasher.write(salt.as_bytes());
+    hasher.write_u8(0);
+    hasher.write(serial.as_bytes());
+    format!("{:016x}", hasher.finish())
+}
+
+/// Numbers the updates from each source (as given in the metadata), so that
+/// receivers can tell when updates have been lost
+#[derive(Default)]
+struct Sequence {
+    /// Number of updates seen from each source
+    counts: HashMap<Option<String>, u64>,
+}
+
+impl Stage for Sequence {
+    fn process(&mut self, mut update: Update<'static>) -> Option<Update<'static>> {
+        let count = self
+            .counts
+            .entry(update.metadata.source.clone())
+            .or_default();
+        *count += 1;
+        update.metadata.sequence = Some(*count);
+        Some(update)
+    }
+}
+
+/// Default for [Config::source_timeout], in seconds
+const DEFAULT_SOURCE_TIMEOUT: f64 = 300.0;
+
+/// Drops updates for an inverter while a more preferred source is also
+/// providing them, so that a fallback source (such as modbus alongside pcap)
+/// only takes over when the preferred one goes quiet
+struct Failover {
+    /// Sources in order of preference
+    priority: Vec<String>,
+    /// Time after which a source is stale
+    timeout: Duration,
+    /// Arrival time of the latest update from each source in `priority`,
+    /// and the index of the source in use, for each inverter. The arrival
+    /// time is used rather than the update timestamp, since the sources
+    /// may take their timestamps from different clocks.
+    last: HashMap<String, (Vec<Option<Instant>>, Option<usize>)>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Failover {
+    fn rank(&self, source: &str) -> Option<usize> {
+        let kind = source.split(':').next().unwrap_or(source);
+        self.priority
+            .iter()
+            .position(|entry| entry == source || entry == kind)
+    }
+}
+
+impl Stage for Failover {
+    fn process(&mut self, update: Update<'static>) -> Option<Update<'static>> {
+        let Some(rank) = update.metadata.source.as_deref().and_then(|s| self.rank(s)) else {
+            return Some(update);
+        };
+        let now = self.clock.instant();
+        let (seen, active) = self
+            .last
+            .entry(update.serial.clone())
+            .or_insert_with(|| (vec![None; self.priority.len()], None));
+        seen[rank] = Some(now);
+        let fresh = |last: &Option<Instant>| last.is_some_and(|last| now - last < self.timeout);
+        if seen[..rank].iter().any(fresh) {
+            return None;
+        }
+        if *active != Some(rank) {
+            if active.is_some() {
+                info!(
+                    "Switching to source {} for {}",
+                    update.metadata.source.as_deref().unwrap_or_default(),
+                    update.serial
+                );
+            }
+            *active = Some(rank);
+        }
+        Some(update)
+    }
+}
+
+/// Applies [Negative] to negative values, according to the field type
+struct Unsigned {
+    policies: HashMap<FieldType, Negative>,
+}
+
+impl Stage for Unsigned {
+    fn process(&mut self, mut update: Update<'static>) -> Option<Update<'static>> {
+        for (i, (field, value)) in update
+            .fields
+            .iter()
+            .zip(update.values.iter_mut())
+            .enumerate()
+        {
+            if *value >= 0.0 || value.is_nan() {
+                continue;
+            }
+            match self.policies.get(&field.field_type) {
+                Some(Negative::Clamp) => *value = 0.0,
+                Some(Negative::Wrap) => {
+                    // Without the raw values, assume a single register,
+                    // since that is where the problem shows up in practice
+                    let words = update.raw.as_ref().map_or(1, |raw| raw[i].len());
+                    if words > 0 {
+                        *value += 65536f64.powi(words as i32) * field.scale;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(update)
+    }
+}
+
+/// Applies [NonFinite] to the values that are infinite, counting them for
+/// each field. NaN values are missing rather than invalid, so they are
+/// passed through without being counted.
+struct Sanitize {
+    mode: NonFinite,
+    replacement: f64,
+    /// Number of infinite values seen, indexed by field ID
+    counts: HashMap<String, u64>,
+}
+
+impl Sanitize {
+    /// Count an infinite value, warning about the first and then each power
+    /// of ten
+    fn record(&mut self, id: &str) {
+        let count = self.counts.entry(id.to_owned()).or_default();
+        *count += 1;
+        let mut n = *count;
+        while n.is_multiple_of(10) {
+            n /= 10;
+        }
+        if n == 1 {
+            warn!("Field {id} has had {count} infinite value(s)");
+        }
+    }
+}
+
+impl Stage for Sanitize {
+    fn process(&mut self, mut update: Update<'static>) -> Option<Update<'static>> {
+        let mut found = false;
+        for (field, value) in update.fields.iter().zip(update.values.iter_mut()) {
+            if value.is_infinite() {
+                found = true;
+                self.record(field.id);
+                *value = match self.mode {
+                    NonFinite::Replace => self.replacement,
+                    _ => f64::NAN,
+                };
+            }
+        }
+        if found && self.mode == NonFinite::Drop {
+            debug!("Dropping update with infinite values for {}", update.serial);
+            return None;
+        }
+        Some(update)
+    }
+}
+
+/// Drops repeats of the previous update from the same inverter
+#[derive(Default)]
+struct Dedup {
+    last: HashMap<String, i64>,
+}
+
+impl Stage for Dedup {
+    fn process(&mut self, update: Update<'static>) -> Option<Update<'static>> {
+        if self.last.get(&update.serial) == Some(&update.timestamp) {
+            debug!("Dropping duplicate update for {}", update.serial);
+            return None;
+        }
+        self.last.insert(update.serial.clone(), update.timestamp);
+        Some(update)
+    }
+}
+
+/// Drops updates that arrive too soon after the last one that was kept
+struct Downsample {
+    /// Minimum interval in nanoseconds
+    interval: i64,
+    last: HashMap<String, i64>,
+}
+
+impl Stage for Downsample {
+    fn process(&mut self, update: Update<'static>) -> Option<Update<'static>> {
+        if let Some(last) = self.last.get(&update.serial) {
+            if update.timestamp >= *last && update.timestamp - *last < self.interval {
+                return None;
+            }
+        }
+        self.last.insert(update.serial.clone(), update.timestamp);
+        Some(update)
+    }
+}
+
+/// Marks updates that follow a gap in the data for an inverter: an interval
+/// of more than `factor` times the usual interval between updates. Gaps are
+/// not included in the usual interval, unless several come in a row (see
+/// [Ewma]).
+struct Gaps {
+    factor: f64,
+    /// Timestamp of the latest update and the average interval (in
+    /// nanoseconds, ignoring the gaps) for each inverter
+    last: HashMap<String, (i64, Ewma)>,
+}
+
+impl Stage for Gaps {
+    fn process(&mut self, mut update: Update<'static>) -> Option<Update<'static>> {
+        let (last, cadence) = self
+            .last
+            .entry(update.serial.clone())
+            .or_insert((update.timestamp, Ewma::with_outliers(self.factor)));
+        let interval = update.timestamp - *last;
+        if interval > 0 {
+            if cadence.add(interval as f64) {
+                let gap = Duration::from_nanos(interval as u64);
+                warn!(
+                    "Updates for {} resumed after a gap of {:.0}s",
+                    update.serial,
+                    gap.as_secs_f64()
+                );
+                update.metadata.gap = Some(gap);
+            }
+            *last = update.timestamp;
+        }
+        Some(update)
+    }
+}
+
+/// Number of fixed-point steps per unit
+pub(crate) const FIXED_SCALE: f64 = 1000.0;
+
+/// Rounds values to fixed point, to remove floating-point noise such as
+/// 54.00000000000001 from scaling the raw values. Missing values are left
+/// as they are.
+struct FixedPoint;
+
+impl Stage for FixedPoint {
+    fn process(&mut self, mut update: Update<'static>) -> Option<Update<'static>> {
+        let fixed: Vec<Option<i64>> = update
+            .values
+            .iter()
+            .map(|v| v.is_finite().then(|| (v * FIXED_SCALE).round() as i64))
+            .collect();
+        for (value, f) in update.values.iter_mut().zip(fixed.iter()) {
+            if let Some(f) = f {
+                // Dividing an integer gives the closest value to the decimal
+                *value = *f as f64 / FIXED_SCALE;
+            }
+        }
+        update.fixed = Some(fixed);
+        Some(update)
+    }
+}
+
+/// A [BinaryField] with the strings leaked, for use in field tables
+struct BinaryRule {
+    id: &'static str,
+    name: &'static str,
+    group: Option<&'static str>,
+    field_type: FieldType,
+    source: String,
+    above: f64,
+}
+
+/// A field table with binary fields appended
+struct ExtendedTable {
+    fields: &'static [Field<'static>],
+    /// Position of the source field and threshold for each binary field
+    sources: Vec<(usize, f64)>,
+}
+
+/// Appends the [BinaryField]s to the updates. Each field table is extended
+/// with the binary fields whose source field it contains.
+struct BinaryFields {
+    rules: Vec<BinaryRule>,
+    /// Extended version of each field table, indexed by the address of the
+    /// original table
+    tables: HashMap<usize, ExtendedTable>,
+}
+
+impl BinaryFields {
+    fn new(binary_fields: &BTreeMap<String, BinaryField>) -> Self {
+        let leak = |s: &str| -> &'static str { s.to_owned().leak() };
+        let rules = binary_fields
+            .iter()
+            .map(|(id, binary)| BinaryRule {
+                id: leak(id),
+                name: leak(&binary.name),
+                group: binary.group.as_deref().map(leak),
+                field_type: binary.field_type,
+                source: binary.field.clone(),
+                above: binary.above,
+            })
+            .collect();
+        Self {
+            rules,
+            tables: HashMap::new(),
+        }
+    }
+
+    /// Get the extended version of a field table. Extended tables are
+    /// leaked, but there are only a few distinct tables.
+    fn table(&mut self, fields: &'static [Field<'static>]) -> &ExtendedTable {
+        let rules = &self.rules;
+        self.tables
+            .entry(fields.as_ptr() as usize)
+            .or_insert_with(|| {
+                let mut extended: Vec<Field<'static>> =
+                    fields.iter().map(|field| Field { ..*field }).collect();
+                let mut sources = vec![];
+                for rule in rules.iter() {
+                    let Some(pos) = fields::position(fields, &rule.source) else {
+                        continue;
+                    };
+                    extended.push(Field {
+                        field_type: rule.field_type,
+                        group: rule.group.unwrap_or(fields[pos].group),
+                        name: rule.name,
+                        id: rule.id,
+                        scale: 1.0,
+                        bias: 0.0,
+                        unit: "",
+                        sum_of: &[],
+                        word_order: WordOrder::Little,
+                        reset: Reset::Never,
+                    });
+                    sources.push((pos, rule.above));
+                }
+                ExtendedTable {
+                    fields: extended.leak(),
+                    sources,
+                }
+            })
+    }
+}
+
+impl Stage for BinaryFields {
+    fn process(&mut self, mut update: Update<'static>) -> Option<Update<'static>> {
+        let table = self.table(update.fields);
+        if table.sources.is_empty() {
+            return Some(update);
+        }
+        for &(pos, above) in table.sources.iter() {
+            let value = update.values[pos];
+            update.values.push(if value.is_nan() {
+                f64::NAN
+            } else {
+                f64::from(u8::from(value > above))
+            });
+        }
+        if let Some(raw) = &mut update.raw {
+            raw.resize(table.fields.len(), vec![]);
+        }
+        update.fields = table.fields;
+        Some(update)
+    }
+}
+
+/// Replaces the serial number according to [SerialMode], and records the
+/// mapping so that commands can be translated back
+struct RenameSerial {
+    mode: SerialMode,
+    salt: String,
+    aliases: HashMap<String, String>,
+    map: SerialMap,
+}
+
+impl RenameSerial {
+    fn rename(&self, serial: &str) -> String {
+        if self.mode == SerialMode::Alias {
+            if let Some(alias) = self.aliases.get(serial) {
+                return alias.clone();
+            }
+        }
+        hash_serial(serial, &self.salt)
+    }
+}
+
+impl Stage for RenameSerial {
+    fn process(&mut self, mut update: Update<'static>) -> Option<Update<'static>> {
+        let renamed = self.rename(&update.serial);
+        let mut map = self.map.lock().unwrap();
+        if !map.contains_key(&renamed) {
+            if self.mode == SerialMode::Alias && !self.aliases.contains_key(&update.serial) {
+                warn!("No alias configured for a serial number; using {renamed}");
+            }
+            map.insert(renamed.clone(), update.serial.clone());
+        }
+        update.serial = renamed;
+        Some(update)
+    }
+}
+
+/// Sequence of stages to run on every update
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage + Send>>,
+    serial_map: Option<SerialMap>,
+}
+
+impl Pipeline {
+    /// Create the pipeline. Returns an error if a calibration curve in
+    /// `overrides` has two points with the same reported value.
+    pub fn new(
+        config: &Config,
+        overrides: &HashMap<String, FieldOverride>,
+    ) -> Result<Self, String> {
+        Self::with_clock(config, overrides, Arc::new(SystemClock))
+    }
+
+    /// Create the pipeline, using `clock` to tell when a source has gone
+    /// stale
+    pub fn with_clock(
+        config: &Config,
+        overrides: &HashMap<String, FieldOverride>,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self, String> {
+        let mut overrides = overrides.clone();
+        for (id, o) in overrides.iter_mut() {
+            o.calibration.sort_by(|a, b| a.0.total_cmp(&b.0));
+            // The reported values must be strictly increasing, since
+            // interpolating between equal ones divides by zero
+            if o.calibration.windows(2).any(|pair| pair[0].0 >= pair[1].0)
+                || o.calibration.iter().any(|p| !p.0.is_finite())
+            {
+                return Err(format!(
+                    "calibration points for {id} must have distinct, finite reported values"
+                ));
+            }
+        }
+        let mut stages: Vec<Box<dyn Stage + Send>> = vec![];
+        // Before the sequence numbers, since updates from a fallback source
+        // are not lost, and so should not show up as gaps
+        if !config.source_priority.is_empty() {
+            let timeout = config.source_timeout.unwrap_or(DEFAULT_SOURCE_TIMEOUT);
+            stages.push(Box::new(Failover {
+                priority: config.source_priority.clone(),
+                timeout: Duration::from_secs_f64(timeout),
+                last: HashMap::new(),
+                clock,
+            }));
+        }
+        // Sequence numbers come next, so that updates dropped by the
+        // later stages show up as gaps
+        stages.push(Box::<Sequence>::default());
+        // Fix the sign before the values are used in sums or overridden
+        if !config.negative.is_empty() {
+            stages.push(Box::new(Unsigned {
+                policies: config.negative.clone(),
+            }));
+        }
+        stages.push(Box::new(FieldValues { overrides }));
+        // After the overrides, so that the states follow the corrected
+        // values
+        if !config.binary_fields.is_empty() {
+            stages.push(Box::new(BinaryFields::new(&config.binary_fields)));
+        }
+        stages.push(Box::new(Sanitize {
+            mode: config.non_finite,
+            replacement: config.non_finite_value,
+            counts: HashMap::new(),
+        }));
+        if config.dedup {
+            stages.push(Box::<Dedup>::default());
+        }
+        if let Some(interval) = config.min_interval {
+            stages.push(Box::new(Downsample {
+                interval: (interval * 1e9) as i64,
+                last: HashMap::new(),
+            }));
+        }
+        if let Some(factor) = config.gap_intervals {
+            stages.push(Box::new(Gaps {
+                factor,
+                last: HashMap::new(),
+            }));
+        }
+        if config.fixed_point {
+            stages.push(Box::new(FixedPoint));
+        }
+        let mut serial_map = None;
+        if config.serial_mode != SerialMode::Plain {
+            let map = SerialMap::default();
+            stages.push(Box::new(RenameSerial {
+                mode: config.serial_mode,
+                salt: config.serial_salt.clone(),
+                aliases: config.serial_aliases.clone(),
+                map: Arc::clone(&map),
+            }));
+            serial_map = Some(map);
+        }
+        Ok(Self { stages, serial_map })
+    }
+
+    /// Mapping from the serial numbers in the output back to the real
+    /// serial numbers, or `None` if they are not changed
+    pub fn serial_map(&self) -> Option<SerialMap> {
+        self.serial_map.clone()
+    }
+
+    /// Run an update through all the stages
+    pub fn process(&mut self, update: UpdateItem) -> Option<UpdateItem> {
+        let mut update = Arc::try_unwrap(update).unwrap_or_else(|update| (*update).clone());
+        for stage in self.stages.iter_mut() {
+            update = stage.process(update)?;
+        }
+        Some(Arc::new(update))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::fields::{Field, FieldType, Reset, WordOrder};
+    use crate::receiver::Metadata;
+    use assert_approx_eq::assert_approx_eq;
+
+    fn update(timestamp: i64, serial: &str) -> UpdateItem {
+        Arc::new(Update::new(timestamp, serial, &[], vec![]))
+    }
+
+    fn timestamps(pipeline: &mut Pipeline, items: &[(i64, &str)]) -> Vec<i64> {
+        items
+            .iter()
+            .filter_map(|(ts, serial)| pipeline.process(update(*ts, serial)))
+            .map(|update| update.timestamp)
+            .collect()
+    }
+
+    #[test]
+    fn test_empty() {
+        let mut pipeline = Pipeline::new(&Config::default(), &HashMap::new()).unwrap();
+        assert_eq!(timestamps(&mut pipeline, &[(1, "a"), (1, "a")]), [1, 1]);
+    }
+
+    const fn field(id: &'static str, sum_of: &'static [(usize, f64)]) -> Field<'static> {
+        Field {
+            field_type: FieldType::Power,
+            group: "PV",
+            name: id,
+            id,
+            scale: 1.0,
+            bias: 0.0,
+            unit: "W",
+            sum_of,
+            word_order: WordOrder::Little,
+            reset: Reset::Never,
+        }
+    }
+
+    static FIELDS: [Field; 3] = [
+        field("a", &[]),
+        field("b", &[]),
+        field("total", &[(0, 1.0), (1, 1.0)]),
+    ];
+
+    #[test]
+    fn test_sums() {
+        let mut pipeline = Pipeline::new(&Config::default(), &HashMap::new()).unwrap();
+        let update = Update::new(0, "a", &FIELDS, vec![2.0, 3.0, f64::NAN]);
+        let update = pipeline.process(Arc::new(update)).unwrap();
+        assert_eq!(update.values, [2.0, 3.0, 5.0]);
+    }
+
+    #[test]
+    fn test_overrides() {
+        let overrides: HashMap<String, FieldOverride> =
+            toml::from_str("a = { scale = -1.0 }\ntotal = { bias = 0.5 }").unwrap();
+        let mut pipeline = Pipeline::new(&Config::default(), &overrides).unwrap();
+        let update = Update::new(0, "a", &FIELDS, vec![2.0, 3.0, f64::NAN]);
+        let update = pipeline.process(Arc::new(update)).unwrap();
+        assert_eq!(update.values, [-2.0, 3.0, 1.5]);
+    }
+
+    #[test]
+    fn test_binary_fields() {
+        let config: Config = toml::from_str(
+            r#"
+            [binary_fields.pv_producing]
+            field = "total"
+            above = 10.0
+            type = "power_detected"
+            name = "Producing"
+
+            [binary_fields.grid_present]
+            field = "grid_voltage"
+            above = 100.0
+            type = "connectivity"
+            name = "Present"
+            "#,
+        )
+        .unwrap();
+        config.check().unwrap();
+        let mut pipeline = Pipeline::new(&config, &HashMap::new()).unwrap();
+        let process = |pipeline: &mut Pipeline, values: Vec<f64>| {
+            let mut update = Update::new(0, "a", &FIELDS, values);
+            update.raw = Some(vec![vec![1], vec![2], vec![]]);
+            pipeline.process(Arc::new(update)).unwrap()
+        };
+        let update = process(&mut pipeline, vec![2.0, 3.0, f64::NAN]);
+        // Only the field whose source is present is added
+        assert_eq!(update.fields.len(), 4);
+        let field = &update.fields[3];
+        assert_eq!(field.id, "pv_producing");
+        assert_eq!(field.group, "PV");
+        assert_eq!(field.name, "Producing");
+        assert_eq!(field.field_type, FieldType::PowerDetected);
+        assert_eq!(update.values, [2.0, 3.0, 5.0, 0.0]);
+        assert_eq!(update.raw.as_ref().unwrap().len(), 4);
+        let next = process(&mut pipeline, vec![8.0, 3.0, f64::NAN]);
+        assert_eq!(next.get("pv_producing"), Some(1.0));
+        // The extended table is reused
+        assert!(std::ptr::eq(next.fields, update.fields));
+    }
+
+    #[test]
+    fn test_binary_fields_check() {
+        let check = |toml: &str| toml::from_str::<Config>(toml).unwrap().check();
+        let binary = |id: &str, field_type: &str| {
+            format!(
+                "[binary_fields.{id}]\nfield = \"grid_voltage\"\nabove = 1.0\n\
+                 type = \"{field_type}\"\nname = \"Present\""
+            )
+        };
+        assert!(check(&binary("grid_present", "connectivity")).is_ok());
+        assert!(check(&binary("grid_present", "voltage")).is_err());
+        // Clashes with the built-in fields
+        #[cfg(any(feature = "afpacket", feature = "modbus", feature = "pcap"))]
+        assert!(check(&binary("grid_voltage", "connectivity")).is_err());
+    }
+
+    #[test]
+    fn test_calibration() {
+        let o = FieldOverride {
+            scale: 1.0,
+            bias: 0.0,
+            calibration: vec![(0.0, 0.0), (10.0, 9.7), (20.0, 19.0)],
+        };
+        assert_approx_eq!(o.apply(-10.0), -9.7);
+        assert_approx_eq!(o.apply(5.0), 4.85);
+        assert_approx_eq!(o.apply(10.0), 9.7);
+        assert_approx_eq!(o.apply(15.0), 14.35);
+        assert_approx_eq!(o.apply(30.0), 28.3);
+        let o = FieldOverride {
+            scale: 2.0,
+            bias: 0.0,
+            calibration: vec![(1.0, 1.5)],
+        };
+        assert_approx_eq!(o.apply(3.0), 6.5);
+    }
+
+    #[test]
+    fn test_calibration_unsorted() {
+        let overrides: HashMap<String, FieldOverride> =
+            toml::from_str("a = { calibration = [[10.0, 20.0], [0.0, 0.0]] }").unwrap();
+        let mut pipeline = Pipeline::new(&Config::default(), &overrides).unwrap();
+        let update = Update::new(0, "a", &FIELDS, vec![2.0, 3.0, f64::NAN]);
+        let update = pipeline.process(Arc::new(update)).unwrap();
+        assert_eq!(update.values, [4.0, 3.0, 7.0]);
+    }
+
+    #[test]
+    fn test_calibration_duplicate() {
+        let overrides: HashMap<String, FieldOverride> =
+            toml::from_str("a = { calibration = [[10.0, 20.0], [0.0, 0.0], [10.0, 21.0]] }")
+                .unwrap();
+        assert!(Pipeline::new(&Config::default(), &overrides).is_err());
+        let overrides: HashMap<String, FieldOverride> =
+            toml::from_str("a = { calibration = [[0.0, 0.0], [nan, 1.0]] }").unwrap();
+        assert!(Pipeline::new(&Config::default(), &overrides).is_err());
+    }
+
+    #[test]
+    fn test_non_finite() {
+        let run = |config: &Config, values: Vec<f64>| {
+            let mut pipeline = Pipeline::new(config, &HashMap::new()).unwrap();
+            let update = Update::new(0, "a", &FIELDS, values);
+            pipeline
+                .process(Arc::new(update))
+                .map(|update| update.values.clone())
+        };
+        // The total is derived from the infinite value
+        let values = run(&Config::default(), vec![f64::INFINITY, 1.0, f64::NAN]).unwrap();
+        assert!(values[0].is_nan());
+        assert_eq!(values[1], 1.0);
+        assert!(values[2].is_nan());
+
+        let config = Config {
+            non_finite: NonFinite::Replace,
+            non_finite_value: -1.0,
+            ..Default::default()
+        };
+        let values = run(&config, vec![f64::NEG_INFINITY, 1.0, f64::NAN]).unwrap();
+        assert_eq!(values, [-1.0, 1.0, -1.0]);
+
+        let config = Config {
+            non_finite: NonFinite::Drop,
+            ..Default::default()
+        };
+        assert_eq!(
+            run(&config, vec![2.0, 1.0, f64::NAN]),
+            Some(vec![2.0, 1.0, 3.0])
+        );
+        assert_eq!(run(&config, vec![f64::INFINITY, 1.0, f64::NAN]), None);
+        // Missing values are not dropped
+        assert!(run(&config, vec![f64::NAN, 1.0, f64::NAN]).is_some());
+    }
+
+    #[test]
+    fn test_negative() {
+        static ENERGY: [Field; 3] = [
+            Field {
+                field_type: FieldType::Energy,
+                scale: 0.1,
+                ..field("a", &[])
+            },
+            field("b", &[]),
+            Field {
+                field_type: FieldType::Energy,
+                ..field("total", &[(0, 1.0), (1, 1.0)])
+            },
+        ];
+        let run = |negative: Negative, raw: Option<Vec<Vec<u16>>>| {
+            let config = Config {
+                negative: HashMap::from([(FieldType::Energy, negative)]),
+                ..Default::default()
+            };
+            let mut pipeline = Pipeline::new(&config, &HashMap::new()).unwrap();
+            let mut update = Update::new(0, "a", &ENERGY, vec![-3000.0, -1.0, f64::NAN]);
+            update.raw = raw;
+            pipeline.process(Arc::new(update)).unwrap().values.clone()
+        };
+        assert_eq!(run(Negative::Allow, None), [-3000.0, -1.0, -3001.0]);
+        // Only the energy counter is changed, and the sum is computed from
+        // the corrected value
+        assert_eq!(run(Negative::Clamp, None), [0.0, -1.0, -1.0]);
+        let values = run(Negative::Wrap, None);
+        assert_approx_eq!(values[0], 3553.6);
+        assert_eq!(values[1], -1.0);
+        assert_approx_eq!(values[2], 3552.6);
+        // The raw values give the number of registers
+        let raw = vec![vec![0; 2], vec![0], vec![]];
+        assert_approx_eq!(run(Negative::Wrap, Some(raw))[0], 429493729.6);
+
+        let config: Config =
+            toml::from_str(r#"negative = { energy = "wrap", state_of_charge = "clamp" }"#).unwrap();
+        assert_eq!(config.negative[&FieldType::Energy], Negative::Wrap);
+        assert_eq!(config.negative[&FieldType::StateOfCharge], Negative::Clamp);
+    }
+
+    #[test]
+    fn test_non_finite_counts() {
+        let mut sanitize = Sanitize {
+            mode: NonFinite::Skip,
+            replacement: 0.0,
+            counts: HashMap::new(),
+        };
+        for _ in 0..3 {
+            let update = Update::new(0, "a", &FIELDS, vec![f64::INFINITY, f64::NAN, 2.0]);
+            let update = sanitize.process(update).unwrap();
+            assert!(update.values[0].is_nan());
+        }
+        assert_eq!(sanitize.counts["a"], 3);
+        // Missing values are not counted
+        assert!(!sanitize.counts.contains_key("b"));
+    }
+
+    #[test]
+    fn test_non_finite_missing() {
+        let mut sanitize = Sanitize {
+            mode: NonFinite::Drop,
+            replacement: 0.0,
+            counts: HashMap::new(),
+        };
+        let update = Update::new(0, "a", &FIELDS, vec![1.0, f64::NAN, 2.0]);
+        let update = sanitize.process(update).unwrap();
+        assert!(update.values[1].is_nan());
+        let update = Update::new(0, "a", &FIELDS, vec![1.0, f64::NEG_INFINITY, 2.0]);
+        assert!(sanitize.process(update).is_none());
+
+        sanitize.mode = NonFinite::Replace;
+        let update = Update::new(0, "a", &FIELDS, vec![f64::NAN, f64::NEG_INFINITY, 2.0]);
+        let update = sanitize.process(update).unwrap();
+        assert!(update.values[0].is_nan());
+        assert_eq!(update.values[1], 0.0);
+        assert!(!sanitize.counts.contains_key("a"));
+    }
+
+    #[test]
+    fn test_fixed_point() {
+        let config = Config {
+            fixed_point: true,
+            ..Default::default()
+        };
+        let mut pipeline = Pipeline::new(&config, &HashMap::new()).unwrap();
+        let update = Update::new(0, "a", &FIELDS, vec![5400.0 * 0.01, -0.0016, f64::NAN]);
+        let update = pipeline.process(Arc::new(update)).unwrap();
+        assert_eq!(update.values, [54.0, -0.002, 53.998]);
+        assert_eq!(update.values[0].to_string(), "54");
+        assert_eq!(update.fixed, Some(vec![Some(54000), Some(-2), Some(53998)]));
+
+        // Values are still rounded when others are missing
+        let update = Update::new(0, "a", &FIELDS, vec![f64::NAN, 0.1 + 0.2, f64::NAN]);
+        let update = pipeline.process(Arc::new(update)).unwrap();
+        assert!(update.values[0].is_nan());
+        assert_eq!(update.values[1].to_string(), "0.3");
+        assert_eq!(update.fixed, Some(vec![None, Some(300), None]));
+    }
+
+    #[test]
+    fn test_hash_serial() {
+        let hashed = hash_serial("1234567890", "");
+        assert_eq!(hashed.len(), 16);
+        assert_eq!(hashed, hash_serial("1234567890", ""));
+        assert_ne!(hashed, hash_serial("1234567891", ""));
+        assert_ne!(hashed, hash_serial("1234567890", "secret"));
+    }
+
+    #[test]
+    fn test_serial_mode() {
+        let config: Config =
+            toml::from_str("serial_mode = \"alias\"\nserial_aliases = { 1234567890 = \"home\" }")
+                .unwrap();
+        let mut pipeline = Pipeline::new(&config, &HashMap::new()).unwrap();
+        let items = [(0, "1234567890"), (0, "2222222222")];
+        let serials: Vec<String> = items
+            .iter()
+            .filter_map(|(ts, serial)| pipeline.process(update(*ts, serial)))
+            .map(|update| update.serial.clone())
+            .collect();
+        let hashed = hash_serial("2222222222", "");
+        assert_eq!(serials, ["home", hashed.as_str()]);
+        let map = pipeline.serial_map().unwrap();
+        let map = map.lock().unwrap();
+        assert_eq!(map["home"], "1234567890");
+        assert_eq!(map[&hashed], "2222222222");
+
+        let pipeline = Pipeline::new(&Config::default(), &HashMap::new()).unwrap();
+        assert!(pipeline.serial_map().is_none());
+    }
+
+    #[test]
+    fn test_dedup() {
+        let config = Config {
+            dedup: true,
+            ..Default::default()
+        };
+        let mut pipeline = Pipeline::new(&config, &HashMap::new()).unwrap();
+        let items = [(1, "a"), (1, "b"), (1, "a"), (2, "a"), (1, "a")];
+        assert_eq!(timestamps(&mut pipeline, &items), [1, 1, 2, 1]);
+    }
+
+    #[test]
+    fn test_sequence() {
+        let config = Config {
+            dedup: true,
+            ..Default::default()
+        };
+        let mut pipeline = Pipeline::new(&config, &HashMap::new()).unwrap();
+        let mut sequence = |timestamp, source: &str| {
+            let metadata = Metadata {
+                source: Some(source.to_owned()),
+                ..Default::default()
+            };
+            let update = Update::new(timestamp, "a", &[], vec![]).with_metadata(metadata);
+            pipeline
+                .process(Arc::new(update))
+                .map(|update| update.metadata.sequence.unwrap())
+        };
+        assert_eq!(sequence(1, "pcap:eth0"), Some(1));
+        assert_eq!(sequence(10, "pylontech"), Some(1));
+        // The duplicate is dropped, leaving a gap
+        assert_eq!(sequence(2, "pcap:eth0"), Some(2));
+        assert_eq!(sequence(2, "pcap:eth0"), None);
+        assert_eq!(sequence(3, "pcap:eth0"), Some(4));
+    }
+
+    #[test]
+    fn test_downsample() {
+        let config = Config {
+            min_interval: Some(10e-9),
+            ..Default::default()
+        };
+        let mut pipeline = Pipeline::new(&config, &HashMap::new()).unwrap();
+        let items = [
+            (0, "a"),
+            (5, "a"),
+            (5, "b"),
+            (10, "a"),
+            (19, "a"),
+            (20, "a"),
+        ];
+        assert_eq!(timestamps(&mut pipeline, &items), [0, 5, 10, 20]);
+    }
+
+    #[test]
+    fn test_gaps() {
+        let config = Config {
+            gap_intervals: Some(3.0),
+            ..Default::default()
+        };
+        let mut pipeline = Pipeline::new(&config, &HashMap::new()).unwrap();
+        let second = 1_000_000_000;
+        let items = [
+            (0, "a"),
+            (60 * second, "a"),
+            (120 * second, "a"),
+            (0, "b"),
+            (150 * second, "a"),
+            (600 * second, "a"),
+            (660 * second, "a"),
+        ];
+        let gaps: Vec<Option<Duration>> = items
+            .iter()
+            .filter_map(|(ts, serial)| pipeline.process(update(*ts, serial)))
+            .map(|update| update.metadata.gap)
+            .collect();
+        assert_eq!(
+            gaps,
+            [
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some(Duration::from_secs(450)),
+                None,
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failover() {
+        let config = Config {
+            source_priority: vec!["pcap".to_owned(), "modbus:/dev/ttyUSB0".to_owned()],
+            source_timeout: Some(10.0),
+            ..Default::default()
+        };
+        let mut pipeline =
+            Pipeline::with_clock(&config, &HashMap::new(), MockClock::new(0)).unwrap();
+        let mut kept = |timestamp, serial: &str, source: &str| {
+            let metadata = Metadata {
+                source: Some(source.to_owned()),
+                ..Default::default()
+            };
+            let update = Update::new(timestamp, serial, &[], vec![]).with_metadata(metadata);
+            pipeline.process(Arc::new(update)).is_some()
+        };
+        let second = 1_000_000_000;
+        // The timestamps come from different clocks: pcap from the
+        // inverter's clock, which is an hour behind
+        let pcap = |seconds: i64| (seconds - 3600) * second;
+        assert!(kept(0, "a", "modbus:/dev/ttyUSB0"));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(kept(pcap(1), "a", "pcap:eth0"));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        // pcap is healthy, so modbus is suppressed
+        assert!(!kept(2 * second, "a", "modbus:/dev/ttyUSB0"));
+        assert!(kept(pcap(3), "a", "pcap:eth0"));
+        // Other inverters and unlisted sources are unaffected
+        assert!(kept(4 * second, "b", "modbus:/dev/ttyUSB0"));
+        assert!(kept(5 * second, "a", "can:can0"));
+        tokio::time::advance(Duration::from_secs(9)).await;
+        assert!(!kept(11 * second, "a", "modbus:/dev/ttyUSB0"));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        // pcap has gone stale, so modbus takes over
+        assert!(kept(12 * second, "a", "modbus:/dev/ttyUSB0"));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(kept(pcap(13), "a", "pcap:eth0"));
+        assert!(!kept(13 * second, "a", "modbus:/dev/ttyUSB0"));
+    }
+
+    #[test]
+    fn test_failover_check() {
+        let config = Config {
+            source_priority: vec!["pcap".to_owned()],
+            source_timeout: Some(0.0),
+            ..Default::default()
+        };
+        assert!(config.check().is_err());
+    }
+
+    #[test]
+    fn test_gaps_check() {
+        let config = |gap_intervals| Config {
+            gap_intervals,
+            ..Default::default()
+        };
+        assert!(config(None).check().is_ok());
+        assert!(config(Some(3.0)).check().is_ok());
+        assert!(config(Some(0.0)).check().is_err());
+        assert!(config(Some(-1.0)).check().is_err());
+        assert!(config(Some(f64::NAN)).check().is_err());
+    }
+}
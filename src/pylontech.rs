@@ -0,0 +1,563 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Auxiliary source that reads per-pack information directly from a
+//! Pylontech (or compatible) battery BMS, using its RS485 protocol.
+
+use futures::channel::mpsc;
+use futures::prelude::*;
+use log::{error, info};
+use serde::Deserialize;
+use serde_with::serde_as;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::time::MissedTickBehavior;
+use tokio_serial::{ClearBuffer, SerialPort, SerialPortBuilderExt, SerialStream};
+
+use crate::fields::{Field, FieldType, Reset, WordOrder};
+use crate::receiver::{Metadata, Update, UpdateStream};
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Protocol version sent in requests
+const VERSION: u8 = 0x20;
+/// Device type code for a battery
+const CID1_BATTERY: u8 = 0x46;
+/// Command to get analog values (in fixed-point)
+const CID2_ANALOG: u8 = 0x42;
+/// Command to get alarm information
+const CID2_ALARM: u8 = 0x44;
+/// Time to wait for the BMS to respond to a query
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Structure corresponding to the `[pylontech]` section of the configuration file.
+#[serde_as]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PylontechConfig {
+    device: String,
+    #[serde_as(as = "serde_with::DurationSecondsWithFrac<f64>")]
+    interval: Duration,
+    #[serde(default = "default_baud")]
+    baud: u32,
+    /// Address of the first pack
+    #[serde(default = "default_address")]
+    address: u8,
+    /// Number of packs (with consecutive addresses)
+    #[serde(default = "default_packs")]
+    packs: u8,
+    /// Name to use in place of an inverter serial number
+    #[serde(default = "default_serial")]
+    serial: String,
+}
+
+fn default_baud() -> u32 {
+    9600
+}
+
+fn default_address() -> u8 {
+    2
+}
+
+fn default_packs() -> u8 {
+    1
+}
+
+fn default_serial() -> String {
+    String::from("pylontech")
+}
+
+impl PylontechConfig {
+    /// Addresses of the packs, or an error if they do not all fit in a byte
+    fn addresses(&self) -> Result<Vec<u8>, String> {
+        (0..self.packs)
+            .map(|i| {
+                self.address.checked_add(i).ok_or_else(|| {
+                    format!(
+                        "pylontech address {} with {} packs goes beyond address 255",
+                        self.address, self.packs
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+/// Checksum over the ASCII characters of a frame, excluding the start and
+/// end markers and the checksum itself
+fn checksum(data: &[u8]) -> u16 {
+    let sum = data.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+    (!sum).wrapping_add(1)
+}
+
+/// Encode the length of the hex-encoded INFO, with its 4-bit checksum
+fn length_code(len: usize) -> u16 {
+    let len = len as u16 & 0xfff;
+    let sum = (len & 0xf) + ((len >> 4) & 0xf) + (len >> 8);
+    let check = (!sum).wrapping_add(1) & 0xf;
+    (check << 12) | len
+}
+
+fn encode_frame(address: u8, cid2: u8, info: &[u8]) -> Vec<u8> {
+    let info: String = info.iter().map(|b| format!("{b:02X}")).collect();
+    let body = format!(
+        "{VERSION:02X}{address:02X}{CID1_BATTERY:02X}{cid2:02X}{:04X}{info}",
+        length_code(info.len())
+    );
+    format!("~{body}{:04X}\r", checksum(body.as_bytes())).into_bytes()
+}
+
+fn parse_hex(text: &[u8]) -> Result<Vec<u8>, Error> {
+    let pairs = text.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return Err("odd number of hex digits".into());
+    }
+    pairs
+        .map(|pair| Ok(u8::from_str_radix(std::str::from_utf8(pair)?, 16)?))
+        .collect()
+}
+
+/// Check the framing and checksums of a response (including the trailing
+/// carriage return), and that it comes from the battery at `address`, and
+/// return the decoded INFO.
+fn decode_frame(frame: &[u8], address: u8) -> Result<Vec<u8>, Error> {
+    let frame = frame
+        .strip_prefix(b"~")
+        .and_then(|frame| frame.strip_suffix(b"\r"))
+        .ok_or("response is not correctly framed")?;
+    if frame.len() < 16 {
+        return Err("response is too short".into());
+    }
+    let (body, expected) = frame.split_at(frame.len() - 4);
+    if parse_hex(expected)? != checksum(body).to_be_bytes() {
+        return Err("response has incorrect checksum".into());
+    }
+    let header = parse_hex(&body[..12])?;
+    // A late response to a query for another pack is not taken as the answer
+    if header[1] != address || header[2] != CID1_BATTERY {
+        return Err(format!(
+            "response is from address {:#04x} and device type {:#04x}, not from the battery at {address:#04x}",
+            header[1], header[2]
+        )
+        .into());
+    }
+    let rtn = header[3];
+    if rtn != 0 {
+        return Err(format!("BMS returned error code {rtn:#04x}").into());
+    }
+    let length = u16::from_be_bytes([header[4], header[5]]);
+    if length_code((length & 0xfff) as usize) != length
+        || (length & 0xfff) as usize != body.len() - 12
+    {
+        return Err("response has incorrect length".into());
+    }
+    parse_hex(&body[12..])
+}
+
+/// Cursor over the INFO of a response
+struct Info<'a> {
+    data: &'a [u8],
+}
+
+impl Info<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        if self.data.len() < N {
+            return Err("response is truncated".into());
+        }
+        let (head, tail) = self.data.split_at(N);
+        self.data = tail;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_be_bytes(self.take()?))
+    }
+
+    fn u24(&mut self) -> Result<u32, Error> {
+        let [a, b, c] = self.take()?;
+        Ok(u32::from_be_bytes([0, a, b, c]))
+    }
+}
+
+/// Analog values for a single pack
+#[derive(Debug, PartialEq)]
+struct Analog {
+    /// Cell voltages in V
+    cells: Vec<f64>,
+    /// Temperatures in °C
+    temperatures: Vec<f64>,
+    /// Current in A (positive when charging)
+    current: f64,
+    /// Pack voltage in V
+    voltage: f64,
+    /// Remaining charge in Ah
+    remaining: f64,
+    /// Full charge in Ah
+    total: f64,
+    cycles: f64,
+}
+
+fn parse_analog(info: &[u8]) -> Result<Analog, Error> {
+    let mut info = Info { data: info };
+    info.u8()?; // INFOFLAG
+    info.u8()?; // Pack address
+    let num_cells = info.u8()?;
+    let cells = (0..num_cells)
+        .map(|_| Ok(info.u16()? as f64 / 1000.0))
+        .collect::<Result<_, Error>>()?;
+    let num_temperatures = info.u8()?;
+    let temperatures = (0..num_temperatures)
+        .map(|_| Ok((info.u16()? as f64 - 2731.0) / 10.0))
+        .collect::<Result<_, Error>>()?;
+    let current = info.u16()? as i16 as f64 / 100.0;
+    let voltage = info.u16()? as f64 / 1000.0;
+    let mut remaining = info.u16()? as f64 / 100.0;
+    let user_defined = info.u8()?;
+    let mut total = info.u16()? as f64 / 100.0;
+    let cycles = info.u16()? as f64;
+    // Packs too large for 16-bit capacities report them again with 24 bits
+    if user_defined >= 4 {
+        remaining = info.u24()? as f64 / 1000.0;
+        total = info.u24()? as f64 / 1000.0;
+    }
+    Ok(Analog {
+        cells,
+        temperatures,
+        current,
+        voltage,
+        remaining,
+        total,
+        cycles,
+    })
+}
+
+/// Count the alarms and protections that are active for a pack
+fn parse_alarms(info: &[u8]) -> Result<u32, Error> {
+    let mut info = Info { data: info };
+    info.u8()?; // INFOFLAG
+    info.u8()?; // Pack address
+    let mut alarms = 0;
+    let num_cells = info.u8()?;
+    for _ in 0..num_cells {
+        alarms += (info.u8()? != 0) as u32;
+    }
+    let num_temperatures = info.u8()?;
+    for _ in 0..num_temperatures {
+        alarms += (info.u8()? != 0) as u32;
+    }
+    // Charge current, pack voltage and discharge current
+    for _ in 0..3 {
+        alarms += (info.u8()? != 0) as u32;
+    }
+    // Status 1 is a bitmask of protections
+    alarms += info.u8()?.count_ones();
+    Ok(alarms)
+}
+
+async fn query(
+    port: &mut BufReader<SerialStream>,
+    address: u8,
+    cid2: u8,
+) -> Result<Vec<u8>, Error> {
+    // Discard anything not yet read (such as a response that arrived after
+    // the previous query timed out), so that it is not taken as the answer
+    let buffered = port.buffer().len();
+    Pin::new(&mut *port).consume(buffered);
+    port.get_mut().clear(ClearBuffer::Input)?;
+    port.get_mut()
+        .write_all(&encode_frame(address, cid2, &[address]))
+        .await?;
+    let mut response = vec![];
+    tokio::time::timeout(RESPONSE_TIMEOUT, port.read_until(b'\r', &mut response)).await??;
+    let info = decode_frame(&response, address)?;
+    // The INFO of both commands starts with INFOFLAG and the pack address
+    if info.get(1).is_some_and(|&pack| pack != address) {
+        return Err(format!("response is for pack {}, not pack {address}", info[1]).into());
+    }
+    Ok(info)
+}
+
+fn leak(s: String) -> &'static str {
+    Box::leak(s.into_boxed_str())
+}
+
+/// Build the field table for the packs, given the number of cells and
+/// temperatures in each one
+fn make_fields(shapes: &[(usize, usize)]) -> Vec<Field<'static>> {
+    let mut fields = vec![];
+    for (i, &(num_cells, num_temperatures)) in shapes.iter().enumerate() {
+        let group = leak(format!("Pack {}", i + 1));
+        let mut add = |field_type, name: String, suffix: String, unit| {
+            fields.push(Field {
+                field_type,
+                group,
+                name: leak(name),
+                id: leak(format!("bms_pack_{}_{suffix}", i + 1)),
+                scale: 1.0,
+                bias: 0.0,
+                unit,
+                sum_of: &[],
+                word_order: WordOrder::Little,
+                reset: Reset::Never,
+            })
+        };
+        add(FieldType::Voltage, "Voltage".into(), "voltage".into(), "V");
+        add(FieldType::Current, "Current".into(), "current".into(), "A");
+        add(FieldType::StateOfCharge, "SOC".into(), "soc".into(), "%");
+        add(
+            FieldType::Charge,
+            "Remaining".into(),
+            "remaining".into(),
+            "Ah",
+        );
+        add(
+            FieldType::Charge,
+            "Capacity".into(),
+            "capacity".into(),
+            "Ah",
+        );
+        add(FieldType::Unitless, "Cycles".into(), "cycles".into(), "");
+        add(FieldType::Unitless, "Alarms".into(), "alarms".into(), "");
+        for j in 1..=num_cells {
+            add(
+                FieldType::Voltage,
+                format!("Cell {j} Voltage"),
+                format!("cell_voltage_{j}"),
+                "V",
+            );
+        }
+        for j in 1..=num_temperatures {
+            add(
+                FieldType::Temperature,
+                format!("Temperature {j}"),
+                format!("temperature_{j}"),
+                "°C",
+            );
+        }
+    }
+    fields
+}
+
+/// Values for one pack, in the order used by [make_fields]
+fn pack_values(analog: &Analog, alarms: u32) -> Vec<f64> {
+    let mut values = vec![
+        analog.voltage,
+        analog.current,
+        // A pack that reports no capacity has an unknown state of charge
+        if analog.total > 0.0 {
+            (analog.remaining / analog.total * 100.0).round()
+        } else {
+            f64::NAN
+        },
+        analog.remaining,
+        analog.total,
+        analog.cycles,
+        alarms as f64,
+    ];
+    values.extend_from_slice(&analog.cells);
+    values.extend_from_slice(&analog.temperatures);
+    values
+}
+
+async fn read_values(
+    port: &mut BufReader<SerialStream>,
+    addresses: &[u8],
+    shapes: &[(usize, usize)],
+) -> Result<Vec<f64>, Error> {
+    let mut values = vec![];
+    for (&address, &shape) in addresses.iter().zip(shapes.iter()) {
+        let analog = parse_analog(&query(port, address, CID2_ANALOG).await?)?;
+        if (analog.cells.len(), analog.temperatures.len()) != shape {
+            return Err(format!("pack {address} changed its number of cells").into());
+        }
+        let alarms = parse_alarms(&query(port, address, CID2_ALARM).await?)?;
+        values.extend(pack_values(&analog, alarms));
+    }
+    Ok(values)
+}
+
+/// Create a stream of updates from the BMS. The stream never ends by
+/// itself, so it is intended to be merged into the main stream with
+/// [crate::receiver::merge_auxiliary].
+pub async fn create_stream(
+    config: &PylontechConfig,
+) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+    let interval = config.interval;
+    let serial = config.serial.clone();
+    let (mut sender, receiver) = mpsc::channel(1);
+    let port = tokio_serial::new(&config.device, config.baud).open_native_async()?;
+    let source = format!("pylontech:{}", config.device);
+    let mut port = BufReader::new(port);
+    let addresses = config.addresses()?;
+    // Query each pack once to find how many cells and sensors it has
+    let mut shapes = vec![];
+    for &address in addresses.iter() {
+        let analog = async { parse_analog(&query(&mut port, address, CID2_ANALOG).await?) }
+            .await
+            .map_err(|err| format!("Failed to query battery pack {address}: {err}"))?;
+        shapes.push((analog.cells.len(), analog.temperatures.len()));
+    }
+    let fields: &'static [Field<'static>] = make_fields(&shapes).leak();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            let start = Instant::now();
+            match read_values(&mut port, &addresses, &shapes).await {
+                Err(err) => {
+                    error!("Failed to read values from BMS: {err:?}");
+                }
+                Ok(values) => {
+                    info!("Received a set of values from the BMS");
+                    let now = chrono::Utc::now();
+                    let update =
+                        Update::new(now.timestamp_nanos_opt().unwrap(), &serial, fields, values)
+                            .with_metadata(Metadata {
+                                source: Some(source.clone()),
+                                protocol: Some("pylontech"),
+                                decode_duration: Some(start.elapsed()),
+                                ..Default::default()
+                            });
+                    if sender.send(Arc::new(update)).await.is_err() {
+                        break; // The main stream has ended
+                    }
+                }
+            }
+        }
+    });
+    Ok(Box::pin(receiver))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Wrap an INFO in a successful response frame
+    fn response(info: &[u8]) -> Vec<u8> {
+        // Responses have a return code where requests have a command
+        encode_frame(2, 0, info)
+    }
+
+    #[test]
+    fn test_encode_frame() {
+        assert_eq!(encode_frame(2, CID2_ANALOG, &[2]), b"~20024642E00202FD33\r");
+    }
+
+    #[test]
+    fn test_decode_frame() {
+        let frame = response(&[1, 2, 3]);
+        assert_eq!(decode_frame(&frame, 2).unwrap(), [1, 2, 3]);
+        let mut bad = frame.clone();
+        bad[13] = b'9';
+        assert!(decode_frame(&bad, 2).is_err());
+        assert!(decode_frame(&frame[1..], 2).is_err());
+        // Error return code
+        assert!(decode_frame(&encode_frame(2, 0x90, &[]), 2).is_err());
+        // Response from another pack
+        assert!(decode_frame(&frame, 3).is_err());
+        // Response from another type of device
+        let mut other = frame.clone();
+        other[5..7].copy_from_slice(b"4A");
+        let sum = checksum(&other[1..other.len() - 5]);
+        let n = other.len();
+        other[n - 5..n - 1].copy_from_slice(format!("{sum:04X}").as_bytes());
+        assert!(decode_frame(&other, 2).is_err());
+    }
+
+    #[test]
+    fn test_parse_analog() {
+        let mut info = vec![0x11, 0x02, 3];
+        for mv in [3300u16, 3310, 3320] {
+            info.extend_from_slice(&mv.to_be_bytes());
+        }
+        info.push(2);
+        for t in [2981u16, 2991] {
+            info.extend_from_slice(&t.to_be_bytes());
+        }
+        info.extend_from_slice(&(-150i16).to_be_bytes());
+        info.extend_from_slice(&9930u16.to_be_bytes());
+        info.extend_from_slice(&3700u16.to_be_bytes());
+        info.push(2);
+        info.extend_from_slice(&5000u16.to_be_bytes());
+        info.extend_from_slice(&123u16.to_be_bytes());
+        let analog = parse_analog(&info).unwrap();
+        assert_eq!(analog.cells, [3.3, 3.31, 3.32]);
+        assert_eq!(analog.temperatures, [25.0, 26.0]);
+        assert_eq!(analog.current, -1.5);
+        assert_eq!(analog.voltage, 9.93);
+        assert_eq!(analog.remaining, 37.0);
+        assert_eq!(analog.total, 50.0);
+        assert_eq!(analog.cycles, 123.0);
+        assert_eq!(pack_values(&analog, 0)[2], 74.0);
+        let empty = Analog {
+            total: 0.0,
+            ..analog
+        };
+        assert!(pack_values(&empty, 0)[2].is_nan());
+        assert!(parse_analog(&info[..info.len() - 1]).is_err());
+
+        // 24-bit capacities
+        let n = info.len();
+        info[n - 5] = 4;
+        info.extend_from_slice(&[0x01, 0x11, 0x70, 0x01, 0x86, 0xa0]);
+        let analog = parse_analog(&info).unwrap();
+        assert_eq!(analog.remaining, 70.0);
+        assert_eq!(analog.total, 100.0);
+    }
+
+    #[test]
+    fn test_parse_alarms() {
+        let info = [0x11, 0x02, 3, 0, 1, 0, 2, 0, 2, 0, 0, 0, 0x82];
+        assert_eq!(parse_alarms(&info).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_addresses() {
+        let config = |text: &str| -> PylontechConfig {
+            toml::from_str(&format!("device = \"/dev/ttyUSB0\"\ninterval = 10\n{text}")).unwrap()
+        };
+        assert_eq!(config("").addresses().unwrap(), [2]);
+        assert_eq!(
+            config("address = 1\npacks = 3").addresses().unwrap(),
+            [1, 2, 3]
+        );
+        assert_eq!(
+            config("address = 250\npacks = 6")
+                .addresses()
+                .unwrap()
+                .len(),
+            6
+        );
+        assert!(config("address = 250\npacks = 7").addresses().is_err());
+    }
+
+    #[test]
+    fn test_make_fields() {
+        let fields = make_fields(&[(2, 1), (3, 0)]);
+        assert_eq!(fields.len(), 7 + 3 + 7 + 3);
+        assert_eq!(fields[8].id, "bms_pack_1_cell_voltage_2");
+        assert_eq!(fields[9].id, "bms_pack_1_temperature_1");
+        assert_eq!(fields[10].id, "bms_pack_2_voltage");
+        assert_eq!(fields[10].group, "Pack 2");
+    }
+}
@@ -0,0 +1,337 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Bounded queues of updates for the receivers
+//!
+//! Each receiver takes updates from its own queue. A queue holds at most a
+//! fixed number of updates, so that a receiver which stops taking them (for
+//! example, because its server is down and it has its own buffer) cannot
+//! use up all the memory. When a queue is full, its [Policy] decides
+//! whether to wait for space or to drop an update.
+
+use futures::prelude::*;
+use serde::Deserialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// What to do with an update for a receiver whose queue is full
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// Wait for the receiver to take an update. This holds up all the
+    /// receivers, and the frontend.
+    Block,
+    /// Drop the oldest update in the queue to make space
+    #[default]
+    DropOldest,
+    /// Drop the new update
+    DropNewest,
+}
+
+/// Queue settings for a single receiver, overriding those in [Config]
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Overrides {
+    pub capacity: Option<usize>,
+    pub policy: Option<Policy>,
+}
+
+/// Structure corresponding to the `[queue]` section of the configuration
+/// file. It is constructed from the config file by serde.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Maximum number of updates waiting for each receiver
+    #[serde(default = "default_capacity")]
+    pub capacity: usize,
+    /// What to do when a queue is full
+    #[serde(default)]
+    pub policy: Policy,
+    /// Settings for individual receivers, keyed by the names used in log
+    /// messages (such as `influxdb2:home`)
+    #[serde(default)]
+    pub receivers: BTreeMap<String, Overrides>,
+}
+
+fn default_capacity() -> usize {
+    10000
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            capacity: default_capacity(),
+            policy: Policy::default(),
+            receivers: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Check the parts of the configuration that serde cannot
+    pub fn check(&self) -> Result<(), String> {
+        let capacities = self.receivers.values().filter_map(|o| o.capacity);
+        if std::iter::once(self.capacity)
+            .chain(capacities)
+            .any(|c| c == 0)
+        {
+            return Err("queue capacity must be at least 1".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Overrides for the receiver called `name`. The workers of a receiver
+    /// (named with a `#` suffix) share the overrides of the receiver.
+    fn overrides(&self, name: &str) -> Option<&Overrides> {
+        self.receivers.get(name).or_else(|| {
+            let (base, _) = name.rsplit_once('#')?;
+            self.receivers.get(base)
+        })
+    }
+
+    /// Capacity and policy for the receiver called `name`
+    pub fn settings(&self, name: &str) -> (usize, Policy) {
+        let overrides = self.overrides(name);
+        (
+            overrides.and_then(|o| o.capacity).unwrap_or(self.capacity),
+            overrides.and_then(|o| o.policy).unwrap_or(self.policy),
+        )
+    }
+
+    /// Keys of [Config::receivers] that do not match any of `names`
+    pub fn unknown<'a>(&'a self, names: &[&str]) -> Vec<&'a str> {
+        self.receivers
+            .keys()
+            .map(String::as_str)
+            .filter(|key| {
+                !names.iter().any(|name| {
+                    name == key || name.rsplit_once('#').is_some_and(|(base, _)| base == *key)
+                })
+            })
+            .collect()
+    }
+}
+
+/// The receiver has gone away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnected;
+
+impl std::fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the receiver has stopped")
+    }
+}
+
+impl std::error::Error for Disconnected {}
+
+struct State<T> {
+    items: VecDeque<T>,
+    /// The sender has finished
+    closed: bool,
+    /// The receiver has been dropped
+    disconnected: bool,
+    /// Sender waiting for space, with [Policy::Block]
+    sender_waker: Option<Waker>,
+    /// Receiver waiting for an item
+    receiver_waker: Option<Waker>,
+}
+
+/// Sending half of a queue, created by [channel]
+pub struct Sender<T> {
+    state: Arc<Mutex<State<T>>>,
+    capacity: usize,
+    policy: Policy,
+}
+
+/// Receiving half of a queue, created by [channel]
+pub struct Receiver<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+/// Create a queue holding up to `capacity` items, which applies `policy`
+/// when it is full
+pub fn channel<T>(capacity: usize, policy: Policy) -> (Sender<T>, Receiver<T>) {
+    let state = Arc::new(Mutex::new(State {
+        items: VecDeque::new(),
+        closed: false,
+        disconnected: false,
+        sender_waker: None,
+        receiver_waker: None,
+    }));
+    let sender = Sender {
+        state: Arc::clone(&state),
+        capacity,
+        policy,
+    };
+    (sender, Receiver { state })
+}
+
+impl<T> Sender<T> {
+    fn poll_send(
+        &self,
+        cx: &mut Context<'_>,
+        item: &mut Option<T>,
+    ) -> Poll<Result<Option<T>, Disconnected>> {
+        let mut state = self.state.lock().unwrap();
+        if state.disconnected {
+            return Poll::Ready(Err(Disconnected));
+        }
+        let mut dropped = None;
+        if state.items.len() >= self.capacity {
+            match self.policy {
+                Policy::Block => {
+                    state.sender_waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+                Policy::DropOldest => dropped = state.items.pop_front(),
+                Policy::DropNewest => return Poll::Ready(Ok(item.take())),
+            }
+        }
+        state.items.extend(item.take());
+        let waker = state.receiver_waker.take();
+        drop(state);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Poll::Ready(Ok(dropped))
+    }
+
+    /// Add an item to the queue. If the queue is full, this waits or drops
+    /// an item according to the policy, and returns the item that was
+    /// dropped (which may be `item` itself).
+    pub async fn send(&self, item: T) -> Result<Option<T>, Disconnected> {
+        let mut item = Some(item);
+        future::poll_fn(|cx| self.poll_send(cx, &mut item)).await
+    }
+
+    /// Indicate that no more items will be sent. The receiver still gets
+    /// the items that are in the queue.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        let waker = state.receiver_waker.take();
+        drop(state);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut state = self.state.lock().unwrap();
+        match state.items.pop_front() {
+            Some(item) => {
+                let waker = state.sender_waker.take();
+                drop(state);
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+                Poll::Ready(Some(item))
+            }
+            None if state.closed => Poll::Ready(None),
+            None => {
+                state.receiver_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.disconnected = true;
+        state.items.clear();
+        let waker = state.sender_waker.take();
+        drop(state);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config: Config = toml::from_str(
+            r#"
+            capacity = 100
+            [receivers."influxdb2:home"]
+            policy = "block"
+            [receivers.mqtt]
+            capacity = 5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.settings("influxdb2:home"), (100, Policy::Block));
+        assert_eq!(config.settings("influxdb2:home#2"), (100, Policy::Block));
+        assert_eq!(config.settings("mqtt"), (5, Policy::DropOldest));
+        assert_eq!(config.settings("journal"), (100, Policy::DropOldest));
+        assert_eq!(config.unknown(&["influxdb2:home#1", "journal"]), ["mqtt"]);
+        assert!(config.check().is_ok());
+        let config = Config {
+            capacity: 0,
+            ..Default::default()
+        };
+        assert!(config.check().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest() {
+        let (sender, receiver) = channel(2, Policy::DropOldest);
+        assert_eq!(sender.send(1).await, Ok(None));
+        assert_eq!(sender.send(2).await, Ok(None));
+        assert_eq!(sender.send(3).await, Ok(Some(1)));
+        sender.close();
+        assert_eq!(receiver.collect::<Vec<_>>().await, [2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_drop_newest() {
+        let (sender, receiver) = channel(2, Policy::DropNewest);
+        assert_eq!(sender.send(1).await, Ok(None));
+        assert_eq!(sender.send(2).await, Ok(None));
+        assert_eq!(sender.send(3).await, Ok(Some(3)));
+        drop(sender);
+        assert_eq!(receiver.collect::<Vec<_>>().await, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_block() {
+        let (sender, mut receiver) = channel(1, Policy::Block);
+        assert_eq!(sender.send(1).await, Ok(None));
+        let mut send = Box::pin(sender.send(2));
+        assert!(futures::poll!(send.as_mut()).is_pending());
+        assert_eq!(receiver.next().await, Some(1));
+        assert_eq!(send.await, Ok(None));
+        assert_eq!(receiver.next().await, Some(2));
+        drop(receiver);
+        assert_eq!(sender.send(3).await, Err(Disconnected));
+    }
+}
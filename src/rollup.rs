@@ -0,0 +1,570 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Hourly and daily rollups of the updates, so that long-term history can
+//! be kept without keeping every raw update.
+//!
+//! For each inverter and field, a rollup gives the minimum, maximum and mean
+//! of a measurement over the period, or the last value and increase of a
+//! counter (an energy total). Periods start on the hour or at midnight in
+//! the local time zone of the machine running sunsniff, and each rollup is
+//! timestamped with the start of its period. A rollup is produced once the
+//! first update of the next period arrives (or when sunsniff stops, in
+//! which case it only covers part of the period).
+
+use async_trait::async_trait;
+use chrono::{Local, TimeZone, Timelike};
+use futures::prelude::*;
+use log::{debug, warn};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::fields::{Field, FieldType, Reset};
+use crate::receiver::{Receiver, Update, UpdateReceiver};
+use crate::routing::Serials;
+use crate::timestamps::Timestamps;
+use crate::units::Units;
+
+/// Length of a rollup period
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+    Hour,
+    Day,
+}
+
+impl Period {
+    /// Name used for the period in the output
+    pub fn label(self) -> &'static str {
+        match self {
+            Period::Hour => "hourly",
+            Period::Day => "daily",
+        }
+    }
+
+    /// Start (in nanoseconds since UNIX epoch) of the period containing
+    /// `timestamp`, in the time zone `tz`
+    pub fn start<Tz: TimeZone>(self, timestamp: i64, tz: &Tz) -> i64 {
+        let time = tz.timestamp_nanos(timestamp);
+        match self {
+            // Subtracting rather than rounding the local time avoids
+            // ambiguity when the clocks go back
+            Period::Hour => {
+                let into = i64::from(time.minute() * 60 + time.second()) * 1_000_000_000
+                    + i64::from(time.nanosecond());
+                timestamp - into
+            }
+            Period::Day => time
+                .date_naive()
+                .and_hms_opt(0, 0, 0)
+                .and_then(|midnight| tz.from_local_datetime(&midnight).earliest())
+                .and_then(|midnight| midnight.timestamp_nanos_opt())
+                .unwrap_or(timestamp),
+        }
+    }
+}
+
+/// Summary of one field over a period
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stats {
+    Measurement {
+        min: f64,
+        max: f64,
+        mean: f64,
+        /// Number of values seen in the period
+        count: usize,
+    },
+    Counter {
+        /// Value at the end of the period
+        last: f64,
+        /// Increase over the period, allowing for daily resets
+        delta: f64,
+    },
+}
+
+/// Summary of one field of one inverter over a period
+#[derive(Clone, PartialEq, Debug)]
+pub struct Rollup {
+    pub period: Period,
+    /// Start of the period, in nanoseconds since UNIX epoch
+    pub start: i64,
+    pub serial: String,
+    pub group: String,
+    pub name: String,
+    pub id: String,
+    pub unit: String,
+    pub stats: Stats,
+}
+
+/// Whether a field is a counter (whose increase is of interest) rather than
+/// a measurement
+fn is_counter(field: &Field<'_>) -> bool {
+    field.field_type == FieldType::Energy || field.reset == Reset::Daily
+}
+
+/// Values of one field accumulated so far in a period
+struct Entry {
+    group: String,
+    name: String,
+    unit: String,
+    stats: Stats,
+}
+
+impl Entry {
+    fn new(field: &Field<'_>, stats: Stats) -> Self {
+        Self {
+            group: field.group.to_owned(),
+            name: field.name.to_owned(),
+            unit: field.unit.to_owned(),
+            stats,
+        }
+    }
+}
+
+/// State for one period length and one inverter
+#[derive(Default)]
+struct Series {
+    /// Start of the current period
+    start: i64,
+    /// Values accumulated in the current period, by field ID
+    entries: BTreeMap<String, Entry>,
+    /// Latest value of each counter, which is kept across periods so that
+    /// the increase between the last update of one period and the first of
+    /// the next is not lost
+    counters: HashMap<String, f64>,
+}
+
+impl Series {
+    fn add(&mut self, field: &Field<'_>, value: f64) {
+        let stats = if is_counter(field) {
+            let delta = match self.counters.insert(field.id.to_owned(), value) {
+                Some(previous) if value >= previous => value - previous,
+                // A daily counter was reset, so it has increased by its
+                // current value since then
+                Some(_) if field.reset == Reset::Daily => value,
+                // A wrapped counter or a replaced inverter
+                _ => 0.0,
+            };
+            match self.entries.get(field.id).map(|entry| entry.stats) {
+                Some(Stats::Counter { delta: total, .. }) => Stats::Counter {
+                    last: value,
+                    delta: total + delta,
+                },
+                _ => Stats::Counter { last: value, delta },
+            }
+        } else {
+            match self.entries.get(field.id).map(|entry| entry.stats) {
+                Some(Stats::Measurement {
+                    min,
+                    max,
+                    mean,
+                    count,
+                }) => Stats::Measurement {
+                    min: min.min(value),
+                    max: max.max(value),
+                    mean: mean + (value - mean) / (count + 1) as f64,
+                    count: count + 1,
+                },
+                _ => Stats::Measurement {
+                    min: value,
+                    max: value,
+                    mean: value,
+                    count: 1,
+                },
+            }
+        };
+        match self.entries.get_mut(field.id) {
+            Some(entry) => entry.stats = stats,
+            None => {
+                self.entries
+                    .insert(field.id.to_owned(), Entry::new(field, stats));
+            }
+        }
+    }
+
+    /// Produce the rollups for the current period, and clear it
+    fn take(&mut self, period: Period, serial: &str) -> Vec<Rollup> {
+        std::mem::take(&mut self.entries)
+            .into_iter()
+            .map(|(id, entry)| Rollup {
+                period,
+                start: self.start,
+                serial: serial.to_owned(),
+                group: entry.group,
+                name: entry.name,
+                id,
+                unit: entry.unit,
+                stats: entry.stats,
+            })
+            .collect()
+    }
+}
+
+/// Computes rollups from a stream of updates
+pub struct Aggregator<Tz: TimeZone = Local> {
+    periods: Vec<Period>,
+    series: HashMap<(Period, String), Series>,
+    tz: Tz,
+}
+
+impl Aggregator {
+    /// Aggregate over each of `periods`, in the local time zone
+    pub fn new(periods: &[Period]) -> Self {
+        Self::with_timezone(periods, Local)
+    }
+}
+
+impl<Tz: TimeZone> Aggregator<Tz> {
+    pub fn with_timezone(periods: &[Period], tz: Tz) -> Self {
+        Self {
+            periods: periods.to_vec(),
+            series: HashMap::new(),
+            tz,
+        }
+    }
+
+    /// Add an update, returning the rollups for any periods that it ends.
+    /// Updates from before the current period (for the same inverter) are
+    /// ignored, since that period has already been produced.
+    pub fn add(&mut self, update: &Update<'_>) -> Vec<Rollup> {
+        let mut rollups = vec![];
+        for &period in self.periods.iter() {
+            let start = period.start(update.timestamp, &self.tz);
+            let series = self
+                .series
+                .entry((period, update.serial.clone()))
+                .or_insert_with(|| Series {
+                    start,
+                    ..Default::default()
+                });
+            if start < series.start {
+                debug!(
+                    "Ignoring update from {} for a {} rollup that has already been produced",
+                    update.serial,
+                    period.label()
+                );
+                continue;
+            } else if start > series.start {
+                rollups.extend(series.take(period, &update.serial));
+                series.start = start;
+            }
+            for (field, value) in update.iter() {
+                if value.is_finite() {
+                    series.add(field, value);
+                }
+            }
+        }
+        rollups
+    }
+
+    /// Produce the rollups for the periods in progress
+    pub fn finish(&mut self) -> Vec<Rollup> {
+        let mut rollups = vec![];
+        for ((period, serial), series) in self.series.iter_mut() {
+            rollups.extend(series.take(*period, serial));
+        }
+        rollups.sort_by(|a, b| (a.start, &a.serial).cmp(&(b.start, &b.serial)));
+        rollups
+    }
+}
+
+/// Structure corresponding to the `[rollup]` section of the configuration
+/// file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// CSV file to append the rollups to
+    pub file: PathBuf,
+    /// Periods to produce rollups for
+    #[serde(default = "default_periods")]
+    pub periods: Vec<Period>,
+    /// Units to convert values to
+    #[serde(default)]
+    pub units: Units,
+    /// Serial numbers of the inverters to send to this backend (all if not
+    /// given)
+    #[serde(default)]
+    pub serials: Serials,
+    /// Whether to use the time reported by the inverter or the time the
+    /// update was received
+    #[serde(default)]
+    pub timestamps: Timestamps,
+}
+
+fn default_periods() -> Vec<Period> {
+    vec![Period::Hour, Period::Day]
+}
+
+const CSV_HEADER: &str = "period,start,serial,group,name,id,unit,min,max,mean,count,last,delta";
+
+/// Quote a CSV value if necessary
+fn csv_quote(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Format a rollup as a CSV line (without the newline), with the start of
+/// the period in the time zone `tz`
+fn csv_line<Tz: TimeZone>(rollup: &Rollup, tz: &Tz) -> String {
+    let stats = match rollup.stats {
+        Stats::Measurement {
+            min,
+            max,
+            mean,
+            count,
+        } => format!("{min},{max},{mean},{count},,"),
+        Stats::Counter { last, delta } => format!(",,,,{last},{delta}"),
+    };
+    format!(
+        "{},{},{},{},{},{},{},{}",
+        rollup.period.label(),
+        tz.timestamp_nanos(rollup.start).fixed_offset().to_rfc3339(),
+        csv_quote(&rollup.serial),
+        csv_quote(&rollup.group),
+        csv_quote(&rollup.name),
+        csv_quote(&rollup.id),
+        csv_quote(&rollup.unit),
+        stats
+    )
+}
+
+/// Appends rollups to a CSV file, with times in the local time zone
+pub struct CsvWriter {
+    writer: LineWriter<File>,
+}
+
+impl CsvWriter {
+    /// Open a file for appending, writing the header if it is new (or
+    /// empty)
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+        let mut writer = LineWriter::new(file);
+        if empty {
+            writeln!(writer, "{CSV_HEADER}")?;
+        }
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, rollups: &[Rollup]) -> std::io::Result<()> {
+        for rollup in rollups.iter() {
+            writeln!(self.writer, "{}", csv_line(rollup, &Local))?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writes the rollups of the updates to a CSV file
+pub struct RollupReceiver {
+    aggregator: Aggregator,
+    writer: CsvWriter,
+}
+
+impl RollupReceiver {
+    pub fn new(config: &Config) -> std::io::Result<Self> {
+        Ok(Self {
+            aggregator: Aggregator::new(&config.periods),
+            writer: CsvWriter::open(&config.file)?,
+        })
+    }
+}
+
+#[async_trait]
+impl Receiver for RollupReceiver {
+    async fn run<'a>(&mut self, mut receiver: UpdateReceiver<'a>) {
+        while let Some(update) = receiver.next().await {
+            let rollups = self.aggregator.add(&update);
+            if let Err(err) = self.writer.write(&rollups) {
+                warn!("Failed to write rollups: {err}");
+            }
+        }
+        let rollups = self.aggregator.finish();
+        if let Err(err) = self
+            .writer
+            .write(&rollups)
+            .and_then(|()| self.writer.flush())
+        {
+            warn!("Failed to write rollups: {err}");
+        }
+    }
+
+    async fn self_test(&mut self, _update: Arc<Update<'static>>) -> Result<(), String> {
+        // Rollups only exist once a period is complete, so just check that
+        // the file can be written
+        self.writer
+            .flush()
+            .map_err(|err| format!("could not write to the rollup file: {err}"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::WordOrder;
+    use chrono::{FixedOffset, Utc};
+
+    const FIELDS: &[Field<'static>] = &[
+        Field {
+            field_type: FieldType::Power,
+            group: "Grid",
+            name: "Power",
+            id: "grid_power",
+            scale: 1.0,
+            bias: 0.0,
+            unit: "W",
+            sum_of: &[],
+            word_order: WordOrder::Little,
+            reset: Reset::Never,
+        },
+        Field {
+            field_type: FieldType::Energy,
+            group: "Grid",
+            name: "Import today",
+            id: "grid_import_today",
+            scale: 1.0,
+            bias: 0.0,
+            unit: "kWh",
+            sum_of: &[],
+            word_order: WordOrder::Little,
+            reset: Reset::Daily,
+        },
+    ];
+
+    const HOUR: i64 = 3600 * 1_000_000_000;
+
+    fn update(timestamp: i64, values: [f64; 2]) -> Update<'static> {
+        Update::new(timestamp, "1234", FIELDS, values.to_vec())
+    }
+
+    #[test]
+    fn test_period_start() {
+        let tz = FixedOffset::east_opt(5 * 3600 + 1800).unwrap();
+        // 2024-03-01 10:15:30 in the time zone
+        let time = tz
+            .with_ymd_and_hms(2024, 3, 1, 10, 15, 30)
+            .unwrap()
+            .timestamp_nanos_opt()
+            .unwrap();
+        let hour = tz.with_ymd_and_hms(2024, 3, 1, 10, 0, 0).unwrap();
+        let day = tz.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+        assert_eq!(
+            Period::Hour.start(time, &tz),
+            hour.timestamp_nanos_opt().unwrap()
+        );
+        assert_eq!(
+            Period::Day.start(time, &tz),
+            day.timestamp_nanos_opt().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let mut aggregator = Aggregator::with_timezone(&[Period::Hour], Utc);
+        assert!(aggregator.add(&update(0, [100.0, 5.0])).is_empty());
+        assert!(aggregator.add(&update(HOUR / 2, [300.0, 6.0])).is_empty());
+        // Out-of-order within the period is still counted
+        assert!(aggregator
+            .add(&update(HOUR / 4, [f64::NAN, 5.5]))
+            .is_empty());
+        let rollups = aggregator.add(&update(HOUR + 1, [200.0, 7.0]));
+        assert_eq!(
+            rollups,
+            vec![
+                Rollup {
+                    period: Period::Hour,
+                    start: 0,
+                    serial: "1234".to_owned(),
+                    group: "Grid".to_owned(),
+                    name: "Import today".to_owned(),
+                    id: "grid_import_today".to_owned(),
+                    unit: "kWh".to_owned(),
+                    // 5.0 -> 6.0 -> 5.5 looks like a reset to 5.5
+                    stats: Stats::Counter {
+                        last: 5.5,
+                        delta: 6.5
+                    },
+                },
+                Rollup {
+                    period: Period::Hour,
+                    start: 0,
+                    serial: "1234".to_owned(),
+                    group: "Grid".to_owned(),
+                    name: "Power".to_owned(),
+                    id: "grid_power".to_owned(),
+                    unit: "W".to_owned(),
+                    stats: Stats::Measurement {
+                        min: 100.0,
+                        max: 300.0,
+                        mean: 200.0,
+                        count: 2
+                    },
+                },
+            ]
+        );
+        // Updates for a period that has already been produced are ignored
+        assert!(aggregator.add(&update(HOUR / 2, [0.0, 0.0])).is_empty());
+        // A daily reset counts the new value as the increase
+        assert!(aggregator.add(&update(HOUR + 2, [400.0, 0.5])).is_empty());
+        let rollups = aggregator.finish();
+        assert_eq!(rollups.len(), 2);
+        assert_eq!(rollups[0].start, HOUR);
+        // The increase from 5.5 to 7.0 crosses the hour, so belongs to it
+        assert_eq!(
+            rollups[0].stats,
+            Stats::Counter {
+                last: 0.5,
+                delta: 2.0
+            }
+        );
+        assert_eq!(
+            rollups[1].stats,
+            Stats::Measurement {
+                min: 200.0,
+                max: 400.0,
+                mean: 300.0,
+                count: 2
+            }
+        );
+        assert!(aggregator.finish().is_empty());
+    }
+
+    #[test]
+    fn test_csv_line() {
+        let mut aggregator = Aggregator::with_timezone(&[Period::Day], Utc);
+        aggregator.add(&update(HOUR, [100.0, 5.0]));
+        let rollups = aggregator.finish();
+        assert_eq!(
+            csv_line(&rollups[0], &Utc),
+            "daily,1970-01-01T00:00:00+00:00,1234,Grid,Import today,grid_import_today,kWh,,,,,5,0"
+        );
+        assert_eq!(
+            csv_line(&rollups[1], &Utc),
+            "daily,1970-01-01T00:00:00+00:00,1234,Grid,Power,grid_power,W,100,100,100,1,,"
+        );
+        assert_eq!(csv_quote("a,\"b\""), "\"a,\"\"b\"\"\"");
+    }
+}
@@ -0,0 +1,130 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Routing of updates to backends by inverter serial number
+
+use serde::Deserialize;
+
+/// Serial numbers for which a backend receives updates, from its `serials`
+/// option. Patterns may contain `*` to match any sequence of characters.
+/// If the option is not given, all serial numbers are matched.
+#[derive(Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Serials {
+    include: Option<Vec<String>>,
+    /// Patterns that are not matched, even if they are included
+    #[serde(skip)]
+    exclude: Vec<String>,
+}
+
+impl Serials {
+    /// Match only the serial numbers that match one of `patterns`
+    pub fn new(patterns: Vec<String>) -> Self {
+        Self {
+            include: Some(patterns),
+            exclude: vec![],
+        }
+    }
+
+    /// Stop matching the serial numbers that match one of `patterns`
+    pub fn exclude(mut self, patterns: impl IntoIterator<Item = String>) -> Self {
+        self.exclude.extend(patterns);
+        self
+    }
+
+    /// Whether updates for `serial` should be sent to the backend
+    pub fn matches(&self, serial: &str) -> bool {
+        let included = match &self.include {
+            Some(patterns) => patterns.iter().any(|pattern| glob(pattern, serial)),
+            None => true,
+        };
+        included && !self.exclude.iter().any(|pattern| glob(pattern, serial))
+    }
+}
+
+/// Match `text` against a pattern in which `*` matches any sequence of
+/// characters
+fn glob(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // There is always at least one part
+    let first = parts.next().unwrap();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty(); // No wildcards
+    };
+    // Match the middle parts as early as possible, leaving the most room
+    // for the last part
+    for part in parts {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_glob() {
+        assert!(glob("2101234567", "2101234567"));
+        assert!(!glob("2101234567", "2101234567-dongle"));
+        assert!(glob("2101234567*", "2101234567-dongle"));
+        assert!(glob("21*67", "2101234567"));
+        assert!(!glob("21*67", "2101234568"));
+        assert!(glob("*", ""));
+        assert!(glob("a*b*c", "abc"));
+        assert!(glob("a*b*c", "axxbyyc"));
+        assert!(!glob("a*b*c", "axxcyyb"));
+        assert!(!glob("ab*ba", "aba"));
+    }
+
+    #[derive(Deserialize)]
+    struct Config {
+        #[serde(default)]
+        serials: Serials,
+    }
+
+    fn parse(text: &str) -> Serials {
+        toml::from_str::<Config>(text).unwrap().serials
+    }
+
+    #[test]
+    fn test_serials() {
+        assert!(parse("").matches("2101234567"));
+        let serials = parse(r#"serials = ["2101*", "1234567890"]"#);
+        assert!(serials.matches("2101234567"));
+        assert!(serials.matches("1234567890"));
+        assert!(!serials.matches("2201234567"));
+        assert!(!parse("serials = []").matches("2101234567"));
+    }
+
+    #[test]
+    fn test_exclude() {
+        let serials = parse("").exclude(["2101*".to_owned()]);
+        assert!(!serials.matches("2101234567"));
+        assert!(serials.matches("2201234567"));
+        let serials = Serials::new(vec!["2*".to_owned()]).exclude(["2101*".to_owned()]);
+        assert!(!serials.matches("2101234567"));
+        assert!(serials.matches("2201234567"));
+        assert!(!serials.matches("1234567890"));
+    }
+}
@@ -0,0 +1,179 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Encrypted values in the configuration file
+//!
+//! Any string in the configuration can be replaced by an ASCII-armored
+//! [age](https://age-encryption.org/) ciphertext, which is decrypted when
+//! the configuration is loaded, using the key given by [`KEY_ENV`] or
+//! [`KEY_FILE_ENV`].
+
+use std::error::Error;
+use toml::Value;
+
+/// Environment variable containing the key
+pub const KEY_ENV: &str = "SUNSNIFF_KEY";
+/// Environment variable naming a file containing the key
+pub const KEY_FILE_ENV: &str = "SUNSNIFF_KEY_FILE";
+
+/// First line of an ASCII-armored age ciphertext
+const ARMOR_BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+
+fn is_encrypted(text: &str) -> bool {
+    text.trim_start().starts_with(ARMOR_BEGIN)
+}
+
+/// Call `f` on every string in `value`, recursively
+fn visit_strings<E>(
+    value: &mut Value,
+    f: &mut impl FnMut(&mut String) -> Result<(), E>,
+) -> Result<(), E> {
+    match value {
+        Value::String(s) => f(s),
+        Value::Array(array) => array.iter_mut().try_for_each(|v| visit_strings(v, f)),
+        Value::Table(table) => table.iter_mut().try_for_each(|(_, v)| visit_strings(v, f)),
+        _ => Ok(()),
+    }
+}
+
+/// Parse a key in the format written by `age-keygen`, which may contain
+/// comments
+#[cfg(feature = "secrets")]
+fn parse_identity(text: &str) -> Result<age::x25519::Identity, Box<dyn Error>> {
+    let line = text
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .ok_or("the key is empty")?;
+    Ok(line.parse()?)
+}
+
+/// Load the key from the environment
+#[cfg(feature = "secrets")]
+fn load_identity() -> Result<age::x25519::Identity, Box<dyn Error>> {
+    if let Ok(key) = std::env::var(KEY_ENV) {
+        parse_identity(&key).map_err(|e| format!("invalid key in {KEY_ENV}: {e}").into())
+    } else if let Some(path) = std::env::var_os(KEY_FILE_ENV) {
+        let key = std::fs::read_to_string(&path)
+            .map_err(|e| format!("cannot read {}: {e}", path.to_string_lossy()))?;
+        parse_identity(&key)
+            .map_err(|e| format!("invalid key in {}: {e}", path.to_string_lossy()).into())
+    } else {
+        Err(format!(
+            "the configuration contains encrypted values, but neither {KEY_ENV} nor {KEY_FILE_ENV} is set"
+        )
+        .into())
+    }
+}
+
+/// Decrypt all the encrypted values in `value` with `identity`
+#[cfg(feature = "secrets")]
+fn decrypt_with(value: &mut Value, identity: &age::x25519::Identity) -> Result<(), Box<dyn Error>> {
+    visit_strings(value, &mut |s: &mut String| -> Result<(), Box<dyn Error>> {
+        if is_encrypted(s) {
+            let plaintext = age::decrypt(identity, s.trim().as_bytes())
+                .map_err(|e| format!("cannot decrypt configuration value: {e}"))?;
+            *s = String::from_utf8(plaintext)
+                .map_err(|_| "decrypted configuration value is not valid UTF-8")?;
+        }
+        Ok(())
+    })
+}
+
+/// Whether `value` contains any encrypted strings
+fn contains_encrypted(value: &mut Value) -> bool {
+    let mut found = false;
+    let _ = visit_strings(value, &mut |s: &mut String| -> Result<(), ()> {
+        found |= is_encrypted(s);
+        Ok(())
+    });
+    found
+}
+
+/// Decrypt all the encrypted values in a configuration. The key is only
+/// needed if there are any.
+pub fn decrypt(value: &mut Value) -> Result<(), Box<dyn Error>> {
+    if !contains_encrypted(value) {
+        return Ok(());
+    }
+    #[cfg(feature = "secrets")]
+    {
+        decrypt_with(value, &load_identity()?)
+    }
+    #[cfg(not(feature = "secrets"))]
+    {
+        Err("the configuration contains encrypted values, but sunsniff was compiled without the `secrets` feature".into())
+    }
+}
+
+/// Encrypt a value for the configuration file
+#[cfg(feature = "secrets")]
+pub fn encrypt(recipient: &str, plaintext: &str) -> Result<String, Box<dyn Error>> {
+    let recipient: age::x25519::Recipient = recipient.parse()?;
+    Ok(age::encrypt_and_armor(&recipient, plaintext.as_bytes())?)
+}
+
+#[cfg(all(test, feature = "secrets"))]
+mod test {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+
+    #[test]
+    fn test_parse_identity() {
+        let identity = age::x25519::Identity::generate();
+        let key = identity.to_string();
+        let text = format!(
+            "# created: 2024-01-01T00:00:00Z\n# public key: {}\n{}\n",
+            identity.to_public(),
+            key.expose_secret()
+        );
+        let parsed = parse_identity(&text).unwrap();
+        assert_eq!(
+            parsed.to_public().to_string(),
+            identity.to_public().to_string()
+        );
+        assert!(parse_identity("# just a comment\n").is_err());
+        assert!(parse_identity("not a key").is_err());
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let identity = age::x25519::Identity::generate();
+        let recipient = identity.to_public().to_string();
+        let password = encrypt(&recipient, "hunter2").unwrap();
+        let token = encrypt(&recipient, "my_token").unwrap();
+        let mut table = toml::Table::new();
+        table.insert("bind".to_owned(), Value::from("0.0.0.0:8080"));
+        table.insert("token".to_owned(), Value::from(token));
+        let mut mqtt = toml::Table::new();
+        mqtt.insert("password".to_owned(), Value::from(password));
+        let mut value = Value::from(toml::Table::from_iter([
+            ("http".to_owned(), Value::from(table)),
+            ("mqtt".to_owned(), Value::from(vec![Value::from(mqtt)])),
+        ]));
+        assert!(contains_encrypted(&mut value));
+        decrypt_with(&mut value, &identity).unwrap();
+        assert!(!contains_encrypted(&mut value));
+        assert_eq!(value["http"]["bind"].as_str(), Some("0.0.0.0:8080"));
+        assert_eq!(value["http"]["token"].as_str(), Some("my_token"));
+        assert_eq!(value["mqtt"][0]["password"].as_str(), Some("hunter2"));
+
+        // Wrong key
+        let mut value = Value::from(encrypt(&recipient, "hunter2").unwrap());
+        let other = age::x25519::Identity::generate();
+        assert!(decrypt_with(&mut value, &other).is_err());
+    }
+}
@@ -0,0 +1,224 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that sends every update to local programs connected to a Unix
+//! socket, as a line of JSON or in one of the binary encodings.
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use log::{info, warn};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::format::Format;
+use crate::receiver::{Receiver, Update, UpdateReceiver};
+use crate::routing::Serials;
+use crate::timestamps::Timestamps;
+use crate::units::Units;
+
+/// Structure corresponding to the `[socket]` section of the configuration
+/// file. It is constructed from the config file by serde.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Path of the socket to create
+    pub path: PathBuf,
+    /// Permissions for the socket
+    #[serde(default = "default_mode")]
+    pub mode: u32,
+    /// Encoding of the updates
+    #[serde(default)]
+    pub format: Format,
+    /// Units to convert values to
+    #[serde(default)]
+    pub units: Units,
+    /// Serial numbers of the inverters to send to this backend (all if not
+    /// given)
+    #[serde(default)]
+    pub serials: Serials,
+    /// Whether to write the time reported by the inverter or the time the
+    /// update was received
+    #[serde(default)]
+    pub timestamps: Timestamps,
+}
+
+fn default_mode() -> u32 {
+    0o600
+}
+
+/// Number of updates to buffer for a client before it misses some
+const CLIENT_CAPACITY: usize = 16;
+
+/// Encode an update. JSON updates are terminated by a newline; the binary
+/// encodings are self-delimiting, so are sent back to back.
+fn encode(update: &Update<'_>, format: Format) -> Arc<[u8]> {
+    let mut data = format.encode(&crate::json::Update::from(update));
+    if format.is_text() {
+        data.push(b'\n');
+    }
+    data.into()
+}
+
+/// Send every update to a client until it disconnects
+async fn send_updates(mut stream: UnixStream, mut updates: broadcast::Receiver<Arc<[u8]>>) {
+    loop {
+        match updates.recv().await {
+            Ok(data) => {
+                if stream.write_all(&data).await.is_err() {
+                    info!("Socket client disconnected");
+                    return;
+                }
+            }
+            Err(RecvError::Lagged(n)) => warn!("Socket client missed {n} updates"),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Accept clients until the listener fails
+async fn accept_clients(listener: UnixListener, sender: broadcast::Sender<Arc<[u8]>>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                info!("Socket client connected");
+                tokio::spawn(send_updates(stream, sender.subscribe()));
+            }
+            Err(err) => {
+                warn!("Error accepting socket client: {err}");
+                return;
+            }
+        }
+    }
+}
+
+pub struct SocketReceiver {
+    listener: Option<UnixListener>,
+    sender: broadcast::Sender<Arc<[u8]>>,
+    format: Format,
+}
+
+impl SocketReceiver {
+    pub fn new(config: &Config) -> std::io::Result<Self> {
+        let listener = crate::unix::bind(&config.path, config.mode, None)?;
+        info!("Sending updates to clients of {}", config.path.display());
+        Ok(Self {
+            listener: Some(listener),
+            sender: broadcast::channel(CLIENT_CAPACITY).0,
+            format: config.format,
+        })
+    }
+}
+
+#[async_trait]
+impl Receiver for SocketReceiver {
+    async fn run<'a>(&mut self, mut receiver: UpdateReceiver<'a>) {
+        if let Some(listener) = self.listener.take() {
+            tokio::spawn(accept_clients(listener, self.sender.clone()));
+        }
+        while let Some(update) = receiver.next().await {
+            // This only fails if there are no clients
+            let _ = self.sender.send(encode(&update, self.format));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType, Reset, WordOrder};
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    static FIELDS: [Field; 2] = [
+        Field {
+            field_type: FieldType::Power,
+            group: "PV",
+            name: "Power",
+            id: "pv_power",
+            scale: 1.0,
+            bias: 0.0,
+            unit: "W",
+            sum_of: &[],
+            word_order: WordOrder::Little,
+            reset: Reset::Never,
+        },
+        Field {
+            field_type: FieldType::StateOfCharge,
+            group: "Battery",
+            name: "SOC",
+            id: "battery_soc",
+            scale: 1.0,
+            bias: 0.0,
+            unit: "%",
+            sum_of: &[],
+            word_order: WordOrder::Little,
+            reset: Reset::Never,
+        },
+    ];
+
+    #[test]
+    fn test_encode() {
+        let update = Update::new(1000, "1234", &FIELDS, vec![1500.0, f64::NAN]);
+        let expected = r#"{"schema_version":1,"serial":"1234","timestamp":1000,"fields":[{"id":"pv_power","group":"PV","name":"Power","unit":"W","value":1500.0},{"id":"battery_soc","group":"Battery","name":"SOC","unit":"%","value":null}]}"#.to_owned() + "\n";
+        assert_eq!(&*encode(&update, Format::Json), expected.as_bytes());
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_encode_msgpack() {
+        // Updates are sent back to back, so it must be possible to decode
+        // them from a stream
+        let mut data = vec![];
+        for timestamp in [1000, 2000] {
+            let update = Update::new(timestamp, "1234", &FIELDS, vec![1500.0, 80.0]);
+            data.extend_from_slice(&encode(&update, Format::Msgpack));
+        }
+        let mut deserializer = rmp_serde::Deserializer::new(&data[..]);
+        for timestamp in [1000, 2000] {
+            let update = crate::json::Update::deserialize(&mut deserializer).unwrap();
+            assert_eq!(update.timestamp, timestamp);
+            assert_eq!(update.fields[1].value, Some(80.0));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receiver() {
+        let path = std::env::temp_dir().join(format!("sunsniff-socket-{}", std::process::id()));
+        let config: Config = toml::from_str(&format!("path = {:?}", path)).unwrap();
+        let mut receiver = SocketReceiver::new(&config).unwrap();
+        let sender = receiver.sender.clone();
+        let (updates, stream) = futures::channel::mpsc::unbounded();
+        let run = tokio::spawn(async move { receiver.run(Box::pin(stream)).await });
+
+        let client = UnixStream::connect(&path).await.unwrap();
+        // Wait for the client to be subscribed, so that it doesn't miss the update
+        while sender.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+        let update = Update::new(1000, "1234", &FIELDS, vec![1500.0, 80.0]);
+        updates.unbounded_send(Arc::new(update)).unwrap();
+        let mut lines = BufReader::new(client).lines();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert!(line.starts_with(r#"{"schema_version":1,"serial":"1234","timestamp":1000,"#));
+
+        drop(updates);
+        run.await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
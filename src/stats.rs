@@ -0,0 +1,92 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Statistics about sunsniff itself rather than the inverters, such as how
+//! long it has been running and when it last received an update. They are
+//! kept globally, so that receivers can report them without being given
+//! access to the frontends and the other receivers.
+
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::monitor::{self, Status};
+
+/// When sunsniff started
+static STARTED: OnceLock<Instant> = OnceLock::new();
+/// Time at which the last update was received, in nanoseconds since the
+/// UNIX epoch, or 0 if there has not been one
+static LAST_UPDATE: AtomicI64 = AtomicI64::new(0);
+
+/// Record that sunsniff has started, so that the uptime is measured from
+/// now rather than from the first use of the statistics
+pub fn start() {
+    STARTED.get_or_init(Instant::now);
+}
+
+/// Record that an update has been received from a frontend
+pub fn updated() {
+    LAST_UPDATE.store(SystemClock.now(), Ordering::Relaxed);
+}
+
+/// Number of frames that could not be decoded
+fn decode_errors() -> u64 {
+    #[cfg(any(feature = "afpacket", feature = "pcap"))]
+    return sunsniff_core::logger::UNKNOWN_FRAMES.load(Ordering::Relaxed);
+    #[cfg(not(any(feature = "afpacket", feature = "pcap")))]
+    0
+}
+
+/// The statistics at one point in time
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    /// Time since sunsniff started
+    pub uptime: Duration,
+    /// When the last update was received, in nanoseconds since the UNIX
+    /// epoch
+    pub last_update: Option<i64>,
+    /// Number of frames that could not be decoded
+    pub decode_errors: u64,
+    /// State of each receiver, including the length of its queue
+    pub receivers: Vec<Status>,
+}
+
+/// Take a snapshot of the current statistics
+pub fn snapshot() -> Snapshot {
+    let last_update = LAST_UPDATE.load(Ordering::Relaxed);
+    Snapshot {
+        uptime: STARTED.get_or_init(Instant::now).elapsed(),
+        last_update: (last_update != 0).then_some(last_update),
+        decode_errors: decode_errors(),
+        receivers: monitor::statuses(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        start();
+        let before = SystemClock.now();
+        updated();
+        let snapshot = snapshot();
+        assert!(snapshot.last_update.unwrap() >= before);
+        assert!(snapshot.uptime < Duration::from_secs(3600));
+    }
+}
@@ -0,0 +1,189 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Client for the Unix socket backend (`sunsniff tail`), which prints the
+//! changes in selected fields as they happen.
+
+use chrono::DateTime;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::UnixStream;
+
+use crate::json;
+
+/// Remembers the last value of each field, to report changes
+struct Tracker {
+    /// Field IDs to report (all if empty)
+    ids: Vec<String>,
+    /// Last value, indexed by serial number and field ID
+    last: HashMap<(String, String), f64>,
+    /// Whether a message has been seen (to check the IDs against)
+    seen: bool,
+}
+
+/// Round away noise from floating-point subtraction
+fn round(value: f64) -> f64 {
+    (value * 1000.0).round() / 1000.0
+}
+
+impl Tracker {
+    fn new(ids: &[String]) -> Self {
+        Self {
+            ids: ids.to_vec(),
+            last: HashMap::new(),
+            seen: false,
+        }
+    }
+
+    /// Process a line from the socket, returning lines describing the
+    /// changes.
+    fn changes(&mut self, line: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let message = json::Update::decode(line)?;
+        if !self.seen {
+            self.seen = true;
+            for id in self.ids.iter() {
+                if !message.fields.iter().any(|field| &field.id == id) {
+                    log::warn!("Field {id:?} is not provided by the frontend");
+                }
+            }
+        }
+        let time = DateTime::from_timestamp_nanos(message.timestamp).format("%Y-%m-%d %H:%M:%S");
+        let mut changes = vec![];
+        for field in message.fields.iter() {
+            if !self.ids.is_empty() && !self.ids.contains(&field.id) {
+                continue;
+            }
+            let Some(value) = field.value else {
+                continue;
+            };
+            let key = (message.serial.to_string(), field.id.clone());
+            let unit = &field.unit;
+            match self.last.insert(key, value) {
+                None => changes.push(format!(
+                    "{time} {} {}: {value} {unit}",
+                    message.serial, field.id
+                )),
+                Some(old) if old != value => changes.push(format!(
+                    "{time} {} {}: {old} -> {value} {unit} ({:+})",
+                    message.serial,
+                    field.id,
+                    round(value - old)
+                )),
+                Some(_) => {}
+            }
+        }
+        Ok(changes)
+    }
+}
+
+/// Check that the socket sends JSON, from the first bytes it sends. The
+/// binary encodings are not supported: CBOR and MessagePack updates start
+/// with a map marker rather than `{`.
+fn check_json(data: &[u8]) -> Result<(), String> {
+    match data.first() {
+        None | Some(b'{') => Ok(()),
+        Some(_) => Err(
+            "The socket is not sending JSON; tail only supports sockets with format = \"json\""
+                .to_owned(),
+        ),
+    }
+}
+
+/// Connect to the socket backend at `path` and write the changes in the
+/// fields with the given IDs (all fields if empty) to `out` until the
+/// connection is closed.
+pub async fn run(
+    path: &Path,
+    ids: &[String],
+    mut out: impl Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let stream = UnixStream::connect(path)
+        .await
+        .map_err(|err| format!("Could not connect to {}: {err}", path.display()))?;
+    let mut reader = BufReader::new(stream);
+    check_json(reader.fill_buf().await?)?;
+    let mut lines = reader.lines();
+    let mut tracker = Tracker::new(ids);
+    while let Some(line) = lines.next_line().await? {
+        for change in tracker.changes(&line)? {
+            writeln!(out, "{change}")?;
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn line(timestamp: i64, power: &str, soc: f64) -> String {
+        format!(
+            r#"{{"schema_version":1,"serial":"1234","timestamp":{timestamp},"fields":[{{"id":"grid_power","group":"Grid","name":"Power","unit":"W","value":{power}}},{{"id":"battery_soc","group":"Battery","name":"SOC","unit":"%","value":{soc}}}]}}"#
+        )
+    }
+
+    #[test]
+    fn test_changes() {
+        let mut tracker = Tracker::new(&["grid_power".to_owned()]);
+        assert_eq!(
+            tracker.changes(&line(0, "100.0", 50.0)).unwrap(),
+            ["1970-01-01 00:00:00 1234 grid_power: 100 W"]
+        );
+        assert!(tracker
+            .changes(&line(1_000_000_000, "100.0", 51.0))
+            .unwrap()
+            .is_empty());
+        // Missing values are skipped rather than reported as changes
+        assert!(tracker
+            .changes(&line(2_000_000_000, "null", 51.0))
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            tracker
+                .changes(&line(3_000_000_000, "-20.1", 52.0))
+                .unwrap(),
+            ["1970-01-01 00:00:03 1234 grid_power: 100 -> -20.1 W (-120.1)"]
+        );
+    }
+
+    #[test]
+    fn test_all_fields() {
+        let mut tracker = Tracker::new(&[]);
+        assert_eq!(tracker.changes(&line(0, "1.0", 50.0)).unwrap().len(), 2);
+        assert_eq!(
+            tracker.changes(&line(0, "1.0", 50.5)).unwrap(),
+            ["1970-01-01 00:00:00 1234 battery_soc: 50 -> 50.5 % (+0.5)"]
+        );
+    }
+
+    #[test]
+    fn test_check_json() {
+        assert!(check_json(line(0, "1.0", 50.0).as_bytes()).is_ok());
+        assert!(check_json(b"").is_ok());
+        // Start of a CBOR or MessagePack map
+        assert!(check_json(b"\xa4\x6eschema_version").is_err());
+        assert!(check_json(b"\x84\xaeschema_version").is_err());
+    }
+
+    #[test]
+    fn test_bad_line() {
+        let mut tracker = Tracker::new(&[]);
+        assert!(tracker.changes("not json").is_err());
+    }
+}
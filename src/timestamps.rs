@@ -0,0 +1,136 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Choice of timestamps written by each receiver
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::clock::{Clock, SystemClock};
+use crate::receiver::Update;
+
+/// Source of the timestamps that a receiver writes, from its `timestamps`
+/// option
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Timestamps {
+    /// The time reported by the inverter
+    #[default]
+    Inverter,
+    /// The time at which sunsniff received the update, for inverters whose
+    /// clocks cannot be trusted
+    Ingestion,
+}
+
+/// Replaces the timestamps of updates with the ingestion time, if
+/// configured. Updates for each inverter are given strictly increasing
+/// timestamps, so that receivers never see two with the same timestamp
+/// (which Influxdb would merge into one point).
+pub struct Restamper {
+    timestamps: Timestamps,
+    /// Last timestamp given to each inverter
+    last: HashMap<String, i64>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Restamper {
+    pub fn new(timestamps: Timestamps) -> Self {
+        Self {
+            timestamps,
+            last: HashMap::new(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Use a different clock for the ingestion time, for testing
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Apply the timestamp policy to an update, received at `now`
+    /// (nanoseconds since UNIX epoch). If the inverter time is used, the
+    /// update is returned unchanged.
+    fn restamp_at(&mut self, update: &Arc<Update<'static>>, now: i64) -> Arc<Update<'static>> {
+        if self.timestamps == Timestamps::Inverter {
+            return Arc::clone(update);
+        }
+        let timestamp = match self.last.get(&update.serial) {
+            Some(&last) if now <= last => last + 1,
+            _ => now,
+        };
+        self.last.insert(update.serial.clone(), timestamp);
+        let mut restamped = Update::clone(update);
+        restamped.timestamp = timestamp;
+        Arc::new(restamped)
+    }
+
+    /// Apply the timestamp policy to an update received now
+    pub fn restamp(&mut self, update: &Arc<Update<'static>>) -> Arc<Update<'static>> {
+        if self.timestamps == Timestamps::Inverter {
+            return Arc::clone(update);
+        }
+        let now = self.clock.now();
+        self.restamp_at(update, now)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::time::Duration;
+
+    fn update(timestamp: i64, serial: &str) -> Arc<Update<'static>> {
+        Arc::new(Update::new(timestamp, serial, &[], vec![]))
+    }
+
+    #[test]
+    fn test_inverter() {
+        let mut restamper = Restamper::new(Timestamps::Inverter);
+        let original = update(5, "a");
+        assert!(Arc::ptr_eq(
+            &restamper.restamp_at(&original, 100),
+            &original
+        ));
+    }
+
+    #[test]
+    fn test_ingestion() {
+        let mut restamper = Restamper::new(Timestamps::Ingestion);
+        let stamp = |restamper: &mut Restamper, serial, now| {
+            restamper.restamp_at(&update(5, serial), now).timestamp
+        };
+        assert_eq!(stamp(&mut restamper, "a", 100), 100);
+        assert_eq!(stamp(&mut restamper, "a", 200), 200);
+        // Two updates at once, or the clock stepping back, still give
+        // increasing timestamps
+        assert_eq!(stamp(&mut restamper, "a", 200), 201);
+        assert_eq!(stamp(&mut restamper, "a", 150), 202);
+        // Each inverter is independent
+        assert_eq!(stamp(&mut restamper, "b", 150), 150);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clock() {
+        let clock = MockClock::new(1000);
+        let mut restamper = Restamper::new(Timestamps::Ingestion).with_clock(clock.clone());
+        assert_eq!(restamper.restamp(&update(5, "a")).timestamp, 1000);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(restamper.restamp(&update(5, "a")).timestamp, 1_000_001_000);
+    }
+}
@@ -0,0 +1,535 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Terminal user interface (`sunsniff tui`), showing the latest values,
+//! sparklines of the key fields, the state of the other receivers and the
+//! log. It is intended for checking an installation before any dashboards
+//! have been set up.
+
+use async_trait::async_trait;
+use futures::channel::oneshot;
+use futures::prelude::*;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Sparkline, Table};
+use ratatui::Frame;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::fields::FieldType;
+use crate::monitor::{Monitor, Status};
+use crate::receiver::{Receiver, Update, UpdateReceiver};
+
+/// Fields to show sparklines for, if the frontend provides them
+const KEY_FIELDS: &[&str] = &[
+    "pv_power",
+    "battery_power",
+    "battery_soc",
+    "grid_power",
+    "load_power",
+];
+/// Number of past values to keep for each sparkline
+const HISTORY: usize = 120;
+/// Number of log lines to keep
+const LOG_LINES: usize = 100;
+/// Interval at which to check for key presses and refresh the receivers
+const TICK: Duration = Duration::from_millis(250);
+
+#[derive(Default)]
+struct LogState {
+    lines: VecDeque<String>,
+    /// Text written since the last newline
+    partial: String,
+}
+
+/// Destination for log messages, which are shown in the interface instead
+/// of being written to the terminal
+#[derive(Clone, Default)]
+pub struct LogBuffer {
+    state: Arc<Mutex<LogState>>,
+}
+
+impl LogBuffer {
+    /// Create a buffer and direct the logger to it
+    pub fn init() -> Self {
+        let buffer = Self::default();
+        crate::logging::init(
+            env_logger::Builder::from_default_env()
+                .target(env_logger::Target::Pipe(Box::new(buffer.clone()))),
+        );
+        buffer
+    }
+
+    /// The most recent `n` complete lines, oldest first
+    fn tail(&self, n: usize) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let skip = state.lines.len().saturating_sub(n);
+        state.lines.iter().skip(skip).cloned().collect()
+    }
+}
+
+impl Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        state.partial.push_str(&String::from_utf8_lossy(buf));
+        while let Some(pos) = state.partial.find('\n') {
+            let line: String = state.partial.drain(..=pos).collect();
+            state.lines.push_back(line.trim_end().to_owned());
+            if state.lines.len() > LOG_LINES {
+                state.lines.pop_front();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Latest value of a field, formatted for display
+struct FieldRow {
+    group: String,
+    name: String,
+    value: String,
+    unit: String,
+}
+
+/// What is known about one inverter
+#[derive(Default)]
+struct Inverter {
+    timestamp: i64,
+    rows: Vec<FieldRow>,
+    /// Past values of the key fields, oldest first
+    history: BTreeMap<&'static str, VecDeque<f64>>,
+    /// Latest value of each key field, formatted with its unit
+    latest: HashMap<&'static str, String>,
+}
+
+impl Inverter {
+    fn add(&mut self, update: &Update<'_>) {
+        self.timestamp = update.timestamp;
+        self.rows.clear();
+        for (field, value) in update.iter() {
+            let unit = match field.field_type {
+                FieldType::Time | FieldType::Timestamp => "",
+                _ => field.unit,
+            };
+            self.rows.push(FieldRow {
+                group: field.group.to_owned(),
+                name: field.name.to_owned(),
+                value: field.format_value(value),
+                unit: unit.to_owned(),
+            });
+            if let Some(&key) = KEY_FIELDS.iter().find(|&&key| key == field.id) {
+                let history = self.history.entry(key).or_default();
+                history.push_back(value);
+                if history.len() > HISTORY {
+                    history.pop_front();
+                }
+                let title = format!(
+                    "{} {}: {} {unit}",
+                    field.group,
+                    field.name,
+                    field.format_value(value)
+                );
+                self.latest.insert(key, title);
+            }
+        }
+    }
+}
+
+/// Scale values for a sparkline, which can only show non-negative integers
+fn sparkline_data(values: &VecDeque<f64>) -> Vec<u64> {
+    let min = values
+        .iter()
+        .copied()
+        .filter(|v| v.is_finite())
+        .fold(f64::INFINITY, f64::min);
+    values
+        .iter()
+        .map(|&v| {
+            if v.is_finite() {
+                ((v - min) * 10.0).round() as u64
+            } else {
+                0
+            }
+        })
+        .collect()
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp_nanos(timestamp)
+        .format("%Y-%m-%d %H:%M:%S UTC")
+        .to_string()
+}
+
+/// State of the interface
+#[derive(Default)]
+struct View {
+    inverters: BTreeMap<String, Inverter>,
+    /// Index of the inverter being shown
+    selected: usize,
+    /// Number of rows scrolled past in the table of values
+    scroll: usize,
+    /// Whether the frontend has stopped sending updates
+    finished: bool,
+}
+
+impl View {
+    fn add(&mut self, update: &Update<'_>) {
+        self.inverters
+            .entry(update.serial.clone())
+            .or_default()
+            .add(update);
+    }
+
+    /// Handle a key press, returning true if the user asked to quit
+    fn key(&mut self, code: KeyCode) -> bool {
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return true,
+            KeyCode::Tab if !self.inverters.is_empty() => {
+                self.selected = (self.selected + 1) % self.inverters.len();
+                self.scroll = 0;
+            }
+            KeyCode::Down | KeyCode::Char('j') => self.scroll += 1,
+            KeyCode::Up | KeyCode::Char('k') => self.scroll = self.scroll.saturating_sub(1),
+            KeyCode::PageDown => self.scroll += 10,
+            KeyCode::PageUp => self.scroll = self.scroll.saturating_sub(10),
+            _ => {}
+        }
+        false
+    }
+
+    fn draw(&self, frame: &mut Frame, statuses: &[Status], log: &[String]) {
+        let [title, sparklines, middle, log_area] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(5),
+            Constraint::Min(5),
+            Constraint::Length(8),
+        ])
+        .areas(frame.area());
+        let [values, receivers] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(middle);
+
+        let selected = self.inverters.iter().nth(self.selected);
+        let mut heading = match selected {
+            Some((serial, inverter)) => format!(
+                "sunsniff: inverter {serial} ({}/{}), updated {}",
+                self.selected + 1,
+                self.inverters.len(),
+                format_timestamp(inverter.timestamp)
+            ),
+            None => "sunsniff: waiting for updates".to_owned(),
+        };
+        if self.finished {
+            heading += " (frontend finished)";
+        }
+        heading += " | Tab: next inverter, ↑↓: scroll, q: quit";
+        frame.render_widget(
+            Paragraph::new(heading).style(Style::new().add_modifier(Modifier::BOLD)),
+            title,
+        );
+
+        if let Some((_, inverter)) = selected {
+            self.draw_inverter(frame, inverter, sparklines, values);
+        }
+        draw_receivers(frame, statuses, receivers);
+        let log: Vec<Line> = log.iter().map(|line| Line::raw(line.as_str())).collect();
+        frame.render_widget(
+            Paragraph::new(log).block(Block::bordered().title("Log")),
+            log_area,
+        );
+    }
+
+    fn draw_inverter(
+        &self,
+        frame: &mut Frame,
+        inverter: &Inverter,
+        sparklines: Rect,
+        values: Rect,
+    ) {
+        if !inverter.history.is_empty() {
+            let n = inverter.history.len() as u32;
+            let areas =
+                Layout::horizontal((0..n).map(|_| Constraint::Ratio(1, n))).split(sparklines);
+            for ((key, history), area) in inverter.history.iter().zip(areas.iter()) {
+                // Show the most recent values that fit
+                let data = sparkline_data(history);
+                let width = area.width.saturating_sub(2) as usize;
+                let data = &data[data.len().saturating_sub(width)..];
+                let title = inverter.latest.get(key).map_or("", String::as_str);
+                frame.render_widget(
+                    Sparkline::default()
+                        .block(Block::bordered().title(title))
+                        .data(data)
+                        .style(Style::new().fg(Color::Yellow)),
+                    *area,
+                );
+            }
+        }
+
+        let rows = inverter.rows.iter().skip(self.scroll).map(|row| {
+            Row::new([
+                row.group.as_str(),
+                row.name.as_str(),
+                row.value.as_str(),
+                row.unit.as_str(),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(10),
+                Constraint::Min(16),
+                Constraint::Length(10),
+                Constraint::Length(5),
+            ],
+        )
+        .header(
+            Row::new(["Group", "Name", "Value", "Unit"])
+                .style(Style::new().add_modifier(Modifier::BOLD)),
+        )
+        .block(Block::bordered().title("Values"));
+        frame.render_widget(table, values);
+    }
+}
+
+fn draw_receivers(frame: &mut Frame, statuses: &[Status], area: Rect) {
+    let rows = statuses.iter().map(|status| {
+        let processing = status
+            .processing
+            .map_or_else(String::new, |p| format!("{:.1} ms", p * 1000.0));
+        let (state, color) = if status.slow {
+            ("slow", Color::Red)
+        } else {
+            ("ok", Color::Green)
+        };
+        Row::new([
+            status.name.clone(),
+            status.sent.to_string(),
+            status.queued.to_string(),
+            processing,
+            state.to_owned(),
+        ])
+        .style(Style::new().fg(color))
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Min(12),
+            Constraint::Length(6),
+            Constraint::Length(6),
+            Constraint::Length(9),
+            Constraint::Length(5),
+        ],
+    )
+    .header(
+        Row::new(["Receiver", "Sent", "Queued", "Time", "State"])
+            .style(Style::new().add_modifier(Modifier::BOLD)),
+    )
+    .block(Block::bordered().title("Receivers"));
+    frame.render_widget(table, area);
+}
+
+pub struct TuiReceiver {
+    /// Monitors of the other receivers
+    monitors: Vec<Arc<Monitor>>,
+    log: LogBuffer,
+    /// Signalled when the user quits
+    quit: Option<oneshot::Sender<()>>,
+}
+
+impl TuiReceiver {
+    /// Create the receiver, and a future that completes when the user quits
+    pub fn new(monitors: Vec<Arc<Monitor>>, log: LogBuffer) -> (Self, oneshot::Receiver<()>) {
+        let (sender, receiver) = oneshot::channel();
+        let tui = Self {
+            monitors,
+            log,
+            quit: Some(sender),
+        };
+        (tui, receiver)
+    }
+
+    /// Check for key presses without blocking, returning true if the user
+    /// asked to quit
+    fn poll_keys(view: &mut View) -> io::Result<bool> {
+        while event::poll(Duration::ZERO)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && view.key(key.code) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    async fn show(&mut self, receiver: &mut UpdateReceiver<'_>) -> io::Result<()> {
+        let mut terminal = ratatui::try_init()?;
+        let mut view = View::default();
+        let mut ticks = tokio::time::interval(TICK);
+        loop {
+            tokio::select! {
+                update = receiver.next(), if !view.finished => match update {
+                    Some(update) => view.add(&update),
+                    None => view.finished = true,
+                },
+                _ = ticks.tick() => {
+                    if Self::poll_keys(&mut view)? {
+                        return Ok(());
+                    }
+                }
+            }
+            let statuses: Vec<Status> = self.monitors.iter().map(|m| m.status()).collect();
+            let log = self.log.tail(LOG_LINES);
+            terminal.draw(|frame| view.draw(frame, &statuses, &log))?;
+        }
+    }
+}
+
+#[async_trait]
+impl Receiver for TuiReceiver {
+    async fn run<'a>(&mut self, mut receiver: UpdateReceiver<'a>) {
+        let result = self.show(&mut receiver).await;
+        ratatui::restore();
+        if let Err(err) = result {
+            eprintln!("Terminal error: {err}");
+        }
+        if let Some(quit) = self.quit.take() {
+            // The main loop may already have finished
+            let _ = quit.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, Reset, WordOrder};
+    use crate::monitor::Config;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    static FIELDS: [Field; 2] = [
+        Field {
+            field_type: FieldType::Power,
+            group: "PV",
+            name: "Power",
+            id: "pv_power",
+            scale: 1.0,
+            bias: 0.0,
+            unit: "W",
+            sum_of: &[],
+            word_order: WordOrder::Little,
+            reset: Reset::Never,
+        },
+        Field {
+            field_type: FieldType::Voltage,
+            group: "Grid",
+            name: "Voltage",
+            id: "grid_voltage",
+            scale: 0.1,
+            bias: 0.0,
+            unit: "V",
+            sum_of: &[],
+            word_order: WordOrder::Little,
+            reset: Reset::Never,
+        },
+    ];
+
+    #[test]
+    fn test_log_buffer() {
+        let mut log = LogBuffer::default();
+        write!(log, "first\nsec").unwrap();
+        assert_eq!(log.tail(10), ["first"]);
+        writeln!(log, "ond").unwrap();
+        assert_eq!(log.tail(10), ["first", "second"]);
+        assert_eq!(log.tail(1), ["second"]);
+    }
+
+    #[test]
+    fn test_inverter_history() {
+        let mut inverter = Inverter::default();
+        for i in 0..HISTORY + 5 {
+            inverter.add(&Update::new(
+                i as i64,
+                "1234",
+                &FIELDS,
+                vec![i as f64, 230.0],
+            ));
+        }
+        assert_eq!(inverter.rows.len(), 2);
+        // Only the key fields have history
+        assert_eq!(inverter.history.len(), 1);
+        let history = &inverter.history["pv_power"];
+        assert_eq!(history.len(), HISTORY);
+        assert_eq!(history.front(), Some(&5.0));
+        assert_eq!(
+            inverter.latest["pv_power"],
+            format!("PV Power: {} W", HISTORY + 4)
+        );
+    }
+
+    #[test]
+    fn test_sparkline_data() {
+        let values = VecDeque::from([-1.0, f64::NAN, 0.5, 2.0]);
+        assert_eq!(sparkline_data(&values), [0, 0, 15, 30]);
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut view = View::default();
+        view.add(&Update::new(0, "1234", &FIELDS, vec![1.0, 230.0]));
+        view.add(&Update::new(0, "5678", &FIELDS, vec![1.0, 230.0]));
+        assert!(!view.key(KeyCode::Down));
+        assert_eq!(view.scroll, 1);
+        assert!(!view.key(KeyCode::Tab));
+        assert_eq!((view.selected, view.scroll), (1, 0));
+        assert!(!view.key(KeyCode::Tab));
+        assert_eq!(view.selected, 0);
+        assert!(view.key(KeyCode::Char('q')));
+    }
+
+    #[test]
+    fn test_draw() {
+        let mut view = View::default();
+        view.add(&Update::new(0, "1234", &FIELDS, vec![1500.0, 230.5]));
+        let monitor = Monitor::new("influxdb2:test", &Config::default());
+        let mut terminal = Terminal::new(TestBackend::new(100, 30)).unwrap();
+        let log = ["something happened".to_owned()];
+        terminal
+            .draw(|frame| view.draw(frame, &[monitor.status()], &log))
+            .unwrap();
+        let text: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(text.contains("inverter 1234 (1/1)"));
+        assert!(text.contains("PV Power: 1500 W"));
+        assert!(text.contains("230.5"));
+        assert!(text.contains("influxdb2:test"));
+        assert!(text.contains("something happened"));
+    }
+}
@@ -0,0 +1,192 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Conversion of values to the units preferred by each receiver
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::fields::Field;
+use crate::pipeline::FIXED_SCALE;
+use crate::receiver::Update;
+
+/// Supported conversions, as (from, to, factor)
+#[rustfmt::skip]
+const CONVERSIONS: &[(&str, &str, f64)] = &[
+    ("W", "kW", 1e-3),
+    ("kW", "W", 1e3),
+    ("VA", "kVA", 1e-3),
+    ("kVA", "VA", 1e3),
+    ("Wh", "kWh", 1e-3),
+    ("kWh", "Wh", 1e3),
+    ("kWh", "MWh", 1e-3),
+    ("Ah", "mAh", 1e3),
+];
+
+/// Unit preferences for a receiver, as found in the `units` option of the
+/// receiver's configuration. Each key is a unit used by the fields and the
+/// value is the unit to convert it to.
+pub type Units = HashMap<String, String>;
+
+/// Converts updates to a receiver's preferred units
+#[derive(Default)]
+pub struct Converter {
+    /// New unit and scale factor for each unit that is converted
+    conversions: HashMap<String, (&'static str, f64)>,
+    /// Converted field tables, indexed by the address of the original table
+    tables: HashMap<usize, &'static [Field<'static>]>,
+}
+
+impl Converter {
+    pub fn new(units: &Units) -> Result<Self, String> {
+        let mut conversions = HashMap::new();
+        for (from, to) in units.iter() {
+            let &(_, to, factor) = CONVERSIONS
+                .iter()
+                .find(|(f, t, _)| f == from && t == to)
+                .ok_or_else(|| format!("Cannot convert {from} to {to}"))?;
+            conversions.insert(from.clone(), (to, factor));
+        }
+        Ok(Self {
+            conversions,
+            tables: HashMap::new(),
+        })
+    }
+
+    /// Get the converted version of a field table. Converted tables are
+    /// leaked, but there are only a few distinct tables.
+    fn table(&mut self, fields: &'static [Field<'static>]) -> &'static [Field<'static>] {
+        let conversions = &self.conversions;
+        self.tables
+            .entry(fields.as_ptr() as usize)
+            .or_insert_with(|| {
+                let converted: Vec<Field<'static>> = fields
+                    .iter()
+                    .map(|field| match conversions.get(field.unit) {
+                        Some(&(unit, factor)) => Field {
+                            unit,
+                            scale: field.scale * factor,
+                            bias: field.bias * factor,
+                            ..*field
+                        },
+                        None => Field { ..*field },
+                    })
+                    .collect();
+                converted.leak()
+            })
+    }
+
+    /// Convert an update. If no conversions apply, the update is returned
+    /// unchanged.
+    pub fn convert(&mut self, update: &Arc<Update<'static>>) -> Arc<Update<'static>> {
+        let factors: Vec<Option<f64>> = update
+            .fields
+            .iter()
+            .map(|field| self.conversions.get(field.unit).map(|&(_, factor)| factor))
+            .collect();
+        if factors.iter().all(Option::is_none) {
+            return Arc::clone(update);
+        }
+        let mut converted = Update::clone(update);
+        converted.fields = self.table(update.fields);
+        for (value, factor) in converted.values.iter_mut().zip(factors.iter()) {
+            *value *= factor.unwrap_or(1.0);
+        }
+        // Round the converted values again, since the factors are not exact
+        if let Some(fixed) = &mut converted.fixed {
+            let values = converted.values.iter_mut();
+            for ((value, f), factor) in values.zip(fixed.iter_mut()).zip(factors.iter()) {
+                if let (Some(f), Some(factor)) = (f, factor) {
+                    *f = (*f as f64 * factor).round() as i64;
+                    *value = *f as f64 / FIXED_SCALE;
+                }
+            }
+        }
+        Arc::new(converted)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{FieldType, Reset, WordOrder};
+
+    const FIELDS: &[Field<'static>] = &[
+        Field {
+            field_type: FieldType::Power,
+            group: "PV",
+            name: "Power",
+            id: "pv_power",
+            scale: 1.0,
+            bias: 0.0,
+            unit: "W",
+            sum_of: &[],
+            word_order: WordOrder::Little,
+            reset: Reset::Never,
+        },
+        Field {
+            field_type: FieldType::Voltage,
+            group: "Grid",
+            name: "Voltage",
+            id: "grid_voltage",
+            scale: 0.1,
+            bias: 0.0,
+            unit: "V",
+            sum_of: &[],
+            word_order: WordOrder::Little,
+            reset: Reset::Never,
+        },
+    ];
+
+    #[test]
+    fn test_convert() {
+        let units = Units::from([("W".to_owned(), "kW".to_owned())]);
+        let mut converter = Converter::new(&units).unwrap();
+        let update = Arc::new(Update::new(1, "1234", FIELDS, vec![1500.0, 230.0]));
+        let converted = converter.convert(&update);
+        assert_eq!(converted.values, [1.5, 230.0]);
+        assert_eq!(converted.fields[0].unit, "kW");
+        assert_eq!(converted.fields[0].scale, 1e-3);
+        assert_eq!(converted.fields[1].unit, "V");
+        // The converted table is reused
+        let again = converter.convert(&update);
+        assert!(std::ptr::eq(again.fields, converted.fields));
+    }
+
+    #[test]
+    fn test_convert_fixed() {
+        let units = Units::from([("W".to_owned(), "kW".to_owned())]);
+        let mut converter = Converter::new(&units).unwrap();
+        let mut update = Update::new(1, "1234", FIELDS, vec![6600.0, 230.0]);
+        update.fixed = Some(vec![Some(6_600_000), Some(230_000)]);
+        let converted = converter.convert(&Arc::new(update));
+        assert_eq!(converted.values[0].to_string(), "6.6");
+        assert_eq!(converted.fixed, Some(vec![Some(6600), Some(230_000)]));
+    }
+
+    #[test]
+    fn test_unchanged() {
+        let mut converter = Converter::new(&Units::new()).unwrap();
+        let update = Arc::new(Update::new(1, "1234", FIELDS, vec![1500.0, 230.0]));
+        assert!(Arc::ptr_eq(&converter.convert(&update), &update));
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let units = Units::from([("W".to_owned(), "kWh".to_owned())]);
+        assert!(Converter::new(&units).is_err());
+    }
+}
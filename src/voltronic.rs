@@ -0,0 +1,354 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Frontend for Voltronic (Axpert, SRNE and rebadged) inverters, which
+//! speak an ASCII query protocol over a USB or RS232 serial port.
+
+use futures::channel::mpsc;
+use futures::prelude::*;
+use log::{error, info};
+use serde::Deserialize;
+use serde_with::serde_as;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::time::MissedTickBehavior;
+use tokio_serial::{ClearBuffer, SerialPort, SerialPortBuilderExt, SerialStream};
+
+use crate::control::{Command, CommandReceiver};
+use crate::fields::{Field, FieldType, Reset, WordOrder};
+use crate::receiver::{Metadata, Update, UpdateStream};
+
+/// Time to wait for the inverter to respond to a query
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Structure corresponding to the `[voltronic]` section of the configuration file.
+#[serde_as]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VoltronicConfig {
+    device: String,
+    #[serde_as(as = "serde_with::DurationSecondsWithFrac<f64>")]
+    interval: Duration,
+    #[serde(default = "default_baud")]
+    baud: u32,
+}
+
+fn default_baud() -> u32 {
+    2400
+}
+
+const fn field(
+    field_type: FieldType,
+    group: &'static str,
+    name: &'static str,
+    id: &'static str,
+    unit: &'static str,
+    sum_of: &'static [(usize, f64)],
+) -> Field<'static> {
+    Field {
+        field_type,
+        group,
+        name,
+        id,
+        scale: 1.0,
+        bias: 0.0,
+        unit,
+        sum_of,
+        word_order: WordOrder::Little,
+        reset: Reset::Never,
+    }
+}
+
+/// Fields reported by the QPIGS command. The ids match the Sunsynk fields
+/// where the quantity is the same.
+#[rustfmt::skip]
+pub const FIELDS: &[Field<'static>] = &[
+    field(FieldType::Voltage, "Grid", "Voltage", "grid_voltage", "V", &[]),
+    field(FieldType::Frequency, "Grid", "Frequency", "grid_frequency", "Hz", &[]),
+    field(FieldType::Voltage, "Load", "Voltage", "load_voltage", "V", &[]),
+    field(FieldType::Frequency, "Load", "Frequency", "load_frequency", "Hz", &[]),
+    field(FieldType::Unitless, "Load", "Apparent Power", "load_apparent_power", "VA", &[]),
+    field(FieldType::Power, "Load", "Power", "load_power", "W", &[]),
+    field(FieldType::Unitless, "Load", "Percentage", "load_percentage", "%", &[]),
+    field(FieldType::Voltage, "Inverter", "Bus Voltage", "inverter_bus_voltage", "V", &[]),
+    field(FieldType::Voltage, "Battery", "Voltage", "battery_voltage", "V", &[]),
+    field(FieldType::Current, "Battery", "Charge Current", "battery_charge_current", "A", &[]),
+    field(FieldType::StateOfCharge, "Battery", "SOC", "battery_soc", "%", &[]),
+    field(FieldType::Temperature, "Inverter", "Heatsink Temperature", "inverter_temperature_heatsink", "°C", &[]),
+    field(FieldType::Current, "PV", "Current 1", "pv_current_1", "A", &[]),
+    field(FieldType::Voltage, "PV", "Voltage 1", "pv_voltage_1", "V", &[]),
+    field(FieldType::Current, "Battery", "Discharge Current", "battery_discharge_current", "A", &[]),
+    field(FieldType::Power, "PV", "Power 1", "pv_power_1", "W", &[]),
+    // Positive when discharging, as for the Sunsynk field
+    field(FieldType::Current, "Battery", "Current", "battery_current", "A", &[(14, 1.0), (9, -1.0)]),
+];
+
+/// Position of each entry of [FIELDS] in the space-separated QPIGS response,
+/// or `None` for derived fields.
+#[rustfmt::skip]
+const POSITIONS: &[Option<usize>] = &[
+    Some(0), Some(1), Some(2), Some(3), Some(4), Some(5), Some(6), Some(7), Some(8),
+    Some(9), Some(10), Some(11), Some(12), Some(13), Some(15), Some(19), None,
+];
+
+/// CRC-16/XMODEM, as used to protect the commands and responses
+fn crc(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Encode the CRC for transmission. The inverters avoid CRC bytes that
+/// could be mistaken for framing characters by incrementing them.
+fn crc_bytes(data: &[u8]) -> [u8; 2] {
+    crc(data).to_be_bytes().map(|b| {
+        if [b'(', b'\r', b'\n'].contains(&b) {
+            b + 1
+        } else {
+            b
+        }
+    })
+}
+
+/// Encode a command, with CRC and terminator
+fn encode_command(command: &str) -> Vec<u8> {
+    let mut out = command.as_bytes().to_vec();
+    out.extend_from_slice(&crc_bytes(command.as_bytes()));
+    out.push(b'\r');
+    out
+}
+
+/// Check the framing and CRC of a response (including the trailing carriage
+/// return) and return the text between the leading `(` and the CRC.
+fn decode_response(response: &[u8]) -> Result<&str, Box<dyn std::error::Error + Send + Sync>> {
+    let response = response
+        .strip_suffix(b"\r")
+        .ok_or("response is not terminated")?;
+    if response.len() < 3 || response[0] != b'(' {
+        return Err("response is malformed".into());
+    }
+    let (body, expected) = response.split_at(response.len() - 2);
+    if crc_bytes(body) != expected {
+        return Err("response has incorrect CRC".into());
+    }
+    let text = std::str::from_utf8(&body[1..])?;
+    if text == "NAK" {
+        return Err("inverter rejected the command".into());
+    }
+    Ok(text)
+}
+
+/// Number of values in a QPIGS response (some firmwares add more)
+const QPIGS_VALUES: usize = 21;
+
+/// Check that the body of a response has the form expected for `command`,
+/// so that a late response to an earlier query is not taken as the answer.
+fn check_format(command: &str, text: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let ok = match command {
+        "QID" => !text.is_empty() && text.bytes().all(|b| b.is_ascii_alphanumeric()),
+        "QPIGS" => {
+            let parts: Vec<&str> = text.split(' ').collect();
+            parts.len() >= QPIGS_VALUES
+                && parts.iter().all(|part| {
+                    !part.is_empty()
+                        && part
+                            .bytes()
+                            .all(|b| b.is_ascii_digit() || b == b'.' || b == b'-' || b == b'+')
+                })
+        }
+        _ => true,
+    };
+    if ok {
+        Ok(())
+    } else {
+        Err(format!("response {text:?} does not match the {command} command").into())
+    }
+}
+
+/// Parse the body of a QPIGS response into values for [FIELDS]
+fn parse_status(text: &str) -> Result<Vec<f64>, Box<dyn std::error::Error + Send + Sync>> {
+    let parts: Vec<&str> = text.split(' ').collect();
+    POSITIONS
+        .iter()
+        .map(|pos| match pos {
+            Some(pos) => {
+                let part = parts.get(*pos).ok_or("QPIGS response is too short")?;
+                Ok(part.parse()?)
+            }
+            None => Ok(f64::NAN), // Derived fields are filled in by the pipeline
+        })
+        .collect()
+}
+
+async fn query(
+    port: &mut BufReader<SerialStream>,
+    command: &str,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    // Discard anything not yet read (such as a response that arrived after
+    // the previous query timed out), so that it is not taken as the answer
+    let buffered = port.buffer().len();
+    Pin::new(&mut *port).consume(buffered);
+    port.get_mut().clear(ClearBuffer::Input)?;
+    port.get_mut().write_all(&encode_command(command)).await?;
+    let mut response = vec![];
+    tokio::time::timeout(RESPONSE_TIMEOUT, port.read_until(b'\r', &mut response)).await??;
+    let text = decode_response(&response)?;
+    check_format(command, text)?;
+    Ok(text.to_owned())
+}
+
+pub async fn create_stream(
+    config: &VoltronicConfig,
+    mut commands: CommandReceiver,
+) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+    let interval = config.interval;
+    let (mut sender, receiver) = mpsc::channel(1);
+    let port = tokio_serial::new(&config.device, config.baud).open_native_async()?;
+    let source = format!("voltronic:{}", config.device);
+    let mut port = BufReader::new(port);
+    let serial = query(&mut port, "QID")
+        .await
+        .map_err(|err| err.to_string())?;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                Some(command) = commands.next() => {
+                    if !command.targets(&serial) {
+                        continue;
+                    }
+                    match command {
+                        Command::PollNow { .. } => {
+                            info!("Polling immediately on request");
+                        }
+                        Command::WriteRegister { .. } => {
+                            error!("Settings cannot be changed on Voltronic inverters");
+                            continue;
+                        }
+                        Command::Shutdown => {
+                            info!("Stopping voltronic frontend");
+                            break;
+                        }
+                    }
+                    // Restart the interval from this poll
+                    interval.reset();
+                }
+            }
+            let start = Instant::now();
+            let values = match query(&mut port, "QPIGS").await {
+                Ok(text) => parse_status(&text).map(|values| (values, text.len())),
+                Err(err) => Err(err),
+            };
+            match values {
+                Err(err) => {
+                    error!("Failed to read values from inverter: {err:?}");
+                }
+                Ok((values, frame_length)) => {
+                    info!("Received a set of values from the inverter");
+                    let now = chrono::Utc::now();
+                    let update =
+                        Update::new(now.timestamp_nanos_opt().unwrap(), &serial, FIELDS, values)
+                            .with_metadata(Metadata {
+                                source: Some(source.clone()),
+                                protocol: Some("voltronic"),
+                                frame_length: Some(frame_length),
+                                decode_duration: Some(start.elapsed()),
+                                ..Default::default()
+                            });
+                    if sender.send(Arc::new(update)).await.is_err() {
+                        break; // The main stream has ended
+                    }
+                }
+            }
+        }
+    });
+    Ok(Box::pin(receiver))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_command() {
+        assert_eq!(encode_command("QPIGS"), b"QPIGS\xb7\xa9\r");
+    }
+
+    #[test]
+    fn test_decode_response() {
+        let body = b"(NAK";
+        let mut response = body.to_vec();
+        response.extend_from_slice(&crc_bytes(body));
+        response.push(b'\r');
+        assert!(decode_response(&response).is_err());
+
+        let body = b"(92932004102443";
+        let mut response = body.to_vec();
+        response.extend_from_slice(&crc_bytes(body));
+        response.push(b'\r');
+        assert_eq!(decode_response(&response).unwrap(), "92932004102443");
+        let n = response.len();
+        response[n - 2] ^= 1;
+        assert!(decode_response(&response).is_err());
+    }
+
+    #[test]
+    fn test_check_format() {
+        let status = "230.0 50.0 229.9 50.0 0345 0302 006 401 53.20 010 085 0034 \
+                      02.1 281.4 00.00 00000 00110110 00 00 00590 010";
+        assert!(check_format("QPIGS", status).is_ok());
+        assert!(check_format("QID", "92932004102443").is_ok());
+        // Responses to the other command
+        assert!(check_format("QPIGS", "92932004102443").is_err());
+        assert!(check_format("QID", status).is_err());
+        // Truncated or corrupted
+        assert!(check_format("QPIGS", &status[..40]).is_err());
+        assert!(check_format("QPIGS", &status.replace("53.20", "53,20")).is_err());
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let text = "230.0 50.0 229.9 50.0 0345 0302 006 401 53.20 010 085 0034 \
+                    02.1 281.4 00.00 00000 00110110 00 00 00590 010";
+        let values = parse_status(text).unwrap();
+        assert_eq!(values.len(), FIELDS.len());
+        let get = |id| values[FIELDS.iter().position(|f| f.id == id).unwrap()];
+        assert_eq!(get("grid_voltage"), 230.0);
+        assert_eq!(get("load_power"), 302.0);
+        assert_eq!(get("battery_voltage"), 53.2);
+        assert_eq!(get("battery_charge_current"), 10.0);
+        assert_eq!(get("battery_discharge_current"), 0.0);
+        assert_eq!(get("pv_power_1"), 590.0);
+        assert!(get("battery_current").is_nan());
+        assert_eq!(FIELDS.len(), POSITIONS.len());
+
+        assert!(parse_status("230.0 50.0").is_err());
+        assert!(parse_status(&text.replace("53.20", "5x.20")).is_err());
+    }
+}
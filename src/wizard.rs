@@ -0,0 +1,303 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Interactive generation of a configuration file
+
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+/// Asks questions on an output stream and reads the answers from an input
+/// stream.
+pub struct Prompter<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Prompter<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self { input, output }
+    }
+
+    /// Ask a question, returning the default if the answer is empty. If
+    /// there is no default, an empty answer is returned as-is.
+    pub fn ask(&mut self, question: &str, default: Option<&str>) -> io::Result<String> {
+        match default {
+            Some(default) => write!(self.output, "{question} [{default}]: ")?,
+            None => write!(self.output, "{question}: ")?,
+        }
+        self.output.flush()?;
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "end of input"));
+        }
+        let answer = line.trim();
+        Ok(match default {
+            Some(default) if answer.is_empty() => default.to_owned(),
+            _ => answer.to_owned(),
+        })
+    }
+
+    /// Ask a question until the answer can be parsed
+    pub fn ask_parse<T: FromStr>(&mut self, question: &str, default: &str) -> io::Result<T> {
+        loop {
+            match self.ask(question, Some(default))?.parse() {
+                Ok(value) => return Ok(value),
+                Err(_) => writeln!(self.output, "Invalid value, please try again")?,
+            }
+        }
+    }
+
+    /// Ask a yes/no question
+    pub fn ask_bool(&mut self, question: &str, default: bool) -> io::Result<bool> {
+        loop {
+            let default_str = if default { "y" } else { "n" };
+            match self
+                .ask(question, Some(default_str))?
+                .to_lowercase()
+                .as_str()
+            {
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => writeln!(self.output, "Please answer y or n")?,
+            }
+        }
+    }
+
+    /// Ask the user to choose one of several options
+    pub fn choose<'a>(&mut self, question: &str, options: &[&'a str]) -> io::Result<&'a str> {
+        loop {
+            let question = format!("{question} ({})", options.join("/"));
+            let answer = self.ask(&question, Some(options[0]))?;
+            if let Some(option) = options.iter().find(|x| **x == answer) {
+                return Ok(option);
+            }
+            writeln!(self.output, "Please choose one of {}", options.join(", "))?;
+        }
+    }
+
+    /// Write a message for the user
+    pub fn say(&mut self, message: &str) -> io::Result<()> {
+        writeln!(self.output, "{message}")
+    }
+}
+
+/// Format a string as a TOML string literal
+fn quote(value: &str) -> String {
+    toml::Value::String(value.to_owned()).to_string()
+}
+
+/// Frontends that are compiled in
+const FRONTENDS: &[&str] = &[
+    #[cfg(any(feature = "afpacket", feature = "pcap"))]
+    "pcap",
+    #[cfg(feature = "modbus")]
+    "modbus",
+    #[cfg(feature = "voltronic")]
+    "voltronic",
+];
+
+#[cfg(any(feature = "afpacket", feature = "pcap"))]
+fn ask_pcap<R: BufRead, W: Write>(p: &mut Prompter<R, W>, out: &mut String) -> io::Result<()> {
+    let device = p.ask("Network device to capture", Some("br0"))?;
+    p.say("The IP address of the dongle can be found with `sunsniff discover`.")?;
+    let ip = p.ask(
+        "IP address of the dongle (blank to capture all TCP traffic)",
+        None,
+    )?;
+    let timezone = p.ask("Timezone of the inverter", Some("Africa/Johannesburg"))?;
+    writeln!(out, "[pcap]").unwrap();
+    writeln!(out, "# Ethernet device to capture").unwrap();
+    writeln!(out, "device = {}", quote(&device)).unwrap();
+    // Without libpcap, filter expressions are not supported
+    let pcap = cfg!(feature = "pcap");
+    match (ip.is_empty(), pcap) {
+        (true, true) => writeln!(out, "# filter = \"src host 192.168.0.21\"").unwrap(),
+        (true, false) => writeln!(out, "# host = \"192.168.0.21\"").unwrap(),
+        (false, true) => {
+            writeln!(out, "# Only inspect traffic from the dongle").unwrap();
+            writeln!(out, "filter = {}", quote(&format!("src host {ip}"))).unwrap();
+        }
+        (false, false) => {
+            writeln!(out, "# Only inspect traffic to and from the dongle").unwrap();
+            writeln!(out, "host = {}", quote(&ip)).unwrap();
+        }
+    }
+    writeln!(out, "# Timezone used by the inverter").unwrap();
+    writeln!(out, "timezone = {}", quote(&timezone)).unwrap();
+    Ok(())
+}
+
+#[cfg(feature = "modbus")]
+fn ask_modbus<R: BufRead, W: Write>(p: &mut Prompter<R, W>, out: &mut String) -> io::Result<()> {
+    let device = p.ask(
+        "Serial device, or host:port for Modbus TCP",
+        Some("/dev/ttyUSB0"),
+    )?;
+    let baud: u32 = p.ask_parse("Baud rate", "9600")?;
+    let modbus_id: u8 = p.ask_parse("Modbus ID of the inverter", "1")?;
+    let interval: f64 = p.ask_parse("Seconds between samples", "20")?;
+    writeln!(out, "[modbus]").unwrap();
+    writeln!(out, "# Serial device, or host:port for Modbus TCP").unwrap();
+    writeln!(out, "device = {}", quote(&device)).unwrap();
+    writeln!(out, "baud = {baud}").unwrap();
+    writeln!(out, "modbus_id = {modbus_id}").unwrap();
+    writeln!(out, "# Time between samples, in seconds").unwrap();
+    writeln!(out, "interval = {interval}").unwrap();
+    Ok(())
+}
+
+#[cfg(feature = "voltronic")]
+fn ask_voltronic<R: BufRead, W: Write>(p: &mut Prompter<R, W>, out: &mut String) -> io::Result<()> {
+    let device = p.ask("Serial device", Some("/dev/ttyUSB0"))?;
+    let baud: u32 = p.ask_parse("Baud rate", "2400")?;
+    let interval: f64 = p.ask_parse("Seconds between samples", "20")?;
+    writeln!(out, "[voltronic]").unwrap();
+    writeln!(out, "device = {}", quote(&device)).unwrap();
+    writeln!(out, "baud = {baud}").unwrap();
+    writeln!(out, "# Time between samples, in seconds").unwrap();
+    writeln!(out, "interval = {interval}").unwrap();
+    Ok(())
+}
+
+#[cfg(feature = "influxdb2")]
+fn ask_influxdb2<R: BufRead, W: Write>(p: &mut Prompter<R, W>, out: &mut String) -> io::Result<()> {
+    if !p.ask_bool("Store values in Influxdb2?", false)? {
+        return Ok(());
+    }
+    let host = p.ask("Influxdb2 URL", Some("http://localhost:8086"))?;
+    let org = p.ask("Organisation", None)?;
+    let bucket = p.ask("Bucket", None)?;
+    let token = p.ask("API token", None)?;
+    writeln!(out, "\n[[influxdb2]]").unwrap();
+    writeln!(out, "host = {}", quote(&host)).unwrap();
+    writeln!(out, "org = {}", quote(&org)).unwrap();
+    writeln!(out, "bucket = {}", quote(&bucket)).unwrap();
+    writeln!(out, "token = {}", quote(&token)).unwrap();
+    Ok(())
+}
+
+#[cfg(feature = "mqtt")]
+fn ask_mqtt<R: BufRead, W: Write>(p: &mut Prompter<R, W>, out: &mut String) -> io::Result<()> {
+    if !p.ask_bool("Publish values to MQTT (e.g. for Home Assistant)?", false)? {
+        return Ok(());
+    }
+    let url = p.ask("Broker URL", Some("mqtt://localhost:1883"))?;
+    let username = p.ask("Username (blank if not needed)", None)?;
+    writeln!(out, "\n[[mqtt]]").unwrap();
+    writeln!(out, "url = {}", quote(&url)).unwrap();
+    if !username.is_empty() {
+        let password = p.ask("Password", None)?;
+        writeln!(out, "username = {}", quote(&username)).unwrap();
+        writeln!(out, "password = {}", quote(&password)).unwrap();
+    }
+    Ok(())
+}
+
+/// Ask the user questions and return the text of a configuration file
+pub fn run<R: BufRead, W: Write>(p: &mut Prompter<R, W>) -> io::Result<String> {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "# Configuration for sunsniff. See the README for all the options.\n"
+    )
+    .unwrap();
+    match p.choose("Frontend", FRONTENDS)? {
+        #[cfg(any(feature = "afpacket", feature = "pcap"))]
+        "pcap" => ask_pcap(p, &mut out)?,
+        #[cfg(feature = "modbus")]
+        "modbus" => ask_modbus(p, &mut out)?,
+        #[cfg(feature = "voltronic")]
+        "voltronic" => ask_voltronic(p, &mut out)?,
+        _ => unreachable!(),
+    }
+    #[cfg(feature = "influxdb2")]
+    ask_influxdb2(p, &mut out)?;
+    #[cfg(feature = "mqtt")]
+    ask_mqtt(p, &mut out)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn prompter(input: &str) -> Prompter<&[u8], Vec<u8>> {
+        Prompter::new(input.as_bytes(), vec![])
+    }
+
+    #[test]
+    fn test_ask() {
+        let mut p = prompter("\nfoo\n\n");
+        assert_eq!(p.ask("A", Some("x")).unwrap(), "x");
+        assert_eq!(p.ask("B", Some("x")).unwrap(), "foo");
+        assert_eq!(p.ask("C", None).unwrap(), "");
+        assert!(p.ask("D", None).is_err());
+        assert_eq!(String::from_utf8(p.output).unwrap(), "A [x]: B [x]: C: D: ");
+    }
+
+    #[test]
+    fn test_ask_bool() {
+        let mut p = prompter("maybe\nY\n\n");
+        assert!(p.ask_bool("Q", false).unwrap());
+        assert!(!p.ask_bool("Q", false).unwrap());
+    }
+
+    #[test]
+    fn test_choose() {
+        let mut p = prompter("c\nb\n");
+        assert_eq!(p.choose("Q", &["a", "b"]).unwrap(), "b");
+    }
+
+    #[test]
+    fn test_ask_parse() {
+        let mut p = prompter("abc\n\n");
+        assert_eq!(p.ask_parse::<u8>("Q", "3").unwrap(), 3);
+    }
+
+    #[test]
+    fn test_quote() {
+        let value = r#"a"b\c'd"#;
+        let config: toml::Table = toml::from_str(&format!("x = {}", quote(value))).unwrap();
+        assert_eq!(config["x"].as_str(), Some(value));
+    }
+
+    #[cfg(feature = "pcap")]
+    #[test]
+    fn test_pcap() {
+        let mut p = prompter("eth0\n192.168.0.21\n\n");
+        let mut out = String::new();
+        ask_pcap(&mut p, &mut out).unwrap();
+        let config: toml::Table = toml::from_str(&out).unwrap();
+        let pcap = config["pcap"].as_table().unwrap();
+        assert_eq!(pcap["device"].as_str(), Some("eth0"));
+        assert_eq!(pcap["filter"].as_str(), Some("src host 192.168.0.21"));
+        assert_eq!(pcap["timezone"].as_str(), Some("Africa/Johannesburg"));
+    }
+
+    #[cfg(feature = "modbus")]
+    #[test]
+    fn test_modbus() {
+        let mut p = prompter("192.168.0.30:502\n\n\n5\n");
+        let mut out = String::new();
+        ask_modbus(&mut p, &mut out).unwrap();
+        let config: toml::Table = toml::from_str(&out).unwrap();
+        let modbus = config["modbus"].as_table().unwrap();
+        assert_eq!(modbus["device"].as_str(), Some("192.168.0.30:502"));
+        assert_eq!(modbus["baud"].as_integer(), Some(9600));
+        assert_eq!(modbus["interval"].as_integer(), Some(5));
+    }
+}
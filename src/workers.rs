@@ -0,0 +1,167 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Running several instances (workers) of a receiver, for receivers that
+//! spend enough CPU time on each update (for example, serialising and
+//! compressing it) to fall behind on a single core. Each worker is given
+//! the updates of a subset of the inverters, chosen by hashing the serial
+//! number, so that the updates from each inverter stay in order. Workers
+//! run on threads of their own, each with a single-threaded runtime.
+
+use futures::channel::oneshot;
+use futures::prelude::*;
+use siphasher::sip::SipHasher13;
+use std::hash::Hasher;
+use std::num::NonZeroUsize;
+
+use crate::receiver::{Receiver, UpdateItem};
+
+/// Position of one worker among the workers of a receiver
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Worker {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl Worker {
+    /// The workers to create for a receiver configured with `count`
+    /// workers, or a single `None` if it only needs one (which is run
+    /// like any other receiver)
+    pub fn all(count: NonZeroUsize) -> Vec<Option<Worker>> {
+        let count = count.get();
+        if count == 1 {
+            return vec![None];
+        }
+        (0..count)
+            .map(|index| Some(Worker { index, count }))
+            .collect()
+    }
+
+    /// Whether the updates from an inverter go to this worker
+    pub fn matches(&self, serial: &str) -> bool {
+        let mut hasher = SipHasher13::new();
+        hasher.write(serial.as_bytes());
+        hasher.finish() % self.count as u64 == self.index as u64
+    }
+
+    /// Name of the worker for log messages, given the name of the receiver
+    pub fn name(&self, receiver: &str) -> String {
+        format!("{receiver}#{}", self.index + 1)
+    }
+}
+
+/// Run a receiver on a new thread. The returned future completes once the
+/// receiver has finished.
+pub fn spawn<S>(
+    name: String,
+    mut receiver: Box<dyn Receiver>,
+    stream: S,
+) -> impl Future<Output = ()>
+where
+    S: Stream<Item = UpdateItem> + Send + 'static,
+{
+    let (sender, done) = oneshot::channel();
+    std::thread::Builder::new()
+        .name(name)
+        .spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(receiver.run(Box::pin(stream)));
+            // The caller may no longer be waiting
+            let _ = sender.send(());
+        })
+        .unwrap();
+    // If the thread panics, the sender is dropped and the receiver is
+    // treated as finished
+    done.map(|_| ())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::receiver::{Update, UpdateReceiver};
+    use async_trait::async_trait;
+    use std::sync::{Arc, Mutex};
+    use std::thread::ThreadId;
+
+    #[test]
+    fn test_all() {
+        assert_eq!(Worker::all(NonZeroUsize::new(1).unwrap()), vec![None]);
+        let workers = Worker::all(NonZeroUsize::new(3).unwrap());
+        assert_eq!(workers.len(), 3);
+        assert_eq!(workers[2], Some(Worker { index: 2, count: 3 }));
+        assert_eq!(
+            workers[2].unwrap().name("influxdb2:main"),
+            "influxdb2:main#3"
+        );
+    }
+
+    #[test]
+    fn test_matches() {
+        let workers: Vec<Worker> = Worker::all(NonZeroUsize::new(4).unwrap())
+            .into_iter()
+            .flatten()
+            .collect();
+        let mut used = [false; 4];
+        for i in 0..100 {
+            let serial = format!("21{i:08}");
+            let matching: Vec<usize> = workers
+                .iter()
+                .filter(|worker| worker.matches(&serial))
+                .map(|worker| worker.index)
+                .collect();
+            // Each inverter goes to exactly one worker
+            assert_eq!(matching.len(), 1);
+            used[matching[0]] = true;
+        }
+        assert!(used.iter().all(|&u| u));
+    }
+
+    /// Records the updates it receives and the thread it runs on
+    struct Recorder {
+        serials: Arc<Mutex<Vec<String>>>,
+        thread: Arc<Mutex<Option<ThreadId>>>,
+    }
+
+    #[async_trait]
+    impl Receiver for Recorder {
+        async fn run<'a>(&mut self, mut receiver: UpdateReceiver<'a>) {
+            *self.thread.lock().unwrap() = Some(std::thread::current().id());
+            while let Some(update) = receiver.next().await {
+                self.serials.lock().unwrap().push(update.serial.clone());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spawn() {
+        let serials = Arc::new(Mutex::new(vec![]));
+        let thread = Arc::new(Mutex::new(None));
+        let receiver = Box::new(Recorder {
+            serials: serials.clone(),
+            thread: thread.clone(),
+        });
+        let updates: Vec<UpdateItem> = ["a", "b"]
+            .into_iter()
+            .map(|serial| Arc::new(Update::new(0, serial, &[], vec![])))
+            .collect();
+        spawn("test".to_owned(), receiver, stream::iter(updates)).await;
+        assert_eq!(*serials.lock().unwrap(), ["a", "b"]);
+        assert_ne!(*thread.lock().unwrap(), Some(std::thread::current().id()));
+    }
+}
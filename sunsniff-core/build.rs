@@ -0,0 +1,572 @@
+/* Copyright 2023-2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
+
+#[path = "build/fields_csv.rs"]
+mod fields_csv;
+
+use fields_csv::{Field, FieldType::*, Row, Source};
+
+impl Field {
+    fn scale(&self) -> f64 {
+        let default_scale = match self.field_type {
+            Charge | Duration | Power | StateOfCharge | Unitless => Some(1.0),
+            Energy | Temperature => Some(0.1),
+            Frequency => Some(0.01),
+            Current | Voltage => None,
+            Time => Some(60.0),
+        };
+        self.scale.or(default_scale).unwrap()
+    }
+
+    fn bias(&self) -> f64 {
+        let default_bias = match self.field_type {
+            Temperature => -100.0,
+            _ => 0.0,
+        };
+        self.bias.unwrap_or(default_bias)
+    }
+
+    fn unit(&self) -> &'static str {
+        match self.field_type {
+            Charge => "Ah",
+            Current => "A",
+            Duration => "min",
+            Energy => "kWh",
+            Frequency => "Hz",
+            Power => "W",
+            StateOfCharge => "%",
+            Temperature => "°C",
+            Time => "s",
+            Voltage => "V",
+            Unitless => "",
+        }
+    }
+}
+
+struct Record {
+    field: Rc<Field>,
+    positions: Vec<i32>,
+}
+
+fn write_fields_data<W>(w: &mut W, records: &[Record]) -> Result<(), Box<dyn Error>>
+where
+    W: Write,
+{
+    writeln!(w, "&[")?;
+    let mut by_id: HashMap<&str, usize> = HashMap::new();
+    for (i, record) in records.iter().enumerate() {
+        let field = &record.field;
+        let scale = field.scale();
+        let bias = field.bias();
+        let unit = field.unit();
+        let sum_of: Vec<(usize, f64)> = field
+            .sum_of
+            .iter()
+            .map(|term| {
+                // A leading minus sign subtracts the field instead of adding it
+                let (id, coeff) = match term.strip_prefix('-') {
+                    Some(id) => (id, -1.0),
+                    None => (term.as_str(), 1.0),
+                };
+                let idx = *by_id
+                    .get(id)
+                    .unwrap_or_else(|| panic!("Prior field {id:?} not found"));
+                (idx, coeff)
+            })
+            .collect();
+        writeln!(
+            w,
+            r#"    crate::fields::Field {{
+        field_type: crate::fields::FieldType::{:?},
+        group: {:?},
+        name: {:?},
+        id: {:?},
+        scale: {scale:?},
+        bias: {bias:?},
+        unit: {unit:?},
+        sum_of: &{:?},
+        word_order: crate::fields::WordOrder::{:?},
+        reset: crate::fields::Reset::{:?},
+    }},"#,
+            field.field_type,
+            field.group,
+            field.name,
+            field.id,
+            sum_of.as_slice(),
+            field.word_order.unwrap_or_default(),
+            field.reset.unwrap_or_default(),
+        )?;
+        by_id.insert(field.id.as_str(), i);
+    }
+    write!(w, "]")?;
+    Ok(())
+}
+
+/// Generate a map from field ID to position in `records`
+fn field_index(records: &[Record]) -> String {
+    let mut builder = phf_codegen::Map::new();
+    for (i, record) in records.iter().enumerate() {
+        builder.entry(record.field.id.as_str(), &i.to_string());
+    }
+    builder.build().to_string()
+}
+
+/// Number of time-of-use programs (must match `program::NUM_PROGRAMS`)
+const NUM_PROGRAMS: usize = 6;
+
+/// Generate the `Option<ProgramFields>` for a table, so that the decoder
+/// does not have to look the fields up for every packet. Returns `None`
+/// (as code) if the table has none of the program fields.
+fn program_fields(records: &[Record]) -> Result<String, String> {
+    let mut ids = vec![];
+    for prefix in ["time", "power", "soc"] {
+        for i in 1..=NUM_PROGRAMS {
+            ids.push(format!("inverter_program_{prefix}_{i}"));
+        }
+    }
+    for suffix in ["power", "soc", "current", "remaining"] {
+        ids.push(format!("inverter_program_{suffix}"));
+    }
+    let found: Vec<Option<usize>> = ids
+        .iter()
+        .map(|id| records.iter().position(|record| &record.field.id == id))
+        .collect();
+    if found.iter().all(Option::is_none) {
+        return Ok("None".to_owned());
+    }
+    if let Some(missing) = found.iter().position(Option::is_none) {
+        return Err(format!("has some program fields but not {}", ids[missing]));
+    }
+    let pos: Vec<usize> = found.into_iter().flatten().collect();
+    let n = NUM_PROGRAMS;
+    Ok(format!(
+        "Some(crate::program::ProgramFields {{ time: {:?}, power: {:?}, soc: {:?}, \
+         current_power: {}, current_soc: {}, current: {}, remaining: {} }})",
+        &pos[..n],
+        &pos[n..2 * n],
+        &pos[2 * n..3 * n],
+        pos[3 * n],
+        pos[3 * n + 1],
+        pos[3 * n + 2],
+        pos[3 * n + 3],
+    ))
+}
+
+/// Maximum number of registers that can be read in one modbus request
+const MAX_READ: i32 = 125;
+
+/// Group the registers used by `records` into runs of consecutive
+/// registers, each of which can be read in one request. Registers that are
+/// not used are not read, since the inverter may reject them.
+fn read_ranges(records: &[Record]) -> Vec<(i32, i32)> {
+    let registers: BTreeSet<i32> = records
+        .iter()
+        .flat_map(|record| record.positions.iter().copied())
+        .collect();
+    let mut ranges: Vec<(i32, i32)> = vec![];
+    for reg in registers {
+        match ranges.last_mut() {
+            Some((start, count)) if *start + *count == reg && *count < MAX_READ => *count += 1,
+            _ => ranges.push((reg, 1)),
+        }
+    }
+    ranges
+}
+
+fn write_fields<W>(w: &mut W, header: &str, records: &[Record]) -> Result<(), Box<dyn Error>>
+where
+    W: Write,
+{
+    writeln!(w, "{header}")?;
+    write!(w, "pub static FIELDS: &[crate::fields::Field] = ")?;
+    write_fields_data(w, records)?;
+    writeln!(w, ";")?;
+    writeln!(w, "/// Positions of the fields in [FIELDS], by ID")?;
+    writeln!(
+        w,
+        "pub static INDEX: crate::fields::FieldIndex = {};",
+        field_index(records)
+    )?;
+
+    writeln!(w, "#[allow(dead_code)]")?;
+    writeln!(w, "mod field_idx {{")?;
+    for (i, record) in records.iter().enumerate() {
+        writeln!(
+            w,
+            "    pub const {}: usize = {};",
+            record.field.id.to_uppercase(),
+            i
+        )?;
+    }
+    writeln!(w, "}}")?;
+
+    Ok(())
+}
+
+/// Write a Markdown table describing all the fields
+fn write_docs<W>(w: &mut W, sources: &[Source], rows: &[Row]) -> Result<(), Box<dyn Error>>
+where
+    W: Write,
+{
+    write!(w, "| ID | Group | Name | Unit | Scale | Bias |")?;
+    for source in sources.iter() {
+        write!(w, " {} |", source.description)?;
+    }
+    writeln!(w, " Sum of |")?;
+    writeln!(w, "|{}", "---|".repeat(sources.len() + 7))?;
+    for Row {
+        field, positions, ..
+    } in rows.iter()
+    {
+        write!(
+            w,
+            "| `{}` | {} | {} | {} | {} | {} |",
+            field.id,
+            field.group,
+            field.name,
+            field.unit(),
+            field.scale(),
+            field.bias()
+        )?;
+        for pos in positions.iter() {
+            let pos = match pos {
+                None => String::new(),
+                Some(pos) if pos.is_empty() => "derived".to_owned(),
+                Some(pos) => {
+                    let pos: Vec<String> = pos.iter().map(|x| x.to_string()).collect();
+                    pos.join(", ")
+                }
+            };
+            write!(w, " {pos} |")?;
+        }
+        writeln!(w, " {} |", field.sum_of.join(" "))?;
+    }
+    Ok(())
+}
+
+/// Duplicate of crate::can::ByteOrder
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+enum ByteOrder {
+    #[default]
+    Little,
+    Big,
+}
+
+/// Location of a value in a CAN frame, from can_fields.csv
+#[derive(Deserialize)]
+struct CanLayout {
+    protocol: String,
+    can_id: String,
+    offset: usize,
+    size: usize,
+    signed: Option<bool>,
+    byte_order: Option<ByteOrder>,
+}
+
+/// A row from can_fields.csv, with the parsed CAN ID
+type CanRow = (Rc<Field>, CanLayout, u32);
+
+/// Generate the field tables for CAN protocols from can_fields.csv
+fn write_can_fields<W>(w: &mut W) -> Result<(), Box<dyn Error>>
+where
+    W: Write,
+{
+    let mut reader = csv::Reader::from_reader(fs::File::open("can_fields.csv")?);
+    let headers = reader.headers()?.clone();
+    // Protocols in order of first appearance, with their rows
+    let mut protocols: Vec<(String, Vec<CanRow>)> = vec![];
+    let mut errors = vec![];
+    for row in reader.records() {
+        let row = row?;
+        let line = row.position().map_or(0, |pos| pos.line());
+        let mut error = |msg: String| errors.push(format!("can_fields.csv:{line}: {msg}"));
+        let mut field: Field = row.deserialize(Some(&headers))?;
+        let layout: CanLayout = row.deserialize(Some(&headers))?;
+        // There are no sensible defaults for the scale of CAN values, and
+        // the Sunsynk temperature bias does not apply.
+        if field.scale.is_none() {
+            error(format!("{} has no scale", field.id));
+        }
+        field.bias.get_or_insert(0.0);
+        if !field.sum_of.is_empty() {
+            error(format!("{} has sum_of, which is not supported", field.id));
+        }
+        if !(1..=8).contains(&layout.size) || layout.offset + layout.size > 8 {
+            error(format!("{} does not fit in a CAN frame", field.id));
+        }
+        let can_id = match layout.can_id.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => None,
+        };
+        let Some(can_id) = can_id else {
+            error(format!("invalid CAN ID {:?}", layout.can_id));
+            continue;
+        };
+        let rows = match protocols
+            .iter_mut()
+            .find(|(name, _)| *name == layout.protocol)
+        {
+            Some((_, rows)) => rows,
+            None => {
+                protocols.push((layout.protocol.clone(), vec![]));
+                &mut protocols.last_mut().unwrap().1
+            }
+        };
+        if rows.iter().any(|(other, _, _)| other.id == field.id) {
+            error(format!("duplicate ID {:?}", field.id));
+        }
+        rows.push((Rc::new(field), layout, can_id));
+    }
+    if !errors.is_empty() {
+        for error in errors.iter() {
+            eprintln!("{error}");
+        }
+        return Err(format!("can_fields.csv has {} error(s)", errors.len()).into());
+    }
+
+    let mut builder = phf_codegen::Map::new();
+    for (name, rows) in protocols.iter() {
+        let records: Vec<Record> = rows
+            .iter()
+            .map(|(field, _, _)| Record {
+                field: field.clone(),
+                positions: vec![],
+            })
+            .collect();
+        let mut buf = Vec::new();
+        writeln!(&mut buf, "    Protocol {{")?;
+        write!(&mut buf, "        fields: ")?;
+        write_fields_data(&mut buf, &records)?;
+        writeln!(&mut buf, ",")?;
+        writeln!(&mut buf, "        layout: &[")?;
+        for (_, layout, can_id) in rows.iter() {
+            writeln!(
+                &mut buf,
+                "            Layout {{ can_id: {can_id:#x}, offset: {}, size: {}, signed: {}, byte_order: ByteOrder::{:?} }},",
+                layout.offset,
+                layout.size,
+                layout.signed.unwrap_or(false),
+                layout.byte_order.unwrap_or_default(),
+            )?;
+        }
+        writeln!(&mut buf, "        ],")?;
+        writeln!(&mut buf, "    }}")?;
+        builder.entry(name.as_str(), &String::from_utf8(buf)?);
+    }
+    writeln!(w, "/// Field definitions for each CAN protocol")?;
+    writeln!(
+        w,
+        "pub static PROTOCOLS: phf::Map<&'static str, Protocol> = {};",
+        builder.build()
+    )?;
+    Ok(())
+}
+
+/// Hash the CSV files that the field tables are generated from (with FNV-1a,
+/// ignoring carriage returns so that checkouts with Windows line endings
+/// agree), to identify the tables in bug reports.
+fn table_hash(paths: &[&str]) -> Result<u64, Box<dyn Error>> {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for path in paths.iter() {
+        for byte in fs::read(path)?.into_iter().filter(|&b| b != b'\r') {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    Ok(hash)
+}
+
+/// Write tests that check the value of every field decoded from a reference
+/// packet, for each packet size that has one in `testdata`. The packet is in
+/// `sunsynk_<size>.hex` and the expected values in `sunsynk_<size>.csv`, so
+/// that a change to fields.csv that alters an existing decode fails loudly.
+fn write_golden_tests<W>(w: &mut W, sizes: &[i32]) -> Result<(), Box<dyn Error>>
+where
+    W: Write,
+{
+    for size in sizes.iter() {
+        let hex_path = format!("testdata/sunsynk_{size}.hex");
+        if !Path::new(&hex_path).exists() {
+            continue;
+        }
+        writeln!(
+            w,
+            "const GOLDEN_{size}: &str = include_str!(concat!(env!(\"CARGO_MANIFEST_DIR\"), \"/{hex_path}\"));"
+        )?;
+        let mut reader =
+            csv::Reader::from_reader(fs::File::open(format!("testdata/sunsynk_{size}.csv"))?);
+        let mut ids = vec![];
+        for record in reader.deserialize() {
+            let (id, value): (String, f64) = record?;
+            writeln!(w, "#[test]")?;
+            writeln!(w, "fn golden_{size}_{id}() {{")?;
+            writeln!(w, "    check_golden(GOLDEN_{size}, {id:?}, {value:?});")?;
+            writeln!(w, "}}")?;
+            ids.push(id);
+        }
+        writeln!(w, "#[test]")?;
+        writeln!(w, "fn golden_{size}_coverage() {{")?;
+        writeln!(w, "    check_coverage(GOLDEN_{size}, &{ids:?});")?;
+        writeln!(w, "}}")?;
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let out_dir = env::var_os("OUT_DIR").unwrap();
+    let out_path = Path::new(&out_dir);
+    let pcap_path = out_path.join("pcap_fields.rs");
+    let modbus_path = out_path.join("modbus_fields.rs");
+    let docs_path = out_path.join("fields.md");
+    let pcap_sizes = [292, 302];
+    let sources = fields_csv::sources(&pcap_sizes);
+    let rows = match fields_csv::read(fs::File::open("fields.csv")?, &sources) {
+        Ok(rows) => rows,
+        Err(errors) => {
+            for error in errors.iter() {
+                eprintln!("{error}");
+            }
+            return Err(format!("fields.csv has {} error(s)", errors.len()).into());
+        }
+    };
+
+    // Extract the records for each source
+    let mut records: Vec<Vec<Record>> = sources.iter().map(|_| vec![]).collect();
+    for row in rows.iter() {
+        for (source_records, positions) in records.iter_mut().zip(row.positions.iter()) {
+            if let Some(positions) = positions {
+                source_records.push(Record {
+                    field: row.field.clone(),
+                    positions: positions.clone(),
+                });
+            }
+        }
+    }
+    let modbus_records = records.pop().unwrap();
+    let pcap_records = records;
+
+    {
+        let mut pcap_writer = fs::File::create(pcap_path)?;
+        let mut builder = phf_codegen::Map::new();
+        for (size, records) in pcap_sizes.iter().zip(pcap_records.iter()) {
+            let mut buf = Vec::new();
+            writeln!(&mut buf, "    FieldOffsets {{")?;
+            write!(&mut buf, "        fields: ")?;
+            write_fields_data(&mut buf, records)?;
+            writeln!(&mut buf, ",")?;
+            writeln!(&mut buf, "        index: {},", field_index(records))?;
+            let programs =
+                program_fields(records).map_err(|msg| format!("{size}-byte packets: {msg}"))?;
+            writeln!(&mut buf, "        programs: {programs},")?;
+            write!(&mut buf, "        offsets: &[")?;
+            for record in records.iter() {
+                writeln!(&mut buf, "            &{:?},", record.positions.as_slice())?;
+            }
+            writeln!(&mut buf, "        ],")?;
+            writeln!(&mut buf, "    }}")?;
+            builder.entry(*size as usize, &String::from_utf8(buf)?);
+        }
+        writeln!(&mut pcap_writer, "struct FieldOffsets {{")?;
+        writeln!(
+            &mut pcap_writer,
+            "    fields: &'static [crate::fields::Field<'static>],"
+        )?;
+        writeln!(&mut pcap_writer, "    index: crate::fields::FieldIndex,")?;
+        writeln!(
+            &mut pcap_writer,
+            "    programs: Option<crate::program::ProgramFields>,"
+        )?;
+        writeln!(
+            &mut pcap_writer,
+            "    offsets: &'static [&'static [usize]],"
+        )?;
+        writeln!(&mut pcap_writer, "}}")?;
+        writeln!(
+            &mut pcap_writer,
+            "/// Field definitions for different packet sizes"
+        )?;
+        writeln!(
+            &mut pcap_writer,
+            "static FIELDS: phf::Map<usize, FieldOffsets> = {};",
+            builder.build()
+        )?;
+    }
+
+    {
+        let mut modbus_writer = fs::File::create(modbus_path)?;
+        write_fields(
+            &mut modbus_writer,
+            "/// Fields retrieved by modbus protocol",
+            &modbus_records,
+        )?;
+        writeln!(&mut modbus_writer, "/// Registers corresponding to fields")?;
+        writeln!(&mut modbus_writer, "pub const REGISTERS: &[&[u16]] = &[")?;
+        for record in modbus_records.iter() {
+            writeln!(
+                &mut modbus_writer,
+                "    &{:?},",
+                record.positions.as_slice()
+            )?;
+        }
+        writeln!(&mut modbus_writer, "];")?;
+        writeln!(
+            &mut modbus_writer,
+            "/// Runs of consecutive registers in [REGISTERS], as (start, count), \
+             which are read with one request each"
+        )?;
+        writeln!(
+            &mut modbus_writer,
+            "pub const READ_RANGES: &[(u16, u16)] = &{:?};",
+            read_ranges(&modbus_records)
+        )?;
+    }
+
+    {
+        let mut docs_writer = fs::File::create(docs_path)?;
+        write_docs(&mut docs_writer, &sources, &rows)?;
+    }
+
+    {
+        let mut can_writer = fs::File::create(out_path.join("can_fields.rs"))?;
+        write_can_fields(&mut can_writer)?;
+    }
+
+    {
+        let mut golden_writer = fs::File::create(out_path.join("sunsynk_golden.rs"))?;
+        write_golden_tests(&mut golden_writer, &pcap_sizes)?;
+    }
+
+    fs::write(
+        out_path.join("table_hash.txt"),
+        format!("{:016x}", table_hash(&["fields.csv", "can_fields.csv"])?),
+    )?;
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=build");
+    println!("cargo:rerun-if-changed=fields.csv");
+    println!("cargo:rerun-if-changed=can_fields.csv");
+    println!("cargo:rerun-if-changed=testdata");
+    Ok(())
+}
@@ -0,0 +1,447 @@
+/* Copyright 2023-2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Reading and checking fields.csv. This is used by the build script, and
+//! is in its own file so that it can be tested.
+
+use csv::StringRecord;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::io::Read;
+use std::rc::Rc;
+
+/// Duplicate of crate::fields::FieldType
+#[derive(Deserialize, Debug, Clone)]
+pub enum FieldType {
+    Charge,
+    Current,
+    Duration,
+    Energy,
+    Frequency,
+    Power,
+    StateOfCharge,
+    Temperature,
+    Time,
+    Voltage,
+    Unitless,
+}
+
+/// Duplicate of crate::fields::WordOrder
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub enum WordOrder {
+    #[default]
+    Little,
+    Big,
+}
+
+/// Duplicate of crate::fields::Reset
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub enum Reset {
+    #[default]
+    Never,
+    Daily,
+}
+
+fn split_str<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: &str = Deserialize::deserialize(deserializer)?;
+    Ok(s.split(' ')
+        .map(|x| x.to_owned())
+        .filter(|x| !x.is_empty())
+        .collect())
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Field {
+    pub field_type: FieldType,
+    pub group: String,
+    pub name: String,
+    pub id: String,
+    pub scale: Option<f64>,
+    #[serde(default)]
+    pub bias: Option<f64>,
+    #[serde(default, deserialize_with = "split_str")]
+    pub sum_of: Vec<String>,
+    pub word_order: Option<WordOrder>,
+    #[serde(default)]
+    pub reset: Option<Reset>,
+}
+
+/// A place that field values are found (a packet layout or modbus)
+pub struct Source {
+    /// Description used in the documentation
+    pub description: String,
+    /// Columns in fields.csv holding the positions
+    pub columns: [String; 2],
+    /// Size of the value at each position
+    pub width: i32,
+    /// Upper bound on position + width
+    pub limit: i32,
+}
+
+/// A row from fields.csv
+pub struct Row {
+    /// Line number in the file (for error messages)
+    pub line: u64,
+    pub field: Rc<Field>,
+    /// Positions for each source, or `None` if the field is not available
+    /// from that source. Derived fields have an empty list.
+    pub positions: Vec<Option<Vec<i32>>>,
+}
+
+/// Maximum number of 16-bit words in a value
+const MAX_WORDS: usize = 4;
+
+/// Parse the positions for one source from a row.
+fn parse_positions(row: &StringRecord, cols: [usize; 2]) -> Result<Option<Vec<i32>>, String> {
+    let cell1 = row.get(cols[0]).unwrap_or("");
+    let cell2 = row.get(cols[1]).unwrap_or("");
+    let parse = |cell: &str| {
+        cell.parse::<i32>()
+            .map_err(|_| format!("invalid position {cell:?}"))
+    };
+    if cell1.is_empty() {
+        if !cell2.is_empty() {
+            return Err("second position given without the first".to_owned());
+        }
+        return Ok(None);
+    }
+    let value1 = parse(cell1)?;
+    if value1 < 0 {
+        if !cell2.is_empty() {
+            return Err("second position given for a derived field".to_owned());
+        }
+        return Ok(Some(vec![]));
+    }
+    let mut positions = vec![value1];
+    // The second column may list several positions for values with more
+    // than two words
+    for cell in cell2.split(' ').filter(|x| !x.is_empty()) {
+        positions.push(parse(cell)?);
+    }
+    if positions.len() > MAX_WORDS {
+        return Err(format!("more than {MAX_WORDS} positions"));
+    }
+    Ok(Some(positions))
+}
+
+/// Check the rows for consistency, returning a list of errors
+fn validate(sources: &[Source], rows: &[Row]) -> Vec<String> {
+    let mut errors = vec![];
+    let mut error = |line: u64, msg: String| errors.push(format!("fields.csv:{line}: {msg}"));
+    // Row index by ID, for the rows seen so far
+    let mut by_id: HashMap<&str, usize> = HashMap::new();
+    // Which row uses each position, for each source
+    let mut used: Vec<HashMap<i32, usize>> = sources.iter().map(|_| HashMap::new()).collect();
+    for (i, row) in rows.iter().enumerate() {
+        let field = &row.field;
+        if let Some(&prev) = by_id.get(field.id.as_str()) {
+            let prev_line = rows[prev].line;
+            error(
+                row.line,
+                format!(
+                    "duplicate ID {:?} (first used on line {prev_line})",
+                    field.id
+                ),
+            );
+        }
+        if !field.sum_of.is_empty() && row.positions.iter().flatten().any(|p| !p.is_empty()) {
+            error(
+                row.line,
+                format!("{} has both sum_of and a position", field.id),
+            );
+        }
+        for term in field.sum_of.iter() {
+            let id = term.strip_prefix('-').unwrap_or(term);
+            if by_id.contains_key(id) {
+                continue;
+            } else if let Some(other) = rows.iter().find(|r| r.field.id == id) {
+                error(
+                    row.line,
+                    format!(
+                        "sum_of refers to {id}, which is defined later (line {})",
+                        other.line
+                    ),
+                );
+            } else {
+                error(row.line, format!("sum_of refers to unknown field {id}"));
+            }
+        }
+        for (s, source) in sources.iter().enumerate() {
+            let Some(positions) = &row.positions[s] else {
+                continue;
+            };
+            for &pos in positions.iter() {
+                if pos + source.width > source.limit {
+                    error(
+                        row.line,
+                        format!("{pos} is out of range for {}", source.description),
+                    );
+                    continue;
+                }
+                for p in pos..(pos + source.width) {
+                    if let Some(&other) = used[s].get(&p) {
+                        error(
+                            row.line,
+                            format!(
+                                "{} overlaps with {} (line {}) in {}",
+                                field.id,
+                                rows[other].field.id,
+                                rows[other].line,
+                                source.description
+                            ),
+                        );
+                        break;
+                    }
+                    used[s].insert(p, i);
+                }
+            }
+            for term in field.sum_of.iter() {
+                let id = term.strip_prefix('-').unwrap_or(term);
+                if let Some(&other) = by_id.get(id) {
+                    if rows[other].positions[s].is_none() {
+                        error(
+                            row.line,
+                            format!(
+                                "sum_of refers to {id}, which is not in {}",
+                                source.description
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+        by_id.entry(field.id.as_str()).or_insert(i);
+    }
+    errors
+}
+
+/// The places that field values are found: the logger packets of each
+/// size, then modbus
+pub fn sources(pcap_sizes: &[i32]) -> Vec<Source> {
+    let mut sources: Vec<Source> = pcap_sizes
+        .iter()
+        .map(|size| Source {
+            description: format!("{size}-byte packet offsets"),
+            columns: [format!("v{size}_offset"), format!("v{size}_offset2")],
+            width: 2,
+            limit: *size,
+        })
+        .collect();
+    sources.push(Source {
+        description: "Modbus registers".to_owned(),
+        columns: ["reg".to_owned(), "reg2".to_owned()],
+        width: 1,
+        limit: 65536,
+    });
+    sources
+}
+
+/// Read the rows of fields.csv and check them, returning a list of errors
+/// if there are any
+pub fn read(data: impl Read, sources: &[Source]) -> Result<Vec<Row>, Vec<String>> {
+    let mut reader = csv::Reader::from_reader(data);
+    let headers = reader
+        .headers()
+        .map_err(|err| vec![format!("fields.csv: {err}")])?
+        .clone();
+    let mut errors = vec![];
+    let mut columns = vec![];
+    for source in sources.iter() {
+        let cols = source
+            .columns
+            .clone()
+            .map(|name| headers.iter().position(|header| header == name));
+        match cols {
+            [Some(col1), Some(col2)] => columns.push([col1, col2]),
+            _ => {
+                return Err(vec![format!(
+                    "fields.csv: missing columns {:?}",
+                    source.columns
+                )])
+            }
+        }
+    }
+
+    let mut rows = vec![];
+    for row in reader.records() {
+        let row = match row {
+            Ok(row) => row,
+            Err(err) => {
+                errors.push(format!("fields.csv: {err}"));
+                continue;
+            }
+        };
+        let line = row.position().map_or(0, |pos| pos.line());
+        let field: Field = match row.deserialize(Some(&headers)) {
+            Ok(field) => field,
+            Err(err) => {
+                errors.push(format!("fields.csv:{line}: {err}"));
+                continue;
+            }
+        };
+        let mut positions = vec![];
+        for (source, cols) in sources.iter().zip(columns.iter()) {
+            match parse_positions(&row, *cols) {
+                Ok(pos) => positions.push(pos),
+                Err(msg) => {
+                    errors.push(format!(
+                        "fields.csv:{line}: {msg} for {}",
+                        source.description
+                    ));
+                    positions.push(None);
+                }
+            }
+        }
+        rows.push(Row {
+            line,
+            field: Rc::new(field),
+            positions,
+        });
+    }
+    errors.extend(validate(sources, &rows));
+    if errors.is_empty() {
+        Ok(rows)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HEADER: &str = "field_type,group,name,id,scale,v292_offset,v292_offset2,\
+                          v302_offset,v302_offset2,reg,reg2,sum_of,word_order,reset\n";
+
+    /// Check a table with the given rows (after the header)
+    fn check(rows: &str) -> Result<Vec<Row>, Vec<String>> {
+        read(format!("{HEADER}{rows}").as_bytes(), &sources(&[292, 302]))
+    }
+
+    /// Check that a table is rejected with a single error containing `msg`
+    fn assert_rejected(rows: &str, msg: &str) {
+        match check(rows) {
+            Ok(_) => panic!("{rows:?} was accepted"),
+            Err(errors) => {
+                assert_eq!(errors.len(), 1, "{errors:?}");
+                assert!(errors[0].contains(msg), "{errors:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_valid() {
+        let rows = check(
+            "Power,Grid,Power,grid_power,,214,,222,,169,,,,\n\
+             Power,Load,Power,load_power,,232,,240,,178,,,,\n\
+             Power,Load,Net,net_power,,-1,,-1,,-1,,load_power -grid_power,,\n\
+             Frequency,Generator,Frequency,gen_frequency,,,,,,196,,,,\n",
+        )
+        .unwrap();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0].line, 2);
+        assert_eq!(
+            rows[0].positions,
+            [Some(vec![214]), Some(vec![222]), Some(vec![169])]
+        );
+        assert_eq!(
+            rows[2].positions,
+            [Some(vec![]), Some(vec![]), Some(vec![])]
+        );
+        assert_eq!(rows[2].field.sum_of, ["load_power", "-grid_power"]);
+        assert_eq!(rows[3].positions, [None, None, Some(vec![196])]);
+    }
+
+    #[test]
+    fn test_duplicate_id() {
+        assert_rejected(
+            "Power,Grid,Power,grid_power,,214,,222,,169,,,,\n\
+             Power,Grid,Power,grid_power,,216,,224,,170,,,,\n",
+            "duplicate ID \"grid_power\" (first used on line 2)",
+        );
+    }
+
+    #[test]
+    fn test_overlap() {
+        assert_rejected(
+            "Power,Grid,Power,grid_power,,214,,222,,169,,,,\n\
+             Power,Load,Power,load_power,,215,,240,,178,,,,\n",
+            "load_power overlaps with grid_power (line 2) in 292-byte packet offsets",
+        );
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        assert_rejected(
+            "Power,Grid,Power,grid_power,,291,,222,,169,,,,\n",
+            "291 is out of range for 292-byte packet offsets",
+        );
+    }
+
+    #[test]
+    fn test_bad_positions() {
+        assert_rejected(
+            "Power,Grid,Power,grid_power,,x,,222,,169,,,,\n",
+            "invalid position \"x\" for 292-byte packet offsets",
+        );
+        assert_rejected(
+            "Power,Grid,Power,grid_power,,,214,222,,169,,,,\n",
+            "second position given without the first",
+        );
+        assert_rejected(
+            "Power,Grid,Power,grid_power,,-1,214,-1,,-1,,,,\n",
+            "second position given for a derived field",
+        );
+        assert_rejected(
+            "Power,Grid,Power,grid_power,,,,,,1,2 3 4 5,,,\n",
+            "more than 4 positions",
+        );
+    }
+
+    #[test]
+    fn test_sum_of() {
+        assert_rejected(
+            "Power,Load,Power,load_power,,232,,240,,178,,,,\n\
+             Power,Grid,Power,grid_power,,214,,222,,169,,load_power,,\n",
+            "grid_power has both sum_of and a position",
+        );
+        assert_rejected(
+            "Power,Load,Net,net_power,,-1,,-1,,-1,,-load_power,,\n\
+             Power,Load,Power,load_power,,232,,240,,178,,,,\n",
+            "sum_of refers to load_power, which is defined later (line 3)",
+        );
+        assert_rejected(
+            "Power,Load,Net,net_power,,-1,,-1,,-1,,load_power,,\n",
+            "sum_of refers to unknown field load_power",
+        );
+        assert_rejected(
+            "Frequency,Generator,Frequency,gen_frequency,,,,,,196,,,,\n\
+             Frequency,Generator,Total,gen_total,,-1,,,,-1,,gen_frequency,,\n",
+            "sum_of refers to gen_frequency, which is not in 292-byte packet offsets",
+        );
+    }
+
+    #[test]
+    fn test_bad_row() {
+        assert_rejected(
+            "Wattage,Grid,Power,grid_power,,214,,222,,169,,,,\n",
+            "fields.csv:2:",
+        );
+    }
+}
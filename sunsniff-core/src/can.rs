@@ -0,0 +1,181 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Decoder for the periodic frames sent by devices on a CAN bus, using the
+//! per-protocol tables generated from `can_fields.csv`.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::fields::Field;
+
+/// Order of the bytes of a value within a frame
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+/// Location of a field's value within a CAN frame
+#[derive(Debug)]
+struct Layout {
+    can_id: u32,
+    /// Byte offset within the frame data
+    offset: usize,
+    /// Size in bytes
+    size: usize,
+    signed: bool,
+    byte_order: ByteOrder,
+}
+
+impl Layout {
+    /// Extract the raw value from the frame data, or `None` if the frame
+    /// is too short
+    fn decode(&self, data: &[u8]) -> Option<i64> {
+        let bytes = data.get(self.offset..self.offset + self.size)?;
+        let mut raw: u64 = 0;
+        for (i, &byte) in bytes.iter().enumerate() {
+            let shift = match self.byte_order {
+                ByteOrder::Little => 8 * i,
+                ByteOrder::Big => 8 * (self.size - 1 - i),
+            };
+            raw |= (byte as u64) << shift;
+        }
+        let unused = 64 - 8 * self.size as u32;
+        Some(if self.signed {
+            ((raw << unused) as i64) >> unused
+        } else {
+            raw as i64
+        })
+    }
+}
+
+/// Fields decoded from the frames of one protocol
+pub struct Protocol {
+    pub fields: &'static [Field<'static>],
+    layout: &'static [Layout],
+}
+
+/// The most recent value of each field in a protocol
+pub struct Values {
+    protocol: &'static Protocol,
+    values: Vec<f64>,
+}
+
+impl Values {
+    pub fn new(protocol: &'static Protocol) -> Self {
+        Self {
+            protocol,
+            values: vec![f64::NAN; protocol.fields.len()],
+        }
+    }
+
+    /// Update the values from a received frame
+    pub fn update(&mut self, can_id: u32, data: &[u8]) {
+        let protocol = self.protocol;
+        for ((field, layout), value) in protocol
+            .fields
+            .iter()
+            .zip(protocol.layout.iter())
+            .zip(self.values.iter_mut())
+        {
+            if layout.can_id == can_id {
+                if let Some(raw) = layout.decode(data) {
+                    *value = (raw as f64) * field.scale + field.bias;
+                }
+            }
+        }
+    }
+
+    /// Whether every field has been received at least once
+    pub fn complete(&self) -> bool {
+        self.values.iter().all(|v| !v.is_nan())
+    }
+
+    /// Values for the fields of the protocol (NaN if not yet received)
+    pub fn values(&self) -> &[f64] {
+        &self.values
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/can_fields.rs"));
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_layout_decode() {
+        let layout = |offset, size, signed, byte_order| Layout {
+            can_id: 0,
+            offset,
+            size,
+            signed,
+            byte_order,
+        };
+        let data = [0x12, 0x34, 0xfe, 0xff];
+        assert_eq!(
+            layout(0, 2, false, ByteOrder::Little).decode(&data),
+            Some(0x3412)
+        );
+        assert_eq!(
+            layout(0, 2, false, ByteOrder::Big).decode(&data),
+            Some(0x1234)
+        );
+        assert_eq!(
+            layout(2, 2, true, ByteOrder::Little).decode(&data),
+            Some(-2)
+        );
+        assert_eq!(
+            layout(2, 2, false, ByteOrder::Little).decode(&data),
+            Some(0xfffe)
+        );
+        assert_eq!(
+            layout(1, 1, false, ByteOrder::Little).decode(&data),
+            Some(0x34)
+        );
+        assert_eq!(layout(3, 2, false, ByteOrder::Little).decode(&data), None);
+    }
+
+    #[test]
+    fn test_pylontech() {
+        let protocol = &PROTOCOLS["pylontech"];
+        let mut values = Values::new(protocol);
+        let get = |values: &Values, id| {
+            values.values[protocol.fields.iter().position(|f| f.id == id).unwrap()]
+        };
+        // 53.2V charge voltage, 50A charge limit, 100A discharge limit, 47V
+        values.update(0x351, &[0x14, 0x02, 0xf4, 0x01, 0xe8, 0x03, 0xd6, 0x01]);
+        // 85% SOC, 99% SOH
+        values.update(0x355, &[85, 0, 99, 0]);
+        assert!(!values.complete());
+        // 51.5V, -12.3A, 21.5°C
+        values.update(0x356, &[0x1e, 0x14, 0x85, 0xff, 0xd7, 0x00]);
+        values.update(0x359, &[0, 0, 0, 0, 2, 0x50, 0x4e, 0]);
+        // Frames for other devices are ignored
+        values.update(0x123, &[0xff; 8]);
+        assert!(values.complete());
+        assert_approx_eq!(get(&values, "bms_charge_voltage"), 53.2);
+        assert_approx_eq!(get(&values, "bms_charge_limit_current"), 50.0);
+        assert_approx_eq!(get(&values, "bms_discharge_limit_current"), 100.0);
+        assert_eq!(get(&values, "bms_soc"), 85.0);
+        assert_approx_eq!(get(&values, "bms_voltage"), 51.5);
+        assert_approx_eq!(get(&values, "bms_current"), -12.3);
+        assert_approx_eq!(get(&values, "bms_temperature"), 21.5);
+        assert_eq!(get(&values, "bms_modules"), 2.0);
+    }
+}
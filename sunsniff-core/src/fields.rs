@@ -0,0 +1,471 @@
+/* Copyright 2022-2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+/// Markdown table describing all the fields and where they are found,
+/// generated from `fields.csv`
+pub const DOCUMENTATION: &str = include_str!(concat!(env!("OUT_DIR"), "/fields.md"));
+
+/// Hash of `fields.csv` and `can_fields.csv`, identifying which revision of
+/// the field tables is compiled in
+pub const TABLE_HASH: &str = include_str!(concat!(env!("OUT_DIR"), "/table_hash.txt"));
+
+/// Type of quantity stored in a field. In the configuration file, these are
+/// written in snake_case (such as `state_of_charge`). They are serialised
+/// with their Rust names, and those are also accepted, so that journals
+/// written by earlier versions can still be read.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "snake_case"))]
+pub enum FieldType {
+    #[serde(alias = "Charge")]
+    Charge,
+    #[serde(alias = "Current")]
+    Current,
+    #[serde(alias = "Duration")]
+    Duration,
+    #[serde(alias = "Energy")]
+    Energy,
+    #[serde(alias = "Frequency")]
+    Frequency,
+    #[serde(alias = "Power")]
+    Power,
+    #[serde(alias = "StateOfCharge")]
+    StateOfCharge,
+    #[serde(alias = "Temperature")]
+    Temperature,
+    #[serde(alias = "Time")]
+    Time,
+    #[serde(alias = "Voltage")]
+    Voltage,
+    #[serde(alias = "Unitless")]
+    Unitless,
+    /// Whether something (such as the grid) is connected, as 1 or 0
+    #[serde(alias = "Connectivity")]
+    Connectivity,
+    /// Whether power is detected (such as from a running generator), as 1
+    /// or 0
+    #[serde(alias = "PowerDetected")]
+    PowerDetected,
+    /// A point in time, as seconds since the UNIX epoch
+    #[serde(alias = "Timestamp")]
+    Timestamp,
+}
+
+impl FieldType {
+    /// Whether values of this type are on/off states (1 or 0)
+    pub fn is_boolean(self) -> bool {
+        matches!(self, FieldType::Connectivity | FieldType::PowerDetected)
+    }
+}
+
+/// Order in which multi-word values are stored
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum WordOrder {
+    /// Least significant word first
+    Little,
+    /// Most significant word first
+    Big,
+}
+
+/// When a cumulative value (such as the energy produced) goes back to zero
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum Reset {
+    /// The value only increases (apart from wrapping or a replaced
+    /// inverter)
+    #[default]
+    Never,
+    /// The value is reset at midnight (inverter local time)
+    Daily,
+}
+
+/// Static description of a field in the data
+#[derive(Debug)]
+pub struct Field<'a> {
+    pub field_type: FieldType,
+    pub group: &'a str,
+    pub name: &'a str,
+    pub id: &'a str,
+    /// Amount by which to scale the raw integer value
+    pub scale: f64,
+    /// Amount to add to the value, after scaling
+    pub bias: f64,
+    pub unit: &'a str,
+    /// Indices of other fields to sum to get this field, each with a
+    /// coefficient (+1 or -1) to multiply it by
+    pub sum_of: &'a [(usize, f64)],
+    /// Order of the words for values made up of multiple words
+    pub word_order: WordOrder,
+    /// When the value is reset, if it is cumulative
+    pub reset: Reset,
+}
+
+impl Field<'_> {
+    pub fn from_u16s(&self, parts: impl IntoIterator<Item = u16>) -> f64 {
+        let mut parts: Vec<u16> = parts.into_iter().collect();
+        if self.word_order == WordOrder::Big {
+            parts.reverse();
+        }
+        let mut raw: u64 = 0;
+        let mut shift: u32 = 0;
+        for part in parts {
+            raw |= (part as u64) << shift;
+            shift += 16;
+        }
+        // Convert to signed by sign-extending the top bit
+        // (TODO: most registers are actually unsigned)
+        let mut raw = ((raw << (64 - shift)) as i64) >> (64 - shift);
+        // Special handling for time fields: HH:MM is encoded as HH*100+MM.
+        if self.field_type == FieldType::Time {
+            let h = raw / 100;
+            let m = raw % 100;
+            raw = h * 60 + m;
+        }
+        (raw as f64) * self.scale + self.bias
+    }
+
+    /// Convert a value to a single raw register value (the inverse of
+    /// [Field::from_u16s] for a single part). Returns `None` if the value
+    /// is not representable.
+    pub fn to_u16(&self, value: f64) -> Option<u16> {
+        let raw = libm::round((value - self.bias) / self.scale);
+        if !(i16::MIN as f64..=i16::MAX as f64).contains(&raw) {
+            return None;
+        }
+        let mut raw = raw as i16;
+        if self.field_type == FieldType::Time {
+            if !(0..24 * 60).contains(&raw) {
+                return None;
+            }
+            raw = raw / 60 * 100 + raw % 60;
+        }
+        Some(raw as u16)
+    }
+
+    /// Format a value as text. This is the shared default used by
+    /// receivers that publish text (see [crate::receiver::Receiver::format_value]).
+    pub fn format_value(&self, value: f64) -> String {
+        match self.field_type {
+            FieldType::Time => format_time(value),
+            FieldType::Timestamp => format_timestamp(value),
+            _ => value.to_string(),
+        }
+    }
+
+    pub fn from_sum(&self, values: &[f64]) -> f64 {
+        self.sum_of
+            .iter()
+            .map(|(idx, coeff)| values[*idx] * coeff)
+            .sum()
+    }
+}
+
+/// Map from field ID to position in a field table, generated at compile time
+pub type FieldIndex = phf::Map<&'static str, usize>;
+
+/// Built-in field tables that have a [FieldIndex]
+fn indexed_tables() -> impl Iterator<Item = (&'static [Field<'static>], &'static FieldIndex)> {
+    #[cfg(feature = "modbus")]
+    let modbus = Some((crate::modbus::FIELDS, &crate::modbus::INDEX));
+    #[cfg(not(feature = "modbus"))]
+    let modbus = None;
+    #[cfg(feature = "sunsynk")]
+    let pcap = crate::logger::sunsynk::tables();
+    #[cfg(not(feature = "sunsynk"))]
+    let pcap = core::iter::empty();
+    modbus.into_iter().chain(pcap)
+}
+
+/// Get the compile-time index of a field table. This is only available for
+/// the built-in tables (Sunsynk packets and modbus registers), and `fields`
+/// must be the table itself rather than a copy.
+pub fn index(fields: &[Field<'_>]) -> Option<&'static FieldIndex> {
+    indexed_tables()
+        .find(|(table, _)| core::ptr::eq(*table, fields))
+        .map(|(_, index)| index)
+}
+
+/// Position of the field with ID `id` in `fields`, using the compile-time
+/// index if there is one
+pub fn position(fields: &[Field<'_>], id: &str) -> Option<usize> {
+    match index(fields) {
+        Some(index) => index.get(id).copied(),
+        None => fields.iter().position(|field| field.id == id),
+    }
+}
+
+/// Built-in field tables without a [FieldIndex]
+fn other_tables() -> impl Iterator<Item = &'static [Field<'static>]> {
+    #[cfg(feature = "can")]
+    let can = crate::can::PROTOCOLS
+        .values()
+        .map(|protocol| protocol.fields);
+    #[cfg(not(feature = "can"))]
+    let can = core::iter::empty();
+    #[cfg(feature = "sunsynk")]
+    let heartbeat = Some(crate::logger::HEARTBEAT_FIELDS);
+    #[cfg(not(feature = "sunsynk"))]
+    let heartbeat = None;
+    #[cfg(feature = "modbus")]
+    let bus = [
+        Some(crate::modbus::BUS_FIELDS),
+        Some(crate::modbus::WRITE_FIELDS),
+    ];
+    #[cfg(not(feature = "modbus"))]
+    let bus = [None, None];
+    can.chain(heartbeat).chain(bus.into_iter().flatten())
+}
+
+/// IDs of the fields in all the built-in tables. Fields that appear in
+/// several tables are repeated.
+pub fn known_ids() -> impl Iterator<Item = &'static str> {
+    indexed_tables()
+        .map(|(fields, _)| fields)
+        .chain(other_tables())
+        .flatten()
+        .map(|field| field.id)
+}
+
+/// Whether `id` is the ID of a field in one of the built-in tables. This is
+/// used to check field IDs in the configuration.
+pub fn is_known(id: &str) -> bool {
+    indexed_tables().any(|(_, index)| index.contains_key(id))
+        || other_tables().flatten().any(|field| field.id == id)
+}
+
+/// Format a value of a [FieldType::Time] field (seconds since midnight) as
+/// HH:MM.
+pub fn format_time(value: f64) -> String {
+    let minutes = libm::round(value / 60.0) as i64;
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+/// Format a value of a [FieldType::Timestamp] field (seconds since the UNIX
+/// epoch) as an RFC 3339 time in UTC. Values that cannot be represented are
+/// formatted as numbers.
+pub fn format_timestamp(value: f64) -> String {
+    let secs = libm::floor(value);
+    let nanos = ((value - secs) * 1e9) as u32;
+    match chrono::DateTime::from_timestamp(secs as i64, nanos) {
+        Some(dt) if value.is_finite() => dt.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true),
+        _ => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+    use proptest::prelude::*;
+
+    fn field() -> Field<'static> {
+        Field {
+            field_type: FieldType::Energy,
+            group: "Grid",
+            name: "Total import",
+            id: "grid_import",
+            scale: 0.1,
+            bias: -10.0, // Not realistic, but useful to test the feature
+            unit: "kWh",
+            sum_of: &[(1, 1.0), (2, -1.0)],
+            word_order: WordOrder::Little,
+            reset: Reset::Never,
+        }
+    }
+
+    #[test]
+    fn test_from_u16s_one() {
+        let f = field();
+        assert_approx_eq!(f.from_u16s([12345]), 1224.5);
+        assert_approx_eq!(f.from_u16s([55536]), -1010.0);
+    }
+
+    #[test]
+    fn test_from_u16s_two() {
+        let f = field();
+        assert_approx_eq!(f.from_u16s([12345, 4321]), 28319330.1);
+        assert_approx_eq!(f.from_u16s([55536, 4321]), 28323649.2);
+        assert_approx_eq!(f.from_u16s([55536, 55536]), -65530456.4);
+    }
+
+    #[test]
+    fn test_from_u16s_four() {
+        let f = field();
+        assert_approx_eq!(f.from_u16s([0, 0, 1, 0]), 429496729.6 - 10.0);
+        assert_approx_eq!(f.from_u16s([55536, 65535, 65535, 65535]), -1010.0);
+    }
+
+    #[test]
+    fn test_from_u16s_big() {
+        let f = Field {
+            word_order: WordOrder::Big,
+            ..field()
+        };
+        assert_approx_eq!(f.from_u16s([4321, 12345]), 28319330.1);
+        assert_approx_eq!(f.from_u16s([65535, 55536]), -1010.0);
+    }
+
+    #[test]
+    fn test_to_u16() {
+        let f = field();
+        assert_eq!(f.to_u16(f.from_u16s([1234])), Some(1234));
+        assert_eq!(f.to_u16(-20.0), Some(65436));
+        assert_eq!(f.to_u16(1e6), None);
+        let f = Field {
+            field_type: FieldType::Time,
+            scale: 60.0,
+            bias: 0.0,
+            ..field()
+        };
+        assert_eq!(f.to_u16(73800.0), Some(2030));
+        assert_eq!(f.to_u16(86400.0), None);
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0.0), "00:00");
+        assert_eq!(format_time(73800.0), "20:30");
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(1667629966.0), "2022-11-05T06:32:46Z");
+        assert_eq!(format_timestamp(1667629966.5), "2022-11-05T06:32:46.500Z");
+        assert_eq!(format_timestamp(f64::NAN), "NaN");
+    }
+
+    #[test]
+    fn test_format_value() {
+        let f = field();
+        assert_eq!(f.format_value(12.5), "12.5");
+        let f = Field {
+            field_type: FieldType::Time,
+            ..field()
+        };
+        assert_eq!(f.format_value(3600.0), "01:00");
+    }
+
+    #[test]
+    fn test_from_sum() {
+        let f = field();
+        let values = [2.0, 3.0, 4.0];
+        assert_eq!(f.from_sum(&values), -1.0);
+    }
+
+    #[test]
+    fn test_index() {
+        for (fields, index) in indexed_tables() {
+            assert_eq!(index.len(), fields.len());
+            for (i, field) in fields.iter().enumerate() {
+                assert_eq!(index.get(field.id), Some(&i));
+                assert_eq!(position(fields, field.id), Some(i));
+            }
+            // Tables with the same fields may be merged, in which case any
+            // of their indices is found
+            assert!(super::index(fields).is_some());
+        }
+        #[cfg(all(feature = "modbus", feature = "sunsynk"))]
+        assert_eq!(indexed_tables().count(), 3);
+        // Copies of the tables are searched instead
+        let copy = [field()];
+        assert!(super::index(&copy).is_none());
+        assert_eq!(position(&copy, "grid_import"), Some(0));
+        assert_eq!(position(&copy, "grid_export"), None);
+    }
+
+    #[test]
+    #[cfg(feature = "sunsynk")]
+    fn test_is_known() {
+        assert!(is_known("battery_charge_total"));
+        assert!(is_known("gen_production_daily"));
+        assert!(is_known("dongle_online"));
+        assert!(!is_known("battery_charge_totl"));
+        assert!(known_ids().any(|id| id == "dongle_online"));
+    }
+
+    #[test]
+    fn test_table_hash() {
+        assert_eq!(TABLE_HASH.len(), 16);
+        assert!(TABLE_HASH.bytes().all(|b| b.is_ascii_hexdigit()));
+    }
+
+    /// Reference implementation of [Field::from_u16s], which composes the
+    /// words arithmetically rather than with shifts
+    fn reference_from_u16s(f: &Field<'_>, parts: &[u16]) -> f64 {
+        let mut words = parts.to_vec();
+        if f.word_order == WordOrder::Little {
+            words.reverse();
+        }
+        let mut raw: i128 = 0;
+        for word in words {
+            raw = raw * 65536 + word as i128;
+        }
+        // Two's complement
+        let modulus = 1i128 << (16 * parts.len());
+        if raw >= modulus / 2 {
+            raw -= modulus;
+        }
+        if f.field_type == FieldType::Time {
+            raw = raw / 100 * 60 + raw % 100;
+        }
+        raw as f64 * f.scale + f.bias
+    }
+
+    prop_compose! {
+        fn arb_field()(
+            field_type in prop::sample::select(vec![
+                FieldType::Energy,
+                FieldType::Power,
+                FieldType::Time,
+            ]),
+            scale in prop::sample::select(vec![1.0, 0.1, 0.01, 10.0, 60.0, -0.1]),
+            bias in -1000.0..1000.0,
+            word_order in prop::sample::select(vec![WordOrder::Little, WordOrder::Big]),
+        ) -> Field<'static> {
+            Field {
+                field_type,
+                scale,
+                bias,
+                word_order,
+                ..field()
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_from_u16s_reference(
+            f in arb_field(),
+            parts in prop::collection::vec(any::<u16>(), 1..=4),
+        ) {
+            prop_assert_eq!(f.from_u16s(parts.iter().copied()), reference_from_u16s(&f, &parts));
+        }
+
+        #[test]
+        fn test_to_u16_round_trip(f in arb_field(), part: u16) {
+            // Only use raw values that are valid times for time fields
+            let part = if f.field_type == FieldType::Time {
+                part % 24 * 100 + part / 24 % 60
+            } else {
+                part
+            };
+            prop_assert_eq!(f.to_u16(f.from_u16s([part])), Some(part));
+        }
+    }
+}
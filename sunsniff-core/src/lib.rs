@@ -0,0 +1,46 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Field definitions and decoders used by sunsniff, without any of the
+//! dependencies needed to capture packets or talk to the backends. The
+//! decoders work on byte slices and register values, so they can be used
+//! from other programs that obtain the data in their own way.
+//!
+//! Without the (default) `std` feature, the crate is `no_std` and only
+//! requires `alloc`. That leaves out the [receiver::Receiver] trait and the
+//! stateful logger decoders, but [logger::sunsynk::decode] is still
+//! available, with the time zone supplied by the caller.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "can")]
+pub mod can;
+pub mod fields;
+#[cfg(feature = "sunsynk")]
+pub mod logger;
+#[cfg(feature = "modbus")]
+pub mod modbus;
+pub mod program;
+pub mod receiver;
+
+// The build script's checks of fields.csv, included here so that they are
+// tested. Most of the field definition is only used by the build script.
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "../build/fields_csv.rs"]
+mod fields_csv;
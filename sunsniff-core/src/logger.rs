@@ -0,0 +1,194 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Decoders for the TCP payloads that WiFi loggers (dongles) send to the
+//! vendor's cloud service
+//!
+//! Each type of logger has a [Protocol] implementation in a submodule. The
+//! field tables are not part of the trait: the build script only generates
+//! them from `fields.csv`, which holds the Sunsynk register map and frame
+//! offsets, so a logger for another brand of inverter would also need its
+//! own table and build step. The tables also need to be added to
+//! [crate::fields::known_ids] so that their field IDs are accepted in the
+//! configuration.
+
+use alloc::format;
+use alloc::vec;
+use core::hash::BuildHasher;
+use core::ops::Range;
+
+use crate::fields::{Field, FieldType, Reset, WordOrder};
+use crate::receiver::Update;
+
+#[cfg(feature = "std")]
+use {
+    alloc::{string::String, vec::Vec},
+    std::hash::RandomState,
+    std::sync::atomic::AtomicU64,
+};
+
+pub mod sunsynk;
+
+/// Number of packets that looked like they came from a logger, but could
+/// not be decoded (for example, because they have an unrecognised length)
+#[cfg(feature = "std")]
+pub static UNKNOWN_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+/// Whether a TCP payload holds a whole frame, for reassembling frames that
+/// the logger split across several TCP segments
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Framing {
+    /// The payload is not a recognised frame or the start of one (it may be
+    /// the continuation of one)
+    Other,
+    /// The payload is a whole frame
+    Complete,
+    /// The payload is the start of a frame, and more data is needed
+    Partial,
+}
+
+/// Decoder for the TCP payloads sent by one type of logger
+#[cfg(feature = "std")]
+pub trait Protocol: Send {
+    /// Decode a payload into an update, along with the raw register values
+    /// for each field. Returns `None` if the payload is not recognised.
+    fn decode(&self, payload: &[u8]) -> Option<(Update<'static>, Vec<Vec<u16>>)>;
+
+    /// Replace identifying information (such as serial numbers) in a
+    /// payload, using [anonymise]. Returns `false` (leaving the payload
+    /// unchanged) if the payload is not from this type of logger, in which
+    /// case it may hold other traffic and should not be shared.
+    fn anonymise(&self, payload: &mut [u8], hasher: &RandomState) -> bool;
+
+    /// If the payload is a keep-alive frame, return the inverter serial
+    /// number from it.
+    fn heartbeat(&self, _payload: &[u8]) -> Option<String> {
+        None
+    }
+
+    /// Whether the payload is a whole frame, the start of one, or neither.
+    /// Protocols that cannot tell treat every payload as a whole frame.
+    fn framing(&self, _payload: &[u8]) -> Framing {
+        Framing::Complete
+    }
+}
+
+const fn heartbeat_field(
+    field_type: FieldType,
+    name: &'static str,
+    id: &'static str,
+    unit: &'static str,
+) -> Field<'static> {
+    Field {
+        field_type,
+        group: "Dongle",
+        name,
+        id,
+        scale: 1.0,
+        bias: 0.0,
+        unit,
+        sum_of: &[],
+        word_order: WordOrder::Little,
+        reset: Reset::Never,
+    }
+}
+
+/// Fields in the updates generated from keep-alive frames: whether the
+/// dongle is online (1, or 0 once the keep-alives stop), and the capture
+/// time of the latest keep-alive. The updates use a serial number made by
+/// appending [HEARTBEAT_SUFFIX] to the inverter serial number, so that they
+/// are not mistaken for (partial) updates from the inverter.
+pub const HEARTBEAT_FIELDS: &[Field<'static>] = &[
+    heartbeat_field(FieldType::Connectivity, "Online", "dongle_online", ""),
+    heartbeat_field(FieldType::Timestamp, "Last seen", "dongle_last_seen", "s"),
+];
+
+pub const HEARTBEAT_SUFFIX: &str = "-dongle";
+
+/// Create the update for a keep-alive frame from the inverter with serial
+/// number `serial`, received at `timestamp` (in nanoseconds since the UNIX
+/// epoch).
+pub fn heartbeat_update(serial: &str, timestamp: i64) -> Update<'static> {
+    let values = vec![1.0, (timestamp / 1_000_000_000) as f64];
+    Update::new(
+        timestamp,
+        format!("{serial}{HEARTBEAT_SUFFIX}"),
+        HEARTBEAT_FIELDS,
+        values,
+    )
+}
+
+/// Create the update reporting that the dongle for the inverter with serial
+/// number `serial` has gone offline at `timestamp`, having last sent a
+/// keep-alive at `last_seen` (both in nanoseconds since the UNIX epoch).
+pub fn heartbeat_timeout_update(serial: &str, last_seen: i64, timestamp: i64) -> Update<'static> {
+    let values = vec![0.0, (last_seen / 1_000_000_000) as f64];
+    Update::new(
+        timestamp,
+        format!("{serial}{HEARTBEAT_SUFFIX}"),
+        HEARTBEAT_FIELDS,
+        values,
+    )
+}
+
+/// Replace an identifier (such as a serial number) in a frame with a
+/// pseudonym of the same length made of decimal digits. The pseudonym
+/// depends only on the identifier and the hasher, so that frames from the
+/// same device can still be matched up with each other. Frames that are too
+/// short to contain the identifier are left unchanged.
+pub fn anonymise(frame: &mut [u8], range: Range<usize>, hasher: &impl BuildHasher) {
+    let Some(id) = frame.get_mut(range) else {
+        return;
+    };
+    let original = id.to_vec();
+    for (i, byte) in id.iter_mut().enumerate() {
+        *byte = b'0' + (hasher.hash_one((&original, i)) % 10) as u8;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::BuildHasherDefault;
+
+    #[test]
+    fn test_anonymise() {
+        let hasher = BuildHasherDefault::<DefaultHasher>::default();
+        let frame = b"\xa5XX1234567890YY".to_vec();
+
+        let mut a = frame.clone();
+        anonymise(&mut a, 3..13, &hasher);
+        assert_eq!(a[..3], frame[..3]);
+        assert_eq!(a[13..], frame[13..]);
+        assert_ne!(a[3..13], frame[3..13]);
+        assert!(a[3..13].iter().all(u8::is_ascii_digit));
+
+        // The same serial gets the same pseudonym, and a different one
+        // gets a different pseudonym
+        let mut b = frame.clone();
+        anonymise(&mut b, 3..13, &hasher);
+        assert_eq!(a, b);
+        let mut c = b"\xa5XX1234567891YY".to_vec();
+        anonymise(&mut c, 3..13, &hasher);
+        assert_ne!(a[3..13], c[3..13]);
+
+        // Short frames are unchanged
+        let mut short = frame[..10].to_vec();
+        anonymise(&mut short, 3..13, &hasher);
+        assert_eq!(short, frame[..10]);
+    }
+}
@@ -0,0 +1,594 @@
+/* Copyright 2022-2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Decoder for the packets sent by Sunsynk (Deye) WiFi dongles
+
+use alloc::borrow::ToOwned;
+use alloc::vec::Vec;
+use chrono::{DateTime, LocalResult, NaiveDate, TimeZone, Timelike};
+use core::ops::Range;
+use log::info;
+
+use super::Framing;
+use crate::fields::{Field, FieldIndex};
+use crate::program::ProgramFields;
+use crate::receiver::Update;
+
+#[cfg(feature = "std")]
+use {
+    super::{anonymise, Protocol, UNKNOWN_FRAMES},
+    alloc::string::String,
+    chrono_tz::Tz,
+    log::warn,
+    std::collections::{BTreeSet, HashMap},
+    std::hash::RandomState,
+    std::sync::atomic::Ordering,
+    std::sync::Mutex,
+    std::time::{Duration, Instant},
+};
+
+/// Expected first byte of the packet
+const MAGIC_HEADER: u8 = 0xa5;
+/// Offsets containing the inverter serial number
+const SERIAL_RANGE: Range<usize> = 11..21;
+/// Offsets containing the control code, which says what kind of frame it is
+const CONTROL_RANGE: Range<usize> = 3..5;
+/// Control code of keep-alive frames (0x4710, little-endian)
+const HEARTBEAT_CONTROL: [u8; 2] = [0x10, 0x47];
+/// Offset at which the timestamp is located
+const DATETIME_OFFSET: usize = 37;
+/// Number of bytes of an unrecognised packet to include in the warning
+#[cfg(feature = "std")]
+const SAMPLE_SIZE: usize = 64;
+/// Minimum time between repeated warnings about unrecognised packets
+#[cfg(feature = "std")]
+const WARNING_INTERVAL: Duration = Duration::from_secs(3600);
+/// Number of unrecognised lengths to log in detail. Beyond that, the
+/// packets are most likely garbage rather than a new firmware version.
+#[cfg(feature = "std")]
+const MAX_SAMPLES: usize = 8;
+
+/// Reason that [decode] could not decode a payload
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    /// The payload does not start with the magic header
+    NotRecognised,
+    /// The payload has the magic header, but a length that is not known
+    UnknownLength(usize),
+    /// The timestamp is invalid, or is invalid or ambiguous in the time zone
+    InvalidTimestamp,
+}
+
+impl core::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::NotRecognised => write!(f, "not a Sunsynk logger packet"),
+            Self::UnknownLength(len) => write!(f, "unrecognised packet length {len}"),
+            Self::InvalidTimestamp => write!(f, "invalid timestamp"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+/// Packet lengths that can be decoded
+pub fn lengths() -> impl Iterator<Item = usize> {
+    FIELDS.keys().copied()
+}
+
+/// Fields decoded from packets of the given length
+pub fn fields(length: usize) -> Option<&'static [Field<'static>]> {
+    Some(FIELDS.get(&length)?.fields)
+}
+
+/// Positions of the fields decoded from packets of the given length, by ID
+pub fn index(length: usize) -> Option<&'static FieldIndex> {
+    Some(&FIELDS.get(&length)?.index)
+}
+
+/// Field tables for all packet lengths, with their indices
+pub(crate) fn tables() -> impl Iterator<Item = (&'static [Field<'static>], &'static FieldIndex)> {
+    FIELDS.values().map(|table| (table.fields, &table.index))
+}
+
+/// Extract the timestamp from the packet.
+///
+/// The timestamp consists of YY-MM-DD HH:MM:SS in 6 one-byte fields, with
+/// the year relative to 2000. It is in local time, so needs to be combined
+/// with the timestamp.
+///
+/// If the timestamp is an invalid time, or is invalid or ambiguous for the
+/// time zone, returns `None`.
+fn parse_timestamp<T: TimeZone>(payload: &[u8], tz: T) -> Option<DateTime<T>> {
+    let dt = NaiveDate::from_ymd_opt(
+        payload[DATETIME_OFFSET] as i32 + 2000,
+        payload[DATETIME_OFFSET + 1] as u32,
+        payload[DATETIME_OFFSET + 2] as u32,
+    )?
+    .and_hms_opt(
+        payload[DATETIME_OFFSET + 3] as u32,
+        payload[DATETIME_OFFSET + 4] as u32,
+        payload[DATETIME_OFFSET + 5] as u32,
+    )?
+    .and_local_timezone(tz);
+    match dt {
+        LocalResult::Single(x) => Some(x),
+        _ => None, // TODO: what to do with ambiguous times - try to guess based on history?
+    }
+}
+
+/// Decode the field values from a payload, along with the raw register
+/// values for each field. If the table has the program settings, the fields
+/// for the active program are computed using `seconds`, the inverter's local
+/// time as seconds since midnight.
+fn decode_values(
+    payload: &[u8],
+    fields: &[Field<'_>],
+    offsets: &[&[usize]],
+    programs: Option<&ProgramFields>,
+    seconds: f64,
+) -> (Vec<f64>, Vec<Vec<u16>>) {
+    let mut values = Vec::with_capacity(fields.len());
+    let mut raw = Vec::with_capacity(fields.len());
+    for (&offsets, field) in offsets.iter().zip(fields.iter()) {
+        let parts: Vec<u16> = offsets
+            .iter()
+            .map(|&offset| {
+                let bytes = &payload[offset..offset + 2];
+                let bytes = <&[u8; 2]>::try_from(bytes).unwrap();
+                u16::from_be_bytes(*bytes)
+            })
+            .collect();
+        let value = if !parts.is_empty() {
+            field.from_u16s(parts.iter().cloned())
+        } else {
+            f64::NAN // Derived fields are filled in by the pipeline
+        };
+        values.push(value);
+        raw.push(parts);
+    }
+    if let Some(programs) = programs {
+        programs.apply(&mut values, seconds);
+    }
+    (values, raw)
+}
+
+/// Decode a payload into an update, along with the raw register values for
+/// each field. The timestamp in the payload is in the inverter's local
+/// time, so `tz` is called with the inverter serial number to get its time
+/// zone.
+pub fn decode<T: TimeZone>(
+    payload: &[u8],
+    tz: impl FnOnce(&str) -> T,
+) -> Result<(Update<'static>, Vec<Vec<u16>>), DecodeError> {
+    if payload.first() != Some(&MAGIC_HEADER) {
+        return Err(DecodeError::NotRecognised);
+    }
+    let Some(field_table) = FIELDS.get(&payload.len()) else {
+        return Err(DecodeError::UnknownLength(payload.len()));
+    };
+    let serial = core::str::from_utf8(&payload[SERIAL_RANGE]).unwrap_or("unknown");
+    let dt = parse_timestamp(payload, tz(serial)).ok_or(DecodeError::InvalidTimestamp)?;
+    info!(
+        "Received packet with timestamp {:?} for inverter {}",
+        dt, serial
+    );
+    let (values, raw) = decode_values(
+        payload,
+        field_table.fields,
+        field_table.offsets,
+        field_table.programs.as_ref(),
+        dt.num_seconds_from_midnight() as f64,
+    );
+    /* unwrapping timestamp_nanos_opt is safe because the encoding
+     * only supports up to 2127 (or 2255 if the year is interpreted
+     * as unsigned), while DateTime supports up to 2262 for
+     * nanosecond timestamps.
+     */
+    let update = Update::new(
+        dt.timestamp_nanos_opt().unwrap(),
+        serial.to_owned(),
+        field_table.fields,
+        values,
+    );
+    Ok((update, raw))
+}
+
+/// Whether the payload is a whole frame, the start of a data frame that
+/// was split across TCP segments, or neither. A payload with the magic
+/// header that is too short to be a keep-alive frame is the start of a data
+/// frame if it is shorter than some known frame length.
+pub fn framing(payload: &[u8]) -> Framing {
+    let len = payload.len();
+    if payload.first() != Some(&MAGIC_HEADER) {
+        Framing::Other
+    } else if len < DATETIME_OFFSET + 6 || FIELDS.contains_key(&len) {
+        Framing::Complete
+    } else if lengths().any(|known| known > len) {
+        Framing::Partial
+    } else {
+        Framing::Other
+    }
+}
+
+/// If the payload is a keep-alive frame, return the inverter serial number
+/// from it.
+pub fn heartbeat_serial(payload: &[u8]) -> Option<&str> {
+    if payload.first() != Some(&MAGIC_HEADER)
+        || payload.get(CONTROL_RANGE) != Some(&HEARTBEAT_CONTROL[..])
+    {
+        return None;
+    }
+    let serial = payload.get(SERIAL_RANGE)?;
+    if !serial.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    Some(core::str::from_utf8(serial).unwrap())
+}
+
+/// Packets that have the magic header but a length that is not known
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct UnknownSizes {
+    /// Lengths that have been logged in detail (at most [MAX_SAMPLES])
+    seen: BTreeSet<usize>,
+    /// Number of packets since the last warning
+    count: u64,
+    last_warning: Option<Instant>,
+}
+
+#[cfg(feature = "std")]
+pub struct Sunsynk {
+    /// Timezone for inverters not listed in `timezones`
+    tz: Tz,
+    /// Timezone for each inverter, by serial number
+    timezones: HashMap<String, Tz>,
+    unknown: Mutex<UnknownSizes>,
+}
+
+#[cfg(feature = "std")]
+impl Sunsynk {
+    pub fn new(tz: Tz, timezones: HashMap<String, Tz>) -> Self {
+        Self {
+            tz,
+            timezones,
+            unknown: Mutex::new(UnknownSizes::default()),
+        }
+    }
+
+    /// Report a packet with the magic header but an unknown length. The
+    /// first packet of each of the first [MAX_SAMPLES] lengths is logged in
+    /// detail, since it most likely comes from a new firmware version. After
+    /// that, a summary is logged at most once per [WARNING_INTERVAL].
+    fn report_unknown(&self, payload: &[u8]) {
+        UNKNOWN_FRAMES.fetch_add(1, Ordering::Relaxed);
+        let mut unknown = self.unknown.lock().unwrap();
+        unknown.count += 1;
+        let now = Instant::now();
+        if unknown.seen.len() < MAX_SAMPLES && unknown.seen.insert(payload.len()) {
+            warn!(
+                "Received a packet of unrecognised length {} (your dongle firmware may \
+                 not be supported yet). First {} bytes, with serial number removed: {}",
+                payload.len(),
+                SAMPLE_SIZE,
+                sample(payload)
+            );
+        } else if unknown
+            .last_warning
+            .is_some_and(|last| now.duration_since(last) < WARNING_INTERVAL)
+        {
+            return;
+        } else {
+            warn!(
+                "Received {} packets of unrecognised length (first lengths seen: {:?})",
+                unknown.count, unknown.seen
+            );
+        }
+        unknown.count = 0;
+        unknown.last_warning = Some(now);
+    }
+}
+
+/// Format the start of a packet as hex, without the serial number
+#[cfg(feature = "std")]
+fn sample(payload: &[u8]) -> String {
+    let mut sample = payload[..payload.len().min(SAMPLE_SIZE)].to_vec();
+    for byte in sample
+        .iter_mut()
+        .take(SERIAL_RANGE.end)
+        .skip(SERIAL_RANGE.start)
+    {
+        *byte = 0;
+    }
+    let hex: Vec<String> = sample.iter().map(|b| format!("{b:02x}")).collect();
+    hex.join(" ")
+}
+
+#[cfg(feature = "std")]
+impl Protocol for Sunsynk {
+    fn decode(&self, payload: &[u8]) -> Option<(Update<'static>, Vec<Vec<u16>>)> {
+        let tz = |serial: &str| self.timezones.get(serial).copied().unwrap_or(self.tz);
+        match decode(payload, tz) {
+            Ok(decoded) => Some(decoded),
+            Err(DecodeError::UnknownLength(_)) => {
+                self.report_unknown(payload);
+                None
+            }
+            Err(DecodeError::InvalidTimestamp) => {
+                UNKNOWN_FRAMES.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            Err(DecodeError::NotRecognised) => None,
+        }
+    }
+
+    fn heartbeat(&self, payload: &[u8]) -> Option<String> {
+        let serial = heartbeat_serial(payload)?;
+        info!("Received keep-alive for inverter {}", serial);
+        Some(serial.to_owned())
+    }
+
+    fn framing(&self, payload: &[u8]) -> Framing {
+        framing(payload)
+    }
+
+    fn anonymise(&self, payload: &mut [u8], hasher: &RandomState) -> bool {
+        if payload.first() != Some(&MAGIC_HEADER) {
+            return false;
+        }
+        anonymise(payload, SERIAL_RANGE, hasher);
+        true
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/pcap_fields.rs"));
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    /// Parse a reference packet from `testdata`, written as hex bytes
+    /// separated by whitespace
+    fn parse_hex(hex: &str) -> Vec<u8> {
+        hex.split_whitespace()
+            .map(|byte| u8::from_str_radix(byte, 16).unwrap())
+            .collect()
+    }
+
+    /// Decode a reference packet, returning the value of each field that is
+    /// read from the packet (rather than being a sum of other fields)
+    fn decode_golden(hex: &str) -> HashMap<&'static str, f64> {
+        let (update, _) = decode(&parse_hex(hex), |_: &str| chrono::Utc).unwrap();
+        update
+            .iter()
+            .filter(|(field, _)| field.sum_of.is_empty())
+            .map(|(field, value)| (field.id, value))
+            .collect()
+    }
+
+    /// Check that a field decodes to the golden value. This is called by the
+    /// tests that build.rs generates from `testdata/sunsynk_*.csv`.
+    fn check_golden(hex: &str, id: &str, expected: f64) {
+        let values = decode_golden(hex);
+        let Some(value) = values.get(id) else {
+            panic!("field {id} has a golden value but is not decoded");
+        };
+        assert_approx_eq!(*value, expected, 1e-6);
+    }
+
+    /// Check that every decoded field has a golden value
+    fn check_coverage(hex: &str, ids: &[&str]) {
+        let mut missing: Vec<_> = decode_golden(hex)
+            .into_keys()
+            .filter(|id| !ids.contains(id))
+            .collect();
+        missing.sort();
+        assert!(
+            missing.is_empty(),
+            "fields without golden values: {missing:?}"
+        );
+    }
+
+    include!(concat!(env!("OUT_DIR"), "/sunsynk_golden.rs"));
+
+    #[test]
+    fn test_unknown_size() {
+        let protocol = Sunsynk::new(Tz::UTC, HashMap::new());
+        let before = UNKNOWN_FRAMES.load(Ordering::Relaxed);
+        let mut payload = vec![0u8; 123];
+        payload[0] = MAGIC_HEADER;
+        assert!(protocol.decode(&payload).is_none());
+        assert!(protocol.decode(&payload).is_none());
+        assert!(UNKNOWN_FRAMES.load(Ordering::Relaxed) >= before + 2);
+        let unknown = protocol.unknown.lock().unwrap();
+        assert!(unknown.seen.contains(&123));
+        // The second packet is not reported until the interval has passed
+        assert_eq!(unknown.count, 1);
+
+        drop(unknown);
+
+        // Only the first few lengths are logged in detail
+        for len in 1000..1100 {
+            let mut payload = vec![0u8; len];
+            payload[0] = MAGIC_HEADER;
+            assert!(protocol.decode(&payload).is_none());
+        }
+        let unknown = protocol.unknown.lock().unwrap();
+        assert_eq!(unknown.seen.len(), MAX_SAMPLES);
+        // Counted since the last detailed report
+        assert_eq!(unknown.count, 100 - (MAX_SAMPLES as u64 - 1));
+        drop(unknown);
+
+        // Packets without the header are not counted
+        payload[0] = 0;
+        assert!(protocol.decode(&payload).is_none());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let utc = |_: &str| chrono::Utc;
+        let mut payload = vec![0u8; 292];
+        assert_eq!(
+            decode(&payload, utc).err(),
+            Some(DecodeError::NotRecognised)
+        );
+        payload[0] = MAGIC_HEADER;
+        // All-zero date is not valid
+        assert_eq!(
+            decode(&payload, utc).err(),
+            Some(DecodeError::InvalidTimestamp)
+        );
+        assert_eq!(
+            decode(&payload[..123], utc).err(),
+            Some(DecodeError::UnknownLength(123))
+        );
+    }
+
+    #[test]
+    fn test_decode_timezone() {
+        let mut payload = vec![0u8; 292];
+        payload[0] = MAGIC_HEADER;
+        payload[SERIAL_RANGE].copy_from_slice(b"1234567890");
+        payload[DATETIME_OFFSET..DATETIME_OFFSET + 6].copy_from_slice(&[22, 11, 5, 8, 32, 46]);
+        let tz = |serial: &str| {
+            assert_eq!(serial, "1234567890");
+            chrono::FixedOffset::east_opt(7200).unwrap()
+        };
+        let (update, raw) = decode(&payload, tz).unwrap();
+        assert_eq!(update.serial, "1234567890");
+        assert_eq!(update.timestamp, 1667629966000000000);
+        assert_eq!(raw.len(), update.fields.len());
+    }
+
+    #[test]
+    fn test_decode_program() {
+        use crate::fields::{FieldType, Reset, WordOrder};
+        use crate::program::NUM_PROGRAMS;
+
+        // A packet layout with the program settings at offset 100 onwards
+        let mut fields = vec![];
+        let mut offsets: Vec<&[usize]> = vec![];
+        let mut payload = vec![0u8; 292];
+        for (k, prefix) in ["time", "power", "soc"].iter().enumerate() {
+            for i in 0..NUM_PROGRAMS {
+                let offset = 100 + 2 * (k * NUM_PROGRAMS + i);
+                let raw = [100, 100, 10][k] * (4 * i as u16 + 1);
+                payload[offset..offset + 2].copy_from_slice(&raw.to_be_bytes());
+                offsets.push(Box::leak(Box::new([offset])));
+                fields.push((
+                    if k == 0 {
+                        FieldType::Time
+                    } else {
+                        FieldType::Unitless
+                    },
+                    format!("inverter_program_{prefix}_{}", i + 1),
+                ));
+            }
+        }
+        for suffix in ["power", "soc", "current", "remaining"] {
+            offsets.push(&[]);
+            fields.push((FieldType::Unitless, format!("inverter_program_{suffix}")));
+        }
+        let fields: Vec<Field<'static>> = fields
+            .into_iter()
+            .map(|(field_type, id)| Field {
+                field_type,
+                group: "Inverter",
+                name: "",
+                id: Box::leak(id.into_boxed_str()),
+                scale: if field_type == FieldType::Time {
+                    60.0
+                } else {
+                    1.0
+                },
+                bias: 0.0,
+                unit: "",
+                sum_of: &[],
+                word_order: WordOrder::Little,
+                reset: Reset::Never,
+            })
+            .collect();
+        let programs = ProgramFields::new(&fields).unwrap();
+
+        // Programs start at 01:00, 05:00, 09:00, ..., so 10:00 is in the third
+        let (values, raw) = decode_values(&payload, &fields, &offsets, Some(&programs), 36000.0);
+        assert_eq!(raw[NUM_PROGRAMS + 1], [500]);
+        assert_eq!(values[NUM_PROGRAMS - 1], 21.0 * 3600.0);
+        assert_eq!(values[3 * NUM_PROGRAMS..], [900.0, 90.0, 3.0, 180.0]);
+    }
+
+    #[test]
+    fn test_program_tables() {
+        // The 292- and 302-byte packets only carry the registers up to
+        // about 210, so the program settings (registers 250-273) are not in
+        // them. If a packet layout gains them, the active program must be
+        // computed for it.
+        for table in FIELDS.values() {
+            let has_settings = table.index.contains_key("inverter_program_time_1");
+            assert_eq!(table.programs.is_some(), has_settings);
+        }
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let protocol = Sunsynk::new(Tz::UTC, HashMap::new());
+        let mut payload =
+            b"\xa5\x01\x00\x10\x47\x00\x00\x00\x00\x00\x001234567890\x00\x15".to_vec();
+        assert_eq!(protocol.heartbeat(&payload).as_deref(), Some("1234567890"));
+        // Not a heartbeat if the serial number is not valid
+        payload[SERIAL_RANGE.start] = b'X';
+        assert!(protocol.heartbeat(&payload).is_none());
+        // Too short to contain the serial number
+        assert!(protocol.heartbeat(&payload[..15]).is_none());
+        // Short frames with other control codes are not heartbeats
+        payload[SERIAL_RANGE.start] = b'1';
+        payload[CONTROL_RANGE].copy_from_slice(&[0x10, 0x48]);
+        assert!(protocol.heartbeat(&payload).is_none());
+        // Data frames are not heartbeats
+        let mut payload = vec![0u8; 292];
+        payload[0] = MAGIC_HEADER;
+        payload[SERIAL_RANGE].copy_from_slice(b"1234567890");
+        assert!(protocol.heartbeat(&payload).is_none());
+    }
+
+    #[test]
+    fn test_framing() {
+        let mut payload = vec![0u8; 302];
+        assert_eq!(framing(&payload), Framing::Other);
+        payload[0] = MAGIC_HEADER;
+        assert_eq!(framing(&payload), Framing::Complete);
+        assert_eq!(framing(&payload[..292]), Framing::Complete);
+        assert_eq!(framing(&payload[..200]), Framing::Partial);
+        // Keep-alive frames are too short to be the start of a data frame
+        assert_eq!(framing(&payload[..23]), Framing::Complete);
+        // Too long to be the start of a known frame
+        payload.resize(400, 0);
+        assert_eq!(framing(&payload), Framing::Other);
+    }
+
+    #[test]
+    fn test_sample() {
+        let payload: Vec<u8> = (0..100).collect();
+        let sample: Vec<String> = sample(&payload).split(' ').map(str::to_owned).collect();
+        assert_eq!(sample.len(), SAMPLE_SIZE);
+        assert_eq!(sample[10], "0a");
+        assert!(sample[SERIAL_RANGE].iter().all(|b| b == "00"));
+        assert_eq!(sample[21], "15");
+        assert_eq!(sample[SAMPLE_SIZE - 1], "3f");
+    }
+}
@@ -0,0 +1,177 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Fields retrieved from the inverter's holding registers over modbus
+
+use alloc::vec::Vec;
+
+use crate::fields::{Field, FieldType, Reset, WordOrder};
+
+/// Register holding the year and month of the inverter clock, followed by
+/// registers for the rest of the date and time
+pub const REG_CLOCK: u16 = 22;
+
+/// ID of the field holding the work mode, one of [WORK_MODES]
+pub const WORK_MODE: &str = "inverter_work_mode";
+
+/// Names of the work modes, in order of their register values
+pub const WORK_MODES: &[&str] = &[
+    "Selling first",
+    "Zero export + limit to load",
+    "Limited to home",
+];
+
+/// Look up a field that corresponds to an inverter setting which may be
+/// changed by writing a single register. Returns the field and its register.
+pub fn setting(id: &str) -> Option<(&'static Field<'static>, u16)> {
+    if !id.starts_with("inverter_program_") && id != WORK_MODE {
+        return None;
+    }
+    let idx = *INDEX.get(id)?;
+    match REGISTERS[idx] {
+        [reg] => Some((&FIELDS[idx], *reg)),
+        _ => None, // Derived or multi-register fields
+    }
+}
+
+/// Names of the values of a setting that is a choice between a few options
+/// (such as the work mode), in order of their register values. Returns
+/// `None` for numeric settings.
+pub fn setting_options(id: &str) -> Option<&'static [&'static str]> {
+    (id == WORK_MODE).then_some(WORK_MODES)
+}
+
+/// Extract the time of day (in seconds since midnight) from the values of
+/// the three registers starting at [REG_CLOCK]
+pub fn clock_seconds(regs: &[u16]) -> f64 {
+    let hour = regs[1] & 0xff;
+    let minute = regs[2] >> 8;
+    let second = regs[2] & 0xff;
+    (hour as f64) * 3600.0 + (minute as f64) * 60.0 + (second as f64)
+}
+
+/// Position of a register in the values read from [READ_RANGES]
+fn read_offset(reg: u16) -> usize {
+    let mut offset = 0;
+    for &(start, count) in READ_RANGES {
+        if reg >= start && reg - start < count {
+            return offset + usize::from(reg - start);
+        }
+        offset += usize::from(count);
+    }
+    unreachable!("register {reg} is not in READ_RANGES");
+}
+
+/// Number of registers read with [READ_RANGES]
+pub fn read_length() -> usize {
+    READ_RANGES
+        .iter()
+        .map(|&(_, count)| usize::from(count))
+        .sum()
+}
+
+/// Split the values read from [READ_RANGES] (concatenated in order, with
+/// [read_length] values in total) into the register values of each field in
+/// [FIELDS], as given by [REGISTERS].
+pub fn field_registers(words: &[u16]) -> Vec<Vec<u16>> {
+    REGISTERS
+        .iter()
+        .map(|regs| regs.iter().map(|&reg| words[read_offset(reg)]).collect())
+        .collect()
+}
+
+const fn bus_field(field_type: FieldType, name: &'static str, id: &'static str) -> Field<'static> {
+    Field {
+        field_type,
+        group: "Modbus",
+        name,
+        id,
+        scale: 1.0,
+        bias: 0.0,
+        unit: "",
+        sum_of: &[],
+        word_order: WordOrder::Little,
+        reset: Reset::Never,
+    }
+}
+
+/// Fields describing the health of the modbus connection to an inverter:
+/// whether the latest poll succeeded, then the number of polls and of each
+/// kind of failure since startup. The updates use a serial number made by
+/// appending [BUS_SUFFIX] to the inverter serial number, so that they are
+/// not mistaken for (partial) updates from the inverter.
+pub const BUS_FIELDS: &[Field<'static>] = &[
+    bus_field(FieldType::Connectivity, "Connected", "modbus_connected"),
+    bus_field(FieldType::Unitless, "Polls", "modbus_polls"),
+    bus_field(FieldType::Unitless, "Exceptions", "modbus_exceptions"),
+    bus_field(FieldType::Unitless, "Timeouts", "modbus_timeouts"),
+    bus_field(FieldType::Unitless, "CRC errors", "modbus_crc_errors"),
+    bus_field(FieldType::Unitless, "I/O errors", "modbus_io_errors"),
+];
+
+pub const BUS_SUFFIX: &str = "-modbus";
+
+/// Fields describing the outcome of writing a setting: the register, the
+/// value written, the value read back afterwards (missing if it could not
+/// be read), and whether the inverter kept the value (1 or 0). The updates
+/// use a serial number made by appending [WRITE_SUFFIX] to the inverter
+/// serial number.
+pub const WRITE_FIELDS: &[Field<'static>] = &[
+    bus_field(
+        FieldType::Unitless,
+        "Write register",
+        "modbus_write_register",
+    ),
+    bus_field(FieldType::Unitless, "Write value", "modbus_write_value"),
+    bus_field(FieldType::Unitless, "Write stored", "modbus_write_stored"),
+    bus_field(
+        FieldType::Unitless,
+        "Write verified",
+        "modbus_write_verified",
+    ),
+];
+
+pub const WRITE_SUFFIX: &str = "-write";
+
+include!(concat!(env!("OUT_DIR"), "/modbus_fields.rs"));
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_ranges() {
+        let mut end = 0;
+        for &(start, count) in READ_RANGES {
+            // Sorted and not touching, otherwise they would have been merged
+            // (unless they are too long)
+            assert!(start >= end);
+            assert!(count > 0 && count <= 125);
+            end = start + count;
+        }
+        // Reading each register's own number gives back the registers
+        let words: Vec<u16> = READ_RANGES
+            .iter()
+            .flat_map(|&(start, count)| start..start + count)
+            .collect();
+        assert_eq!(words.len(), read_length());
+        let registers = field_registers(&words);
+        assert_eq!(registers.len(), REGISTERS.len());
+        for (regs, expected) in registers.iter().zip(REGISTERS) {
+            assert_eq!(regs, expected);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Derived fields describing the currently active time-of-use program
+
+use alloc::format;
+
+use super::fields::Field;
+
+pub const NUM_PROGRAMS: usize = 6;
+
+/// Indices of the program-related fields within a field table. The tables
+/// for logger packets have these filled in by build.rs.
+pub struct ProgramFields {
+    pub(crate) time: [usize; NUM_PROGRAMS],
+    pub(crate) power: [usize; NUM_PROGRAMS],
+    pub(crate) soc: [usize; NUM_PROGRAMS],
+    pub(crate) current_power: usize,
+    pub(crate) current_soc: usize,
+    pub(crate) current: usize,
+    pub(crate) remaining: usize,
+}
+
+fn find(fields: &[Field<'_>], id: &str) -> Option<usize> {
+    crate::fields::position(fields, id)
+}
+
+fn find_all(fields: &[Field<'_>], prefix: &str) -> Option<[usize; NUM_PROGRAMS]> {
+    let mut out = [0; NUM_PROGRAMS];
+    for (i, idx) in out.iter_mut().enumerate() {
+        *idx = find(fields, &format!("{prefix}_{}", i + 1))?;
+    }
+    Some(out)
+}
+
+impl ProgramFields {
+    /// Locate the program fields in a field table. Returns `None` if the
+    /// table does not contain all of them.
+    pub fn new(fields: &[Field<'_>]) -> Option<Self> {
+        Some(Self {
+            time: find_all(fields, "inverter_program_time")?,
+            power: find_all(fields, "inverter_program_power")?,
+            soc: find_all(fields, "inverter_program_soc")?,
+            current_power: find(fields, "inverter_program_power")?,
+            current_soc: find(fields, "inverter_program_soc")?,
+            current: find(fields, "inverter_program_current")?,
+            remaining: find(fields, "inverter_program_remaining")?,
+        })
+    }
+
+    /// Fill in the fields for the active program, given the inverter's
+    /// local time as seconds since midnight.
+    pub fn apply(&self, values: &mut [f64], now: f64) {
+        let mut prog = NUM_PROGRAMS - 1;
+        for i in 0..(NUM_PROGRAMS - 1) {
+            let start = values[self.time[i]];
+            let stop = values[self.time[i + 1]];
+            if now >= start && now < stop {
+                prog = i;
+                break;
+            }
+        }
+        values[self.current_power] = values[self.power[prog]];
+        values[self.current_soc] = values[self.soc[prog]];
+        values[self.current] = (prog + 1) as f64;
+        // The last program wraps around midnight to the first one
+        let next = values[self.time[(prog + 1) % NUM_PROGRAMS]];
+        let mut until = libm::fmod(next - now, 86400.0);
+        if until < 0.0 {
+            until += 86400.0;
+        }
+        values[self.remaining] = libm::ceil(until / 60.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{FieldType, Reset, WordOrder};
+
+    fn fields() -> Vec<Field<'static>> {
+        let mut ids = vec![];
+        for prefix in ["time", "power", "soc"] {
+            for i in 1..=NUM_PROGRAMS {
+                ids.push(format!("inverter_program_{prefix}_{i}"));
+            }
+        }
+        for suffix in ["power", "soc", "current", "remaining"] {
+            ids.push(format!("inverter_program_{suffix}"));
+        }
+        ids.into_iter()
+            .map(|id| Field {
+                field_type: FieldType::Unitless,
+                group: "Inverter",
+                name: "",
+                id: Box::leak(id.into_boxed_str()),
+                scale: 1.0,
+                bias: 0.0,
+                unit: "",
+                sum_of: &[],
+                word_order: WordOrder::Little,
+                reset: Reset::Never,
+            })
+            .collect()
+    }
+
+    fn values() -> Vec<f64> {
+        let mut values = vec![0.0; 3 * NUM_PROGRAMS + 4];
+        let times = [1.0, 5.0, 9.0, 13.0, 17.0, 21.0];
+        for i in 0..NUM_PROGRAMS {
+            values[i] = times[i] * 3600.0;
+            values[NUM_PROGRAMS + i] = (i as f64 + 1.0) * 100.0;
+            values[2 * NUM_PROGRAMS + i] = (i as f64 + 1.0) * 10.0;
+        }
+        values
+    }
+
+    #[test]
+    fn test_missing() {
+        assert!(ProgramFields::new(&fields()[1..]).is_none());
+    }
+
+    #[test]
+    fn test_middle() {
+        let pf = ProgramFields::new(&fields()).unwrap();
+        let mut values = values();
+        pf.apply(&mut values, 10.0 * 3600.0);
+        assert_eq!(values[3 * NUM_PROGRAMS..], [300.0, 30.0, 3.0, 180.0]);
+    }
+
+    #[test]
+    fn test_wrap() {
+        let pf = ProgramFields::new(&fields()).unwrap();
+        let mut values = values();
+        pf.apply(&mut values, 22.0 * 3600.0);
+        assert_eq!(values[3 * NUM_PROGRAMS..], [600.0, 60.0, 6.0, 180.0]);
+        pf.apply(&mut values, 1800.0);
+        assert_eq!(values[3 * NUM_PROGRAMS..], [600.0, 60.0, 6.0, 30.0]);
+    }
+}
@@ -0,0 +1,315 @@
+/* Copyright 2022-2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Trait to be implemented by receiver plugins
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use super::fields::{self, Field};
+
+#[cfg(feature = "std")]
+use {
+    alloc::boxed::Box, async_trait::async_trait, core::pin::Pin, futures::prelude::*,
+    std::sync::OnceLock,
+};
+
+/// A set of values associated with all fields
+#[derive(Clone, Debug)]
+pub struct Update<'a> {
+    /// Nanoseconds since UNIX epoch
+    pub timestamp: i64,
+    /// Inverter serial number
+    pub serial: String,
+    /// Fields contained in the update
+    pub fields: &'a [Field<'a>],
+    /// Values for the fields in `fields` (with the same length)
+    pub values: Vec<f64>,
+    /// Raw register values for each field in `fields` (empty for derived
+    /// fields). This is only populated if the frontend is configured with
+    /// `raw_values`.
+    pub raw: Option<Vec<Vec<u16>>>,
+    /// Values in thousandths of a unit, if the pipeline is configured with
+    /// `fixed_point`, with `None` for missing and infinite values. Unlike
+    /// `values`, these can be compared for exact equality.
+    pub fixed: Option<Vec<Option<i64>>>,
+    /// Information about where the update came from
+    pub metadata: Metadata,
+    /// Positions of the fields by ID, for [Update::get]
+    index: SortedFields,
+}
+
+/// Positions of the fields in [Update::fields], sorted by ID, for tables
+/// without a compile-time [fields::FieldIndex]. They are only sorted the first time
+/// a field is looked up. Without the `std` feature, lookups just search the
+/// fields.
+#[derive(Default)]
+struct SortedFields {
+    #[cfg(feature = "std")]
+    sorted: OnceLock<Vec<usize>>,
+}
+
+impl SortedFields {
+    /// Position of the field with ID `id`, if it is in `fields`
+    fn position(&self, fields: &[Field<'_>], id: &str) -> Option<usize> {
+        if let Some(index) = fields::index(fields) {
+            return index.get(id).copied();
+        }
+        #[cfg(feature = "std")]
+        {
+            let sorted = self.sorted.get_or_init(|| {
+                let mut sorted: Vec<usize> = (0..fields.len()).collect();
+                sorted.sort_by_key(|&i| fields[i].id);
+                sorted
+            });
+            // Fields may have been replaced since the index was built, so
+            // check the result and fall back to searching.
+            let id_at = |i: usize| fields.get(i).map(|field| field.id);
+            if let Ok(pos) = sorted.binary_search_by(|&i| id_at(i).cmp(&Some(id))) {
+                if id_at(sorted[pos]) == Some(id) {
+                    return Some(sorted[pos]);
+                }
+            }
+        }
+        fields.iter().position(|field| field.id == id)
+    }
+}
+
+/// The index refers to the fields of the update it was built for, so a copy
+/// starts without one
+impl Clone for SortedFields {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl core::fmt::Debug for SortedFields {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("SortedFields")
+    }
+}
+
+/// Describes how an update was obtained. Frontends fill in what they know;
+/// receivers may optionally publish it alongside the values.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    /// Frontend instance that produced the update (e.g. `pcap:eth0`)
+    pub source: Option<String>,
+    /// Protocol (or protocol variant) that was decoded
+    pub protocol: Option<&'static str>,
+    /// Length of the frame that was decoded, in bytes
+    pub frame_length: Option<usize>,
+    /// Time taken to decode the frame (or poll the device)
+    pub decode_duration: Option<Duration>,
+    /// Position of the update among those from the same source, counting
+    /// from 1. This is filled in by the sunsniff pipeline (before any
+    /// updates are dropped), so that gaps can be detected downstream.
+    pub sequence: Option<u64>,
+    /// Time since the previous update for the same inverter, if it was long
+    /// enough to count as a gap in the data. This is filled in by the
+    /// sunsniff pipeline if it is configured to detect gaps.
+    pub gap: Option<Duration>,
+}
+
+/// Trait to be implemented by receiver plugins
+#[cfg(feature = "std")]
+#[async_trait]
+pub trait Receiver: Send {
+    /// Run forever, receiving a stream of updates
+    async fn run<'a>(&mut self, receiver: UpdateReceiver<'a>);
+
+    /// Deliver a single update, reporting whether it succeeded. This is used
+    /// by the startup self-test, before [Receiver::run] is called. The
+    /// default just runs [Receiver::run] on the update, which suits
+    /// receivers that have no way to fail.
+    async fn self_test(&mut self, update: Arc<Update<'static>>) -> Result<(), String> {
+        self.run(Box::pin(stream::once(future::ready(update))))
+            .await;
+        Ok(())
+    }
+
+    /// Format a value for receivers that publish text. The default is
+    /// [Field::format_value], which receivers should only override if they
+    /// need a different representation.
+    fn format_value(&self, field: &Field<'_>, value: f64) -> String {
+        field.format_value(value)
+    }
+}
+
+impl<'a> Update<'a> {
+    pub fn new(
+        timestamp: i64,
+        serial: impl Into<String>,
+        fields: &'a [Field<'a>],
+        values: Vec<f64>,
+    ) -> Self {
+        Update {
+            timestamp,
+            serial: serial.into(),
+            fields,
+            values,
+            raw: None,
+            fixed: None,
+            metadata: Metadata::default(),
+            index: SortedFields::default(),
+        }
+    }
+
+    /// Position of the field with ID `id` in [Update::fields]
+    pub fn position(&self, id: &str) -> Option<usize> {
+        self.index.position(self.fields, id)
+    }
+
+    /// Value of the field with ID `id`, if the update contains it
+    pub fn get(&self, id: &str) -> Option<f64> {
+        self.values.get(self.position(id)?).copied()
+    }
+
+    /// Iterate over the fields with their values
+    pub fn iter(&self) -> impl Iterator<Item = (&Field<'a>, f64)> + '_ {
+        self.fields.iter().zip(self.values.iter().copied())
+    }
+
+    /// Iterate over the fields in a group (such as `Battery`) with their
+    /// values
+    pub fn iter_group<'s>(
+        &'s self,
+        group: &'s str,
+    ) -> impl Iterator<Item = (&'s Field<'a>, f64)> + 's {
+        self.iter().filter(move |(field, _)| field.group == group)
+    }
+
+    /// Attach raw register values to the update
+    pub fn with_raw(mut self, raw: Vec<Vec<u16>>) -> Self {
+        self.raw = Some(raw);
+        self
+    }
+
+    /// Attach metadata to the update
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Fill in the values of fields that are sums of other fields. The
+    /// sunsniff binary does this in its pipeline, so this is only needed
+    /// by other users of the decoders.
+    pub fn fill_sums(&mut self) {
+        for (i, field) in self.fields.iter().enumerate() {
+            if !field.sum_of.is_empty() {
+                self.values[i] = field.from_sum(&self.values);
+            }
+        }
+    }
+}
+
+pub type UpdateItem = Arc<Update<'static>>;
+#[cfg(feature = "std")]
+pub type UpdateStream = Pin<Box<dyn Stream<Item = UpdateItem>>>;
+/// Stream of updates passed to [Receiver::run]
+#[cfg(feature = "std")]
+pub type UpdateReceiver<'a> = Pin<Box<dyn Stream<Item = Arc<Update<'a>>> + Send + 'a>>;
+
+/// Merge the updates from an auxiliary source (which never ends by itself)
+/// into the main stream. The merged stream ends when the main stream does.
+#[cfg(feature = "std")]
+pub fn merge_auxiliary(main: UpdateStream, auxiliary: UpdateStream) -> UpdateStream {
+    let main = main.map(Some).chain(stream::once(future::ready(None)));
+    Box::pin(
+        stream::select(main, auxiliary.map(Some))
+            .take_while(|item| future::ready(item.is_some()))
+            .filter_map(future::ready),
+    )
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+    use crate::fields::{FieldType, Reset, WordOrder};
+
+    const fn field(group: &'static str, id: &'static str) -> Field<'static> {
+        Field {
+            field_type: FieldType::Power,
+            group,
+            name: id,
+            id,
+            scale: 1.0,
+            bias: 0.0,
+            unit: "W",
+            sum_of: &[],
+            word_order: WordOrder::Little,
+            reset: Reset::Never,
+        }
+    }
+
+    const FIELDS: &[Field<'static>] = &[
+        field("Battery", "battery_power"),
+        field("Grid", "grid_power"),
+        field("Battery", "battery_soc"),
+    ];
+
+    #[test]
+    fn test_get() {
+        let update = Update::new(1, "a", FIELDS, vec![-500.0, 120.0, 54.0]);
+        assert_eq!(update.get("battery_soc"), Some(54.0));
+        assert_eq!(update.get("grid_power"), Some(120.0));
+        assert_eq!(update.get("pv_power"), None);
+        assert_eq!(update.position("battery_power"), Some(0));
+
+        // Replacing the fields after the index is built still finds them
+        let mut update = update.clone();
+        assert_eq!(update.get("battery_soc"), Some(54.0));
+        update.fields = &FIELDS[1..];
+        update.values.remove(0);
+        assert_eq!(update.get("battery_soc"), Some(54.0));
+        assert_eq!(update.get("battery_power"), None);
+    }
+
+    #[test]
+    #[cfg(feature = "modbus")]
+    fn test_get_indexed() {
+        let fields = crate::modbus::FIELDS;
+        let values = (0..fields.len()).map(|i| i as f64).collect();
+        let update = Update::new(1, "a", fields, values);
+        for (i, field) in fields.iter().enumerate() {
+            assert_eq!(update.get(field.id), Some(i as f64));
+        }
+        assert_eq!(update.get("no_such_field"), None);
+    }
+
+    #[test]
+    fn test_iter_group() {
+        let update = Update::new(1, "a", FIELDS, vec![-500.0, 120.0, 54.0]);
+        let battery: Vec<_> = update
+            .iter_group("Battery")
+            .map(|(field, value)| (field.id, value))
+            .collect();
+        assert_eq!(battery, [("battery_power", -500.0), ("battery_soc", 54.0)]);
+        assert_eq!(update.iter().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_merge_auxiliary() {
+        let main: UpdateStream =
+            Box::pin(stream::iter([Arc::new(Update::new(1, "a", &[], vec![]))]));
+        let auxiliary: UpdateStream = Box::pin(stream::pending());
+        let merged: Vec<_> = merge_auxiliary(main, auxiliary).collect().await;
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].serial, "a");
+    }
+}
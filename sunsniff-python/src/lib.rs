@@ -0,0 +1,182 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Python bindings for the field tables and the Sunsynk logger decoder, so
+//! that captured packets can be analysed from Python.
+
+use chrono_tz::Tz;
+use pyo3::exceptions::{PyKeyError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use sunsniff_core::fields::Field;
+use sunsniff_core::logger::sunsynk::{self, DecodeError};
+use sunsniff_core::{can, modbus};
+
+/// Describe a field as a dictionary. Fields referenced by `sum_of` are
+/// given by ID.
+fn field_dict<'py>(
+    py: Python<'py>,
+    fields: &[Field<'_>],
+    field: &Field<'_>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new(py);
+    dict.set_item("id", field.id)?;
+    dict.set_item("group", field.group)?;
+    dict.set_item("name", field.name)?;
+    dict.set_item("unit", field.unit)?;
+    dict.set_item("type", format!("{:?}", field.field_type))?;
+    dict.set_item("scale", field.scale)?;
+    dict.set_item("bias", field.bias)?;
+    let sum_of: Vec<(&str, f64)> = field
+        .sum_of
+        .iter()
+        .map(|&(idx, coeff)| (fields[idx].id, coeff))
+        .collect();
+    dict.set_item("sum_of", sum_of)?;
+    Ok(dict)
+}
+
+fn field_list<'py>(py: Python<'py>, fields: &[Field<'_>]) -> PyResult<Bound<'py, PyList>> {
+    let dicts = fields
+        .iter()
+        .map(|field| field_dict(py, fields, field))
+        .collect::<PyResult<Vec<_>>>()?;
+    PyList::new(py, dicts)
+}
+
+/// Decode the TCP payload of a packet sent by a Sunsynk logger.
+///
+/// Returns a dictionary with the timestamp (in nanoseconds since the UNIX
+/// epoch), the inverter serial number, and the values and raw register
+/// values, each indexed by field ID. The timestamp in the packet is in the
+/// inverter's local time, given by `timezone`. Returns `None` if the
+/// payload is not from a logger, and raises `ValueError` if it is but
+/// cannot be decoded.
+#[pyfunction]
+#[pyo3(signature = (payload, timezone = "UTC"))]
+fn decode_frame<'py>(
+    py: Python<'py>,
+    payload: &[u8],
+    timezone: &str,
+) -> PyResult<Option<Bound<'py, PyDict>>> {
+    let tz: Tz = timezone
+        .parse()
+        .map_err(|_| PyValueError::new_err(format!("Unknown time zone {timezone:?}")))?;
+    let (mut update, raw) = match sunsynk::decode(payload, |_| tz) {
+        Ok(decoded) => decoded,
+        Err(DecodeError::NotRecognised) => return Ok(None),
+        Err(err) => return Err(PyValueError::new_err(err.to_string())),
+    };
+    update.fill_sums();
+    let values = PyDict::new(py);
+    let raw_values = PyDict::new(py);
+    for ((field, value), parts) in update.fields.iter().zip(update.values.iter()).zip(raw) {
+        values.set_item(field.id, value)?;
+        raw_values.set_item(field.id, parts)?;
+    }
+    let dict = PyDict::new(py);
+    dict.set_item("timestamp", update.timestamp)?;
+    dict.set_item("serial", &update.serial)?;
+    dict.set_item("values", values)?;
+    dict.set_item("raw", raw_values)?;
+    Ok(Some(dict))
+}
+
+/// Describe the fields decoded from logger packets of the given length
+#[pyfunction]
+fn sunsynk_fields(py: Python<'_>, length: usize) -> PyResult<Bound<'_, PyList>> {
+    let fields = sunsynk::fields(length)
+        .ok_or_else(|| PyKeyError::new_err(format!("Unknown packet length {length}")))?;
+    field_list(py, fields)
+}
+
+/// Describe the fields read over modbus, including the registers that each
+/// is read from
+#[pyfunction]
+fn modbus_fields(py: Python<'_>) -> PyResult<Bound<'_, PyList>> {
+    let list = field_list(py, modbus::FIELDS)?;
+    for (dict, registers) in list.iter().zip(modbus::REGISTERS.iter()) {
+        dict.set_item("registers", registers.to_vec())?;
+    }
+    Ok(list)
+}
+
+/// Describe the fields decoded from the frames of a CAN bus protocol
+#[pyfunction]
+fn can_fields<'py>(py: Python<'py>, protocol: &str) -> PyResult<Bound<'py, PyList>> {
+    let protocol = can::PROTOCOLS
+        .get(protocol)
+        .ok_or_else(|| PyKeyError::new_err(format!("Unknown CAN protocol {protocol:?}")))?;
+    field_list(py, protocol.fields)
+}
+
+#[pymodule]
+fn sunsniff(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let mut lengths: Vec<usize> = sunsynk::lengths().collect();
+    lengths.sort();
+    m.add("SUNSYNK_LENGTHS", lengths)?;
+    m.add_function(wrap_pyfunction!(decode_frame, m)?)?;
+    m.add_function(wrap_pyfunction!(sunsynk_fields, m)?)?;
+    m.add_function(wrap_pyfunction!(modbus_fields, m)?)?;
+    m.add_function(wrap_pyfunction!(can_fields, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_frame() {
+        let mut payload = vec![0u8; 292];
+        payload[0] = 0xa5;
+        payload[11..21].copy_from_slice(b"1234567890");
+        payload[37..43].copy_from_slice(&[22, 11, 5, 8, 32, 46]);
+        Python::with_gil(|py| {
+            let frame = decode_frame(py, &payload, "Africa/Johannesburg")
+                .unwrap()
+                .unwrap();
+            let timestamp: i64 = frame
+                .get_item("timestamp")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(timestamp, 1667629966000000000);
+            let values = frame.get_item("values").unwrap().unwrap();
+            let soc: f64 = values.get_item("battery_soc").unwrap().extract().unwrap();
+            assert_eq!(soc, 0.0);
+
+            assert!(decode_frame(py, &payload[1..], "UTC").unwrap().is_none());
+            assert!(decode_frame(py, &payload[..100], "UTC").is_err());
+            assert!(decode_frame(py, &payload, "Nowhere/Special").is_err());
+        });
+    }
+
+    #[test]
+    fn test_fields() {
+        Python::with_gil(|py| {
+            let fields = modbus_fields(py).unwrap();
+            assert_eq!(fields.len(), modbus::FIELDS.len());
+            let first = fields.get_item(0).unwrap();
+            let id: String = first.get_item("id").unwrap().extract().unwrap();
+            assert_eq!(id, modbus::FIELDS[0].id);
+            assert!(sunsynk_fields(py, 292).is_ok());
+            assert!(sunsynk_fields(py, 1).is_err());
+            assert!(can_fields(py, "pylontech").is_ok());
+        });
+    }
+}
@@ -0,0 +1,350 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Minimal reader for pcap and pcapng capture files, which extracts the TCP
+//! payloads
+
+/// A TCP payload from a captured packet
+#[derive(Debug)]
+pub struct Segment<'a> {
+    /// Index of the packet in the file (starting from 1, as in Wireshark)
+    pub index: usize,
+    /// Capture time in nanoseconds since the UNIX epoch
+    pub time: i64,
+    pub payload: &'a [u8],
+}
+
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const IPPROTO_TCP: u8 = 6;
+
+/// Reads integers with the byte order of the file
+#[derive(Clone, Copy)]
+struct Endian {
+    big: bool,
+}
+
+impl Endian {
+    fn u16(self, data: &[u8], offset: usize) -> Option<u16> {
+        let bytes = data.get(offset..offset + 2)?.try_into().unwrap();
+        Some(if self.big {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(self, data: &[u8], offset: usize) -> Option<u32> {
+        let bytes = data.get(offset..offset + 4)?.try_into().unwrap();
+        Some(if self.big {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+}
+
+fn be16(data: &[u8], offset: usize) -> Option<u16> {
+    Endian { big: true }.u16(data, offset)
+}
+
+/// Extract the TCP payload from an IPv4 or IPv6 packet
+fn ip_payload(packet: &[u8]) -> Option<&[u8]> {
+    let (protocol, payload) = match packet.first()? >> 4 {
+        4 => {
+            let header = ((packet[0] & 0xf) as usize) * 4;
+            let total = (be16(packet, 2)? as usize).min(packet.len());
+            (*packet.get(9)?, packet.get(header..total)?)
+        }
+        6 => {
+            // Extension headers are not supported
+            let total = (40 + be16(packet, 4)? as usize).min(packet.len());
+            (*packet.get(6)?, packet.get(40..total)?)
+        }
+        _ => return None,
+    };
+    if protocol != IPPROTO_TCP {
+        return None;
+    }
+    let header = ((payload.get(12)? >> 4) as usize) * 4;
+    payload.get(header..)
+}
+
+/// Extract the TCP payload from a packet with the given link type
+fn tcp_payload(linktype: u32, data: &[u8]) -> Option<&[u8]> {
+    let (ethertype, packet) = match linktype {
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            while be16(data, offset)? == ETHERTYPE_VLAN {
+                offset += 4;
+            }
+            (be16(data, offset)?, data.get(offset + 2..)?)
+        }
+        LINKTYPE_LINUX_SLL => (be16(data, 14)?, data.get(16..)?),
+        LINKTYPE_LINUX_SLL2 => (be16(data, 0)?, data.get(20..)?),
+        LINKTYPE_RAW => return ip_payload(data),
+        _ => return None,
+    };
+    match ethertype {
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => ip_payload(packet),
+        _ => None,
+    }
+}
+
+/// Read a classic pcap file
+fn read_pcap(data: &[u8]) -> Result<Vec<Segment<'_>>, String> {
+    let (endian, nano) = match data[..4] {
+        [0xd4, 0xc3, 0xb2, 0xa1] => (Endian { big: false }, false),
+        [0xa1, 0xb2, 0xc3, 0xd4] => (Endian { big: true }, false),
+        [0x4d, 0x3c, 0xb2, 0xa1] => (Endian { big: false }, true),
+        [0xa1, 0xb2, 0x3c, 0x4d] => (Endian { big: true }, true),
+        _ => unreachable!(),
+    };
+    let linktype = endian.u32(data, 20).ok_or("Truncated file header")?;
+    let mut segments = vec![];
+    let mut offset = 24;
+    let mut index = 0;
+    while offset < data.len() {
+        index += 1;
+        let (Some(sec), Some(frac), Some(len)) = (
+            endian.u32(data, offset),
+            endian.u32(data, offset + 4),
+            endian.u32(data, offset + 8),
+        ) else {
+            return Err(format!("Packet {index} is truncated"));
+        };
+        let start = offset + 16;
+        let packet = data
+            .get(start..start + len as usize)
+            .ok_or_else(|| format!("Packet {index} is truncated"))?;
+        let time = sec as i64 * 1_000_000_000 + frac as i64 * if nano { 1 } else { 1000 };
+        if let Some(payload) = tcp_payload(linktype, packet) {
+            segments.push(Segment {
+                index,
+                time,
+                payload,
+            });
+        }
+        offset = start + len as usize;
+    }
+    Ok(segments)
+}
+
+/// Interface described by a pcapng Interface Description Block
+struct Interface {
+    linktype: u32,
+    /// Nanoseconds per timestamp unit
+    resolution: f64,
+}
+
+impl Interface {
+    fn parse(endian: Endian, body: &[u8]) -> Option<Self> {
+        let linktype = endian.u16(body, 0)? as u32;
+        let mut resolution = 1000.0; // Microseconds unless specified
+        let mut offset = 8;
+        while let (Some(code), Some(len)) = (endian.u16(body, offset), endian.u16(body, offset + 2))
+        {
+            if code == 0 {
+                break; // End of options
+            }
+            let value = body.get(offset + 4..offset + 4 + len as usize)?;
+            if code == 9 && len == 1 {
+                // if_tsresol: a power of 10 (or 2, if the top bit is set)
+                let exp = (value[0] & 0x7f) as i32;
+                let base: f64 = if value[0] & 0x80 != 0 { 2.0 } else { 10.0 };
+                resolution = 1e9 / base.powi(exp);
+            }
+            offset += 4 + (len as usize).next_multiple_of(4);
+        }
+        Some(Self {
+            linktype,
+            resolution,
+        })
+    }
+}
+
+/// Read a pcapng file
+fn read_pcapng(data: &[u8]) -> Result<Vec<Segment<'_>>, String> {
+    let mut segments = vec![];
+    let mut interfaces = vec![];
+    let mut endian = Endian { big: false };
+    let mut offset = 0;
+    let mut index = 0;
+    while offset < data.len() {
+        let block_type = endian.u32(data, offset).ok_or("Truncated block")?;
+        if block_type == 0x0a0d0d0a {
+            // Section Header Block, which sets the byte order
+            endian.big = match data.get(offset + 8..offset + 12) {
+                Some([0x1a, 0x2b, 0x3c, 0x4d]) => true,
+                Some([0x4d, 0x3c, 0x2b, 0x1a]) => false,
+                _ => return Err("Invalid section header".to_owned()),
+            };
+            interfaces.clear();
+        }
+        let len = endian.u32(data, offset + 4).ok_or("Truncated block")? as usize;
+        if len < 12 {
+            return Err("Invalid block length".to_owned());
+        }
+        let body = data
+            .get(offset + 8..offset + len - 4)
+            .ok_or("Truncated block")?;
+        match block_type {
+            1 => interfaces.push(Interface::parse(endian, body).ok_or("Invalid interface")?),
+            3 | 6 => {
+                index += 1;
+                // Simple Packet Blocks have no interface ID or timestamp
+                let (interface, time, header) = if block_type == 6 {
+                    let (Some(id), Some(high), Some(low)) = (
+                        endian.u32(body, 0),
+                        endian.u32(body, 4),
+                        endian.u32(body, 8),
+                    ) else {
+                        return Err(format!("Packet {index} is truncated"));
+                    };
+                    let interface = interfaces
+                        .get(id as usize)
+                        .ok_or_else(|| format!("Packet {index} has unknown interface {id}"))?;
+                    let ticks = ((high as u64) << 32) | low as u64;
+                    (interface, (ticks as f64 * interface.resolution) as i64, 20)
+                } else {
+                    let interface = interfaces
+                        .first()
+                        .ok_or_else(|| format!("Packet {index} has no interface"))?;
+                    (interface, 0, 4)
+                };
+                let captured = endian
+                    .u32(body, header - 4)
+                    .ok_or_else(|| format!("Packet {index} is truncated"))?;
+                let packet = body
+                    .get(header..header + captured as usize)
+                    .ok_or_else(|| format!("Packet {index} is truncated"))?;
+                if let Some(payload) = tcp_payload(interface.linktype, packet) {
+                    segments.push(Segment {
+                        index,
+                        time,
+                        payload,
+                    });
+                }
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+    Ok(segments)
+}
+
+/// Extract the TCP payloads from a pcap or pcapng file
+pub fn read(data: &[u8]) -> Result<Vec<Segment<'_>>, String> {
+    match data.get(..4) {
+        Some([0x0a, 0x0d, 0x0d, 0x0a]) => read_pcapng(data),
+        Some([0xd4, 0xc3, 0xb2, 0xa1])
+        | Some([0xa1, 0xb2, 0xc3, 0xd4])
+        | Some([0x4d, 0x3c, 0xb2, 0xa1])
+        | Some([0xa1, 0xb2, 0x3c, 0x4d]) => read_pcap(data),
+        _ => Err("Not a pcap or pcapng file".to_owned()),
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// Wrap a TCP payload in Ethernet, IPv4 and TCP headers
+    pub fn ethernet_packet(payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; 14 + 20 + 20];
+        packet[12..14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        packet[14] = 0x45;
+        let total = (20 + 20 + payload.len()) as u16;
+        packet[16..18].copy_from_slice(&total.to_be_bytes());
+        packet[14 + 9] = IPPROTO_TCP;
+        packet[34 + 12] = 5 << 4;
+        packet.extend_from_slice(payload);
+        // Ethernet padding, which must not be included in the payload
+        packet.extend_from_slice(&[0; 4]);
+        packet
+    }
+
+    /// Build a little-endian, microsecond pcap file
+    pub fn pcap_file(packets: &[(u32, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut data = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&65535u32.to_le_bytes());
+        data.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        for (sec, usec, packet) in packets {
+            data.extend_from_slice(&sec.to_le_bytes());
+            data.extend_from_slice(&usec.to_le_bytes());
+            data.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            data.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            data.extend_from_slice(packet);
+        }
+        data
+    }
+
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let len = 12 + body.len().next_multiple_of(4);
+        let mut data = block_type.to_le_bytes().to_vec();
+        data.extend_from_slice(&(len as u32).to_le_bytes());
+        data.extend_from_slice(body);
+        data.resize(len - 4, 0);
+        data.extend_from_slice(&(len as u32).to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_pcap() {
+        let packet = ethernet_packet(b"hello");
+        let data = pcap_file(&[(10, 5, packet.clone()), (11, 0, vec![0; 20])]);
+        let segments = read(&data).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].index, 1);
+        assert_eq!(segments[0].time, 10_000_005_000);
+        assert_eq!(segments[0].payload, b"hello");
+
+        assert!(read(&data[..data.len() - 1]).is_err());
+        assert!(read(b"nonsense").is_err());
+    }
+
+    #[test]
+    fn test_pcapng() {
+        let mut shb = vec![0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0];
+        shb.extend_from_slice(&[0xff; 8]);
+        // Interface with nanosecond timestamps
+        let mut idb = vec![1, 0, 0, 0, 0, 0, 0, 0];
+        idb.extend_from_slice(&[9, 0, 1, 0, 9, 0, 0, 0, 0, 0, 0, 0]);
+        let packet = ethernet_packet(b"hello");
+        let ticks: u64 = 10_000_000_123;
+        let mut epb = vec![0; 4];
+        epb.extend_from_slice(&((ticks >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(ticks as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&packet);
+        let mut data = block(0x0a0d0d0a, &shb);
+        data.extend(block(1, &idb));
+        data.extend(block(6, &epb));
+        let segments = read(&data).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].time, ticks as i64);
+        assert_eq!(segments[0].payload, b"hello");
+    }
+}
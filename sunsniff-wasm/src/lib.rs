@@ -0,0 +1,268 @@
+/* Copyright 2024 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! WebAssembly build of the Sunsynk logger decoder, used by the pcap
+//! inspector in `examples/pcap-inspector`.
+//!
+//! To avoid needing any tools beyond cargo, the interface is a few plain
+//! exported functions rather than wasm-bindgen. The caller allocates
+//! buffers with [alloc], writes the capture file and time zone name into
+//! them, calls [inspect], and reads the JSON result from [result_ptr].
+
+use chrono_tz::Tz;
+use serde::Serialize;
+use std::cell::RefCell;
+use sunsniff_core::logger::sunsynk::{self, DecodeError};
+
+mod capture;
+
+/// Number of bytes of an unrecognised frame to include in the report
+const SAMPLE_SIZE: usize = 64;
+
+/// A decoded value
+#[derive(Serialize)]
+struct Value {
+    id: &'static str,
+    group: &'static str,
+    name: &'static str,
+    unit: &'static str,
+    /// `None` (null in JSON) if the value is not available
+    value: Option<f64>,
+}
+
+/// What a logger frame turned out to contain
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Content {
+    Data {
+        serial: String,
+        /// Inverter timestamp, in nanoseconds since the UNIX epoch
+        timestamp: i64,
+        values: Vec<Value>,
+    },
+    KeepAlive {
+        serial: String,
+    },
+    /// A frame that looks like it came from a logger but could not be
+    /// decoded. The sample is the start of the frame in hex.
+    Error {
+        error: String,
+        sample: String,
+    },
+}
+
+#[derive(Serialize)]
+struct Frame {
+    /// Packet number in the capture file
+    packet: usize,
+    /// Capture time, in nanoseconds since the UNIX epoch
+    capture_time: i64,
+    length: usize,
+    #[serde(flatten)]
+    content: Content,
+}
+
+#[derive(Serialize)]
+struct Report {
+    /// Number of TCP segments with a payload
+    segments: usize,
+    frames: Vec<Frame>,
+}
+
+/// Decode a single TCP payload, returning `None` if it is not from a logger
+fn decode(payload: &[u8], tz: Tz) -> Option<Content> {
+    if let Some(serial) = sunsynk::heartbeat_serial(payload) {
+        return Some(Content::KeepAlive {
+            serial: serial.to_owned(),
+        });
+    }
+    match sunsynk::decode(payload, |_| tz) {
+        Ok((mut update, _)) => {
+            update.fill_sums();
+            let values = update
+                .iter()
+                .map(|(field, value)| Value {
+                    id: field.id,
+                    group: field.group,
+                    name: field.name,
+                    unit: field.unit,
+                    value: value.is_finite().then_some(value),
+                })
+                .collect();
+            Some(Content::Data {
+                serial: update.serial,
+                timestamp: update.timestamp,
+                values,
+            })
+        }
+        Err(DecodeError::NotRecognised) => None,
+        Err(err) => {
+            let sample = payload.iter().take(SAMPLE_SIZE);
+            let sample: Vec<String> = sample.map(|b| format!("{b:02x}")).collect();
+            Some(Content::Error {
+                error: err.to_string(),
+                sample: sample.join(" "),
+            })
+        }
+    }
+}
+
+/// Decode all the logger frames in a capture file, returning a JSON report
+fn inspect_file(data: &[u8], timezone: &str) -> Result<String, String> {
+    let tz: Tz = timezone
+        .parse()
+        .map_err(|_| format!("Unknown time zone {timezone:?}"))?;
+    let segments = capture::read(data)?;
+    let mut report = Report {
+        segments: 0,
+        frames: vec![],
+    };
+    for segment in segments.iter().filter(|s| !s.payload.is_empty()) {
+        report.segments += 1;
+        if let Some(content) = decode(segment.payload, tz) {
+            report.frames.push(Frame {
+                packet: segment.index,
+                capture_time: segment.time,
+                length: segment.payload.len(),
+                content,
+            });
+        }
+    }
+    serde_json::to_string(&report).map_err(|err| err.to_string())
+}
+
+thread_local! {
+    /// Result of the last call to [inspect]
+    static RESULT: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// Allocate a buffer of `len` bytes for passing data to [inspect]
+#[no_mangle]
+pub extern "C" fn alloc(len: usize) -> *mut u8 {
+    let mut buffer = Vec::<u8>::with_capacity(len);
+    let ptr = buffer.as_mut_ptr();
+    std::mem::forget(buffer);
+    ptr
+}
+
+/// Free a buffer returned by [alloc]
+///
+/// # Safety
+///
+/// `ptr` and `len` must come from a single call to [alloc], and the buffer
+/// must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn dealloc(ptr: *mut u8, len: usize) {
+    // SAFETY: guaranteed by the caller
+    drop(unsafe { Vec::from_raw_parts(ptr, 0, len) });
+}
+
+/// Decode a capture file and time zone name (both in buffers from
+/// [alloc]). Returns the length of the result, which is JSON if
+/// `ok` is set to 1, or an error message otherwise.
+///
+/// # Safety
+///
+/// The pointers must refer to initialised buffers of the given lengths
+/// (allocated with [alloc]) and `ok` must be valid for writing.
+#[no_mangle]
+pub unsafe extern "C" fn inspect(
+    data: *const u8,
+    data_len: usize,
+    timezone: *const u8,
+    timezone_len: usize,
+    ok: *mut u32,
+) -> usize {
+    // SAFETY: guaranteed by the caller
+    let (data, timezone) = unsafe {
+        (
+            std::slice::from_raw_parts(data, data_len),
+            std::slice::from_raw_parts(timezone, timezone_len),
+        )
+    };
+    let result = match std::str::from_utf8(timezone) {
+        Ok(timezone) => inspect_file(data, timezone),
+        Err(_) => Err("Time zone is not valid UTF-8".to_owned()),
+    };
+    // SAFETY: guaranteed by the caller
+    unsafe { *ok = result.is_ok() as u32 };
+    let result = result.unwrap_or_else(|err| err);
+    let len = result.len();
+    RESULT.set(result);
+    len
+}
+
+/// Pointer to the result of the last call to [inspect]
+#[no_mangle]
+pub extern "C" fn result_ptr() -> *const u8 {
+    RESULT.with_borrow(|result| result.as_ptr())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use capture::test::{ethernet_packet, pcap_file};
+
+    fn frame(len: usize) -> Vec<u8> {
+        let mut payload = vec![0u8; len];
+        payload[0] = 0xa5;
+        payload[11..21].copy_from_slice(b"1234567890");
+        if len > 43 {
+            payload[37..43].copy_from_slice(&[22, 11, 5, 8, 32, 46]);
+        } else {
+            // Keep-alive control code
+            payload[3..5].copy_from_slice(&[0x10, 0x47]);
+        }
+        payload
+    }
+
+    #[test]
+    fn test_inspect_file() {
+        let data = pcap_file(&[
+            (1, 0, ethernet_packet(&frame(292))),
+            (2, 0, ethernet_packet(&frame(23))),
+            (3, 0, ethernet_packet(&frame(123))),
+            (4, 0, ethernet_packet(b"GET / HTTP/1.1")),
+        ]);
+        let report = inspect_file(&data, "Africa/Johannesburg").unwrap();
+        let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+        assert_eq!(report["segments"], 4);
+        let frames = report["frames"].as_array().unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0]["kind"], "data");
+        assert_eq!(frames[0]["packet"], 1);
+        assert_eq!(frames[0]["serial"], "1234567890");
+        assert_eq!(frames[0]["timestamp"], 1667629966000000000i64);
+        assert_eq!(frames[0]["values"][0]["value"], 0.0);
+        assert_eq!(frames[1]["kind"], "keep_alive");
+        assert_eq!(frames[2]["kind"], "error");
+        assert_eq!(frames[2]["error"], "unrecognised packet length 123");
+
+        assert!(inspect_file(&data, "Nowhere/Special").is_err());
+    }
+
+    #[test]
+    fn test_inspect() {
+        let data = pcap_file(&[(1, 0, ethernet_packet(&frame(292)))]);
+        let mut ok = 0;
+        // SAFETY: the pointers refer to live slices
+        let len = unsafe { inspect(data.as_ptr(), data.len(), b"UTC".as_ptr(), 3, &mut ok) };
+        assert_eq!(ok, 1);
+        // SAFETY: result_ptr points to a string of length len
+        let result = unsafe { std::slice::from_raw_parts(result_ptr(), len) };
+        assert!(result.starts_with(b"{\"segments\":1,"));
+    }
+}